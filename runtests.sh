@@ -3,6 +3,6 @@
 # Run the tests
 echo "Running tests..."
 
-cargo build && cd tests && ./runtests
+cargo build && cargo test && cd tests && ./runtests
 
 echo "Tests completed."
@@ -0,0 +1,857 @@
+use std::collections::HashMap;
+
+use crate::{
+    asm::Line,
+    cfg::Cfg,
+    codegen::{self, Backend, Options},
+    diagnostic::{Diagnostic, Result},
+    ir::{Address, BinOp, Cond, Function, Inst, Module, UnOp, VReg},
+    passes::{Pass, Passes},
+    regalloc::{self, Allocation},
+    stack::Frame,
+    target::Os,
+    types::Type,
+};
+
+// x9 to x15 may be clobbered by a call, x19 to x28 have to be given back as
+// they were found. x0 to x7 carry the arguments and are never handed out, so
+// setting up a call cannot overwrite a value still to be moved. x16 and x17
+// are kept for addresses and values in between, x18 belongs to the platform.
+const REGISTER_NAMES: [&str; 17] = [
+    "x9", "x10", "x11", "x12", "x13", "x14", "x15", "x19", "x20", "x21", "x22", "x23", "x24",
+    "x25", "x26", "x27", "x28",
+];
+const POOL_SIZE: usize = 17;
+// the registers in the pool from here on are callee saved
+const CALLEE_SAVED: usize = 7;
+
+const PARAM_REGISTERS: [&str; 8] = ["x0", "x1", "x2", "x3", "x4", "x5", "x6", "x7"];
+
+const SCRATCH: &str = "x16";
+const SCRATCH2: &str = "x17";
+
+// the 32 bit view of a 64 bit register, writing it clears the upper half
+fn w(register: &str) -> String {
+    format!("w{}", &register[1..])
+}
+
+fn condition(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "eq",
+        Cond::Ne => "ne",
+        Cond::Lt => "lt",
+        Cond::Le => "le",
+        Cond::Gt => "gt",
+        Cond::Ge => "ge",
+        Cond::Ult => "lo",
+        Cond::Ule => "ls",
+        Cond::Ugt => "hi",
+        Cond::Uge => "hs",
+    }
+}
+
+fn shift_instruction(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Shl => "lsl",
+        BinOp::Shr => "asr",
+        _ => "lsr",
+    }
+}
+
+// Generates ARM64 assembly following the AAPCS64 calling convention: the
+// first eight arguments in x0 to x7, the rest on the stack, the result in x0
+// and the return address in x30. Apple packs the arguments on the stack
+// tighter than that, which only matters when calling C with more than eight.
+pub struct CodeGen {
+    module: Module,
+    passes: Passes,
+    options: Options,
+    data: String,
+    text: Vec<Line>,
+    // where the function's virtual registers are kept
+    allocation: Allocation,
+    // the instruction being generated, in the flattened function
+    index: usize,
+    // constants written straight into the instructions reading them
+    immediates: HashMap<VReg, u64>,
+    // the size of the function's locals, spilled values go below them
+    locals: isize,
+    // the callee saved registers the function uses, kept below the spills
+    saved: Vec<usize>,
+    // every function's frame and the calls it makes, for --stack-usage
+    frames: Vec<Frame>,
+    // the last label handed out, blocks without one are numbered on from it
+    // the label of every string literal, to the one its text is emitted under
+    strings: HashMap<String, String>,
+}
+
+impl Backend for CodeGen {
+    fn generate(&mut self) -> Result<String> {
+        let (data, strings) = codegen::data(&self.module, self.options);
+        self.data = data;
+        self.strings = strings;
+
+        self.directive(".text");
+        self.directive(".p2align 2");
+        for function in self.module.functions.clone() {
+            self.function(&function)?;
+        }
+
+        // the peephole rules only know x86
+        Ok(self.combine())
+    }
+
+    fn frames(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.frames)
+    }
+}
+
+// The builtins for AArch64, the runtime linked with every program
+pub fn runtime(options: Options) -> String {
+    let mut codegen = CodeGen::new(Module::default(), Passes::new(0), options);
+    codegen.runtime();
+    codegen.combine()
+}
+
+impl CodeGen {
+    pub fn new(module: Module, passes: Passes, options: Options) -> Self {
+        Self {
+            passes,
+            options,
+            module,
+            data: String::new(),
+            text: Vec::new(),
+            allocation: Allocation::default(),
+            index: 0,
+            immediates: HashMap::new(),
+            locals: 0,
+            saved: Vec::new(),
+            frames: Vec::new(),
+            strings: HashMap::new(),
+        }
+    }
+
+    // the print builtins hand their argument to printf, which on macOS takes
+    // everything after the format on the stack, and readint has scanf read a
+    // number into its frame
+    fn runtime(&mut self) {
+        let section = match self.options.target.os {
+            Os::Macos => "__TEXT,__cstring",
+            _ => ".rodata",
+        };
+        self.data.push_str(&format!("\t.section\t{}\n", section));
+        self.data.push_str(".LC0:\n\t.asciz\t\"%d\\n\"\n");
+        self.data.push_str(".LC1:\n\t.asciz\t\"%c\"\n");
+        self.data.push_str(".LC2:\n\t.asciz\t\"%s\"\n");
+        self.data.push_str(".LC3:\n\t.asciz\t\"%llu\"\n");
+        self.data.push_str(".LC5:\n\t.asciz\t\"%llu\\n\"\n");
+        self.data.push_str(".LC6:\n\t.asciz\t\"%lld\\n\"\n");
+        self.data.push_str(".LC7:\n\t.asciz\t\"0x%llx\\n\"\n");
+        self.data.push_str(&format!(
+            ".LC9:\n\t.asciz\t\"{}\"\n",
+            codegen::PROFILE_FORMAT
+        ));
+
+        self.directive(".text");
+        self.directive(".p2align 2");
+        for (name, format) in [
+            ("printint", ".LC0"),
+            ("printchar", ".LC1"),
+            ("printstr", ".LC2"),
+            ("printu64", ".LC5"),
+            ("printi64", ".LC6"),
+            ("printhex", ".LC7"),
+        ] {
+            self.builtin(name);
+            self.emit("stp", &["x29", "x30", "[sp, #-16]!"]);
+            self.emit("mov", &["x29", "sp"]);
+            match self.options.target.os {
+                Os::Macos => {
+                    self.emit("sub", &["sp", "sp", "#16"]);
+                    self.emit("str", &["x0", "[sp]"]);
+                }
+                _ => self.emit("mov", &["x1", "x0"]),
+            }
+            self.symbol_address("x0", format, false);
+            self.emit("bl", &[&self.symbol("printf")]);
+            self.emit("mov", &["sp", "x29"]);
+            self.emit("ldp", &["x29", "x30", "[sp]", "#16"]);
+            self.emit("ret", &[]);
+        }
+
+        self.builtin("readint");
+        self.emit("stp", &["x29", "x30", "[sp, #-32]!"]);
+        self.emit("mov", &["x29", "sp"]);
+        self.emit("str", &["xzr", "[x29, #16]"]);
+        self.emit("add", &["x1", "x29", "#16"]);
+        if self.options.target.os == Os::Macos {
+            self.emit("sub", &["sp", "sp", "#16"]);
+            self.emit("str", &["x1", "[sp]"]);
+        }
+        self.symbol_address("x0", ".LC3", false);
+        self.emit("bl", &[&self.symbol("scanf")]);
+        self.emit("ldr", &["x0", "[x29, #16]"]);
+        self.emit("mov", &["sp", "x29"]);
+        self.emit("ldp", &["x29", "x30", "[sp]", "#32"]);
+        self.emit("ret", &[]);
+
+        // a failed assert or check flushes what the program printed, writes
+        // where it is to stderr and aborts
+        self.builtin("crust.panic");
+        self.emit("stp", &["x29", "x30", "[sp, #-32]!"]);
+        self.emit("mov", &["x29", "sp"]);
+        self.emit("str", &["x0", "[x29, #16]"]);
+        self.emit("mov", &["x0", "xzr"]);
+        self.emit("bl", &[&self.symbol("fflush")]);
+        self.stderr("x1");
+        self.emit("ldr", &["x0", "[x29, #16]"]);
+        self.emit("bl", &[&self.symbol("fputs")]);
+        self.emit("bl", &[&self.symbol("abort")]);
+
+        self.data.push_str(codegen::RANDOM_STATE);
+        self.builtin("rand");
+        self.symbol_address("x1", ".LC4", false);
+        self.emit("ldr", &["x0", "[x1]"]);
+        for shift in ["lsl #13", "lsr #7", "lsl #17"] {
+            self.emit("eor", &["x0", "x0", "x0", shift]);
+        }
+        self.emit("str", &["x0", "[x1]"]);
+        self.emit("ret", &[]);
+
+        // clock_ms has clock_gettime fill in a timespec in its frame and
+        // counts its seconds and nanoseconds in milliseconds
+        self.builtin("clock_ms");
+        self.emit("stp", &["x29", "x30", "[sp, #-32]!"]);
+        self.emit("mov", &["x29", "sp"]);
+        let clock = format!("#{}", codegen::monotonic_clock(self.options.target));
+        self.emit("mov", &["x0", &clock]);
+        self.emit("add", &["x1", "x29", "#16"]);
+        self.emit("bl", &[&self.symbol("clock_gettime")]);
+        self.emit("ldp", &["x0", "x1", "[x29, #16]"]);
+        self.emit("mov", &["x2", "#1000"]);
+        self.emit("mul", &["x0", "x0", "x2"]);
+        self.constant("x2", 1000000);
+        self.emit("udiv", &["x1", "x1", "x2"]);
+        self.emit("add", &["x0", "x0", "x1"]);
+        self.emit("ldp", &["x29", "x30", "[sp]", "#32"]);
+        self.emit("ret", &[]);
+
+        self.profiler();
+    }
+
+    // The hooks --instrument-functions has every function call, with the
+    // function's record in x0: calls, depth, deepest, the next record and
+    // the function's name. crust.report writes every record to stderr.
+    fn profiler(&mut self) {
+        let label = |function: &str, name: &str| self.options.target.label(function, name);
+        let counted = label("crust.enter", "counted");
+        let (next, done) = (label("crust.report", "next"), label("crust.report", "done"));
+        self.data.push_str(codegen::PROFILE_LIST);
+
+        // the first call links the record onto the last one, or makes it
+        // the first when there is none
+        self.builtin("crust.enter");
+        self.emit("ldr", &["x2", "[x0]"]);
+        self.emit("cbnz", &["x2", &counted]);
+        self.emit("str", &["x1", "[x0, #32]"]);
+        self.symbol_address("x3", ".LC8", false);
+        self.emit("ldr", &["x4", "[x3, #8]"]);
+        self.emit("add", &["x5", "x4", "#24"]);
+        self.emit("cmp", &["x4", "#0"]);
+        self.emit("csel", &["x5", "x3", "x5", "eq"]);
+        self.emit("str", &["x0", "[x5]"]);
+        self.emit("str", &["x0", "[x3, #8]"]);
+        self.label(&counted);
+        self.emit("add", &["x2", "x2", "#1"]);
+        self.emit("str", &["x2", "[x0]"]);
+        self.emit("ldp", &["x2", "x3", "[x0, #8]"]);
+        self.emit("add", &["x2", "x2", "#1"]);
+        self.emit("cmp", &["x2", "x3"]);
+        self.emit("csel", &["x3", "x2", "x3", "hi"]);
+        self.emit("stp", &["x2", "x3", "[x0, #8]"]);
+        self.emit("ret", &[]);
+
+        self.builtin("crust.exit");
+        self.emit("ldr", &["x1", "[x0, #8]"]);
+        self.emit("sub", &["x1", "x1", "#1"]);
+        self.emit("str", &["x1", "[x0, #8]"]);
+        self.emit("ret", &[]);
+
+        // the record is kept in x19 across the calls, the variadic arguments
+        // of fprintf go on the stack on macOS
+        self.builtin("crust.report");
+        self.emit("stp", &["x29", "x30", "[sp, #-32]!"]);
+        self.emit("mov", &["x29", "sp"]);
+        self.emit("str", &["x19", "[x29, #16]"]);
+        self.emit("mov", &["x0", "xzr"]);
+        self.emit("bl", &[&self.symbol("fflush")]);
+        self.symbol_address("x19", ".LC8", false);
+        self.emit("ldr", &["x19", "[x19]"]);
+        self.label(&next);
+        self.emit("cbz", &["x19", &done]);
+        self.stderr("x0");
+        self.symbol_address("x1", ".LC9", false);
+        self.emit("ldr", &["x2", "[x19, #32]"]);
+        self.emit("ldr", &["x3", "[x19]"]);
+        self.emit("ldr", &["x4", "[x19, #16]"]);
+        if self.options.target.os == Os::Macos {
+            self.emit("sub", &["sp", "sp", "#32"]);
+            self.emit("stp", &["x2", "x3", "[sp]"]);
+            self.emit("str", &["x4", "[sp, #16]"]);
+        }
+        self.emit("bl", &[&self.symbol("fprintf")]);
+        self.emit("mov", &["sp", "x29"]);
+        self.emit("ldr", &["x19", "[x19, #24]"]);
+        self.emit("b", &[&next]);
+        self.label(&done);
+        self.emit("ldr", &["x19", "[x29, #16]"]);
+        self.emit("ldp", &["x29", "x30", "[sp]", "#32"]);
+        self.emit("ret", &[]);
+    }
+
+    // puts the C library's stderr in the register
+    fn stderr(&mut self, to: &str) {
+        let stderr = match self.options.target.os {
+            Os::Macos => "___stderrp",
+            _ => "stderr",
+        };
+        self.symbol_address(to, stderr, true);
+        self.emit("ldr", &[to, &format!("[{}]", to)]);
+    }
+
+    // the data and text sections together
+    fn combine(&mut self) -> String {
+        let mut assembly = std::mem::take(&mut self.data);
+        for line in &self.text {
+            assembly.push_str(&format!("{}\n", line));
+        }
+        assembly
+    }
+
+    fn builtin(&mut self, name: &str) {
+        let symbol = self.symbol(name);
+        for directive in codegen::runtime_symbol(self.options.target, &symbol) {
+            self.directive(&directive);
+        }
+        self.label(&symbol);
+    }
+
+    fn emit(&mut self, op: &str, operands: &[&str]) {
+        self.text.push(Line::Inst {
+            op: op.to_string(),
+            operands: operands.iter().map(|operand| operand.to_string()).collect(),
+        });
+    }
+
+    fn label(&mut self, name: &str) {
+        self.text.push(Line::Label(name.to_string()));
+    }
+
+    fn directive(&mut self, directive: &str) {
+        self.text.push(Line::Directive(directive.to_string()));
+    }
+
+    fn symbol(&self, name: &str) -> String {
+        self.options.target.symbol(name)
+    }
+
+    // the register an instruction reads a virtual register from
+    fn register(&self, vreg: VReg) -> &'static str {
+        REGISTER_NAMES[self.allocation.register(vreg, self.index)]
+    }
+
+    // the register an instruction writes a virtual register to
+    fn destination(&self, vreg: VReg) -> &'static str {
+        REGISTER_NAMES[self.allocation.destination(vreg, self.index)]
+    }
+
+    // where a virtual register that did not get a register lives, from x29
+    fn spill_slot(&self, vreg: VReg) -> Option<isize> {
+        let slot = *self.allocation.slots.get(&vreg)? as isize;
+        Some(-self.locals - 8 * (slot + 1))
+    }
+
+    // where a callee saved register is kept while the function runs
+    fn save_slot(&self, index: usize) -> isize {
+        let slot = (self.allocation.slots.len() + index) as isize;
+        -self.locals - 8 * (slot + 1)
+    }
+
+    // movz sets 16 bits and clears the others, movk fills in the rest
+    fn constant(&mut self, to: &str, value: u64) {
+        self.emit("movz", &[to, &format!("#{}", value & 0xffff)]);
+        for shift in [16, 32, 48] {
+            let part = (value >> shift) & 0xffff;
+            if part != 0 {
+                let shift = format!("lsl #{}", shift);
+                self.emit("movk", &[to, &format!("#{}", part), &shift]);
+            }
+        }
+    }
+
+    // puts x29 plus the offset into a register, an immediate has 12 bits
+    fn frame_address(&mut self, to: &str, offset: isize) {
+        let instruction = if offset < 0 { "sub" } else { "add" };
+        let distance = offset.unsigned_abs();
+        if distance < 4096 {
+            self.emit(instruction, &[to, "x29", &format!("#{}", distance)]);
+        } else {
+            self.constant(to, distance as u64);
+            self.emit(instruction, &[to, "x29", to]);
+        }
+    }
+
+    // a slot of the frame, which a load or store only reaches by its offset
+    // within 256 bytes either side of x29
+    fn frame(&mut self, offset: isize) -> String {
+        if (-256..256).contains(&offset) {
+            return format!("[x29, #{}]", offset);
+        }
+        self.frame_address(SCRATCH, offset);
+        format!("[{}]", SCRATCH)
+    }
+
+    // The address of a symbol is put together from the 4KB page it is on and
+    // where on the page it is. From the GOT, that is where the address is.
+    fn symbol_address(&mut self, to: &str, symbol: &str, got: bool) {
+        let (page, offset) = match (self.options.target.os, got) {
+            (Os::Macos, false) => (format!("{}@PAGE", symbol), format!("{}@PAGEOFF", symbol)),
+            (Os::Macos, true) => (
+                format!("{}@GOTPAGE", symbol),
+                format!("{}@GOTPAGEOFF", symbol),
+            ),
+            (_, false) => (symbol.to_string(), format!(":lo12:{}", symbol)),
+            (_, true) => (format!(":got:{}", symbol), format!(":got_lo12:{}", symbol)),
+        };
+        self.emit("adrp", &[to, &page]);
+        if got {
+            self.emit("ldr", &[to, &format!("[{}, {}]", to, offset)]);
+        } else {
+            self.emit("add", &[to, to, &offset]);
+        }
+    }
+
+    // string literals never leave the file, other globals are reached through
+    // the GOT in position independent code, and extern ones always
+    fn global_address(&mut self, to: &str, name: &str) {
+        match self.strings.get(name) {
+            Some(label) => self.symbol_address(to, &label.clone(), false),
+            None => {
+                let got = self.options.pic || self.module.is_external(name);
+                self.symbol_address(to, &self.symbol(name), got)
+            }
+        }
+    }
+
+    fn operand(&mut self, address: &Address) -> String {
+        match address {
+            Address::Global(name) => {
+                self.global_address(SCRATCH, name);
+                format!("[{}]", SCRATCH)
+            }
+            Address::Local(offset) => self.frame(*offset),
+            Address::Indirect(vreg) => format!("[{}]", self.register(*vreg)),
+        }
+    }
+
+    fn function(&mut self, function: &Function) -> Result<()> {
+        let cfg = Cfg::build(&function.insts, &mut function.last_label());
+        self.immediates = codegen::immediates(&function.insts);
+        self.allocation =
+            regalloc::allocate(&cfg, &self.immediates, POOL_SIZE, PARAM_REGISTERS.len())?;
+        // rounded up to whole slots
+        self.locals = (function.stack_size as isize + 7) & !7;
+
+        self.function_preamble(function)?;
+        let insts = cfg.flatten();
+        // the return after a tail call, control never comes back to it
+        let mut skip = None;
+        for (i, inst) in insts.iter().enumerate() {
+            if skip == Some(i) {
+                continue;
+            }
+            self.index = i;
+
+            for vreg in self.allocation.register_uses(inst) {
+                if let Some(offset) = self.spill_slot(vreg) {
+                    let slot = self.frame(offset);
+                    self.emit("ldr", &[self.register(vreg), &slot]);
+                }
+            }
+
+            match codegen::tail_call(&insts, i, PARAM_REGISTERS.len()) {
+                Some((name, args)) if self.passes.enabled(Pass::TailCalls) => {
+                    self.tail_call(name, args);
+                    skip = Some(i + 1);
+                }
+                _ => self.instruction(inst, function)?,
+            }
+
+            if let Some(dst) = inst.def() {
+                if let Some(offset) = self.spill_slot(dst) {
+                    let slot = self.frame(offset);
+                    self.emit("str", &[self.destination(dst), &slot]);
+                }
+            }
+        }
+        self.function_postamble(function);
+        if codegen::function_section(self.options.target, &function.placement).is_some() {
+            self.directive(".text");
+        }
+
+        Ok(())
+    }
+
+    fn function_preamble(&mut self, function: &Function) -> Result<()> {
+        for directive in codegen::place_function(self.options.target, &function.placement) {
+            self.directive(&directive);
+        }
+        let symbol = self.symbol(&function.name);
+        let exported = self.options.exports(function);
+        match self.options.target.os {
+            Os::Macos if exported => self.directive(&format!(".globl {}", symbol)),
+            Os::Macos => {}
+            _ => {
+                if exported {
+                    self.directive(&format!(".global {}", symbol));
+                }
+                self.directive(&format!(".type\t{}, %function", symbol));
+            }
+        }
+        self.label(&symbol);
+        self.emit("stp", &["x29", "x30", "[sp, #-16]!"]);
+        self.emit("mov", &["x29", "sp"]);
+
+        // The frame holds the locals, then the spilled values, then the
+        // callee saved registers, with sp kept a multiple of 16
+        let used = self.allocation.used_registers();
+        self.saved = (CALLEE_SAVED..POOL_SIZE)
+            .filter(|r| used.contains(r))
+            .collect();
+        let size = self.locals + 8 * (self.allocation.slots.len() + self.saved.len()) as isize;
+        let size = ((size + 15) & !15) as u64;
+        // with the frame record above it
+        self.frames.push(Frame {
+            name: function.name.clone(),
+            size: size + 16,
+            ..Frame::default()
+        });
+        if size >= 4096 {
+            self.constant(SCRATCH, size);
+            self.emit("sub", &["sp", "sp", SCRATCH]);
+        } else if size > 0 {
+            self.emit("sub", &["sp", "sp", &format!("#{}", size)]);
+        }
+
+        for (index, r) in self.saved.clone().into_iter().enumerate() {
+            let slot = self.frame(self.save_slot(index));
+            self.emit("str", &[REGISTER_NAMES[r], &slot]);
+        }
+
+        // Copy the parameters to their slots, the first eight from their
+        // registers and the rest from where the caller stored them, above
+        // the frame record
+        for (i, param) in function.params.iter().enumerate() {
+            let register = match PARAM_REGISTERS.get(i) {
+                Some(register) => *register,
+                None => {
+                    let stored = 16 + 8 * (i - PARAM_REGISTERS.len());
+                    self.emit("ldr", &[SCRATCH2, &format!("[x29, #{}]", stored)]);
+                    SCRATCH2
+                }
+            };
+            let slot = self.frame(param.offset);
+            self.store(register, slot, &param.ty)?;
+        }
+
+        Ok(())
+    }
+
+    fn function_postamble(&mut self, function: &Function) {
+        self.label(&self.options.target.label(&function.name, "end"));
+        self.leave_frame();
+        self.emit("ret", &[]);
+    }
+
+    // gives the caller back its registers, its stack pointer and its frame
+    fn leave_frame(&mut self) {
+        for (index, r) in self.saved.clone().into_iter().enumerate() {
+            let slot = self.frame(self.save_slot(index));
+            self.emit("ldr", &[REGISTER_NAMES[r], &slot]);
+        }
+
+        self.emit("mov", &["sp", "x29"]);
+        self.emit("ldp", &["x29", "x30", "[sp]", "#16"]);
+    }
+
+    fn instruction(&mut self, inst: &Inst, function: &Function) -> Result<()> {
+        match inst {
+            Inst::Const { dst, .. } if self.immediates.contains_key(dst) => {}
+            Inst::Const { dst, value } => {
+                let r = self.destination(*dst);
+                self.constant(r, *value);
+            }
+            Inst::Copy { dst, src } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                self.move_register(src, r);
+            }
+            Inst::Cast { dst, src, ty } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                self.cast(r, src, ty);
+            }
+            Inst::Binary {
+                op: op @ (BinOp::Shl | BinOp::Shr | BinOp::UShr),
+                dst,
+                left,
+                right,
+            } if self.immediates.contains_key(right) => {
+                let left = self.register(*left);
+                let r = self.destination(*dst);
+                let amount = format!("#{}", self.immediates[right] & 63);
+                self.emit(shift_instruction(*op), &[r, left, &amount]);
+            }
+            Inst::Binary {
+                op,
+                dst,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let r = self.destination(*dst);
+                let instruction = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "sdiv",
+                    BinOp::UDiv => "udiv",
+                    BinOp::And => "and",
+                    BinOp::Or => "orr",
+                    BinOp::Xor => "eor",
+                    BinOp::Shl | BinOp::Shr | BinOp::UShr => shift_instruction(*op),
+                };
+                self.emit(instruction, &[r, left, right]);
+            }
+            Inst::Unary { op, dst, src } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                match op {
+                    UnOp::Neg => self.emit("neg", &[r, src]),
+                    UnOp::Not => self.emit("mvn", &[r, src]),
+                    UnOp::LogicalNot => {
+                        self.emit("cmp", &[src, "#0"]);
+                        self.emit("cset", &[r, "eq"]);
+                    }
+                    UnOp::ToBool => {
+                        self.emit("cmp", &[src, "#0"]);
+                        self.emit("cset", &[r, "ne"]);
+                    }
+                }
+            }
+            Inst::Compare {
+                cond,
+                dst,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let r = self.destination(*dst);
+                self.emit("cmp", &[left, right]);
+                self.emit("cset", &[r, condition(*cond)]);
+            }
+            Inst::Select {
+                cond,
+                dst,
+                left,
+                right,
+                then,
+                otherwise,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let (then, otherwise) = (self.register(*then), self.register(*otherwise));
+                let r = self.destination(*dst);
+                self.emit("cmp", &[left, right]);
+                self.emit("csel", &[r, then, otherwise, condition(*cond)]);
+            }
+            Inst::Load { dst, addr, ty } => {
+                let operand = self.operand(addr);
+                let r = self.destination(*dst);
+                self.load(r, operand, ty)?;
+            }
+            Inst::Store { src, addr, ty } => {
+                let operand = self.operand(addr);
+                self.store(self.register(*src), operand, ty)?;
+            }
+            Inst::Lea { dst, addr } => {
+                let r = self.destination(*dst);
+                match addr {
+                    Address::Global(name) => self.global_address(r, name),
+                    Address::Local(offset) => self.frame_address(r, *offset),
+                    Address::Indirect(vreg) => self.move_register(self.register(*vreg), r),
+                }
+            }
+            Inst::Call { dst, name, args } => {
+                self.function_call(name, args);
+                let r = self.destination(*dst);
+                self.emit("mov", &[r, "x0"]);
+            }
+            Inst::Return { src, ty } => {
+                self.return_value(self.register(*src), ty)?;
+
+                // leave through the epilogue, a return is not always the last statement
+                self.emit("b", &[&self.options.target.label(&function.name, "end")]);
+            }
+            Inst::Jump { target } => {
+                self.emit("b", &[&self.options.target.label(&function.name, target)]);
+            }
+            Inst::Branch {
+                cond,
+                left,
+                right,
+                target,
+            } => {
+                self.emit("cmp", &[self.register(*left), self.register(*right)]);
+                let jump = format!("b.{}", condition(*cond));
+                self.emit(&jump, &[&self.options.target.label(&function.name, target)]);
+            }
+            Inst::Label(label) => {
+                self.label(&self.options.target.label(&function.name, label));
+            }
+            Inst::Phi { .. } => {
+                return Err(Diagnostic::error("Unexpected phi outside of SSA form"));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cast(&mut self, r: &str, src: &str, ty: &Type) {
+        match ty {
+            Type::U8 | Type::Char => self.emit("uxtb", &[&w(r), &w(src)]),
+            Type::I8 => self.emit("sxtb", &[r, &w(src)]),
+            Type::U16 => self.emit("uxth", &[&w(r), &w(src)]),
+            Type::I16 => self.emit("sxth", &[r, &w(src)]),
+            Type::U32 => self.emit("mov", &[&w(r), &w(src)]),
+            Type::I32 => self.emit("sxtw", &[r, &w(src)]),
+            _ => self.move_register(src, r),
+        }
+    }
+
+    fn move_register(&mut self, from: &str, to: &str) {
+        if from != to {
+            self.emit("mov", &[to, from]);
+        }
+    }
+
+    fn load(&mut self, r: &str, operand: String, ty: &Type) -> Result<()> {
+        let (instruction, register) = match ty {
+            Type::U8 | Type::Char => ("ldrb", w(r)),
+            Type::I8 => ("ldrsb", r.to_string()),
+            Type::U16 => ("ldrh", w(r)),
+            Type::I16 => ("ldrsh", r.to_string()),
+            Type::U32 => ("ldr", w(r)),
+            Type::I32 => ("ldrsw", r.to_string()),
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => {
+                ("ldr", r.to_string())
+            }
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+        };
+
+        self.emit(instruction, &[&register, &operand]);
+        Ok(())
+    }
+
+    fn store(&mut self, r: &str, operand: String, ty: &Type) -> Result<()> {
+        let (instruction, register) = match ty {
+            Type::U8 | Type::I8 | Type::Char => ("strb", w(r)),
+            Type::U16 | Type::I16 => ("strh", w(r)),
+            Type::U32 | Type::I32 => ("str", w(r)),
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => {
+                ("str", r.to_string())
+            }
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+        };
+
+        self.emit(instruction, &[&register, &operand]);
+        Ok(())
+    }
+
+    fn return_value(&mut self, r: &str, ty: &Type) -> Result<()> {
+        if !ty.is_int() && !matches!(ty, Type::Pointer { .. } | Type::Closure { .. }) {
+            return Err(Diagnostic::error(format!("Unexpected type {:?}", ty)));
+        }
+
+        self.cast("x0", r, ty);
+        Ok(())
+    }
+
+    fn function_call(&mut self, name: &str, args: &[VReg]) {
+        // the live registers the callee may clobber are kept on the stack
+        // across the call, above the arguments that go there
+        let saved = self
+            .allocation
+            .live_across(self.index)
+            .into_iter()
+            .filter(|r| *r < CALLEE_SAVED)
+            .collect::<Vec<_>>();
+        let stack_args = args.len().saturating_sub(PARAM_REGISTERS.len());
+        let size = (8 * (stack_args + saved.len()) + 15) & !15;
+        if size > 0 {
+            self.emit("sub", &["sp", "sp", &format!("#{}", size)]);
+        }
+        for (i, r) in saved.iter().enumerate() {
+            let slot = format!("[sp, #{}]", 8 * (stack_args + i));
+            self.emit("str", &[REGISTER_NAMES[*r], &slot]);
+        }
+
+        // arguments beyond the eighth, spilled ones straight from their slot
+        for (i, arg) in args.iter().skip(PARAM_REGISTERS.len()).enumerate() {
+            let register = match self.spill_slot(*arg) {
+                Some(offset) => {
+                    let slot = self.frame(offset);
+                    self.emit("ldr", &[SCRATCH2, &slot]);
+                    SCRATCH2
+                }
+                None => self.register(*arg),
+            };
+            self.emit("str", &[register, &format!("[sp, #{}]", 8 * i)]);
+        }
+
+        for (arg, to) in args.iter().zip(PARAM_REGISTERS) {
+            self.emit("mov", &[to, self.register(*arg)]);
+        }
+
+        self.emit("bl", &[&self.symbol(name)]);
+        let pushed = size as u64;
+        self.frames
+            .last_mut()
+            .unwrap()
+            .calls
+            .push((name.to_string(), pushed));
+
+        for (i, r) in saved.iter().enumerate() {
+            let slot = format!("[sp, #{}]", 8 * (stack_args + i));
+            self.emit("ldr", &[REGISTER_NAMES[*r], &slot]);
+        }
+        if size > 0 {
+            self.emit("add", &["sp", "sp", &format!("#{}", size)]);
+        }
+    }
+
+    // Branches to the function instead of calling it, once this frame is
+    // gone, so it returns straight to our caller and the stack does not grow.
+    fn tail_call(&mut self, name: &str, args: &[VReg]) {
+        for (arg, to) in args.iter().zip(PARAM_REGISTERS) {
+            self.emit("mov", &[to, self.register(*arg)]);
+        }
+
+        self.leave_frame();
+        self.emit("b", &[&self.symbol(name)]);
+        self.frames
+            .last_mut()
+            .unwrap()
+            .tail_calls
+            .push(name.to_string());
+    }
+}
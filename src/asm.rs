@@ -0,0 +1,223 @@
+use std::fmt;
+
+// The syntax the assembly is printed in, GNU as reads both
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Att,
+    Intel,
+}
+
+impl Syntax {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "att" => Some(Syntax::Att),
+            "intel" => Some(Syntax::Intel),
+            _ => None,
+        }
+    }
+}
+
+// One line of the text section, kept in pieces until it is printed so the
+// peephole rules can look at instructions without parsing them back.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Line {
+    Label(String),
+    Inst { op: String, operands: Vec<String> },
+    // anything the assembler is told rather than asked to run, as written
+    Directive(String),
+}
+
+impl Line {
+    pub fn is_inst(&self, name: &str) -> bool {
+        matches!(self, Line::Inst { op, .. } if op == name)
+    }
+
+    // whether the line reads the flags a compare leaves behind
+    pub fn reads_flags(&self) -> bool {
+        match self {
+            Line::Inst { op, .. } => {
+                (op.starts_with('j') && op != "jmp")
+                    || op.starts_with("set")
+                    || op.starts_with("cmov")
+                    || op == "adc"
+                    || op == "sbb"
+            }
+            _ => false,
+        }
+    }
+
+    // Whether the line surely overwrites all the flags. A shift by %cl is
+    // left out, shifting by zero leaves them as they were.
+    pub fn writes_flags(&self) -> bool {
+        match self {
+            Line::Inst { op, .. } => matches!(
+                op.as_str(),
+                "addq"
+                    | "subq"
+                    | "imulq"
+                    | "idivq"
+                    | "divq"
+                    | "negq"
+                    | "andq"
+                    | "orq"
+                    | "xorq"
+                    | "xorl"
+                    | "cmpq"
+                    | "testq"
+                    // whatever is called leaves the flags undefined
+                    | "call"
+            ),
+            _ => false,
+        }
+    }
+
+    // whether control may continue somewhere other than the next line
+    pub fn is_control_flow(&self) -> bool {
+        match self {
+            Line::Label(_) => true,
+            Line::Inst { op, .. } => op.starts_with('j') || op == "ret",
+            Line::Directive(_) => false,
+        }
+    }
+}
+
+// Instructions are written the AT&T way, with the operand size in the name
+// of the instruction. Intel syntax leaves that out and puts it on the memory
+// operand instead, and lists the destination first.
+impl Line {
+    pub fn format(&self, syntax: Syntax) -> String {
+        match (syntax, self) {
+            // GNU as takes a symbol named like a register or an operator of
+            // Intel syntax, `rcx` or `offset`, for what it is named like,
+            // quoted or not, so the instruction is written the AT&T way
+            (Syntax::Intel, Line::Inst { operands, .. })
+                if operands
+                    .iter()
+                    .any(|operand| symbol(operand).is_some_and(intel_reserved)) =>
+            {
+                format!("\t.att_syntax\n{}\n\t.intel_syntax noprefix", self)
+            }
+            (Syntax::Intel, Line::Inst { op, operands }) => {
+                let (op, size) = intel_instruction(op);
+                let operands = operands
+                    .iter()
+                    .rev()
+                    .map(|operand| intel_operand(operand, size))
+                    .collect::<Vec<_>>();
+                if operands.is_empty() {
+                    format!("\t{}", op)
+                } else {
+                    format!("\t{}\t{}", op, operands.join(", "))
+                }
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+// the name of an instruction in Intel syntax and the size of what it reads
+// from memory, lea only computes the address
+fn intel_instruction(op: &str) -> (&str, Option<&str>) {
+    let size = |suffix| match suffix {
+        'b' => "BYTE",
+        'w' => "WORD",
+        'l' => "DWORD",
+        _ => "QWORD",
+    };
+
+    match op {
+        "movzbq" | "movzbl" => ("movzx", Some("BYTE")),
+        "movzwq" | "movzwl" => ("movzx", Some("WORD")),
+        "movsbq" => ("movsx", Some("BYTE")),
+        "movswq" => ("movsx", Some("WORD")),
+        "movslq" => ("movsxd", Some("DWORD")),
+        "leaq" => ("lea", None),
+        _ => {
+            let sized = [
+                "mov", "add", "sub", "imul", "idiv", "div", "and", "or", "xor", "neg", "not",
+                "cmp", "test", "sal", "sar", "shr", "push", "pop",
+            ];
+            match op.split_at(op.len() - 1) {
+                (name, suffix) if sized.contains(&name) => {
+                    (name, Some(size(suffix.chars().next().unwrap())))
+                }
+                _ => (op, None),
+            }
+        }
+    }
+}
+
+// the symbol an operand refers to, `x` in `$x`, `x(%rip)`,
+// `x@GOTPCREL(%rip)` or `x@PLT`
+fn symbol(operand: &str) -> Option<&str> {
+    let operand = operand.strip_prefix('$').unwrap_or(operand);
+    let name = operand.split(['(', '@', '+']).next()?;
+    let identifier = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+    identifier.then_some(name)
+}
+
+// whether Intel syntax reads the name as a register or an operator, in any
+// case, rather than as a symbol
+fn intel_reserved(name: &str) -> bool {
+    const NAMES: &[&str] = &[
+        "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "rip", "eax", "ebx", "ecx", "edx",
+        "esi", "edi", "ebp", "esp", "eip", "ax", "bx", "cx", "dx", "si", "di", "bp", "sp", "ip",
+        "al", "bl", "cl", "dl", "ah", "bh", "ch", "dh", "sil", "dil", "bpl", "spl", "cs", "ds",
+        "es", "fs", "gs", "ss", "st", "offset", "ptr", "byte", "word", "dword", "fword", "qword",
+        "tbyte", "oword", "short", "flat", "and", "or", "xor", "not", "mod", "shl", "shr", "eq",
+        "ne", "lt", "le", "gt", "ge",
+    ];
+    let name = name.to_ascii_lowercase();
+    let numbered = |prefix: &str, suffixes: &[char]| {
+        name.strip_prefix(prefix).is_some_and(|rest| {
+            let digits = rest.trim_end_matches(suffixes);
+            !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+        })
+    };
+    NAMES.contains(&name.as_str())
+        || name.ends_with("mmword")
+        // r8 to r15 with their b, w, d and l forms, xmm0 and the like
+        || numbered("r", &['b', 'w', 'd', 'l'])
+        || ["xmm", "ymm", "zmm", "mm", "k", "cr", "dr", "tr", "bnd"]
+            .iter()
+            .any(|prefix| numbered(prefix, &[]))
+}
+
+// $5 is 5, %rax is rax and -8(%rbp) is QWORD PTR [rbp-8]
+fn intel_operand(operand: &str, size: Option<&str>) -> String {
+    if let Some(immediate) = operand.strip_prefix('$') {
+        return immediate.to_string();
+    }
+    if let Some(register) = operand.strip_prefix('%') {
+        return register.to_string();
+    }
+    let Some((displacement, base)) = operand.split_once('(') else {
+        // a label to jump to or a function to call
+        return operand.to_string();
+    };
+
+    let base = base.trim_end_matches(')').trim_start_matches('%');
+    let address = match displacement {
+        "" => format!("[{}]", base),
+        _ if displacement.starts_with('-') => format!("[{}{}]", base, displacement),
+        _ => format!("[{}+{}]", base, displacement),
+    };
+    match size {
+        Some(size) => format!("{} PTR {}", size, address),
+        None => address,
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Line::Label(name) => write!(f, "{}:", name),
+            Line::Inst { op, operands } if operands.is_empty() => write!(f, "\t{}", op),
+            Line::Inst { op, operands } => write!(f, "\t{}\t{}", op, operands.join(", ")),
+            Line::Directive(directive) => write!(f, "\t{}", directive),
+        }
+    }
+}
@@ -1,6 +1,49 @@
 use std::{cell::RefCell, rc::Rc};
 
-use crate::{lexer::Token, parser::Symbol, types::Type};
+use crate::{
+    attributes::Attribute,
+    intern::{intern, Name},
+    lexer::{Token, TokenType},
+    parser::Symbol,
+    types::Type,
+};
+
+// Where a node is in the source, as the line and column of its first
+// character and of the one just after its last
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Span {
+    pub start: (usize, usize),
+    pub end: (usize, usize),
+}
+
+impl Span {
+    // from the start of the first token to the end of the last
+    pub fn new(first: &Token, last: &Token) -> Self {
+        let mut end = (last.line, last.column);
+        for c in last.lexeme.map(Name::text).unwrap_or_default().chars() {
+            end = match c {
+                '\n' => (end.0 + 1, 1),
+                _ => (end.0, end.1 + 1),
+            };
+        }
+        Span {
+            start: (first.line, first.column),
+            end,
+        }
+    }
+
+    pub fn of(token: &Token) -> Self {
+        Self::new(token, token)
+    }
+
+    // from the start of this one to the end of the other
+    pub fn to(self, other: Span) -> Self {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum LiteralValue {
@@ -12,6 +55,19 @@ pub enum LiteralValue {
     String { value: String, label: String },
 }
 
+// What makes a function the body of a lambda. Its first parameter is the
+// environment, the number of the lambda followed by the value of each
+// variable it captured, 8 bytes each, which every closure it makes has a
+// copy of its own of.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    // the type of the closures the lambda makes
+    pub ty: Type,
+    // the locals standing for the captured variables, in the order they are
+    // in the environment, which they are loaded from on entry
+    pub captures: Vec<Rc<RefCell<Symbol>>>,
+}
+
 #[derive(Debug, Clone)]
 pub enum Node {
     BinaryExpr {
@@ -19,85 +75,207 @@ pub enum Node {
         operator: Token,
         right: Box<Node>,
         ty: Type,
+        span: Span,
     },
     UnaryExpr {
         operator: Token,
         right: Box<Node>,
         ty: Type,
+        span: Span,
     },
     WidenExpr {
         right: Box<Node>,
         ty: Type,
+        span: Span,
     },
     ScaleExpr {
         right: Box<Node>,
         size: usize,
         ty: Type,
+        span: Span,
     },
     LiteralExpr {
         value: LiteralValue,
         ty: Type,
+        span: Span,
     },
     VarDecl {
         symbol: Rc<RefCell<Symbol>>,
         is_local: bool,
         ty: Type,
+        attributes: Vec<Attribute>,
+        // a global declared `extern`, which is defined outside the program
+        external: bool,
+        // the value a global starts with, zero without one
+        init: Option<u64>,
+        span: Span,
     },
     VarDeclMany {
         symbols: Vec<Rc<RefCell<Symbol>>>,
         is_local: bool,
         ty: Type,
+        attributes: Vec<Attribute>,
+        external: bool,
+        span: Span,
     },
     AssignStmt {
         left: Box<Node>,
         expr: Box<Node>,
+        span: Span,
     },
     CompoundStmt {
         statements: Vec<Node>,
+        span: Span,
     },
     IfStmt {
         condition: Box<Node>,
         then_branch: Box<Node>,
         else_branch: Option<Box<Node>>,
+        span: Span,
     },
     WhileStmt {
         condition: Box<Node>,
         body: Box<Node>,
+        // the increment of a `for`, run after the body and by a continue
+        step: Option<Box<Node>>,
+        label: Option<Token>,
+        span: Span,
+    },
+    // out of the innermost loop, or the one with the label
+    BreakStmt {
+        keyword: Token,
+        label: Option<Token>,
+        span: Span,
+    },
+    // on to the next pass of the innermost loop, or the one with the label
+    ContinueStmt {
+        keyword: Token,
+        label: Option<Token>,
+        span: Span,
     },
     FnDecl {
         identifier: Token,
+        // what the function is called in the assembly
+        name: String,
         body: Box<Node>,
         stack_size: usize,
         return_type: Option<Type>,
         params: Vec<Rc<RefCell<Symbol>>>,
+        attributes: Vec<Attribute>,
+        public: bool,
+        // only for a lambda
+        environment: Option<Environment>,
+        span: Span,
     },
     FnCall {
         identifier: Token,
+        // the function called, as the assembly names it
+        name: String,
+        args: Vec<Node>,
+        ty: Type,
+        // a local the values are left in, for a function returning several
+        result: Option<isize>,
+        span: Span,
+    },
+    // the values a function returning several returns, `(q, r)`
+    TupleExpr {
+        elements: Vec<Node>,
+        ty: Type,
+        span: Span,
+    },
+    // `q, r = divmod(x, y)`, the call and an assignment of each of its
+    // values, which a TupleElement stands for
+    TupleAssign {
+        call: Box<Node>,
+        assignments: Vec<Node>,
+        span: Span,
+    },
+    // one of the values of the call of the TupleAssign it is in
+    TupleElement {
+        index: usize,
+        ty: Type,
+        span: Span,
+    },
+    // A lambda where it is written, which fills in its environment in a
+    // slot of the function it is in and gives its address
+    Lambda {
+        // the name of the function made of its body
+        function: String,
+        // the variables it captures, of the function it is in
+        captures: Vec<Rc<RefCell<Symbol>>>,
+        // the type of the closure, whose result may be wider than the body's
+        ty: Type,
+        span: Span,
+    },
+    // a call through a variable holding a closure
+    ClosureCall {
+        callee: Box<Node>,
         args: Vec<Node>,
         ty: Type,
+        // a local the result is left in, whichever lambda is called
+        result: isize,
+        span: Span,
     },
     ReturnStmt {
+        keyword: Token,
         expr: Box<Node>,
         fn_name: Rc<RefCell<Symbol>>,
+        span: Span,
     },
     PostIncStmt {
         left: Box<Node>,
+        span: Span,
     },
     PostDecStmt {
         left: Box<Node>,
+        span: Span,
     },
     PreIncStmt {
         right: Box<Node>,
+        span: Span,
     },
     PreDecStmt {
         right: Box<Node>,
+        span: Span,
     },
     ToBool {
         expr: Box<Node>,
+        span: Span,
     },
 }
 
 impl Node {
+    pub fn span(&self) -> Span {
+        match self {
+            Node::BinaryExpr { span, .. }
+            | Node::UnaryExpr { span, .. }
+            | Node::WidenExpr { span, .. }
+            | Node::ScaleExpr { span, .. }
+            | Node::LiteralExpr { span, .. }
+            | Node::VarDecl { span, .. }
+            | Node::VarDeclMany { span, .. }
+            | Node::AssignStmt { span, .. }
+            | Node::CompoundStmt { span, .. }
+            | Node::IfStmt { span, .. }
+            | Node::WhileStmt { span, .. }
+            | Node::BreakStmt { span, .. }
+            | Node::ContinueStmt { span, .. }
+            | Node::FnDecl { span, .. }
+            | Node::FnCall { span, .. }
+            | Node::TupleExpr { span, .. }
+            | Node::TupleAssign { span, .. }
+            | Node::TupleElement { span, .. }
+            | Node::Lambda { span, .. }
+            | Node::ClosureCall { span, .. }
+            | Node::ReturnStmt { span, .. }
+            | Node::PostIncStmt { span, .. }
+            | Node::PostDecStmt { span, .. }
+            | Node::PreIncStmt { span, .. }
+            | Node::PreDecStmt { span, .. }
+            | Node::ToBool { span, .. } => *span,
+        }
+    }
+
     pub fn ty(&self) -> Option<Type> {
         match self {
             Node::BinaryExpr { ty, .. } => Some(ty.clone()),
@@ -111,14 +289,114 @@ impl Node {
             Node::CompoundStmt { .. } => None,
             Node::IfStmt { .. } => None,
             Node::WhileStmt { .. } => None,
+            Node::BreakStmt { .. } => None,
+            Node::ContinueStmt { .. } => None,
             Node::FnDecl { .. } => None,
             Node::FnCall { ty, .. } => Some(ty.clone()),
+            Node::TupleExpr { ty, .. } => Some(ty.clone()),
+            Node::TupleAssign { .. } => None,
+            Node::TupleElement { ty, .. } => Some(ty.clone()),
+            Node::Lambda { ty, .. } => Some(ty.clone()),
+            Node::ClosureCall { ty, .. } => Some(ty.clone()),
             Node::ReturnStmt { .. } => None,
-            Node::PostIncStmt { left } => left.ty(),
-            Node::PostDecStmt { left } => left.ty(),
-            Node::PreIncStmt { right } => right.ty(),
-            Node::PreDecStmt { right } => right.ty(),
+            Node::PostIncStmt { left, .. } => left.ty(),
+            Node::PostDecStmt { left, .. } => left.ty(),
+            Node::PreIncStmt { right, .. } => right.ty(),
+            Node::PreDecStmt { right, .. } => right.ty(),
             Node::ToBool { .. } => Some(Type::U8),
         }
     }
 }
+
+// Whether an operator compares, divides or shifts its operands as signed
+// values. Literals are always unsigned, so it is enough for one side to be
+// signed, except for a shift, where only the value being shifted counts.
+pub fn signed_operation(operator: TokenType, left: &Node, right: &Node) -> bool {
+    let signed = |node: &Node| node.ty().is_some_and(|ty| ty.is_signed());
+    match operator {
+        TokenType::RightShift => signed(left),
+        _ => signed(left) || signed(right),
+    }
+}
+
+// Whether a call to the function ends the program instead of coming back,
+// which counts as a return for every path through it
+pub fn never_returns(identifier: &Token) -> bool {
+    identifier.lexeme == Some(intern("exit"))
+}
+
+// Whether the function is one of min, max and abs, which are computed where
+// they are called instead
+pub fn is_arithmetic(identifier: &Token) -> bool {
+    matches!(
+        identifier.lexeme.map(Name::text).as_deref(),
+        Some("min" | "max" | "abs")
+    )
+}
+
+impl Node {
+    // whether every path through this statement ends in a return, or exit
+    pub fn always_returns(&self) -> bool {
+        match self {
+            Node::ReturnStmt { .. } => true,
+            // anything after a returning statement is dead, so one is enough
+            Node::CompoundStmt { statements, .. } => statements.iter().any(Node::always_returns),
+            Node::IfStmt {
+                then_branch,
+                else_branch: Some(else_branch),
+                ..
+            } => then_branch.always_returns() && else_branch.always_returns(),
+            // an endless loop is only left by a return, or a break out of it
+            Node::WhileStmt {
+                condition,
+                body,
+                label,
+                ..
+            } => condition.is_true_constant() && !body.breaks_out(label.as_ref(), false),
+            Node::FnCall { identifier, .. } => never_returns(identifier),
+            _ => false,
+        }
+    }
+
+    // Whether a break in the statement leaves the loop with the label, the
+    // statement being in its body and in as many loops of its own as nested
+    // says
+    fn breaks_out(&self, label: Option<&Token>, nested: bool) -> bool {
+        match self {
+            Node::BreakStmt { label: None, .. } => !nested,
+            Node::BreakStmt {
+                label: Some(target),
+                ..
+            } => label.is_some_and(|label| label.lexeme == target.lexeme),
+            Node::CompoundStmt { statements, .. } => statements
+                .iter()
+                .any(|statement| statement.breaks_out(label, nested)),
+            Node::IfStmt {
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                then_branch.breaks_out(label, nested)
+                    || else_branch
+                        .as_ref()
+                        .is_some_and(|branch| branch.breaks_out(label, nested))
+            }
+            Node::WhileStmt { body, .. } => body.breaks_out(label, true),
+            _ => false,
+        }
+    }
+
+    fn is_true_constant(&self) -> bool {
+        match self {
+            Node::ToBool { expr, .. } => expr.is_true_constant(),
+            Node::LiteralExpr { value, .. } => match value {
+                LiteralValue::U8(value) => *value != 0,
+                LiteralValue::U16(value) => *value != 0,
+                LiteralValue::U32(value) => *value != 0,
+                LiteralValue::U64(value) => *value != 0,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use std::rc::Rc;
+
+use crate::{
+    intern::Name,
+    ir::Placement,
+    lexer::{Literal, Token},
+};
+
+// An attribute in front of a function or a global, `#[name]` or
+// `#[name(argument)]`, one the registry below knows with the argument it
+// takes. Whether it means anything on what it is in front of is up to the
+// checker.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attribute {
+    pub name: Token,
+    pub argument: Option<Token>,
+}
+
+impl Attribute {
+    pub fn name(&self) -> Rc<str> {
+        self.name.lexeme.map(Name::text).unwrap_or_default()
+    }
+
+    pub fn kind(&self) -> &'static Kind {
+        find(&self.name()).expect("the parser only keeps known attributes")
+    }
+}
+
+// What goes in the parentheses of an attribute
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Argument {
+    None,
+    // the name of a section, a string
+    Section,
+    // a power of two
+    Alignment,
+}
+
+#[derive(Debug)]
+pub struct Kind {
+    pub name: &'static str,
+    pub argument: Argument,
+    pub functions: bool,
+    pub globals: bool,
+}
+
+impl Kind {
+    // what it can be in front of, for when it is in front of something else
+    pub fn applies_to(&self) -> &'static str {
+        match (self.functions, self.globals) {
+            (true, true) => "functions and globals",
+            (true, false) => "functions",
+            _ => "globals",
+        }
+    }
+}
+
+const ATTRIBUTES: [Kind; 5] = [
+    // a hint that calls to the function are better off inlined
+    Kind {
+        name: "inline",
+        argument: Argument::None,
+        functions: true,
+        globals: false,
+    },
+    // keep the name of the symbol as it is written
+    Kind {
+        name: "no_mangle",
+        argument: Argument::None,
+        functions: true,
+        globals: true,
+    },
+    Kind {
+        name: "section",
+        argument: Argument::Section,
+        functions: true,
+        globals: true,
+    },
+    Kind {
+        name: "align",
+        argument: Argument::Alignment,
+        functions: true,
+        globals: true,
+    },
+    // the function rarely runs, and goes apart from the code that does
+    Kind {
+        name: "cold",
+        argument: Argument::None,
+        functions: true,
+        globals: false,
+    },
+];
+
+pub fn find(name: &str) -> Option<&'static Kind> {
+    ATTRIBUTES.iter().find(|kind| kind.name == name)
+}
+
+// the names of the attributes there are, for an unknown one
+pub fn names() -> String {
+    let names: Vec<&str> = ATTRIBUTES.iter().map(|kind| kind.name).collect();
+    format!(
+        "{} or {}",
+        names[..names.len() - 1].join(", "),
+        names[names.len() - 1]
+    )
+}
+
+pub fn has(attributes: &[Attribute], name: &str) -> bool {
+    attributes
+        .iter()
+        .any(|attribute| &*attribute.name() == name)
+}
+
+// Where the attributes put a function or a global, the last of them winning
+// where they say different things
+pub fn placement(attributes: &[Attribute]) -> Placement {
+    let mut placement = Placement::default();
+    for attribute in attributes {
+        let argument = attribute
+            .argument
+            .as_ref()
+            .and_then(|token| token.value.clone());
+        match (&*attribute.name(), argument) {
+            ("section", Some(Literal::String { value, .. })) => placement.section = Some(value),
+            ("align", Some(Literal::Integer(n))) => placement.align = Some(n as usize),
+            ("cold", _) => placement.cold = true,
+            _ => {}
+        }
+    }
+    placement
+}
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+
+use crate::ir::{Inst, Label, VReg};
+
+// A straight run of instructions that is only entered at the top and only
+// left at the bottom. The label is kept apart from the instructions.
+pub struct BasicBlock {
+    pub label: Label,
+    pub insts: Vec<Inst>,
+}
+
+// The control-flow graph of one function body. Block 0 is the entry, the
+// rest are in the order they were laid out, which is also the order they are
+// flattened back in: a block without a jump at the end falls through to the
+// next one.
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    successors: Vec<Vec<usize>>,
+    predecessors: Vec<Vec<usize>>,
+}
+
+// The registers live on entry to and on exit from each block.
+pub struct Liveness {
+    pub live_in: Vec<HashSet<VReg>>,
+    pub live_out: Vec<HashSet<VReg>>,
+}
+
+impl Cfg {
+    // Cuts a function body at its labels and after every jump, branch and
+    // return. Blocks that start without a label get a fresh one from `labels`.
+    pub fn build(insts: &[Inst], labels: &mut Label) -> Self {
+        // an entry block nothing jumps back to, so values coming into the
+        // function have somewhere to live
+        *labels += 1;
+        let mut blocks = vec![BasicBlock {
+            label: *labels,
+            insts: Vec::new(),
+        }];
+        let mut open = true;
+
+        for inst in insts {
+            match inst {
+                Inst::Label(label) => {
+                    blocks.push(BasicBlock {
+                        label: *label,
+                        insts: Vec::new(),
+                    });
+                    open = true;
+                }
+                _ => {
+                    if !open {
+                        *labels += 1;
+                        blocks.push(BasicBlock {
+                            label: *labels,
+                            insts: Vec::new(),
+                        });
+                    }
+                    blocks.last_mut().unwrap().insts.push(inst.clone());
+                    open = !inst.is_terminator();
+                }
+            }
+        }
+
+        let mut cfg = Cfg {
+            blocks,
+            successors: Vec::new(),
+            predecessors: Vec::new(),
+        };
+        cfg.connect();
+        cfg
+    }
+
+    // works the edges out again, after the blocks or their jumps changed
+    pub fn connect(&mut self) {
+        let count = self.blocks.len();
+        self.successors = (0..count)
+            .map(|index| self.find_successors(index))
+            .collect();
+        self.predecessors = vec![Vec::new(); count];
+        for index in 0..count {
+            for successor in self.successors[index].clone() {
+                self.predecessors[successor].push(index);
+            }
+        }
+    }
+
+    fn find_successors(&self, index: usize) -> Vec<usize> {
+        let next = (index + 1 < self.blocks.len()).then_some(index + 1);
+
+        let mut successors = match self.blocks[index].insts.last() {
+            Some(Inst::Jump { target }) => vec![self.position(*target)],
+            Some(Inst::Branch { target, .. }) => vec![self.position(*target), next],
+            Some(Inst::Return { .. }) => vec![],
+            _ => vec![next],
+        }
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+
+        // a branch to the very next block leaves it one way only
+        successors.dedup();
+        successors
+    }
+
+    pub fn position(&self, label: Label) -> Option<usize> {
+        self.blocks.iter().position(|block| block.label == label)
+    }
+
+    pub fn successors(&self, block: usize) -> &[usize] {
+        &self.successors[block]
+    }
+
+    pub fn predecessors(&self, block: usize) -> &[usize] {
+        &self.predecessors[block]
+    }
+
+    pub fn reachable(&self) -> Vec<bool> {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut pending = vec![0];
+        while let Some(index) = pending.pop() {
+            if !reachable[index] {
+                reachable[index] = true;
+                pending.extend(self.successors(index));
+            }
+        }
+
+        reachable
+    }
+
+    // whether control can run off the end of the function without a return
+    pub fn falls_off_end(&self) -> bool {
+        let last = self.blocks.len() - 1;
+        self.reachable()[last]
+            && !matches!(
+                self.blocks[last].insts.last(),
+                Some(Inst::Jump { .. } | Inst::Return { .. })
+            )
+    }
+
+    // Drops the blocks control can never get to, and reports whether there
+    // were any. A block nothing reaches is never fallen into either, so the
+    // layout of the rest stays valid.
+    pub fn remove_unreachable(&mut self) -> bool {
+        let reachable = self.reachable();
+        if reachable.iter().all(|reachable| *reachable) {
+            return false;
+        }
+
+        let mut reachable = reachable.into_iter();
+        self.blocks.retain(|_| reachable.next().unwrap());
+        self.connect();
+        true
+    }
+
+    // Classic backwards dataflow to a fixed point. Expects code out of SSA
+    // form, a phi would count as reading all its arguments in its own block.
+    pub fn liveness(&self) -> Liveness {
+        let count = self.blocks.len();
+        // what each block reads before writing it, and what it writes
+        let mut reads = vec![HashSet::new(); count];
+        let mut writes = vec![HashSet::new(); count];
+        for (index, block) in self.blocks.iter().enumerate() {
+            for inst in &block.insts {
+                for vreg in inst.uses() {
+                    if !writes[index].contains(&vreg) {
+                        reads[index].insert(vreg);
+                    }
+                }
+                if let Some(dst) = inst.def() {
+                    writes[index].insert(dst);
+                }
+            }
+        }
+
+        let mut liveness = Liveness {
+            live_in: vec![HashSet::new(); count],
+            live_out: vec![HashSet::new(); count],
+        };
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for index in (0..count).rev() {
+                let live_out = self
+                    .successors(index)
+                    .iter()
+                    .flat_map(|successor| liveness.live_in[*successor].iter().copied())
+                    .collect::<HashSet<_>>();
+                let mut live_in = reads[index].clone();
+                live_in.extend(live_out.difference(&writes[index]));
+
+                if live_in != liveness.live_in[index] || live_out != liveness.live_out[index] {
+                    liveness.live_in[index] = live_in;
+                    liveness.live_out[index] = live_out;
+                    changed = true;
+                }
+            }
+        }
+
+        liveness
+    }
+
+    pub fn flatten(self) -> Vec<Inst> {
+        let mut insts = Vec::new();
+        for block in self.blocks {
+            insts.push(Inst::Label(block.label));
+            insts.extend(block.insts);
+        }
+
+        insts
+    }
+}
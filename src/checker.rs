@@ -0,0 +1,249 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    ast::{never_returns, LiteralValue, Node},
+    attributes::{self, Attribute},
+    diagnostic::Diagnostic,
+    lexer::Token,
+    lint::{Lint, Lints},
+    parser::Symbol,
+    visit::{walk_node, Visitor},
+};
+
+// Runs over the parsed program looking for code that compiles but is most
+// likely a mistake. Everything it finds is reported through the lint levels.
+pub struct Checker<'a> {
+    lints: &'a Lints,
+    diagnostics: Vec<Diagnostic>,
+    // locals of the current function and whether they have been read
+    locals: Vec<(Rc<RefCell<Symbol>>, bool)>,
+}
+
+impl<'a> Checker<'a> {
+    pub fn new(lints: &'a Lints) -> Self {
+        Self {
+            lints,
+            diagnostics: Vec::new(),
+            locals: Vec::new(),
+        }
+    }
+
+    pub fn check(mut self, nodes: &[Node]) -> Vec<Diagnostic> {
+        for node in nodes {
+            match node {
+                Node::FnDecl {
+                    body, attributes, ..
+                } => {
+                    self.attributes(attributes, true);
+                    self.locals.clear();
+                    self.visit_node(body);
+                    self.unused_variables();
+                }
+                Node::VarDecl {
+                    attributes,
+                    external,
+                    ..
+                }
+                | Node::VarDeclMany {
+                    attributes,
+                    external,
+                    ..
+                } => {
+                    self.attributes(attributes, false);
+                    if *external {
+                        self.placed_elsewhere(attributes);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.diagnostics
+    }
+
+    fn warn(&mut self, lint: Lint, diagnostic: Diagnostic) {
+        if let Some(diagnostic) = self.lints.report(lint, diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    // The attributes that do nothing where they are: on the kind of item
+    // they aren't for, after the same one, or asking to inline what is cold
+    fn attributes(&mut self, attributes: &[Attribute], function: bool) {
+        for (i, attribute) in attributes.iter().enumerate() {
+            let kind = attribute.kind();
+            let applies = if function {
+                kind.functions
+            } else {
+                kind.globals
+            };
+            let earlier = attributes[..i]
+                .iter()
+                .find(|earlier| earlier.name() == attribute.name());
+            let diagnostic = if !applies {
+                let item = if function { "a function" } else { "a global" };
+                Diagnostic::at(
+                    &attribute.name,
+                    format!("#[{}] has no effect on {}", kind.name, item),
+                )
+                .with_label(format!("only for {}", kind.applies_to()))
+            } else if let Some(earlier) = earlier {
+                Diagnostic::at(&attribute.name, format!("#[{}] is repeated", kind.name))
+                    .with_label(format!("already on line {}", earlier.name.line))
+            } else if kind.name == "inline" && attributes::has(attributes, "cold") {
+                Diagnostic::at(
+                    &attribute.name,
+                    "#[inline] has no effect on a cold function",
+                )
+                .with_label("the function is #[cold]")
+            } else {
+                continue;
+            };
+            self.warn(Lint::UnusedAttributes, diagnostic);
+        }
+    }
+
+    // where an extern global goes is up to what defines it
+    fn placed_elsewhere(&mut self, attributes: &[Attribute]) {
+        for attribute in attributes {
+            if matches!(&*attribute.name(), "section" | "align") {
+                self.warn(
+                    Lint::UnusedAttributes,
+                    Diagnostic::at(
+                        &attribute.name,
+                        format!("#[{}] has no effect on an extern global", attribute.name()),
+                    )
+                    .with_label("it is defined elsewhere"),
+                );
+            }
+        }
+    }
+
+    fn unused_variables(&mut self) {
+        for (symbol, used) in std::mem::take(&mut self.locals) {
+            let identifier = symbol.borrow().identifier.clone();
+            let name = identifier.lexeme.unwrap().to_string();
+            // same convention as rust, a leading underscore means unused on purpose
+            if used || name.starts_with('_') {
+                continue;
+            }
+
+            self.warn(
+                Lint::UnusedVariables,
+                Diagnostic::at(&identifier, format!("Unused variable {}", name))
+                    .with_label("declared here but never read"),
+            );
+        }
+    }
+
+    fn mark_used(&mut self, symbol: &Rc<RefCell<Symbol>>) {
+        if let Some(local) = self
+            .locals
+            .iter_mut()
+            .find(|(local, _)| Rc::ptr_eq(local, symbol))
+        {
+            local.1 = true;
+        }
+    }
+
+    fn compound_stmt(&mut self, statements: &[Node]) {
+        let mut returned = false;
+        for (i, statement) in statements.iter().enumerate() {
+            self.visit_node(statement);
+
+            let jumps = matches!(
+                statement,
+                Node::BreakStmt { .. } | Node::ContinueStmt { .. }
+            );
+            if returned || i + 1 == statements.len() || !(jumps || statement.always_returns()) {
+                continue;
+            }
+            returned = true;
+
+            let label = if matches!(statement, Node::ReturnStmt { .. }) {
+                "any code following this return is unreachable"
+            } else if jumps {
+                "any code following this jump out of the loop is unreachable"
+            } else if matches!(statement, Node::FnCall { .. }) {
+                "the program exits here, any code following this call is unreachable"
+            } else {
+                "every path returns, any code following this statement is unreachable"
+            };
+            // point at the return or the jump, or at the whole statement for
+            // an endless loop with no return in it
+            let keyword = match statement {
+                Node::BreakStmt { keyword, .. } | Node::ContinueStmt { keyword, .. } => {
+                    Some(keyword)
+                }
+                _ => return_keyword(statement),
+            };
+            let diagnostic = match keyword {
+                Some(keyword) => Diagnostic::at(keyword, "Unreachable statement"),
+                None => Diagnostic::spanning(statement.span(), "Unreachable statement"),
+            };
+            self.warn(Lint::UnreachableCode, diagnostic.with_label(label));
+        }
+    }
+}
+
+impl Visitor for Checker<'_> {
+    fn visit_node(&mut self, node: &Node) {
+        match node {
+            Node::LiteralExpr {
+                value: LiteralValue::Identifier(symbol),
+                ..
+            } => self.mark_used(symbol),
+            // a lambda reads what it captures when the closure is made
+            Node::Lambda { captures, .. } => {
+                for symbol in captures {
+                    self.mark_used(symbol);
+                }
+            }
+            Node::VarDecl {
+                symbol,
+                is_local: true,
+                ..
+            } => self.locals.push((symbol.clone(), false)),
+            Node::VarDeclMany {
+                symbols,
+                is_local: true,
+                ..
+            } => {
+                for symbol in symbols {
+                    self.locals.push((symbol.clone(), false));
+                }
+            }
+            // writing to a variable is not a use of it, writing through it is
+            Node::AssignStmt { left, expr, .. }
+                if matches!(
+                    left.as_ref(),
+                    Node::LiteralExpr {
+                        value: LiteralValue::Identifier(_),
+                        ..
+                    }
+                ) =>
+            {
+                self.visit_node(expr)
+            }
+            Node::CompoundStmt { statements, .. } => self.compound_stmt(statements),
+            _ => walk_node(self, node),
+        }
+    }
+}
+
+fn return_keyword(node: &Node) -> Option<&Token> {
+    match node {
+        Node::ReturnStmt { keyword, .. } => Some(keyword),
+        Node::FnCall { identifier, .. } if never_returns(identifier) => Some(identifier),
+        Node::CompoundStmt { statements, .. } => statements.iter().find_map(return_keyword),
+        Node::IfStmt {
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            return_keyword(then_branch).or_else(|| else_branch.as_deref().and_then(return_keyword))
+        }
+        Node::WhileStmt { body, .. } => return_keyword(body),
+        _ => None,
+    }
+}
@@ -0,0 +1,48 @@
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Check {
+    Div,
+    Overflow,
+    Bounds,
+    Null,
+}
+
+const CHECKS: [Check; 4] = [Check::Div, Check::Overflow, Check::Bounds, Check::Null];
+
+impl Check {
+    // the name used on the command line, e.g. `--checks=div`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Check::Div => "div",
+            Check::Overflow => "overflow",
+            Check::Bounds => "bounds",
+            Check::Null => "null",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        CHECKS.iter().find(|check| check.name() == name).copied()
+    }
+}
+
+// The runtime checks compiled into the program, none unless asked for. A
+// check that fails says where it is and aborts, as a failed assert does.
+#[derive(Debug, Clone, Default)]
+pub struct Checks {
+    enabled: HashSet<Check>,
+}
+
+impl Checks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enable(&mut self, check: Check) {
+        self.enabled.insert(check);
+    }
+
+    pub fn enabled(&self, check: Check) -> bool {
+        self.enabled.contains(&check)
+    }
+}
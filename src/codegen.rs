@@ -1,1413 +1,1377 @@
+use std::collections::HashMap;
+
 use crate::{
-    ast::{LiteralValue, Node},
-    lexer::{Token, TokenType},
-    parser::{StorageClass, Symbol},
+    asm::{Line, Syntax},
+    cfg::Cfg,
+    diagnostic::{Diagnostic, Result},
+    ir::{Address, BinOp, Cond, Function, Global, Inst, Module, Placement, UnOp, VReg},
+    passes::{Pass, Passes},
+    peephole,
+    regalloc::{self, Allocation},
+    stack::Frame,
+    target::{Os, Target},
+    timing,
     types::Type,
 };
 
 pub struct Assembly {
     pub data: String,
-    pub text: String,
+    pub text: Vec<Line>,
 }
 impl Assembly {
     fn new() -> Assembly {
         Assembly {
             data: String::new(),
-            text: String::new(),
+            text: Vec::new(),
         }
     }
 }
 
+// How the assembly is written, as opposed to what it does
+#[derive(Debug, Clone, Copy)]
+pub struct Options {
+    pub target: Target,
+    pub syntax: Syntax,
+    // Position independent code that may go into a shared library, where
+    // globals and functions can come from elsewhere, so they are reached
+    // through the GOT and the PLT. Otherwise they are known to be in the
+    // executable, which works for a position independent one as well.
+    pub pic: bool,
+    // Code for a shared library, which only exports the functions declared
+    // pub. Everything else stays a local symbol.
+    pub library: bool,
+}
+
+impl Options {
+    // whether a function is a global symbol
+    pub fn exports(&self, function: &Function) -> bool {
+        !self.library || function.public
+    }
+}
+
+// What the compiler writes out, assembly for the target, an object file
+// assembled from it, an executable or a shared library the C compiler links
+// from it with the runtime, LLVM IR that clang or llc compile for it, or the
+// runtime's assembly on its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Emit {
+    Asm,
+    Obj,
+    Exe,
+    Dylib,
+    LlvmIr,
+    Runtime,
+}
+
+impl Emit {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "asm" => Some(Emit::Asm),
+            "obj" => Some(Emit::Obj),
+            "exe" => Some(Emit::Exe),
+            "dylib" => Some(Emit::Dylib),
+            "llvm-ir" => Some(Emit::LlvmIr),
+            "runtime" => Some(Emit::Runtime),
+            _ => None,
+        }
+    }
+
+    // the file it is written to
+    pub fn output(&self) -> &'static str {
+        match self {
+            Emit::Asm => "out.s",
+            Emit::Obj => "out.o",
+            Emit::Exe => "out",
+            Emit::Dylib => "out.so",
+            Emit::LlvmIr => "out.ll",
+            Emit::Runtime => "zrt.s",
+        }
+    }
+}
+
+// What turns the optimized module into assembly, there is one for every
+// architecture
+pub trait Backend {
+    fn generate(&mut self) -> Result<String>;
+
+    // the frames of the functions generated, for the targets that lay them
+    // out themselves
+    fn frames(&mut self) -> Vec<Frame> {
+        Vec::new()
+    }
+}
+
 pub struct CodeGen {
-    nodes: Vec<Node>,
+    module: Module,
+    passes: Passes,
+    options: Options,
     assembly: Assembly,
-    registers: [bool; 10],
-    label_count: usize,
-    assignment_depth: usize,
-    local_offset: isize,
-    stack_offset: isize,
+    // where the function's virtual registers are kept
+    allocation: Allocation,
+    // the instruction being generated, in the flattened function
+    index: usize,
+    // constants written straight into the instructions reading them
+    immediates: HashMap<VReg, u64>,
+    // the size of the function's locals, spilled values go below them
+    locals: isize,
+    // the callee saved registers the function uses, kept below the spills
+    saved: Vec<usize>,
+    // how far %rsp is below %rbp, a multiple of 16
+    frame_size: isize,
+    // every function's frame and the calls it makes, for --stack-usage
+    frames: Vec<Frame>,
+    // the last label handed out, blocks without one are numbered on from it
+    // the label of every string literal, to the one its text is emitted under
+    strings: HashMap<String, String>,
 }
 
-const FIRST_PARAM_REG: isize = 9;
+// %rdx and %rcx are left out of the pool, division and shifts clobber them
+const POOL_SIZE: usize = 8;
+
+const R10: usize = 0;
+const R12: usize = 2;
+const R13: usize = 3;
+// the registers in the pool a function has to give back as it found them,
+// Windows counts %rsi and %rdi among them
+const CALLEE_SAVED: [usize; 2] = [R12, R13];
+const WINDOWS_CALLEE_SAVED: [usize; 4] = [R12, R13, RSI, RDI];
+
+const RDI: usize = 7;
+const RSI: usize = 6;
+const RDX: usize = 8;
+const RCX: usize = 9;
+const R8: usize = 5;
+const R9: usize = 4;
+const PARAM_REGISTERS: [usize; 6] = [RDI, RSI, RDX, RCX, R8, R9];
+const WINDOWS_PARAM_REGISTERS: [usize; 4] = [RCX, RDX, R8, R9];
+// the room a Windows caller leaves above the return address for the callee
+// to keep its register parameters in
+const SHADOW_SPACE: usize = 32;
 
 const REGISTER_NAMES: [&str; 10] = [
-    "%r10", "%r11", "%r12", "%r13", "%r9", "%r8", "%rcx", "%rdx", "%rsi", "%rdi",
+    "%r10", "%r11", "%r12", "%r13", "%r9", "%r8", "%rsi", "%rdi", "%rdx", "%rcx",
 ];
 const BYTE_REGISTER_NAMES: [&str; 10] = [
-    "%r10b", "%r11b", "%r12b", "%r13b", "%r9b", "%r8b", "%cl", "%dl", "%sil", "%dil",
+    "%r10b", "%r11b", "%r12b", "%r13b", "%r9b", "%r8b", "%sil", "%dil", "%dl", "%cl",
 ];
 const WORD_REGISTER_NAMES: [&str; 10] = [
-    "%r10w", "%r11w", "%r12w", "%r13w", "%r9w", "%r8w", "%cx", "%dx", "%si", "%di",
+    "%r10w", "%r11w", "%r12w", "%r13w", "%r9w", "%r8w", "%si", "%di", "%dx", "%cx",
 ];
 const DWORD_REGISTER_NAMES: [&str; 10] = [
-    "%r10d", "%r11d", "%r12d", "%r13d", "%r9d", "%r8d", "%ecx", "%edx", "%esi", "%edi",
+    "%r10d", "%r11d", "%r12d", "%r13d", "%r9d", "%r8d", "%esi", "%edi", "%edx", "%ecx",
 ];
 
-impl CodeGen {
-    pub fn new(nodes: Vec<Node>) -> Self {
-        Self {
-            nodes,
-            assembly: Assembly::new(),
-            registers: [false; 10],
-            label_count: 0,
-            assignment_depth: 0,
-            local_offset: 0,
-            stack_offset: 0,
-        }
+fn set_instruction(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "sete",
+        Cond::Ne => "setne",
+        Cond::Lt => "setl",
+        Cond::Le => "setle",
+        Cond::Gt => "setg",
+        Cond::Ge => "setge",
+        Cond::Ult => "setb",
+        Cond::Ule => "setbe",
+        Cond::Ugt => "seta",
+        Cond::Uge => "setae",
     }
+}
 
-    pub fn generate(&mut self) -> String {
-        self.preamble();
-
-        for node in self.nodes.clone() {
-            self.generate_node(node);
-        }
+fn move_instruction(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "cmove",
+        Cond::Ne => "cmovne",
+        Cond::Lt => "cmovl",
+        Cond::Le => "cmovle",
+        Cond::Gt => "cmovg",
+        Cond::Ge => "cmovge",
+        Cond::Ult => "cmovb",
+        Cond::Ule => "cmovbe",
+        Cond::Ugt => "cmova",
+        Cond::Uge => "cmovae",
+    }
+}
 
-        // combine the data and text sections
-        let mut assembly = String::new();
-        assembly.push_str(&self.assembly.data);
-        assembly.push_str(&self.assembly.text);
+fn shift_instruction(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Shl => "salq",
+        BinOp::Shr => "sarq",
+        _ => "shrq",
+    }
+}
 
-        assembly
+fn jump_instruction(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "je",
+        Cond::Ne => "jne",
+        Cond::Lt => "jl",
+        Cond::Le => "jle",
+        Cond::Gt => "jg",
+        Cond::Ge => "jge",
+        Cond::Ult => "jb",
+        Cond::Ule => "jbe",
+        Cond::Ugt => "ja",
+        Cond::Uge => "jae",
     }
+}
 
-    fn generate_node(&mut self, node: Node) -> usize {
-        match node {
-            Node::LiteralExpr { value, ty } => match value {
-                LiteralValue::U8(u) => self.load(u as u64, ty),
-                LiteralValue::U16(u) => self.load(u as u64, ty),
-                LiteralValue::U32(u) => self.load(u as u64, ty),
-                LiteralValue::U64(u) => self.load(u, ty),
-                LiteralValue::Identifier(s) => match s.borrow().class {
-                    StorageClass::Local | StorageClass::Param => {
-                        self.load_local(s.borrow().to_owned(), ty)
-                    }
-                    StorageClass::Global => self.load_global(s.borrow().clone(), ty),
-                },
-                LiteralValue::String { value: s, label } => {
-                    self.define_string(label.clone(), s);
-                    self.load_string(label)
-                }
-            },
-            Node::BinaryExpr {
-                left,
-                operator,
-                right,
-                ty,
-            } => {
-                let left = self.generate_node(*left);
-                let right = self.generate_node(*right);
-
-                match operator.token_type {
-                    TokenType::Add => self.add(left, right),
-                    TokenType::Sub => self.subtract(left, right),
-                    TokenType::Mul => self.multiply(left, right),
-                    TokenType::Div => self.divide(left, right),
-                    TokenType::Equal
-                    | TokenType::NotEqual
-                    | TokenType::LessThan
-                    | TokenType::LessThanOrEqual
-                    | TokenType::GreaterThan
-                    | TokenType::GreaterThanOrEqual => {
-                        self.compare_and_set(operator.token_type, left, right)
-                    }
-                    TokenType::LogicalAnd => self.logical_and(left, right, ty),
-                    TokenType::LogicalOr => self.logical_or(left, right, ty),
-                    TokenType::Ampersand => self.bitwise_and(left, right, ty),
-                    TokenType::Or => self.bitwise_or(left, right, ty),
-                    TokenType::Xor => self.bitwise_xor(left, right, ty),
-                    TokenType::LeftShift => self.shift_left(left, right, ty),
-                    TokenType::RightShift => self.shift_right(left, right, ty),
-                    _ => panic!("Unexpected token {:?}", operator),
-                }
-            }
-            Node::UnaryExpr {
-                operator, right, ..
-            } => {
-                match operator.token_type {
-                    TokenType::Sub => {
-                        let right_node = self.generate_node(*right.clone());
-                        self.negate(right_node)
-                    }
-                    TokenType::Ampersand => {
-                        // get identifier
-                        let symbol = match &*right {
-                            Node::LiteralExpr {
-                                value: LiteralValue::Identifier(s),
-                                ..
-                            } => s,
-                            _ => panic!("Unexpected token {:?}", right),
-                        };
-
-                        self.address_of(symbol.borrow().clone())
-                    }
-                    TokenType::Mul => {
-                        let right_node = self.generate_node(*right.clone());
-                        self.dereference(right_node, right.ty().unwrap())
-                    }
-                    TokenType::Invert => {
-                        let right_node = self.generate_node(*right.clone());
-                        self.invert(right_node)
-                    }
-                    TokenType::LogicalNot => {
-                        let right_node = self.generate_node(*right.clone());
-                        self.logical_not(right_node)
-                    }
-                    _ => panic!("Unexpected token {:?}", operator),
-                }
-            }
-            Node::ScaleExpr { right, size, ty } => {
-                let right_node = self.generate_node(*right.clone());
-                let shift_by = match size {
-                    2 => 1,
-                    4 => 2,
-                    8 => 3,
-                    _ => panic!("Unexpected type {:?}", ty),
-                };
-                self.scale(right_node, shift_by)
-            }
-            Node::WidenExpr { right, ty } => {
-                let right_node = self.generate_node(*right.clone());
-                self.widen(right_node, right.ty().unwrap(), ty)
-            }
-            Node::VarDecl {
-                symbol,
-                is_local,
-                ty,
-            } => {
-                if !is_local {
-                    self.define_global(
-                        symbol
-                            .borrow()
-                            .identifier
-                            .lexeme
-                            .as_ref()
-                            .unwrap()
-                            .to_owned(),
-                        ty,
-                    );
-                }
-                0
-            }
-            Node::VarDeclMany {
-                symbols,
-                is_local,
-                ty,
-            } => {
-                for symbol in symbols {
-                    if !is_local {
-                        self.define_global(
-                            symbol
-                                .borrow()
-                                .identifier
-                                .lexeme
-                                .as_ref()
-                                .unwrap()
-                                .to_owned(),
-                            ty.clone(),
-                        );
-                    }
-                }
-                0
-            }
-            Node::AssignStmt { left, expr } => {
-                self.assignment_depth += 1;
-                let r = match *left.clone() {
-                    Node::LiteralExpr {
-                        value: LiteralValue::Identifier(s),
-                        ..
-                    } => {
-                        let register = self.generate_node(*expr.clone());
-                        self.store(
-                            register,
-                            s.borrow().clone(),
-                            s.borrow().ty.as_ref().unwrap().to_owned(),
-                        );
-                        self.assignment_depth -= 1;
-                        register
-                    }
-                    Node::UnaryExpr {
-                        operator,
-                        right,
-                        ty,
-                    } => match operator.token_type {
-                        TokenType::Mul => {
-                            let right_node = self.generate_node(*right.clone());
-                            let expr_node = self.generate_node(*expr.clone());
-                            self.store_dereference(expr_node, right_node, ty);
-                            self.free_register(expr_node);
-                            self.assignment_depth -= 1;
-                            right_node
-                        }
-                        _ => panic!("Unexpected token {:?}", left),
-                    },
-                    _ => panic!("Unexpected token {:?}", left),
-                };
+impl Backend for CodeGen {
+    fn generate(&mut self) -> Result<String> {
+        self.directive(".text");
 
-                if self.assignment_depth == 0 {
-                    self.free_register(r);
-                }
+        let (data, strings) = data(&self.module, self.options);
+        self.assembly.data.push_str(&data);
+        self.strings = strings;
 
-                r
-            }
-            Node::IfStmt {
-                condition,
-                then_branch,
-                else_branch,
-            } => self.if_stmt(condition, then_branch, else_branch),
-            Node::CompoundStmt { statements } => {
-                for statement in statements {
-                    self.generate_node(statement);
-                }
-                0
-            }
-            Node::WhileStmt { condition, body } => self.while_stmt(condition, body),
-            Node::FnDecl {
-                identifier,
-                body,
-                stack_size,
-                params,
-                ..
-            } => self.function(
-                identifier,
-                params.iter().map(|param| param.borrow().clone()).collect(),
-                stack_size,
-                body,
-            ),
-            Node::FnCall {
-                identifier, args, ..
-            } => {
-                // TODO: fix ths hack
-                let r = self.function_call(identifier.clone(), args);
-                if identifier.lexeme.unwrap() == "printint" {
-                    self.free_register(r);
-                    0
-                } else {
-                    r
-                }
-            }
-            Node::ReturnStmt { expr, fn_name } => self.return_stmt(expr, fn_name.borrow().clone()),
-            Node::PostIncStmt { left } => self.post_inc_stmt(left),
-            Node::PostDecStmt { left } => self.post_dec_stmt(left),
-            Node::PreIncStmt { right } => self.pre_inc_stmt(right),
-            Node::PreDecStmt { right } => self.pre_dec_stmt(right),
-            Node::ToBool { expr } => self.expr_to_bool(expr),
+        for function in self.module.functions.clone() {
+            self.function(&function)?;
         }
-    }
 
-    fn preamble(&mut self) {
-        self.free_all_registers();
-        self.assembly.data.push_str("\t.data\n");
-        self.assembly.data.push_str("buffer: .space 21\n");
-
-        self.assembly.text.push_str("\t.text\n");
-
-        //         self.assembly.text.push_str(
-        //             "
-        // .global printint
-        // printint:
-        //     # Prologue
-        //     push %rbp
-        //     mov %rsp, %rbp
-
-        //     # Check if input number is 0
-        //     cmp $0, %rdi
-        //     je print_zero
-
-        //     # Prepare for conversion
-        //     mov %rdi, %rax        # Copy number to rax
-        //     mov $buffer+20, %rsi  # Load address of the second last digit space
-        //     movb $10, (%rsi)     # Store newline character after the number
-
-        //     # Convert number to string
-        // convert_loop:
-        //     xor %rdx, %rdx        # Clear rdx for div
-        //     mov $10, %rbx         # Set divisor to 10
-        //     div %rbx              # Divide rax by 10, result in rax, remainder in rdx
-        //     add $48, %rdx         # Convert remainder to ASCII
-        //     dec %rsi
-        //     mov %dl, (%rsi)       # Store ASCII character
-        //     test %rax, %rax       # Check if number is zero
-        //     jnz convert_loop      # If not, continue loop
-
-        //     # Set up for write syscall
-        //     movq $buffer+21, %rdi
-        //     subq %rsi, %rdi
-        //     movq %rdi, %rdx
-        //     mov $1, %rax          # syscall number for sys_write
-        //     mov $1, %rdi          # file descriptor 1 (stdout)
-        //     syscall
-
-        //     # Epilogue
-        //     mov $0, %rax
-        //     leave
-        //     ret
-
-        // print_zero:
-        //     # Handle printing 0
-        //     mov $1, %rax          # syscall number for sys_write
-        //     mov $1, %rdi          # file descriptor 1 (stdout)
-        //     lea buffer+19, %rsi   # point to '0'
-        //     movb $'0', (%rsi)     # place '0' in buffer
-        //     movb $10, 1(%rsi)     # place newline after '0'
-        //     mov $2, %rdx          # length is 2 (including newline)
-        //     syscall
-
-        //     mov $0, %rax
-        //     leave
-        //     ret
-
-        // .global printchar
-        // printchar:
-        //     # Prologue
-        //     push %rbp
-        //     mov %rsp, %rbp
-
-        //     # Move character to buffer
-        //     lea buffer, %rsi
-        //     mov %rdi, (%rsi)
-
-        //     # Set up for write syscall
-        //     mov $1, %rax          # syscall number for sys_write
-        //     mov $1, %rdi          # file descriptor 1 (stdout)
-        //     mov $1, %rdx          # length is 1
-        //     syscall
-
-        //     # Epilogue
-        //     mov $0, %rax
-        //     leave
-        //     ret
-        // ",
-        //         );
-
-        self.assembly.text.push_str(".LC0:\n");
-        self.assembly.text.push_str("\t.string\t\"%d\\n\"\n");
-        self.assembly.text.push_str("printint:\n");
-        self.assembly.text.push_str("\tpushq\t%rbp\n");
-        self.assembly.text.push_str("\tmovq\t%rsp, %rbp\n");
-        self.assembly.text.push_str("\tsubq\t$16, %rsp\n");
-        self.assembly.text.push_str("\tmovl\t%edi, -4(%rbp)\n");
-        self.assembly.text.push_str("\tmovl\t-4(%rbp), %eax\n");
-        self.assembly.text.push_str("\tmovl\t%eax, %esi\n");
-        self.assembly.text.push_str("\tleaq\t.LC0(%rip), %rdi\n");
-        self.assembly.text.push_str("\tmovl\t$0, %eax\n");
-        self.assembly.text.push_str("\tcall\tprintf@PLT\n");
-        self.assembly.text.push_str("\tnop\n");
-        self.assembly.text.push_str("\tleave\n");
-        self.assembly.text.push_str("\tret\n\n");
-
-        self.assembly.text.push_str(".LC1:\n");
-        self.assembly.text.push_str("\t.string\t\"%c\"\n");
-        self.assembly.text.push_str("printchar:\n");
-        self.assembly.text.push_str("\tpushq\t%rbp\n");
-        self.assembly.text.push_str("\tmovq\t%rsp, %rbp\n");
-        self.assembly.text.push_str("\tsubq\t$16, %rsp\n");
-        self.assembly.text.push_str("\tmovl\t%edi, -4(%rbp)\n");
-        self.assembly.text.push_str("\tmovl\t-4(%rbp), %eax\n");
-        self.assembly.text.push_str("\tmovl\t%eax, %esi\n");
-        self.assembly.text.push_str("\tleaq\t.LC1(%rip), %rdi\n");
-        self.assembly.text.push_str("\tmovl\t$0, %eax\n");
-        self.assembly.text.push_str("\tcall\tprintf@PLT\n");
-        self.assembly.text.push_str("\tnop\n");
-        self.assembly.text.push_str("\tleave\n");
-        self.assembly.text.push_str("\tret\n\n");
+        let mut text = std::mem::take(&mut self.assembly.text);
+        if self.passes.enabled(Pass::Peephole) {
+            text = timing::time(Pass::Peephole.name(), || {
+                peephole::optimize(text, self.options.target)
+            });
+        }
+        Ok(self.combine(text))
     }
 
-    fn load(&mut self, value: u64, _ty: Type) -> usize {
-        let r = self.allocate_register();
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t${}, {}\n", value, REGISTER_NAMES[r]));
-        r
+    fn frames(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.frames)
     }
+}
 
-    fn load_global(&mut self, symbol: Symbol, ty: Type) -> usize {
-        let r = self.allocate_register();
-        let identifier = symbol.identifier.lexeme.unwrap();
-        if ty == Type::U8 || ty == Type::Char {
-            self.assembly.text.push_str(&format!(
-                "\tmovzbq\t{}, {}\n",
-                identifier, REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::U16 {
-            self.assembly
-                .text
-                .push_str(&format!("\tmovzx\t{}, {}\n", identifier, REGISTER_NAMES[r]));
-        } else if ty == Type::U32 {
-            self.assembly.text.push_str(&format!(
-                "\tmov\t{}, {}\n",
-                identifier, DWORD_REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::U64 || matches!(ty, Type::Pointer { .. }) {
-            self.assembly
-                .text
-                .push_str(&format!("\tmovq\t{}, {}\n", identifier, REGISTER_NAMES[r]));
-        } else if ty == Type::I8 {
-            self.assembly.text.push_str(&format!(
-                "\tmovsbq\t{}, {}\n",
-                identifier, REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::I16 {
-            self.assembly
-                .text
-                .push_str(&format!("\tmovsx\t{}, {}\n", identifier, REGISTER_NAMES[r]));
-        } else if ty == Type::I32 {
-            self.assembly.text.push_str(&format!(
-                "\tmov\t{}, {}\n",
-                identifier, DWORD_REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::I64 {
-            self.assembly
-                .text
-                .push_str(&format!("\tmovq\t{}, {}\n", identifier, REGISTER_NAMES[r]));
-        } else if let Type::Array { .. } = ty {
-            self.assembly
-                .text
-                .push_str(&format!("\tleaq\t{}, {}\n", identifier, REGISTER_NAMES[r]));
-        } else {
-            panic!("Unexpected type {:?}", ty);
-        }
+// The state of rand's xorshift generator, which starts from the seed of
+// Marsaglia's paper every run and is never 0
+pub const RANDOM_STATE: &str = "\t.data\n\t.balign\t8\n.LC4:\n\t.quad\t88172645463325252\n";
 
-        r
-    }
+// The first and the last record of the functions the profiler has seen,
+// crust.enter links a record onto the last one the first time its function
+// is called
+pub const PROFILE_LIST: &str = "\t.data\n\t.balign\t8\n.LC8:\n\t.quad\t0\n\t.quad\t0\n";
 
-    fn store(&mut self, register: usize, symbol: Symbol, ty: Type) {
-        let ty = match ty {
-            Type::Array { ty, .. } => ty.pointer_to(),
-            _ => ty,
-        };
-        match symbol.class {
-            StorageClass::Local | StorageClass::Param => self.store_local(register, symbol, ty),
-            StorageClass::Global => self.store_global(register, symbol, ty),
-        }
-    }
+// what crust.report writes for every record
+pub const PROFILE_FORMAT: &str = "%s: %llu calls, %llu deep\\n";
 
-    fn store_local(&mut self, register: usize, symbol: Symbol, ty: Type) {
-        let offset = symbol.offset.unwrap();
+// The builtins for x86-64, the runtime linked with every program
+pub fn runtime(options: Options) -> String {
+    let mut codegen = CodeGen::new(Module::default(), Passes::new(0), options);
+    codegen.runtime();
+    let text = std::mem::take(&mut codegen.assembly.text);
+    codegen.combine(text)
+}
 
-        if ty == Type::U8 || ty == Type::Char {
-            self.assembly.text.push_str(&format!(
-                "\tmovb\t{}, {}(%rbp)\n",
-                BYTE_REGISTER_NAMES[register], offset
-            ));
-        } else if ty == Type::U16 {
-            self.assembly.text.push_str(&format!(
-                "\tmovw\t{}, {}(%rbp)\n",
-                WORD_REGISTER_NAMES[register], offset
-            ));
-        } else if ty == Type::U32 {
-            self.assembly.text.push_str(&format!(
-                "\tmovl\t{}, {}(%rbp)\n",
-                DWORD_REGISTER_NAMES[register], offset
-            ));
-        } else if ty == Type::U64 || matches!(ty, Type::Pointer { .. }) {
-            self.assembly.text.push_str(&format!(
-                "\tmovq\t{}, {}(%rbp)\n",
-                REGISTER_NAMES[register], offset
-            ));
-        } else if ty == Type::I8 {
-            self.assembly.text.push_str(&format!(
-                "\tmovb\t{}, {}(%rbp)\n",
-                BYTE_REGISTER_NAMES[register], offset
-            ));
-        } else if ty == Type::I16 {
-            self.assembly.text.push_str(&format!(
-                "\tmovw\t{}, {}(%rbp)\n",
-                WORD_REGISTER_NAMES[register], offset
-            ));
-        } else if ty == Type::I32 {
-            self.assembly.text.push_str(&format!(
-                "\tmovl\t{}, {}(%rbp)\n",
-                DWORD_REGISTER_NAMES[register], offset
-            ));
-        } else if ty == Type::I64 || matches!(ty, Type::Pointer { .. }) {
-            self.assembly.text.push_str(&format!(
-                "\tmovq\t{}, {}(%rbp)\n",
-                REGISTER_NAMES[register], offset
-            ));
-        } else {
-            panic!("Unexpected type {:?}", ty);
-        }
+// The directives that make a builtin of the runtime a global symbol the
+// program can call from its own file, but hidden, so a shared library
+// doesn't export it. COFF has no such thing, a DLL exports what it is told.
+pub fn runtime_symbol(target: Target, symbol: &str) -> Vec<String> {
+    match target.os {
+        Os::Linux | Os::Wasi => vec![format!(".globl {}", symbol), format!(".hidden {}", symbol)],
+        Os::Macos => vec![
+            format!(".globl {}", symbol),
+            format!(".private_extern {}", symbol),
+        ],
+        Os::Windows => vec![format!(".globl {}", symbol)],
     }
+}
 
-    fn store_global(&mut self, register: usize, symbol: Symbol, ty: Type) {
-        let identifier = symbol.identifier.lexeme.unwrap();
-        if ty == Type::U8 || ty == Type::Char {
-            self.assembly.text.push_str(&format!(
-                "\tmovb\t{}, {}\n",
-                BYTE_REGISTER_NAMES[register], identifier
-            ));
-        } else if ty == Type::U16 {
-            self.assembly.text.push_str(&format!(
-                "\tmovw\t{}, {}\n",
-                WORD_REGISTER_NAMES[register], identifier
-            ));
-        } else if ty == Type::U32 {
-            self.assembly.text.push_str(&format!(
-                "\tmovl\t{}, {}\n",
-                DWORD_REGISTER_NAMES[register], identifier
-            ));
-        } else if ty == Type::U64 || matches!(ty, Type::Pointer { .. }) {
-            self.assembly.text.push_str(&format!(
-                "\tmovq\t{}, {}\n",
-                REGISTER_NAMES[register], identifier
-            ));
-        } else if ty == Type::I8 {
-            self.assembly.text.push_str(&format!(
-                "\tmovb\t{}, {}\n",
-                BYTE_REGISTER_NAMES[register], identifier
-            ));
-        } else if ty == Type::I16 {
-            self.assembly.text.push_str(&format!(
-                "\tmovw\t{}, {}\n",
-                WORD_REGISTER_NAMES[register], identifier
-            ));
-        } else if ty == Type::I32 {
-            self.assembly.text.push_str(&format!(
-                "\tmovl\t{}, {}\n",
-                DWORD_REGISTER_NAMES[register], identifier
-            ));
-        } else if ty == Type::I64 || matches!(ty, Type::Pointer { .. }) {
-            self.assembly.text.push_str(&format!(
-                "\tmovq\t{}, {}\n",
-                REGISTER_NAMES[register], identifier
-            ));
-            panic!("Unexpected type {:?}", ty);
-        } else {
-            panic!("Unexpected type {:?}", ty);
-        }
+// The clock_gettime clock that only ever moves forward, CLOCK_MONOTONIC,
+// which the C libraries number differently. Windows has no such function.
+pub fn monotonic_clock(target: Target) -> &'static str {
+    match target.os {
+        Os::Macos => "6",
+        _ => "1",
     }
+}
 
-    fn widen(&mut self, register: usize, _old_ty: Type, _new_ty: Type) -> usize {
-        register
+impl CodeGen {
+    pub fn new(module: Module, passes: Passes, options: Options) -> Self {
+        Self {
+            passes,
+            options,
+            module,
+            assembly: Assembly::new(),
+            allocation: Allocation::default(),
+            index: 0,
+            immediates: HashMap::new(),
+            locals: 0,
+            strings: HashMap::new(),
+            saved: Vec::new(),
+            frame_size: 0,
+            frames: Vec::new(),
+        }
     }
 
-    fn define_global(&mut self, identifier: String, ty: Type) {
-        let type_size = ty.size();
-
-        self.assembly
-            .data
-            .push_str(&format!("\t.data\n\t.global\t{}\n", identifier));
-        self.assembly.data.push_str(&format!("{}:\n", identifier));
+    // the data and text sections together
+    fn combine(&self, text: Vec<Line>) -> String {
+        let mut assembly = String::new();
+        if self.options.syntax == Syntax::Intel {
+            assembly.push_str("\t.intel_syntax noprefix\n");
+        }
+        assembly.push_str(&self.assembly.data);
+        for line in text {
+            assembly.push_str(&format!("{}\n", line.format(self.options.syntax)));
+        }
+        assembly
+    }
 
-        let count = match ty {
-            Type::Array { count, .. } => count,
-            _ => 1,
-        };
+    fn runtime(&mut self) {
+        self.directive(".text");
 
-        let size_str = match type_size {
-            1 => "\t.byte\t0\n",
-            2 => "\t.short\t0\n",
-            4 => "\t.long\t0\n",
-            8 => "\t.quad\t0\n",
-            _ => panic!("Unexpected size {}", type_size),
+        let (printf, scanf) = (self.libc("printf"), self.libc("scanf"));
+        // the register the value comes in, and the ones printf takes the
+        // format and the value in, with the frame leaving room for the
+        // shadow space on Windows
+        let (argument, format, value, frame) = match self.options.target.os {
+            Os::Windows => ("%rcx", "%rcx", "%rdx", "$48"),
+            _ => ("%rdi", "%rdi", "%rsi", "$16"),
         };
 
-        for _ in 0..count {
-            self.assembly.data.push_str(size_str);
+        // printint prints the low 32 bits and printchar the low 8, printstr
+        // takes all of a pointer and the others all of a number
+        for (name, label, text) in [
+            ("printint", ".LC0", "%d\\n"),
+            ("printchar", ".LC1", "%c"),
+            ("printstr", ".LC2", "%s"),
+            ("printu64", ".LC5", "%llu\\n"),
+            ("printi64", ".LC6", "%lld\\n"),
+            ("printhex", ".LC7", "0x%llx\\n"),
+        ] {
+            self.label(label);
+            self.directive(&format!(".asciz\t\"{}\"", text));
+            self.builtin(name);
+            self.emit("pushq", &["%rbp"]);
+            self.emit("movq", &["%rsp", "%rbp"]);
+            self.emit("subq", &[frame, "%rsp"]);
+            self.emit("movq", &[argument, value]);
+            self.emit("leaq", &[&format!("{}(%rip)", label), format]);
+            self.emit("movl", &["$0", "%eax"]);
+            self.emit("call", &[&printf]);
+            self.emit("nop", &[]);
+            self.emit("leave", &[]);
+            self.emit("ret", &[]);
         }
-    }
 
-    fn add(&mut self, left: usize, right: usize) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\taddq\t{}, {}\n",
-            REGISTER_NAMES[left], REGISTER_NAMES[right]
-        ));
-        self.free_register(left);
-        right
-    }
+        // readint has scanf read a number into its frame, which is left at 0
+        // when there is none to read
+        self.label(".LC3");
+        self.directive(".asciz\t\"%llu\"");
+        self.builtin("readint");
+        self.emit("pushq", &["%rbp"]);
+        self.emit("movq", &["%rsp", "%rbp"]);
+        self.emit("subq", &[frame, "%rsp"]);
+        self.emit("movq", &["$0", "-8(%rbp)"]);
+        self.emit("leaq", &["-8(%rbp)", value]);
+        self.emit("leaq", &[".LC3(%rip)", format]);
+        self.emit("movl", &["$0", "%eax"]);
+        self.emit("call", &[&scanf]);
+        self.emit("movq", &["-8(%rbp)", "%rax"]);
+        self.emit("leave", &[]);
+        self.emit("ret", &[]);
+
+        // a failed assert or check flushes what the program printed, writes
+        // where it is to stderr and aborts
+        self.builtin("crust.panic");
+        self.emit("pushq", &["%rbp"]);
+        self.emit("movq", &["%rsp", "%rbp"]);
+        self.emit("subq", &[frame, "%rsp"]);
+        self.emit("movq", &[argument, "-8(%rbp)"]);
+        self.emit("movq", &["$0", argument]);
+        self.emit("call", &[&self.libc("fflush")]);
+        self.stderr(value);
+        self.emit("movq", &["-8(%rbp)", format]);
+        self.emit("call", &[&self.libc("fputs")]);
+        self.emit("call", &[&self.libc("abort")]);
+
+        // rand steps a xorshift generator on the state it keeps in .data
+        self.assembly.data.push_str(RANDOM_STATE);
+        self.builtin("rand");
+        self.emit("movq", &[".LC4(%rip)", "%rax"]);
+        for (instruction, amount) in [("salq", "$13"), ("shrq", "$7"), ("salq", "$17")] {
+            self.emit("movq", &["%rax", "%rdx"]);
+            self.emit(instruction, &[amount, "%rdx"]);
+            self.emit("xorq", &["%rdx", "%rax"]);
+        }
+        self.emit("movq", &["%rax", ".LC4(%rip)"]);
+        self.emit("ret", &[]);
+
+        // clock_ms has clock_gettime fill in a timespec in its frame and
+        // counts its seconds and nanoseconds in milliseconds, Windows counts
+        // them itself
+        self.builtin("clock_ms");
+        self.emit("pushq", &["%rbp"]);
+        self.emit("movq", &["%rsp", "%rbp"]);
+        self.emit("subq", &[frame, "%rsp"]);
+        if self.options.target.os == Os::Windows {
+            self.emit("call", &[&self.libc("GetTickCount64")]);
+        } else {
+            let clock = format!("${}", monotonic_clock(self.options.target));
+            self.emit("movl", &[&clock, "%edi"]);
+            self.emit("leaq", &["-16(%rbp)", "%rsi"]);
+            self.emit("call", &[&self.libc("clock_gettime")]);
+            self.emit("movq", &["-8(%rbp)", "%rax"]);
+            self.emit("xorl", &["%edx", "%edx"]);
+            self.emit("movq", &["$1000000", "%rcx"]);
+            self.emit("divq", &["%rcx"]);
+            self.emit("movq", &["-16(%rbp)", "%rcx"]);
+            self.emit("imulq", &["$1000", "%rcx"]);
+            self.emit("addq", &["%rcx", "%rax"]);
+        }
+        self.emit("leave", &[]);
+        self.emit("ret", &[]);
 
-    fn subtract(&mut self, left: usize, right: usize) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\tsubq\t{}, {}\n",
-            REGISTER_NAMES[right], REGISTER_NAMES[left]
-        ));
-        self.free_register(right);
-        left
+        self.profiler(argument, value);
     }
 
-    fn multiply(&mut self, left: usize, right: usize) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\timulq\t{}, {}\n",
-            REGISTER_NAMES[left], REGISTER_NAMES[right]
-        ));
-        self.free_register(left);
-        right
+    // The hooks --instrument-functions has every function call, with the
+    // function's record in the first argument. crust.enter links a record
+    // onto the list the first time and counts the call and how deeply the
+    // calls are nested, crust.exit counts one less, and crust.report writes
+    // every record on the list to stderr.
+    fn profiler(&mut self, record: &str, name: &str) {
+        let label = |name: &str| self.options.target.label("crust.enter", name);
+        let (counted, first) = (label("counted"), label("first"));
+        let field = |offset: usize| format!("{}({})", offset, record);
+        self.assembly.data.push_str(PROFILE_LIST);
+        self.builtin("crust.enter");
+        self.emit("cmpq", &["$0", &field(0)]);
+        self.emit("jne", &[&counted]);
+        self.emit("movq", &[name, &field(32)]);
+        // the last record's next, or the first record when there is none
+        self.emit("leaq", &[".LC8(%rip)", "%rax"]);
+        self.emit("movq", &["8(%rax)", "%r8"]);
+        self.emit("testq", &["%r8", "%r8"]);
+        self.emit("je", &[&first]);
+        self.emit("leaq", &["24(%r8)", "%rax"]);
+        self.label(&first);
+        self.emit("movq", &[record, "(%rax)"]);
+        self.emit("leaq", &[".LC8(%rip)", "%rax"]);
+        self.emit("movq", &[record, "8(%rax)"]);
+        self.label(&counted);
+        self.emit("addq", &["$1", &field(0)]);
+        self.emit("movq", &[&field(8), "%rax"]);
+        self.emit("addq", &["$1", "%rax"]);
+        self.emit("movq", &["%rax", &field(8)]);
+        self.emit("cmpq", &[&field(16), "%rax"]);
+        self.emit("cmovb", &[&field(16), "%rax"]);
+        self.emit("movq", &["%rax", &field(16)]);
+        self.emit("ret", &[]);
+
+        self.builtin("crust.exit");
+        self.emit("subq", &["$1", &field(8)]);
+        self.emit("ret", &[]);
+
+        // the record is kept in %rbx across the calls, and the shadow space
+        // on Windows is followed by the fifth argument
+        let (next, done) = (
+            self.options.target.label("crust.report", "next"),
+            self.options.target.label("crust.report", "done"),
+        );
+        self.label(".LC9");
+        self.directive(&format!(".asciz\t\"{}\"", PROFILE_FORMAT));
+        self.builtin("crust.report");
+        self.emit("pushq", &["%rbp"]);
+        self.emit("movq", &["%rsp", "%rbp"]);
+        self.emit("pushq", &["%rbx"]);
+        self.emit("subq", &["$40", "%rsp"]);
+        self.emit("movq", &["$0", record]);
+        self.emit("call", &[&self.libc("fflush")]);
+        self.emit("leaq", &[".LC8(%rip)", "%rax"]);
+        self.emit("movq", &["(%rax)", "%rbx"]);
+        self.label(&next);
+        self.emit("testq", &["%rbx", "%rbx"]);
+        self.emit("je", &[&done]);
+        let windows = self.options.target.os == Os::Windows;
+        let (stream, format, text, calls) = match windows {
+            true => ("%rcx", "%rdx", "%r8", "%r9"),
+            false => ("%rdi", "%rsi", "%rdx", "%rcx"),
+        };
+        self.stderr(stream);
+        self.emit("leaq", &[".LC9(%rip)", format]);
+        self.emit("movq", &["32(%rbx)", text]);
+        self.emit("movq", &["(%rbx)", calls]);
+        if windows {
+            self.emit("movq", &["16(%rbx)", "%rax"]);
+            self.emit("movq", &["%rax", "32(%rsp)"]);
+        } else {
+            self.emit("movq", &["16(%rbx)", "%r8"]);
+        }
+        self.emit("movl", &["$0", "%eax"]);
+        self.emit("call", &[&self.libc("fprintf")]);
+        self.emit("movq", &["24(%rbx)", "%rbx"]);
+        self.emit("jmp", &[&next]);
+        self.label(&done);
+        self.emit("movq", &["-8(%rbp)", "%rbx"]);
+        self.emit("leave", &[]);
+        self.emit("ret", &[]);
     }
 
-    fn divide(&mut self, left: usize, right: usize) -> usize {
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, %rax\n", REGISTER_NAMES[left]));
-        self.assembly.text.push_str("\tcqo\n");
-        self.assembly
-            .text
-            .push_str(&format!("\tidivq\t{}\n", REGISTER_NAMES[right]));
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t%rax, {}\n", REGISTER_NAMES[left]));
-        self.free_register(right);
-        left
+    // a function of the C library the runtime calls
+    fn libc(&self, name: &str) -> String {
+        match self.options.target.os {
+            Os::Macos | Os::Windows => self.symbol(name),
+            _ => format!("{}@PLT", name),
+        }
     }
 
-    fn allocate_register(&mut self) -> usize {
-        for (i, available) in self.registers.iter_mut().enumerate() {
-            if !*available {
-                *available = true;
-                return i;
+    // puts the C library's stderr in the register, which on Windows takes a
+    // call
+    fn stderr(&mut self, register: &str) {
+        match self.options.target.os {
+            Os::Windows => {
+                self.emit("movl", &["$2", "%ecx"]);
+                self.emit("call", &["__acrt_iob_func"]);
+                self.emit("movq", &["%rax", register]);
+            }
+            os => {
+                let stderr = match os {
+                    Os::Macos => "___stderrp",
+                    _ => "stderr",
+                };
+                self.emit("movq", &[&format!("{}@GOTPCREL(%rip)", stderr), register]);
+                self.emit("movq", &[&format!("({})", register), register]);
             }
         }
-
-        panic!("No available register");
     }
 
-    fn free_register(&mut self, register: usize) {
-        self.registers[register] = false;
+    fn builtin(&mut self, name: &str) {
+        let symbol = self.symbol(name);
+        for directive in runtime_symbol(self.options.target, &symbol) {
+            self.directive(&directive);
+        }
+        self.label(&symbol);
     }
 
-    fn free_all_registers(&mut self) {
-        for i in 0..self.registers.len() {
-            self.free_register(i);
-        }
+    fn emit(&mut self, op: &str, operands: &[&str]) {
+        self.assembly.text.push(Line::Inst {
+            op: op.to_string(),
+            operands: operands.iter().map(|operand| operand.to_string()).collect(),
+        });
     }
 
-    fn compare_and_jump(&mut self, operation: TokenType, left: usize, right: usize, label: usize) {
-        // get inverted jump instructions
-        let jump_instruction = match operation {
-            TokenType::Equal => "jne",
-            TokenType::NotEqual => "je",
-            TokenType::LessThan => "jge",
-            TokenType::LessThanOrEqual => "jg",
-            TokenType::GreaterThan => "jle",
-            TokenType::GreaterThanOrEqual => "jl",
-            _ => panic!("Unexpected token {:?}", operation),
-        };
+    fn label(&mut self, name: &str) {
+        self.assembly.text.push(Line::Label(name.to_string()));
+    }
 
-        self.assembly.text.push_str(&format!(
-            "\tcmpq\t{}, {}\n",
-            REGISTER_NAMES[right], REGISTER_NAMES[left]
-        ));
+    fn directive(&mut self, directive: &str) {
         self.assembly
             .text
-            .push_str(&format!("\t{} L{}\n", jump_instruction, label));
-        self.free_all_registers();
+            .push(Line::Directive(directive.to_string()));
     }
 
-    fn compare_and_set(&mut self, operation: TokenType, left: usize, right: usize) -> usize {
-        // get set instructions
-        let set_instruction = match operation {
-            TokenType::Equal => "sete",
-            TokenType::NotEqual => "setne",
-            TokenType::LessThan => "setl",
-            TokenType::LessThanOrEqual => "setle",
-            TokenType::GreaterThan => "setg",
-            TokenType::GreaterThanOrEqual => "setge",
-            _ => panic!("Unexpected token {:?}", operation),
-        };
-
-        self.assembly.text.push_str(&format!(
-            "\tcmpq\t{}, {}\n",
-            REGISTER_NAMES[right], REGISTER_NAMES[left]
-        ));
-        self.assembly.text.push_str(&format!(
-            "\t{} {}\n",
-            set_instruction, BYTE_REGISTER_NAMES[right]
-        ));
-        self.assembly.text.push_str(&format!(
-            "\tmovzbq\t{}, {}\n",
-            BYTE_REGISTER_NAMES[right], REGISTER_NAMES[right]
-        ));
-        self.free_register(left);
-        right
+    // the register an instruction reads a virtual register from
+    fn register(&self, vreg: VReg) -> usize {
+        self.allocation.register(vreg, self.index)
     }
 
-    fn label(&mut self) -> usize {
-        self.label_count += 1;
-        self.label_count
+    // the register an instruction writes a virtual register to
+    fn destination(&self, vreg: VReg) -> usize {
+        self.allocation.destination(vreg, self.index)
     }
 
-    fn generate_label(&mut self, label: usize) {
-        self.assembly.text.push_str(&format!("L{}:\n", label));
+    // where a virtual register that did not get a register lives
+    fn spill_slot(&self, vreg: VReg) -> Option<String> {
+        let slot = *self.allocation.slots.get(&vreg)? as isize;
+        Some(format!("{}(%rbp)", -self.locals - 8 * (slot + 1)))
     }
 
-    fn jump(&mut self, label: usize) {
-        self.assembly.text.push_str(&format!("\tjmp\tL{}\n", label));
+    // where a callee saved register is kept while the function runs
+    fn save_slot(&self, index: usize) -> String {
+        let slot = (self.allocation.slots.len() + index) as isize;
+        format!("{}(%rbp)", -self.locals - 8 * (slot + 1))
     }
 
-    fn if_stmt(
-        &mut self,
-        condition: Box<Node>,
-        then_branch: Box<Node>,
-        else_branch: Option<Box<Node>>,
-    ) -> usize {
-        let false_label = self.label();
-        let end_label = self.label();
-
-        let (left_reg, right_reg, operation) = match *condition {
-            Node::BinaryExpr {
-                left,
-                operator,
-                right,
-                ..
-            } => {
-                let left_reg = self.generate_node(*left);
-                let right_reg = self.generate_node(*right);
-
-                (left_reg, right_reg, operator.token_type)
-            }
-            Node::ToBool { expr } => {
-                let left_reg = self.generate_node(*expr);
-                let right_reg = self.allocate_register();
-                self.assembly.text.push_str(&format!(
-                    "\ttest\t{}, {}\n",
-                    REGISTER_NAMES[right_reg], REGISTER_NAMES[right_reg]
-                ));
-                (left_reg, right_reg, TokenType::NotEqual)
-            }
-            _ => panic!("Unexpected token {:?}", condition),
+    // whether a global is only reached through the address the GOT holds,
+    // string literals never leave the file. COFF has no GOT, code on Windows
+    // reaches everything relative to %rip as it is.
+    fn through_got(&self, address: &Address) -> bool {
+        let Address::Global(name) = address else {
+            return false;
         };
+        let got = self.options.pic || self.module.is_external(name);
+        got && self.options.target.os != Os::Windows && !self.strings.contains_key(name)
+    }
 
-        // zero jump to the false label
-        self.compare_and_jump(operation, left_reg, right_reg, false_label);
-        self.free_all_registers();
-
-        // generate the then branch code
-        self.generate_node(*then_branch);
-        self.free_all_registers();
-        // unconditional jump to the end label
-        self.jump(end_label);
-
-        // generate the false label
-        self.generate_label(false_label);
-
-        // generate the else branch code
-        if let Some(else_branch) = else_branch {
-            self.generate_node(*else_branch);
-            self.free_all_registers();
+    fn param_registers(&self) -> &'static [usize] {
+        match self.options.target.os {
+            Os::Windows => &WINDOWS_PARAM_REGISTERS,
+            _ => &PARAM_REGISTERS,
         }
+    }
 
-        // generate the end label
-        self.generate_label(end_label);
-        0
+    fn callee_saved(&self) -> &'static [usize] {
+        match self.options.target.os {
+            Os::Windows => &WINDOWS_CALLEE_SAVED,
+            _ => &CALLEE_SAVED,
+        }
     }
 
-    fn while_stmt(&mut self, condition: Box<Node>, body: Box<Node>) -> usize {
-        let start_label = self.label();
-        let end_label = self.label();
+    fn shadow_space(&self) -> usize {
+        match self.options.target.os {
+            Os::Windows => SHADOW_SPACE,
+            _ => 0,
+        }
+    }
 
-        self.generate_label(start_label);
+    fn symbol(&self, name: &str) -> String {
+        self.options.target.symbol(name)
+    }
 
-        let (left_reg, right_reg, operation) = match *condition {
-            Node::BinaryExpr {
-                left,
-                operator,
-                right,
-                ..
-            } => {
-                let left_reg = self.generate_node(*left);
-                let right_reg = self.generate_node(*right);
+    // the name a function is called by, Mach-O and COFF have no PLT to name
+    fn function_symbol(&self, name: &str) -> String {
+        match self.options.target.os {
+            Os::Macos | Os::Windows => self.symbol(name),
+            _ if self.options.pic => format!("{}@PLT", name),
+            _ => self.symbol(name),
+        }
+    }
 
-                (left_reg, right_reg, operator.token_type)
-            }
-            Node::ToBool { expr } => {
-                let left_reg = self.generate_node(*expr);
-                let right_reg = self.allocate_register();
-                self.assembly
-                    .text
-                    .push_str(&format!("\tmovq\t$0, {}\n", REGISTER_NAMES[right_reg]));
-
-                (left_reg, right_reg, TokenType::NotEqual)
+    fn operand(&mut self, address: &Address) -> String {
+        match address {
+            Address::Global(name) if self.through_got(address) => {
+                let got = format!("{}@GOTPCREL(%rip)", self.symbol(name));
+                self.emit("movq", &[&got, "%rax"]);
+                "(%rax)".to_string()
             }
-            _ => panic!("Unexpected token {:?}", condition),
-        };
+            Address::Global(name) => match self.strings.get(name) {
+                Some(label) => format!("{}(%rip)", label),
+                None => format!("{}(%rip)", self.symbol(name)),
+            },
+            Address::Local(offset) => format!("{}(%rbp)", offset),
+            Address::Indirect(vreg) => format!("({})", REGISTER_NAMES[self.register(*vreg)]),
+        }
+    }
 
-        // zero jump to the end label
-        self.compare_and_jump(operation, left_reg, right_reg, end_label);
-        self.free_all_registers();
+    fn function(&mut self, function: &Function) -> Result<()> {
+        let cfg = Cfg::build(&function.insts, &mut function.last_label());
+        self.immediates = immediates(&function.insts);
+        self.allocation = regalloc::allocate(
+            &cfg,
+            &self.immediates,
+            POOL_SIZE,
+            self.param_registers().len(),
+        )?;
+        // rounded up to whole slots
+        self.locals = (function.stack_size as isize + 7) & !7;
+
+        self.function_preamble(function)?;
+        let insts = cfg.flatten();
+        // the return after a tail call, control never comes back to it
+        let mut skip = None;
+        for (i, inst) in insts.iter().enumerate() {
+            if skip == Some(i) {
+                continue;
+            }
+            self.index = i;
 
-        // generate the body code
-        self.generate_node(*body);
-        self.free_all_registers();
+            for vreg in self.allocation.register_uses(inst) {
+                if let Some(slot) = self.spill_slot(vreg) {
+                    self.emit("movq", &[&slot, REGISTER_NAMES[self.register(vreg)]]);
+                }
+            }
 
-        // unconditional jump to the start label
-        self.jump(start_label);
+            match tail_call(&insts, i, self.param_registers().len()) {
+                Some((name, args)) if self.passes.enabled(Pass::TailCalls) => {
+                    self.tail_call(name, args);
+                    skip = Some(i + 1);
+                }
+                _ => self.instruction(inst, function)?,
+            }
 
-        // generate the end label
-        self.generate_label(end_label);
-        0
-    }
+            if let Some(dst) = inst.def() {
+                if let Some(slot) = self.spill_slot(dst) {
+                    self.emit("movq", &[REGISTER_NAMES[self.destination(dst)], &slot]);
+                }
+            }
+        }
+        self.function_postamble(function);
+        if function_section(self.options.target, &function.placement).is_some() {
+            self.directive(".text");
+        }
 
-    fn function(
-        &mut self,
-        identifier: Token,
-        params: Vec<Symbol>,
-        stack_size: usize,
-        body: Box<Node>,
-    ) -> usize {
-        let fn_name = identifier.lexeme.unwrap();
-        self.function_preamble(fn_name.clone(), params, stack_size);
-        self.generate_node(*body);
-        self.function_postamble(fn_name.clone());
-        0
+        Ok(())
     }
 
-    fn function_preamble(&mut self, name: String, mut params: Vec<Symbol>, stack_size: usize) {
-        let mut param_reg = FIRST_PARAM_REG;
-
-        self.assembly
-            .text
-            .push_str(&format!("\t.global {}\n", name));
-        self.assembly
-            .text
-            .push_str(&format!("\t.type\t{}, @function\n", name));
-        self.assembly.text.push_str(&format!("{}:\n", name));
-        self.assembly.text.push_str("\tpushq\t%rbp\n");
-        self.assembly.text.push_str("\tmovq\t%rsp, %rbp\n");
-
-        // Copy any in-register parameters to the stack
-        // Stop after six parameter registers
-        for (i, param) in params.iter_mut().enumerate() {
-            if i >= 6 {
-                break;
+    fn function_preamble(&mut self, function: &Function) -> Result<()> {
+        for directive in place_function(self.options.target, &function.placement) {
+            self.directive(&directive);
+        }
+        let name = &function.name;
+        let symbol = self.symbol(name);
+        let exported = self.options.exports(function);
+        if exported {
+            match self.options.target.os {
+                Os::Linux => self.directive(&format!(".global {}", symbol)),
+                _ => self.directive(&format!(".globl {}", symbol)),
             }
+        }
+        match self.options.target.os {
+            Os::Macos => {}
+            // a COFF symbol of type function and storage class external, or
+            // static when it isn't exported
+            Os::Windows => {
+                let class = if exported { 2 } else { 3 };
+                self.directive(&format!(
+                    ".def\t{};\t.scl\t{};\t.type\t32;\t.endef",
+                    symbol, class
+                ));
+            }
+            _ => self.directive(&format!(".type\t{}, @function", symbol)),
+        }
+        self.label(&symbol);
+        self.emit("pushq", &["%rbp"]);
+        self.emit("movq", &["%rsp", "%rbp"]);
+
+        // The frame holds the locals, then the spilled values, then the
+        // callee saved registers, with %rsp kept a multiple of 16 below
+        // where it was at the call
+        let used = self.allocation.used_registers();
+        self.saved = self
+            .callee_saved()
+            .iter()
+            .copied()
+            .filter(|r| used.contains(r))
+            .collect();
+        let size = self.locals + 8 * (self.allocation.slots.len() + self.saved.len()) as isize;
+        self.frame_size = (size + 15) & !15;
+        // with the return address and %rbp above it
+        self.frames.push(Frame {
+            name: function.name.clone(),
+            size: self.frame_size as u64 + 16,
+            ..Frame::default()
+        });
+        if self.frame_size > 0 {
+            self.emit("subq", &[&format!("${}", self.frame_size), "%rsp"]);
+        }
 
-            self.store_local(param_reg as usize, param.clone(), param.clone().ty.unwrap());
-
-            param_reg -= 1;
+        for (index, r) in self.saved.clone().into_iter().enumerate() {
+            let slot = self.save_slot(index);
+            self.emit("movq", &[REGISTER_NAMES[r], &slot]);
         }
 
-        // Align the stack pointer to be a multiple of 16
-        // less than its previous value
-        self.stack_offset = (self.local_offset + stack_size as isize + 15) & !15;
+        // Copy the parameters to their slots, the first six (four on
+        // Windows) from their registers and the rest from where the caller
+        // pushed them, above the return address and the shadow space.
+        // Nothing is in %r10 yet.
+        let registers = self.param_registers();
+        for (i, param) in function.params.iter().enumerate() {
+            let operand = format!("{}(%rbp)", param.offset);
+            let register = match registers.get(i) {
+                Some(register) => *register,
+                None => {
+                    let pushed = 16 + self.shadow_space() + 8 * (i - registers.len());
+                    self.emit("movq", &[&format!("{}(%rbp)", pushed), REGISTER_NAMES[R10]]);
+                    R10
+                }
+            };
+            self.store(register, operand, &param.ty)?;
+        }
 
-        self.assembly
-            .text
-            .push_str(&format!("\taddq\t${}, %rsp\n", -self.stack_offset));
+        Ok(())
     }
 
-    fn function_postamble(&mut self, fn_name: String) {
-        self.assembly
-            .text
-            .push_str(format!("{}_end:\n", fn_name).as_str());
-
-        self.assembly
-            .text
-            .push_str(&format!("\taddq\t${}, %rsp\n", self.stack_offset));
-        self.assembly.text.push_str("\tpopq\t%rbp\n");
-        self.assembly.text.push_str("\tret\n");
-
-        self.local_offset = 0;
-        self.stack_offset = 0;
+    fn function_postamble(&mut self, function: &Function) {
+        self.label(&self.options.target.label(&function.name, "end"));
+        self.leave_frame();
+        self.emit("ret", &[]);
     }
 
-    fn function_call(&mut self, identifier: crate::lexer::Token, args: Vec<Node>) -> usize {
-        for (i, arg) in args.iter().rev().enumerate() {
-            let register = self.generate_node(arg.clone());
-            self.copy_arg(register, args.len() - i);
-            self.free_register(register);
+    // gives the caller back its registers and its stack pointer
+    fn leave_frame(&mut self) {
+        for (index, r) in self.saved.clone().into_iter().enumerate() {
+            let slot = self.save_slot(index);
+            self.emit("movq", &[&slot, REGISTER_NAMES[r]]);
         }
 
-        self.assembly
-            .text
-            .push_str(&format!("\tcall\t{}\n", identifier.lexeme.unwrap()));
-
-        if args.len() > 6 {
-            self.assembly
-                .text
-                .push_str(&format!("\taddq\t${}, %rsp\n", 8 * (args.len() - 6)));
+        if self.frame_size > 0 {
+            self.emit("addq", &[&format!("${}", self.frame_size), "%rsp"]);
         }
-
-        let out_register = self.allocate_register();
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t%rax, {}\n", REGISTER_NAMES[out_register]));
-        out_register
+        self.emit("popq", &["%rbp"]);
     }
 
-    fn return_stmt(&mut self, expr: Box<Node>, fn_name: Symbol) -> usize {
-        let register = self.generate_node(*expr);
-        match fn_name.ty.clone().unwrap() {
-            Type::U8 => {
-                self.assembly.text.push_str(&format!(
-                    "\tmovzbl\t{}, %eax\n",
-                    BYTE_REGISTER_NAMES[register]
-                ));
+    fn instruction(&mut self, inst: &Inst, function: &Function) -> Result<()> {
+        match inst {
+            Inst::Const { dst, .. } if self.immediates.contains_key(dst) => {}
+            Inst::Const { dst, value } => {
+                let r = self.destination(*dst);
+                self.emit("movq", &[&format!("${}", value), REGISTER_NAMES[r]]);
             }
-            Type::U16 => {
-                self.assembly.text.push_str(&format!(
-                    "\tmovzwl\t{}, %eax\n",
-                    WORD_REGISTER_NAMES[register]
-                ));
+            Inst::Copy { dst, src } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                self.move_register(src, r);
             }
-            Type::U32 => {
-                self.assembly.text.push_str(&format!(
-                    "\tmovl\t{}, %eax\n",
-                    DWORD_REGISTER_NAMES[register]
-                ));
+            Inst::Cast { dst, src, ty } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                self.cast(r, src, ty);
             }
-            Type::U64 | Type::Pointer { .. } => {
-                self.assembly
-                    .text
-                    .push_str(&format!("\tmovq\t{}, %rax\n", REGISTER_NAMES[register]));
+            Inst::Binary {
+                op: op @ (BinOp::Shl | BinOp::Shr | BinOp::UShr),
+                dst,
+                left,
+                right,
+            } if self.immediates.contains_key(right) => {
+                let left = self.register(*left);
+                let r = self.destination(*dst);
+                self.move_register(left, r);
+                let instruction = shift_instruction(*op);
+                let amount = format!("${}", self.immediates[right] & 63);
+                self.emit(instruction, &[&amount, REGISTER_NAMES[r]]);
             }
-            _ => panic!("Unexpected type {:?}", fn_name.ty.clone().unwrap()),
-        }
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, %rax\n", REGISTER_NAMES[register]));
-        self.free_register(register);
-        0
-    }
-
-    fn address_of(&mut self, symbol: Symbol) -> usize {
-        let r = self.allocate_register();
+            Inst::Binary {
+                op,
+                dst,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let r = self.destination(*dst);
+                self.binary(*op, r, left, right);
+            }
+            Inst::Unary { op, dst, src } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                self.unary(*op, r, src);
+            }
+            Inst::Compare {
+                cond,
+                dst,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let r = self.destination(*dst);
+                self.emit("cmpq", &[REGISTER_NAMES[right], REGISTER_NAMES[left]]);
+                self.set_flag(set_instruction(*cond), r);
+            }
+            Inst::Select {
+                cond,
+                dst,
+                left,
+                right,
+                then,
+                otherwise,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let (then, otherwise) = (self.register(*then), self.register(*otherwise));
+                let r = self.destination(*dst);
+                self.emit("cmpq", &[REGISTER_NAMES[right], REGISTER_NAMES[left]]);
+                // the result may share a register with either value, the one
+                // it holds already is the one that is kept
+                let (cond, moved, kept) = if r == then {
+                    (cond.negate(), otherwise, then)
+                } else {
+                    (*cond, then, otherwise)
+                };
+                self.move_register(kept, r);
+                self.emit(
+                    move_instruction(cond),
+                    &[REGISTER_NAMES[moved], REGISTER_NAMES[r]],
+                );
+            }
+            Inst::Load { dst, addr, ty } => {
+                let operand = self.operand(addr);
+                let r = self.destination(*dst);
+                self.load(r, operand, ty)?;
+            }
+            Inst::Store { src, addr, ty } => {
+                let operand = self.operand(addr);
+                self.store(self.register(*src), operand, ty)?;
+            }
+            Inst::Lea {
+                dst,
+                addr: addr @ Address::Global(name),
+            } if self.through_got(addr) => {
+                let r = self.destination(*dst);
+                let got = format!("{}@GOTPCREL(%rip)", self.symbol(name));
+                self.emit("movq", &[&got, REGISTER_NAMES[r]]);
+            }
+            Inst::Lea { dst, addr } => {
+                let operand = self.operand(addr);
+                let r = self.destination(*dst);
+                self.emit("leaq", &[&operand, REGISTER_NAMES[r]]);
+            }
+            Inst::Call { dst, name, args } => {
+                self.function_call(name, args)?;
+                let r = self.destination(*dst);
+                self.emit("movq", &["%rax", REGISTER_NAMES[r]]);
+            }
+            Inst::Return { src, ty } => {
+                self.return_value(self.register(*src), ty)?;
 
-        match symbol.class {
-            StorageClass::Global => {
-                self.assembly.text.push_str(&format!(
-                    "\tleaq\t{}(%rip), {}\n",
-                    symbol.identifier.lexeme.unwrap(),
-                    REGISTER_NAMES[r]
-                ));
+                // leave through the epilogue, a return is not always the last statement
+                self.emit("jmp", &[&self.options.target.label(&function.name, "end")]);
             }
-            StorageClass::Local | StorageClass::Param => {
-                let offset = symbol.offset.unwrap();
-                self.assembly.text.push_str(&format!(
-                    "\tleaq\t{}(%rbp), {}\n",
-                    offset, REGISTER_NAMES[r]
-                ));
+            Inst::Jump { target } => {
+                self.emit("jmp", &[&self.options.target.label(&function.name, target)]);
+            }
+            Inst::Branch {
+                cond,
+                left,
+                right,
+                target,
+            } => {
+                self.emit(
+                    "cmpq",
+                    &[
+                        REGISTER_NAMES[self.register(*right)],
+                        REGISTER_NAMES[self.register(*left)],
+                    ],
+                );
+                self.emit(
+                    jump_instruction(*cond),
+                    &[&self.options.target.label(&function.name, target)],
+                );
+            }
+            Inst::Label(label) => {
+                self.label(&self.options.target.label(&function.name, label));
+            }
+            Inst::Phi { .. } => {
+                return Err(Diagnostic::error("Unexpected phi outside of SSA form"));
             }
         }
 
-        r
+        Ok(())
     }
 
-    fn dereference(&mut self, register: usize, ty: Type) -> usize {
-        let ty = match ty {
-            Type::Array { ty, .. } => ty.pointer_to(),
-            _ => ty,
-        };
-        match ty {
-            Type::Pointer { ty, count } => {
-                if count > 1 {
-                    self.assembly.text.push_str(&format!(
-                        "\tmovq\t({}), {}\n",
-                        REGISTER_NAMES[register], REGISTER_NAMES[register]
-                    ));
+    fn binary(&mut self, op: BinOp, r: usize, left: usize, right: usize) {
+        let instruction = match op {
+            BinOp::Add => "addq",
+            BinOp::Sub => "subq",
+            BinOp::Mul => "imulq",
+            BinOp::And => "andq",
+            BinOp::Or => "orq",
+            BinOp::Xor => "xorq",
+            BinOp::Div | BinOp::UDiv => {
+                self.emit("movq", &[REGISTER_NAMES[left], "%rax"]);
+                // the upper half of the dividend, the sign or zero
+                if op == BinOp::Div {
+                    self.emit("cqo", &[]);
+                    self.emit("idivq", &[REGISTER_NAMES[right]]);
                 } else {
-                    match *ty {
-                        Type::U8 | Type::Char => self.assembly.text.push_str(&format!(
-                            "\tmovzbq\t({}), {}\n",
-                            REGISTER_NAMES[register], REGISTER_NAMES[register]
-                        )),
-                        Type::U16 => self.assembly.text.push_str(&format!(
-                            "\tmovzx\t({}), {}\n",
-                            REGISTER_NAMES[register], REGISTER_NAMES[register]
-                        )),
-                        Type::U32 => self.assembly.text.push_str(&format!(
-                            "\tmovq\t({}), {}\n",
-                            REGISTER_NAMES[register], REGISTER_NAMES[register]
-                        )),
-                        Type::U64 => self.assembly.text.push_str(&format!(
-                            "\tmovq\t({}), {}\n",
-                            REGISTER_NAMES[register], REGISTER_NAMES[register]
-                        )),
-                        _ => panic!("Unexpected type {:?}", ty),
-                    }
+                    self.emit("xorl", &["%edx", "%edx"]);
+                    self.emit("divq", &[REGISTER_NAMES[right]]);
                 }
+                self.emit("movq", &["%rax", REGISTER_NAMES[r]]);
+                return;
+            }
+            BinOp::Shl | BinOp::Shr | BinOp::UShr => {
+                let instruction = shift_instruction(op);
+                self.emit("movq", &[REGISTER_NAMES[right], "%rcx"]);
+                self.move_register(left, r);
+                self.emit(instruction, &["%cl", REGISTER_NAMES[r]]);
+                return;
             }
-            _ => panic!("Unexpected type {:?}", ty),
+        };
+
+        if r == right && r != left {
+            // the result landed on the right operand, which is only fine if
+            // the order of the operands does not matter
+            if op == BinOp::Sub {
+                self.emit("negq", &[REGISTER_NAMES[r]]);
+                self.emit("addq", &[REGISTER_NAMES[left], REGISTER_NAMES[r]]);
+            } else {
+                self.emit(instruction, &[REGISTER_NAMES[left], REGISTER_NAMES[r]]);
+            }
+            return;
         }
 
-        register
+        self.move_register(left, r);
+        self.emit(instruction, &[REGISTER_NAMES[right], REGISTER_NAMES[r]]);
     }
 
-    fn scale(&mut self, register: usize, value: u8) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\tsalq\t${}, {}\n",
-            value, REGISTER_NAMES[register]
-        ));
-        register
+    fn unary(&mut self, op: UnOp, r: usize, src: usize) {
+        match op {
+            UnOp::Neg => {
+                self.move_register(src, r);
+                self.emit("negq", &[REGISTER_NAMES[r]]);
+            }
+            UnOp::Not => {
+                self.move_register(src, r);
+                self.emit("notq", &[REGISTER_NAMES[r]]);
+            }
+            UnOp::LogicalNot => {
+                self.emit("testq", &[REGISTER_NAMES[src], REGISTER_NAMES[src]]);
+                self.set_flag("sete", r);
+            }
+            UnOp::ToBool => {
+                self.emit("cmpq", &["$0", REGISTER_NAMES[src]]);
+                self.set_flag("setne", r);
+            }
+        }
     }
 
-    fn store_dereference(&mut self, expr_node: usize, right_node: usize, ty: Type) -> usize {
-        let ty = match ty {
-            Type::Array { ty, .. } => ty.pointer_to(),
-            _ => ty,
+    fn cast(&mut self, r: usize, src: usize, ty: &Type) {
+        let (instruction, from, to) = match ty {
+            Type::U8 | Type::Char => ("movzbq", BYTE_REGISTER_NAMES[src], REGISTER_NAMES[r]),
+            Type::I8 => ("movsbq", BYTE_REGISTER_NAMES[src], REGISTER_NAMES[r]),
+            Type::U16 => ("movzwq", WORD_REGISTER_NAMES[src], REGISTER_NAMES[r]),
+            Type::I16 => ("movswq", WORD_REGISTER_NAMES[src], REGISTER_NAMES[r]),
+            // writing the low half of a register clears the upper half
+            Type::U32 => ("movl", DWORD_REGISTER_NAMES[src], DWORD_REGISTER_NAMES[r]),
+            Type::I32 => ("movslq", DWORD_REGISTER_NAMES[src], REGISTER_NAMES[r]),
+            _ => return self.move_register(src, r),
         };
 
-        match ty.value_at() {
-            Type::U8 => self.assembly.text.push_str(&format!(
-                "\tmovb\t{}, ({})\n",
-                BYTE_REGISTER_NAMES[expr_node], REGISTER_NAMES[right_node]
-            )),
-            Type::U16 => self.assembly.text.push_str(&format!(
-                "\tmovw\t{}, ({})\n",
-                WORD_REGISTER_NAMES[expr_node], REGISTER_NAMES[right_node]
-            )),
-            Type::U32 => self.assembly.text.push_str(&format!(
-                "\tmovl\t{}, ({})\n",
-                DWORD_REGISTER_NAMES[expr_node], REGISTER_NAMES[right_node]
-            )),
-            Type::U64 => self.assembly.text.push_str(&format!(
-                "\tmovq\t{}, ({})\n",
-                REGISTER_NAMES[expr_node], REGISTER_NAMES[right_node]
-            )),
-            _ => panic!("Unexpected type {:?}", ty),
-        };
-        expr_node
+        self.emit(instruction, &[from, to]);
     }
 
-    fn define_string(&mut self, label: String, s: String) {
-        self.assembly.data.push_str(&format!("{}:\n", label));
-        for c in s.as_bytes() {
-            self.assembly.data.push_str(&format!("\t.byte\t{}\n", c));
+    fn move_register(&mut self, from: usize, to: usize) {
+        if from != to {
+            self.emit("movq", &[REGISTER_NAMES[from], REGISTER_NAMES[to]]);
         }
-        self.assembly.data.push_str("\t.byte\t0\n");
     }
 
-    fn load_string(&mut self, label: String) -> usize {
-        let r = self.allocate_register();
-        self.assembly
-            .text
-            .push_str(&format!("\tleaq\t{}, {}\n", label, REGISTER_NAMES[r]));
-        r
+    fn set_flag(&mut self, instruction: &str, r: usize) {
+        self.emit(instruction, &[BYTE_REGISTER_NAMES[r]]);
+        self.emit("movzbq", &[BYTE_REGISTER_NAMES[r], REGISTER_NAMES[r]]);
     }
 
-    fn post_inc_stmt(&mut self, left: Box<Node>) -> usize {
-        // should increment the value and return the old value
-        let left = match *left.clone() {
-            Node::LiteralExpr {
-                value: LiteralValue::Identifier(s),
-                ..
-            } => s,
-            _ => panic!("Unexpected token {:?}", left),
-        };
-
-        match left.clone().borrow().class {
-            StorageClass::Global => {
-                let left = {
-                    let borrowed = left.borrow();
-                    borrowed.identifier.lexeme.clone().unwrap()
-                };
-                let r = self.allocate_register();
-                let r2 = self.allocate_register();
-                self.assembly
-                    .text
-                    .push_str(&format!("\tmovq\t{}, {}\n", left, REGISTER_NAMES[r]));
-                self.assembly.text.push_str(&format!(
-                    "\tmovq\t{}, {}\n",
-                    REGISTER_NAMES[r], REGISTER_NAMES[r2]
-                ));
-                self.assembly
-                    .text
-                    .push_str(&format!("\taddq\t$1, {}\n", REGISTER_NAMES[r]));
-                self.assembly
-                    .text
-                    .push_str(&format!("\tmovq\t{}, {}\n", REGISTER_NAMES[r], left));
-                self.free_register(r);
-                r2
+    fn load(&mut self, r: usize, operand: String, ty: &Type) -> Result<()> {
+        let (instruction, register) = match ty {
+            Type::U8 | Type::Char => ("movzbq", REGISTER_NAMES[r]),
+            Type::I8 => ("movsbq", REGISTER_NAMES[r]),
+            Type::U16 => ("movzwq", REGISTER_NAMES[r]),
+            Type::I16 => ("movswq", REGISTER_NAMES[r]),
+            Type::U32 => ("movl", DWORD_REGISTER_NAMES[r]),
+            Type::I32 => ("movslq", REGISTER_NAMES[r]),
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => {
+                ("movq", REGISTER_NAMES[r])
             }
-            StorageClass::Local | StorageClass::Param => {
-                let offset = match left.borrow().offset {
-                    Some(s) => s,
-                    None => panic!("Symbol not found"),
-                };
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+        };
 
-                let r = self.allocate_register();
-                let r2 = self.allocate_register();
-                self.assembly.text.push_str(&format!(
-                    "\tmovq\t{}(%rbp), {}\n",
-                    offset, REGISTER_NAMES[r]
-                ));
-                self.assembly.text.push_str(&format!(
-                    "\tmovq\t{}, {}\n",
-                    REGISTER_NAMES[r], REGISTER_NAMES[r2]
-                ));
-                self.assembly
-                    .text
-                    .push_str(&format!("\taddq\t$1, {}\n", REGISTER_NAMES[r]));
-                self.assembly.text.push_str(&format!(
-                    "\tmovq\t{}, {}(%rbp)\n",
-                    REGISTER_NAMES[r], offset
-                ));
-                self.free_register(r);
-                r2
-            }
-        }
+        self.emit(instruction, &[&operand, register]);
+        Ok(())
     }
 
-    fn post_dec_stmt(&mut self, left: Box<Node>) -> usize {
-        // should decrement the value and return the old value
-        let left = match *left {
-            Node::LiteralExpr {
-                value: LiteralValue::Identifier(i),
-                ..
-            } => i,
-            _ => panic!("Unexpected token {:?}", left),
+    fn store(&mut self, r: usize, operand: String, ty: &Type) -> Result<()> {
+        let (instruction, register) = match ty {
+            Type::U8 | Type::I8 | Type::Char => ("movb", BYTE_REGISTER_NAMES[r]),
+            Type::U16 | Type::I16 => ("movw", WORD_REGISTER_NAMES[r]),
+            Type::U32 | Type::I32 => ("movl", DWORD_REGISTER_NAMES[r]),
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => {
+                ("movq", REGISTER_NAMES[r])
+            }
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
         };
 
-        let binding = left.borrow();
-        let left = binding.identifier.lexeme.as_ref().unwrap();
-        let r = self.allocate_register();
-        let r2 = self.allocate_register();
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, {}\n", left, REGISTER_NAMES[r]));
-        self.assembly.text.push_str(&format!(
-            "\tmovq\t{}, {}\n",
-            REGISTER_NAMES[r], REGISTER_NAMES[r2]
-        ));
-        self.assembly
-            .text
-            .push_str(&format!("\tsubq\t$1, {}\n", REGISTER_NAMES[r]));
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, {}\n", REGISTER_NAMES[r], left));
-        self.free_register(r);
-        r2
+        self.emit(instruction, &[register, &operand]);
+        Ok(())
     }
 
-    fn pre_inc_stmt(&mut self, right: Box<Node>) -> usize {
-        let right = match *right.clone() {
-            Node::LiteralExpr {
-                value: LiteralValue::Identifier(i),
-                ..
-            } => i,
-            _ => panic!("Unexpected token {:?}", right),
+    fn return_value(&mut self, r: usize, ty: &Type) -> Result<()> {
+        let (instruction, register, destination) = match ty {
+            Type::U8 | Type::Char => ("movzbl", BYTE_REGISTER_NAMES[r], "%eax"),
+            Type::I8 => ("movsbq", BYTE_REGISTER_NAMES[r], "%rax"),
+            Type::U16 => ("movzwl", WORD_REGISTER_NAMES[r], "%eax"),
+            Type::I16 => ("movswq", WORD_REGISTER_NAMES[r], "%rax"),
+            Type::U32 => ("movl", DWORD_REGISTER_NAMES[r], "%eax"),
+            Type::I32 => ("movslq", DWORD_REGISTER_NAMES[r], "%rax"),
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => {
+                ("movq", REGISTER_NAMES[r], "%rax")
+            }
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
         };
 
-        let binding = right.borrow();
-        let right = binding.identifier.lexeme.as_ref().unwrap();
-        let r = self.allocate_register();
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, {}\n", right, REGISTER_NAMES[r]));
-        self.assembly
-            .text
-            .push_str(&format!("\taddq\t$1, {}\n", REGISTER_NAMES[r]));
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, {}\n", REGISTER_NAMES[r], right));
-        r
+        self.emit(instruction, &[register, destination]);
+        Ok(())
     }
 
-    fn pre_dec_stmt(&mut self, right: Box<Node>) -> usize {
-        let right = match *right.clone() {
-            Node::LiteralExpr {
-                value: LiteralValue::Identifier(i),
-                ..
-            } => i,
-            _ => panic!("Unexpected token {:?}", right),
-        };
+    fn function_call(&mut self, name: &str, args: &[VReg]) -> Result<()> {
+        // keep the live registers the callee may clobber on the stack
+        // across the call
+        let saved = self
+            .allocation
+            .live_across(self.index)
+            .into_iter()
+            .filter(|r| !self.callee_saved().contains(r))
+            .collect::<Vec<_>>();
+        for r in &saved {
+            self.emit("pushq", &[REGISTER_NAMES[*r]]);
+        }
 
-        let binding = right.borrow();
-        let right = binding.identifier.lexeme.as_ref().unwrap();
-        let r = self.allocate_register();
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, {}\n", right, REGISTER_NAMES[r]));
-        self.assembly
-            .text
-            .push_str(&format!("\tsubq\t$1, {}\n", REGISTER_NAMES[r]));
-        self.assembly
-            .text
-            .push_str(&format!("\tmovq\t{}, {}\n", REGISTER_NAMES[r], right));
-        r
-    }
+        // the stack has to be 16 byte aligned at the call
+        let registers = self.param_registers();
+        let stack_args = args.len().saturating_sub(registers.len());
+        let padding = (saved.len() + stack_args) % 2 * 8;
+        if padding > 0 {
+            self.emit("subq", &[&format!("${}", padding), "%rsp"]);
+        }
 
-    fn expr_to_bool(&mut self, expr: Box<Node>) -> usize {
-        let r = self.generate_node(*expr);
-        self.assembly
-            .text
-            .push_str(&format!("\tcmpq\t$0, {}\n", REGISTER_NAMES[r]));
-        self.assembly
-            .text
-            .push_str(&format!("\tsetne\t{}\n", BYTE_REGISTER_NAMES[r]));
-        self.assembly.text.push_str(&format!(
-            "\tmovzbq\t{}, {}\n",
-            BYTE_REGISTER_NAMES[r], REGISTER_NAMES[r]
-        ));
-        r
-    }
+        // arguments beyond the registers go on the stack, the last one first
+        // and spilled ones straight from their slot
+        for arg in args.iter().skip(registers.len()).rev() {
+            let operand = match self.spill_slot(*arg) {
+                Some(slot) => slot,
+                None => REGISTER_NAMES[self.register(*arg)].to_string(),
+            };
+            self.emit("pushq", &[&operand]);
+        }
 
-    fn negate(&mut self, right_node: usize) -> usize {
-        self.assembly
-            .text
-            .push_str(&format!("\tnegq\t{}\n", REGISTER_NAMES[right_node]));
-        right_node
-    }
+        let moves = args
+            .iter()
+            .zip(registers)
+            .map(|(from, to)| (REGISTER_NAMES[self.register(*from)], REGISTER_NAMES[*to]))
+            .collect();
+        self.parallel_move(moves);
 
-    fn invert(&mut self, right_node: usize) -> usize {
-        self.assembly
-            .text
-            .push_str(&format!("\tnotq\t{}\n", REGISTER_NAMES[right_node]));
-        right_node
+        let shadow = self.shadow_space();
+        if shadow > 0 {
+            self.emit("subq", &[&format!("${}", shadow), "%rsp"]);
+        }
+        self.emit("call", &[&self.function_symbol(name)]);
+
+        let cleanup = 8 * stack_args + padding + shadow;
+        // what this call keeps on the stack below the frame
+        let pushed = (8 * saved.len() + cleanup) as u64;
+        self.frames
+            .last_mut()
+            .unwrap()
+            .calls
+            .push((name.to_string(), pushed));
+        if cleanup > 0 {
+            self.emit("addq", &[&format!("${}", cleanup), "%rsp"]);
+        }
+
+        for r in saved.iter().rev() {
+            self.emit("popq", &[REGISTER_NAMES[*r]]);
+        }
+
+        Ok(())
     }
 
-    fn logical_not(&mut self, right_node: usize) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\ttestq\t{}, {}\n",
-            REGISTER_NAMES[right_node], REGISTER_NAMES[right_node]
-        ));
-        self.assembly
-            .text
-            .push_str(&format!("\tsete\t{}\n", BYTE_REGISTER_NAMES[right_node]));
-        self.assembly.text.push_str(&format!(
-            "\tmovzbq\t{}, {}\n",
-            BYTE_REGISTER_NAMES[right_node], REGISTER_NAMES[right_node]
-        ));
-        right_node
+    // Jumps to the function instead of calling it, once this frame is gone,
+    // so it returns straight to our caller and the stack does not grow.
+    fn tail_call(&mut self, name: &str, args: &[VReg]) {
+        let moves = args
+            .iter()
+            .zip(self.param_registers())
+            .map(|(from, to)| (REGISTER_NAMES[self.register(*from)], REGISTER_NAMES[*to]))
+            .collect();
+        self.parallel_move(moves);
+
+        self.leave_frame();
+        self.emit("jmp", &[&self.function_symbol(name)]);
+        self.frames
+            .last_mut()
+            .unwrap()
+            .tail_calls
+            .push(name.to_string());
     }
 
-    fn load_local(&mut self, symbol: Symbol, ty: Type) -> usize {
-        let offset = match symbol.offset {
-            Some(s) => s,
-            None => panic!("Symbol not found"),
-        };
+    // The arguments may already sit in parameter registers, possibly each in
+    // the one another argument needs. Moves whose destination nobody still
+    // has to read go first, a cycle is broken by parking a value in %rax.
+    fn parallel_move(&mut self, mut moves: Vec<(&str, &str)>) {
+        moves.retain(|(from, to)| from != to);
+
+        while !moves.is_empty() {
+            let ready = moves
+                .iter()
+                .position(|(_, to)| !moves.iter().any(|(from, _)| from == to));
+
+            let (from, to) = match ready {
+                Some(i) => moves.remove(i),
+                None => {
+                    let (from, to) = moves.remove(0);
+                    moves.push(("%rax", to));
+                    (from, "%rax")
+                }
+            };
 
-        let r = self.allocate_register();
-        if ty == Type::U8 || ty == Type::Char {
-            self.assembly.text.push_str(&format!(
-                "\tmovzb\t{}(%rbp), {}\n",
-                offset, REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::U16 {
-            self.assembly.text.push_str(&format!(
-                "\tmovzw\t{}(%rbp), {}\n",
-                offset, REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::U32 {
-            self.assembly.text.push_str(&format!(
-                "\tmovl\t{}(%rbp), {}\n",
-                offset, DWORD_REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::U64 || matches!(ty, Type::Pointer { .. }) {
-            self.assembly.text.push_str(&format!(
-                "\tmovq\t{}(%rbp), {}\n",
-                offset, REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::I8 {
-            self.assembly.text.push_str(&format!(
-                "\tmovsbq\t{}(%rbp), {}\n",
-                offset, REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::I16 {
-            self.assembly.text.push_str(&format!(
-                "\tmovsx\t{}(%rbp), {}\n",
-                offset, REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::I32 {
-            self.assembly.text.push_str(&format!(
-                "\tmovl\t{}(%rbp), {}\n",
-                offset, DWORD_REGISTER_NAMES[r]
-            ));
-        } else if ty == Type::I64 {
-            self.assembly.text.push_str(&format!(
-                "\tmovq\t{}(%rbp), {}\n",
-                offset, REGISTER_NAMES[r]
-            ));
-        } else if let Type::Array { .. } = ty {
-            self.assembly.text.push_str(&format!(
-                "\tleaq\t{}(%rbp), {}\n",
-                offset, REGISTER_NAMES[r]
-            ));
-        } else {
-            panic!("Unexpected type {:?}", ty);
+            self.emit("movq", &[from, to]);
         }
-
-        r
     }
+}
 
-    fn logical_and(&self, _left: usize, _right: usize, _ty: Type) -> usize {
-        todo!()
+// The globals and string literals of the module, written the same for every
+// architecture, and the label each literal's text is emitted under.
+pub fn data(module: &Module, options: Options) -> (String, HashMap<String, String>) {
+    let target = options.target;
+    let mut data = String::new();
+    // an extern global is only referred to, the linker finds it elsewhere
+    for global in module.globals.iter().filter(|global| !global.external) {
+        define_global(&mut data, target, global, !options.library);
     }
 
-    fn logical_or(&self, _left: usize, _right: usize, _ty: Type) -> usize {
-        todo!()
+    // identical literals share a single copy of their text
+    let mut strings = HashMap::new();
+    let mut pool: HashMap<&str, &str> = HashMap::new();
+    for string in &module.strings {
+        let label = *pool.entry(&string.value).or_insert(&string.label);
+        if label == string.label {
+            define_string(&mut data, target, &target.local(label), &string.value);
+        }
+        strings.insert(string.label.clone(), target.local(label));
     }
 
-    fn bitwise_and(&mut self, left: usize, right: usize, _ty: Type) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\tandq\t{}, {}\n",
-            REGISTER_NAMES[left], REGISTER_NAMES[right]
-        ));
-        self.free_register(left);
-        right
+    (data, strings)
+}
+
+// The directive that switches to a section named by `#[section]`. ELF and
+// COFF take flags saying whether it holds code or data, Mach-O wants the
+// segment and section as in `__TEXT,__boot` and gets the name as it is.
+fn section(target: Target, name: &str, code: bool) -> String {
+    match (target.os, code) {
+        (Os::Macos, _) => format!(".section {}", name),
+        (Os::Windows, true) => format!(".section {},\"xr\"", name),
+        (Os::Windows, false) => format!(".section {},\"dw\"", name),
+        (_, true) => format!(".section {},\"ax\"", name),
+        (_, false) => format!(".section {},\"aw\"", name),
     }
+}
 
-    fn bitwise_or(&mut self, left: usize, right: usize, _ty: Type) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\torq\t{}, {}\n",
-            REGISTER_NAMES[left], REGISTER_NAMES[right]
-        ));
-        self.free_register(left);
-        right
+// The section a function goes in instead of .text: the one `#[section]`
+// names, or .text.unlikely for a `#[cold]` one on ELF, which the linker
+// gathers away from the code that runs often
+pub fn function_section(target: Target, placement: &Placement) -> Option<String> {
+    match &placement.section {
+        Some(name) => Some(name.clone()),
+        None if placement.cold && target.os == Os::Linux => Some(String::from(".text.unlikely")),
+        None => None,
     }
+}
 
-    fn bitwise_xor(&mut self, left: usize, right: usize, _ty: Type) -> usize {
-        self.assembly.text.push_str(&format!(
-            "\txorq\t{}, {}\n",
-            REGISTER_NAMES[left], REGISTER_NAMES[right]
-        ));
-        self.free_register(left);
-        right
+// What goes in front of a function placed with attributes, the backends
+// switch back to .text after it
+pub fn place_function(target: Target, placement: &Placement) -> Vec<String> {
+    let mut directives = Vec::new();
+    if let Some(name) = function_section(target, placement) {
+        directives.push(section(target, &name, true));
     }
+    if let Some(align) = placement.align {
+        directives.push(format!(".balign {}", align));
+    }
+    directives
+}
 
-    fn shift_left(&mut self, left: usize, right: usize, ty: Type) -> usize {
-        let (c_register, mov, r) = match ty.size() {
-            1 => ("%cl", "mov", BYTE_REGISTER_NAMES[right]),
-            2 => ("%cx", "movw", WORD_REGISTER_NAMES[right]),
-            4 => ("%ecx", "movl", DWORD_REGISTER_NAMES[right]),
-            8 => ("%rcx", "movq", REGISTER_NAMES[right]),
-            _ => panic!("Unexpected type {:?}", ty),
-        };
-        self.assembly
-            .text
-            .push_str(&format!("\t{}\t {}, {}\n", mov, r, c_register));
-        self.assembly.text.push_str(&format!(
-            "\tsalq\t {}, {}\n",
-            c_register, REGISTER_NAMES[left]
-        ));
-        self.free_register(right);
-        left
+// Globals start out zeroed, so they go in .bss and take no room in the
+// executable, each on the boundary its type needs unless `#[align]` asks for
+// more. One with an initial value goes in .data instead. .balign counts it in
+// bytes everywhere, .align takes a power of two on ARM and RISC-V. A shared
+// library keeps them to itself.
+fn define_global(data: &mut String, target: Target, global: &Global, exported: bool) {
+    let name = target.symbol(&global.name);
+    let ty = &global.ty;
+    let align = global.placement.align.unwrap_or(1).max(ty.align());
+    match (&global.placement.section, global.init, target.os) {
+        // the alignment is given as a power of two
+        (None, None, Os::Macos) => {
+            if exported {
+                data.push_str(&format!("\t.globl\t{}\n", name));
+            }
+            data.push_str(&format!(
+                "\t.zerofill __DATA,__bss,{},{},{}\n",
+                name,
+                ty.storage_size(),
+                align.trailing_zeros()
+            ));
+        }
+        (placed, init, os) => {
+            let section = match (placed, init) {
+                (Some(placed), _) => section(target, placed, false),
+                (None, Some(_)) => String::from(".data"),
+                (None, None) => String::from(".bss"),
+            };
+            data.push_str(&format!("\t{}\n", section));
+            if exported {
+                let global = if os == Os::Macos { ".globl" } else { ".global" };
+                data.push_str(&format!("\t{}\t{}\n", global, name));
+            }
+            data.push_str(&format!("\t.balign\t{}\n", align));
+            data.push_str(&format!("{}:\n", name));
+            match init {
+                Some(value) => data.push_str(&format!("\t{}\n", value_directive(ty, value))),
+                None => data.push_str(&format!("\t.zero\t{}\n", ty.storage_size())),
+            }
+        }
     }
+}
 
-    fn shift_right(&mut self, left: usize, right: usize, ty: Type) -> usize {
-        let (c_register, mov, r) = match ty.size() {
-            1 => ("%cl", "mov", BYTE_REGISTER_NAMES[right]),
-            2 => ("%cx", "movw", WORD_REGISTER_NAMES[right]),
-            4 => ("%ecx", "movl", DWORD_REGISTER_NAMES[right]),
-            8 => ("%rcx", "movq", REGISTER_NAMES[right]),
-            _ => panic!("Unexpected type {:?}", ty),
-        };
-        self.assembly
-            .text
-            .push_str(&format!("\t{}\t {}, {}\n", mov, r, c_register));
-        self.assembly.text.push_str(&format!(
-            "\tsarq\t {}, {}\n",
-            c_register, REGISTER_NAMES[left]
-        ));
-        self.free_register(right);
-        left
+// the directive writing an integer of the type's size, given in full as an
+// unsigned number since a signed one comes sign extended to 64 bits
+fn value_directive(ty: &Type, value: u64) -> String {
+    let (directive, bits) = match ty.size() {
+        1 => (".byte", 8),
+        2 => (".short", 16),
+        4 => (".long", 32),
+        _ => return format!(".quad\t{}", value),
+    };
+    format!("{}\t{}", directive, value & ((1 << bits) - 1))
+}
+
+fn define_string(data: &mut String, target: Target, label: &str, s: &str) {
+    let section = match target.os {
+        Os::Macos => "__TEXT,__cstring",
+        Os::Windows => ".rdata,\"dr\"",
+        _ => ".rodata",
+    };
+    data.push_str(&format!("\t.section\t{}\n", section));
+    data.push_str(&format!("{}:\n", label));
+    for c in s.as_bytes() {
+        data.push_str(&format!("\t.byte\t{}\n", c));
     }
+    data.push_str("\t.byte\t0\n");
+}
 
-    fn copy_arg(&mut self, register: usize, arg_pos: usize) {
-        /*
-        FIRST_PARAM_REG = 9
-        const REGISTER_NAMES: [&str; 10] = ["%r10", "%r11", "%r12", "%r13", "%r9", "%r8", "%rcx", "%rdx", "%rsi", "%rdi"];
+// A call whose result is returned as it is, with no arguments on the stack
+// that would have to outlive this frame, as more than `arguments` would be,
+// and no address of a local handed out, which the callee could still use.
+// The parser rejects narrowing a return value, so what the callee leaves in
+// the return register already fits.
+pub fn tail_call(insts: &[Inst], i: usize, arguments: usize) -> Option<(&str, &[VReg])> {
+    let escapes = || {
+        insts.iter().any(|inst| {
+            matches!(
+                inst,
+                Inst::Lea {
+                    addr: Address::Local(_),
+                    ..
+                }
+            )
+        })
+    };
+    match (&insts[i], insts.get(i + 1)) {
+        // the arguments past the registers go in the caller's frame, under
+        // its return address, which a jump would leave the callee reading
+        // the wrong ones from, so such a call stays a call
+        (Inst::Call { dst, name, args }, Some(Inst::Return { src, .. }))
+            if src == dst && args.len() <= arguments && !escapes() =>
+        {
+            Some((name, args))
+        }
+        _ => None,
+    }
+}
 
-         */
-        if arg_pos > 6 {
-            self.assembly
-                .text
-                .push_str(&format!("\tpushq\t{}\n", REGISTER_NAMES[register]));
-        } else {
-            self.assembly.text.push_str(&format!(
-                "\tmovq\t{}, {}\n",
-                REGISTER_NAMES[register],
-                REGISTER_NAMES[(FIRST_PARAM_REG - (arg_pos as isize) + 1) as usize]
-            ));
+// The constants only ever read as shift amounts, which x86 takes as an
+// immediate rather than in %cl and AArch64 rather than in a register.
+pub fn immediates(insts: &[Inst]) -> HashMap<VReg, u64> {
+    let mut immediates = insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::Const { dst, value } => Some((*dst, *value)),
+            _ => None,
+        })
+        .collect::<HashMap<_, _>>();
+
+    for inst in insts {
+        let amount = match inst {
+            Inst::Binary {
+                op: BinOp::Shl | BinOp::Shr | BinOp::UShr,
+                left,
+                right,
+                ..
+            } if left != right => Some(*right),
+            _ => None,
+        };
+        for vreg in inst.uses() {
+            if Some(vreg) != amount {
+                immediates.remove(&vreg);
+            }
         }
     }
+
+    immediates
 }
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+
+use crate::{
+    aarch64,
+    asm::Syntax,
+    ast::Node,
+    checker::Checker,
+    checks::Checks,
+    codegen::{self, Backend, CodeGen, Emit},
+    diagnostic::Diagnostic,
+    elf, fold,
+    ir::Module,
+    lexer::{Lexer, Token},
+    link,
+    lint::Lints,
+    llvm, lower,
+    parser::Parser,
+    passes::{Pass, Passes},
+    preprocess::{preprocess, Includes},
+    riscv64, runtime, ssa, stack,
+    target::{Arch, Os, Target},
+    timing, wasm32,
+};
+
+// The errors a compilation stopped at, in the order they were found
+pub type Diagnostics = Vec<Diagnostic>;
+
+// What to compile a program for and into, the command line options that
+// aren't about the compiler itself
+pub struct Options {
+    pub target: Target,
+    pub syntax: Syntax,
+    // reach globals through the GOT and functions through the PLT
+    pub pic: bool,
+    pub emit: Emit,
+    pub passes: Passes,
+    pub lints: Lints,
+    // the runtime checks compiled into the program
+    pub checks: Checks,
+    // count the calls of every function and print them at exit
+    pub instrument: bool,
+    // `-L` and `-l`, handed on to the linker as they are
+    pub libraries: Vec<String>,
+    // `-I`, the directories `#include` looks in
+    pub include_paths: Vec<String>,
+    // `-D`, the macros defined before the source, each with its text
+    pub defines: Vec<(String, String)>,
+}
+
+impl Default for Options {
+    // assembly for the machine the compiler runs on, optimized, as `crust` does
+    fn default() -> Self {
+        Options {
+            target: Target::host(),
+            syntax: Syntax::Att,
+            pic: false,
+            emit: Emit::Asm,
+            passes: Passes::new(2),
+            lints: Lints::new(),
+            checks: Checks::new(),
+            instrument: false,
+            libraries: Vec::new(),
+            include_paths: Vec::new(),
+            defines: Vec::new(),
+        }
+    }
+}
+
+// What compiling a program gave: the contents of the file `emit` asked for,
+// and the warnings the program got
+#[derive(Debug)]
+pub struct Artifacts {
+    pub output: Vec<u8>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// The compiler for other programs to drive, a build script or a playground,
+// with every stage the command line goes through on its own as well. The
+// lines of the diagnostics count from the start of the source.
+pub struct Compiler {
+    options: Options,
+    // the files the source was read from, with the line each starts on
+    files: Vec<(String, usize)>,
+}
+
+impl Compiler {
+    pub fn new(mut options: Options) -> Self {
+        // a shared library may be loaded anywhere
+        if options.emit == Emit::Dylib {
+            options.pic = true;
+        }
+        Compiler {
+            options,
+            files: Vec::new(),
+        }
+    }
+
+    // Names the files the source was read from, each with the line of the
+    // source it starts on, for the message of a failed assert. Without them
+    // it says `<source>`.
+    pub fn with_files(mut self, files: Vec<(String, usize)>) -> Self {
+        self.files = files;
+        self
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    // Compiles a whole program into what the options ask for, or the errors
+    // that stopped it. It doesn't panic: should the compiler itself fail,
+    // that is an error too, though the panic hook still gets to see it.
+    pub fn compile_source(&self, source: &str) -> Result<Artifacts, Diagnostics> {
+        panic::catch_unwind(AssertUnwindSafe(|| self.compile(source)))
+            .unwrap_or_else(|panic| Err(vec![Diagnostic::internal(panic)]))
+    }
+
+    fn compile(&self, source: &str) -> Result<Artifacts, Diagnostics> {
+        self.validate().map_err(|e| vec![e])?;
+        // the runtime is the same whatever the program
+        if self.options.emit == Emit::Runtime {
+            let output = self.runtime().map_err(|e| vec![e])?.into_bytes();
+            return Ok(Artifacts {
+                output,
+                warnings: Vec::new(),
+            });
+        }
+        let (_, nodes) = self.parse_source(source)?;
+        let warnings = self.check(&nodes);
+        let nodes = self.fold(nodes);
+        let mut module = self.lower(&nodes)?;
+        self.optimize(&mut module);
+        let assembly = self.generate(module).map_err(|e| vec![e])?;
+        let output = self.emit(&assembly).map_err(|e| vec![e])?;
+        Ok(Artifacts { output, warnings })
+    }
+
+    // the options that don't go together
+    pub fn validate(&self) -> Result<(), Diagnostic> {
+        let Options { target, syntax, .. } = self.options;
+        if syntax == Syntax::Intel && target.arch != Arch::X86_64 {
+            return Err(Diagnostic::error(
+                "--syntax=intel is only for x86-64 targets",
+            ));
+        }
+
+        // the built in assembler only reads the AT&T syntax of x86-64 ELF
+        let linux = Target {
+            arch: Arch::X86_64,
+            os: Os::Linux,
+        };
+        if self.options.emit == Emit::Obj && (target != linux || syntax == Syntax::Intel) {
+            return Err(Diagnostic::error(
+                "-c and --emit=obj are only for x86_64-linux with AT&T syntax",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn lex(&self, source: &str) -> Result<Vec<Token>, Diagnostics> {
+        let mut lexer = Lexer::new(source.to_string());
+        timing::time("lexing", || lexer.scan_tokens().cloned())
+    }
+
+    // the tokens with the macros expanded and the files included
+    pub fn preprocess(&self, tokens: Vec<Token>) -> Result<Vec<Token>, Diagnostics> {
+        let includes = Includes {
+            files: self.files.clone(),
+            paths: self.options.include_paths.clone(),
+        };
+        timing::time("preprocessing", || {
+            preprocess(tokens, &includes, &self.options.defines)
+        })
+    }
+
+    // the syntax tree, and the parser for the symbols it found
+    pub fn parse(&self, tokens: Vec<Token>) -> Result<(Parser, Vec<Node>), Diagnostics> {
+        let mut parser = Parser::new(tokens);
+        let nodes = timing::time("parsing", || parser.parse().cloned())?;
+        Ok((parser, nodes))
+    }
+
+    // The syntax tree of the source, lexed, preprocessed and parsed. With
+    // no directive and no `-D` there is nothing to preprocess, and the
+    // parser reads the tokens straight from the lexer, so the lexing row
+    // is timed a token at a time and counts in the parsing one as well.
+    pub fn parse_source(&self, source: &str) -> Result<(Parser, Vec<Node>), Diagnostics> {
+        if has_directives(source) || !self.options.defines.is_empty() {
+            let tokens = self.preprocess(self.lex(source)?)?;
+            return self.parse(tokens);
+        }
+        let mut lexer = Lexer::new(source.to_string());
+        let tokens = std::iter::from_fn(move || timing::time("lexing", || lexer.next()));
+        let mut parser = Parser::streaming(tokens);
+        let nodes = timing::time("parsing", || parser.parse().cloned())?;
+        Ok((parser, nodes))
+    }
+
+    // the warnings of the lints that aren't allowed
+    pub fn check(&self, nodes: &[Node]) -> Vec<Diagnostic> {
+        timing::time("checking", || {
+            Checker::new(&self.options.lints).check(nodes)
+        })
+    }
+
+    pub fn fold(&self, nodes: Vec<Node>) -> Vec<Node> {
+        if !self.options.passes.enabled(Pass::ConstantFolding) {
+            return nodes;
+        }
+        timing::time(Pass::ConstantFolding.name(), || fold::fold(nodes))
+    }
+
+    pub fn lower(&self, nodes: &[Node]) -> Result<Module, Diagnostics> {
+        timing::time("lowering", || {
+            lower::lower(
+                nodes,
+                &self.files,
+                &self.options.checks,
+                self.options.instrument,
+            )
+        })
+    }
+
+    // what each function is called in the object file, for those mangled
+    pub fn symbols(&self, nodes: &[Node]) -> HashMap<String, String> {
+        lower::symbols(nodes, &self.files)
+    }
+
+    pub fn optimize(&self, module: &mut Module) {
+        ssa::optimize(module, &self.options.passes);
+    }
+
+    fn codegen_options(&self) -> codegen::Options {
+        codegen::Options {
+            target: self.options.target,
+            syntax: self.options.syntax,
+            pic: self.options.pic,
+            // a shared library exports only what is pub
+            library: self.options.emit == Emit::Dylib,
+        }
+    }
+
+    fn backend(&self, module: Module) -> Box<dyn Backend> {
+        let options = self.codegen_options();
+        let passes = self.options.passes.clone();
+        match options.target.arch {
+            _ if self.options.emit == Emit::LlvmIr => {
+                Box::new(llvm::CodeGen::new(module, options.target))
+            }
+            Arch::X86_64 => Box::new(CodeGen::new(module, passes, options)),
+            Arch::Aarch64 => Box::new(aarch64::CodeGen::new(module, passes, options)),
+            Arch::Riscv64 => Box::new(riscv64::CodeGen::new(module, passes, options)),
+            Arch::Wasm32 => Box::new(wasm32::CodeGen::new(module)),
+        }
+    }
+
+    // the assembly for the target, or LLVM IR
+    pub fn generate(&self, module: Module) -> Result<String, Diagnostic> {
+        let mut backend = self.backend(module);
+        timing::time("code generation", || backend.generate())
+    }
+
+    // The stack every function takes, as `--stack-usage` reports it. The
+    // frames are only known once the assembly is generated, and only to the
+    // backends that lay them out themselves.
+    pub fn stack_usage(&self, module: Module) -> Result<String, Diagnostic> {
+        if self.options.emit == Emit::LlvmIr || self.options.target.arch == Arch::Wasm32 {
+            return Err(Diagnostic::error(
+                "--stack-usage is only for x86-64, aarch64 and riscv64 assembly",
+            ));
+        }
+        let mut backend = self.backend(module);
+        timing::time("code generation", || backend.generate())?;
+        Ok(stack::report(&backend.frames()))
+    }
+
+    // the builtins the program calls, assembled and linked along with it
+    pub fn runtime(&self) -> Result<String, Diagnostic> {
+        runtime::assembly(self.codegen_options())
+    }
+
+    // What goes in the output file: the assembly as it is, assembled into an
+    // object file, or an executable or library the C compiler links from it
+    // and the runtime
+    pub fn emit(&self, assembly: &str) -> Result<Vec<u8>, Diagnostic> {
+        let Options {
+            target,
+            pic,
+            emit,
+            ref libraries,
+            ..
+        } = self.options;
+        match emit {
+            Emit::Obj => timing::time("assembling", || elf::assemble(assembly)),
+            Emit::Exe | Emit::Dylib => link::link_bytes(
+                assembly,
+                &self.runtime()?,
+                target,
+                pic,
+                emit == Emit::Dylib,
+                libraries,
+            ),
+            Emit::Asm | Emit::LlvmIr => Ok(assembly.as_bytes().to_vec()),
+            Emit::Runtime => Ok(self.runtime()?.into_bytes()),
+        }
+    }
+}
+
+// whether a directive may be in the source, a `#` before a name, even if
+// only in a string or a comment
+fn has_directives(source: &str) -> bool {
+    source
+        .split('#')
+        .skip(1)
+        .any(|after| after.starts_with(|c: char| c.is_alphabetic() || c == '_'))
+}
@@ -0,0 +1,82 @@
+use crate::{
+    ast::{signed_operation, LiteralValue, Node},
+    diagnostic::{Diagnostic, Result},
+    fold,
+    intern::Name,
+    ir,
+    lexer::{Token, TokenType},
+};
+
+// Evaluates an expression that has to be known at compile time: the value of
+// a `const`, the size of an array and the initial value of a global. It
+// computes what the folder would, but says why when it can't, pointing at
+// `at`, where the expression starts.
+pub fn evaluate(node: &Node, at: &Token) -> Result<u64> {
+    let value = match node {
+        Node::LiteralExpr { value, .. } => match value {
+            LiteralValue::U8(value) => *value as u64,
+            LiteralValue::U16(value) => *value as u64,
+            LiteralValue::U32(value) => *value as u64,
+            LiteralValue::U64(value) => *value,
+            LiteralValue::Identifier(symbol) => {
+                let name = symbol
+                    .borrow()
+                    .identifier
+                    .lexeme
+                    .map(Name::text)
+                    .unwrap_or_default();
+                return Err(not_constant(at, &format!("Variable {}", name)));
+            }
+            LiteralValue::String { .. } => return Err(not_constant(at, "A string")),
+        },
+        Node::BinaryExpr {
+            left,
+            operator,
+            right,
+            ..
+        } => {
+            let (a, b) = (evaluate(left, at)?, evaluate(right, at)?);
+            match operator.token_type {
+                TokenType::LogicalAnd => (a != 0 && b != 0) as u64,
+                TokenType::LogicalOr => (a != 0 || b != 0) as u64,
+                TokenType::Div if b == 0 => {
+                    return Err(Diagnostic::at(operator, "Division by zero in a constant")
+                        .with_label("divides by zero"))
+                }
+                op => fold::binary(op, a, b, signed_operation(op, left, right))
+                    .ok_or_else(|| Diagnostic::at(operator, "Overflow in a constant"))?,
+            }
+        }
+        Node::UnaryExpr {
+            operator, right, ..
+        } => match operator.token_type {
+            TokenType::Sub => evaluate(right, at)?.wrapping_neg(),
+            TokenType::Invert => !evaluate(right, at)?,
+            TokenType::LogicalNot => (evaluate(right, at)? == 0) as u64,
+            TokenType::Ampersand => return Err(not_constant(at, "An address")),
+            _ => return Err(not_constant(at, "A dereference")),
+        },
+        Node::WidenExpr { right, ty, .. } => {
+            let value = evaluate(right, at)?;
+            right
+                .ty()
+                .map_or(Vec::new(), |from| from.widening(ty))
+                .iter()
+                .fold(value, ir::cast)
+        }
+        Node::ScaleExpr { right, size, .. } => evaluate(right, at)?.wrapping_mul(*size as u64),
+        Node::ToBool { expr, .. } => (evaluate(expr, at)? != 0) as u64,
+        Node::FnCall { identifier, .. } => {
+            let name = identifier.lexeme.map(Name::text).unwrap_or_default();
+            return Err(not_constant(at, &format!("A call to {}", name)));
+        }
+        _ => return Err(not_constant(at, "This expression")),
+    };
+
+    Ok(value)
+}
+
+fn not_constant(at: &Token, what: &str) -> Diagnostic {
+    Diagnostic::at(at, format!("{} is not a constant expression", what))
+        .with_label("not known at compile time")
+}
@@ -0,0 +1,114 @@
+use std::{any::Any, fmt};
+
+use crate::{ast::Span, lexer::Token};
+
+pub type Result<T> = std::result::Result<T, Diagnostic>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub label: Option<String>,
+    // the lint a warning comes from, like `unused-variables`
+    pub code: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, line: usize, column: usize) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            line,
+            column,
+            length: 1,
+            label: None,
+            code: None,
+        }
+    }
+
+    pub fn at(token: &Token, message: impl Into<String>) -> Self {
+        let length = match token.lexeme {
+            Some(lexeme) => lexeme.text().chars().count().max(1),
+            None => 1,
+        };
+        Self::new(message, token.line, token.column).with_length(length)
+    }
+
+    // A node, underlined as far as its first line goes
+    pub fn spanning(span: Span, message: impl Into<String>) -> Self {
+        let (line, column) = span.start;
+        let length = if span.end.0 == line {
+            span.end.1 - column
+        } else {
+            1
+        };
+        Self::new(message, line, column).with_length(length)
+    }
+
+    // errors raised after parsing (e.g. in codegen) don't always have a location
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::new(message, 0, 0)
+    }
+
+    // the compiler itself failing, with what it panicked with
+    pub fn internal(panic: Box<dyn Any + Send>) -> Self {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        Self::error(format!("internal compiler error: {}", message))
+    }
+
+    pub fn with_length(mut self, length: usize) -> Self {
+        self.length = length.max(1);
+        self
+    }
+
+    pub fn with_severity(mut self, severity: Severity) -> Self {
+        self.severity = severity;
+        self
+    }
+
+    pub fn with_label(mut self, label: impl Into<String>) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.code = Some(code.into());
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(
+                f,
+                "{} at line {} column {}",
+                self.message, self.line, self.column
+            )
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use std::path::{Path, PathBuf};
+
+// What every stage made of a file for `--dump-all`, written to a directory
+// named after it with the files numbered in the order the stages ran
+pub struct Dump {
+    directory: PathBuf,
+    count: usize,
+}
+
+fn fail(path: &Path, e: std::io::Error) -> ! {
+    eprintln!("error: cannot write {}: {}", path.display(), e);
+    std::process::exit(1);
+}
+
+impl Dump {
+    // `tests/test10` is dumped into `test10.dump` in the current directory
+    pub fn new(file: &str) -> Self {
+        let name = PathBuf::from(file);
+        let name = name.file_name().unwrap_or(name.as_os_str());
+        let directory = PathBuf::from(format!("{}.dump", name.to_string_lossy()));
+        std::fs::create_dir_all(&directory).unwrap_or_else(|e| fail(&directory, e));
+
+        Self {
+            directory,
+            count: 0,
+        }
+    }
+
+    pub fn write(&mut self, name: &str, contents: &str) {
+        self.count += 1;
+        let path = self.directory.join(format!("{:02}-{}", self.count, name));
+        std::fs::write(&path, contents).unwrap_or_else(|e| fail(&path, e));
+    }
+}
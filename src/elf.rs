@@ -0,0 +1,929 @@
+use std::collections::HashMap;
+
+use crate::diagnostic::{Diagnostic, Result};
+
+// The sections code and data go in, numbered as their headers are after the
+// null one
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Section {
+    Text,
+    Data,
+    Bss,
+    Rodata,
+}
+
+const SECTIONS: [Section; 4] = [Section::Text, Section::Data, Section::Bss, Section::Rodata];
+
+impl Section {
+    fn name(&self) -> &'static str {
+        match self {
+            Section::Text => ".text",
+            Section::Data => ".data",
+            Section::Bss => ".bss",
+            Section::Rodata => ".rodata",
+        }
+    }
+
+    // the index of its header, and of its symbol
+    fn index(&self) -> usize {
+        *self as usize + 1
+    }
+}
+
+// the relocations of the x86-64 psABI
+const R_X86_64_PC32: u32 = 2;
+const R_X86_64_PLT32: u32 = 4;
+const R_X86_64_REX_GOTPCRELX: u32 = 42;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Base {
+    Register(u8),
+    // relative to the next instruction, the symbol is filled in by the linker
+    Rip { symbol: String, got: bool },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operand {
+    Register { number: u8, size: usize },
+    Immediate(i64),
+    Memory { base: Base, displacement: i64 },
+    // where a jump or a call goes
+    Target { symbol: String, plt: bool },
+}
+
+impl Operand {
+    // sil, dil, spl and bpl only exist with a REX prefix, without one the
+    // same numbers name the high bytes of the first four registers
+    fn needs_rex(&self) -> bool {
+        matches!(
+            self,
+            Operand::Register {
+                number: 4..=7,
+                size: 1
+            }
+        )
+    }
+}
+
+struct Relocation {
+    offset: usize,
+    symbol: String,
+    kind: u32,
+    addend: i64,
+}
+
+// a jump or a call, the displacement is known once every label is
+struct Fixup {
+    offset: usize,
+    symbol: String,
+    plt: bool,
+    call: bool,
+}
+
+// the number of a general purpose register and its size in bytes
+fn register(name: &str) -> Option<(u8, usize)> {
+    const QWORD: [&str; 8] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi"];
+    const DWORD: [&str; 8] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi"];
+    const WORD: [&str; 8] = ["ax", "cx", "dx", "bx", "sp", "bp", "si", "di"];
+    const BYTE: [&str; 8] = ["al", "cl", "dl", "bl", "spl", "bpl", "sil", "dil"];
+
+    for (names, size) in [(QWORD, 8), (DWORD, 4), (WORD, 2), (BYTE, 1)] {
+        if let Some(number) = names.iter().position(|n| *n == name) {
+            return Some((number as u8, size));
+        }
+    }
+
+    // r8 to r15, with a suffix for the narrower ones
+    let digits = name.strip_prefix('r')?;
+    let end = digits
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(digits.len());
+    let number = digits[..end]
+        .parse::<u8>()
+        .ok()
+        .filter(|n| (8..16).contains(n))?;
+    let size = match &digits[end..] {
+        "" => 8,
+        "d" => 4,
+        "w" => 2,
+        "b" => 1,
+        _ => return None,
+    };
+    Some((number, size))
+}
+
+fn immediate(value: &str) -> Option<i64> {
+    match value.strip_prefix('-') {
+        Some(magnitude) => magnitude.parse::<i64>().ok().map(|v| -v),
+        // literals are unsigned and may use all 64 bits
+        None => value.parse::<u64>().ok().map(|v| v as i64),
+    }
+}
+
+fn operand(text: &str) -> Result<Operand> {
+    let unknown = || Diagnostic::error(format!("Unknown operand {}", text));
+
+    if let Some(value) = text.strip_prefix('$') {
+        return immediate(value).map(Operand::Immediate).ok_or_else(unknown);
+    }
+    if let Some(name) = text.strip_prefix('%') {
+        let (number, size) = register(name).ok_or_else(unknown)?;
+        return Ok(Operand::Register { number, size });
+    }
+    let Some((displacement, base)) = text.split_once('(') else {
+        let (symbol, plt) = match text.strip_suffix("@PLT") {
+            Some(symbol) => (symbol, true),
+            None => (text, false),
+        };
+        return Ok(Operand::Target {
+            symbol: symbol.to_string(),
+            plt,
+        });
+    };
+
+    let base = base.strip_suffix(')').ok_or_else(unknown)?;
+    if base == "%rip" {
+        let (symbol, got) = match displacement.strip_suffix("@GOTPCREL") {
+            Some(symbol) => (symbol, true),
+            None => (displacement, false),
+        };
+        return Ok(Operand::Memory {
+            base: Base::Rip {
+                symbol: symbol.to_string(),
+                got,
+            },
+            displacement: 0,
+        });
+    }
+
+    let number = match base.strip_prefix('%').and_then(register) {
+        Some((number, 8)) => number,
+        _ => return Err(unknown()),
+    };
+    let displacement = match displacement {
+        "" => 0,
+        _ => immediate(displacement).ok_or_else(unknown)?,
+    };
+    Ok(Operand::Memory {
+        base: Base::Register(number),
+        displacement,
+    })
+}
+
+// the condition a jcc, setcc or cmovcc tests, as encoded in its opcode
+fn condition(name: &str) -> Option<u8> {
+    let code = match name {
+        "b" => 0x2,
+        "ae" => 0x3,
+        "e" => 0x4,
+        "ne" => 0x5,
+        "be" => 0x6,
+        "a" => 0x7,
+        "l" => 0xc,
+        "ge" => 0xd,
+        "le" => 0xe,
+        "g" => 0xf,
+        _ => return None,
+    };
+    Some(code)
+}
+
+// the operand size an instruction's suffix gives it
+fn sized(op: &str) -> Option<(&str, usize)> {
+    let (name, suffix) = op.split_at(op.len().checked_sub(1)?);
+    let size = match suffix {
+        "b" => 1,
+        "w" => 2,
+        "l" => 4,
+        "q" => 8,
+        _ => return None,
+    };
+    Some((name, size))
+}
+
+// the bytes of a string between double quotes, with C escapes
+fn string(text: &str) -> Result<Vec<u8>> {
+    let unknown = || Diagnostic::error(format!("Unknown string {}", text));
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(unknown)?;
+
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        let byte = match chars.next().ok_or_else(unknown)? {
+            'n' => b'\n',
+            't' => b'\t',
+            'r' => b'\r',
+            '0' => 0,
+            '\\' => b'\\',
+            '"' => b'"',
+            _ => return Err(unknown()),
+        };
+        bytes.push(byte);
+    }
+
+    Ok(bytes)
+}
+
+// Turns the assembly CodeGen writes for x86_64-linux straight into a
+// relocatable ELF object, so no assembler is needed to link it. It knows the
+// AT&T instructions and directives CodeGen uses and nothing more, and picks
+// the same encodings GNU as does where it can, jumps always take 32 bits.
+pub fn assemble(assembly: &str) -> Result<Vec<u8>> {
+    let mut assembler = Assembler::new();
+    for line in assembly.lines() {
+        assembler.line(line)?;
+    }
+    assembler.resolve();
+    Ok(assembler.object())
+}
+
+struct Assembler {
+    section: Section,
+    contents: [Vec<u8>; 4],
+    alignments: [usize; 4],
+    // the section and offset of every label, and the order they came in
+    labels: HashMap<String, (Section, usize)>,
+    order: Vec<String>,
+    globals: Vec<String>,
+    functions: Vec<String>,
+    fixups: Vec<Fixup>,
+    relocations: Vec<Relocation>,
+}
+
+impl Assembler {
+    fn new() -> Self {
+        Self {
+            section: Section::Text,
+            contents: Default::default(),
+            alignments: [1; 4],
+            labels: HashMap::new(),
+            order: Vec::new(),
+            globals: Vec::new(),
+            functions: Vec::new(),
+            fixups: Vec::new(),
+            relocations: Vec::new(),
+        }
+    }
+
+    fn here(&self) -> usize {
+        self.contents[self.section as usize].len()
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        self.contents[self.section as usize].extend_from_slice(bytes);
+    }
+
+    fn line(&mut self, line: &str) -> Result<()> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Ok(());
+        }
+
+        let (first, rest) = line
+            .split_once(char::is_whitespace)
+            .map(|(first, rest)| (first, rest.trim()))
+            .unwrap_or((line, ""));
+        if let Some(label) = first.strip_suffix(':') {
+            self.label(label)?;
+            return self.line(rest);
+        }
+
+        if first.starts_with('.') {
+            return self.directive(first, rest);
+        }
+
+        let operands = match rest {
+            "" => Vec::new(),
+            _ => rest.split(", ").map(operand).collect::<Result<Vec<_>>>()?,
+        };
+        self.instruction(first, &operands)
+            .ok_or_else(|| Diagnostic::error(format!("Cannot assemble {}", line)))
+    }
+
+    fn label(&mut self, name: &str) -> Result<()> {
+        if self.labels.contains_key(name) {
+            return Err(Diagnostic::error(format!("Label {} defined twice", name)));
+        }
+        self.labels
+            .insert(name.to_string(), (self.section, self.here()));
+        self.order.push(name.to_string());
+        Ok(())
+    }
+
+    fn directive(&mut self, name: &str, arguments: &str) -> Result<()> {
+        let unknown = || Diagnostic::error(format!("Unknown directive {} {}", name, arguments));
+        let number = || arguments.parse::<usize>().map_err(|_| unknown());
+
+        match name {
+            ".text" => self.section = Section::Text,
+            ".data" => self.section = Section::Data,
+            ".bss" => self.section = Section::Bss,
+            // sections of `#[section]` are left to a real assembler
+            ".section" => {
+                let section = arguments.split(',').next().unwrap_or(arguments);
+                // a `#[cold]` function stays with the rest of the code
+                if section == ".text.unlikely" {
+                    self.section = Section::Text;
+                    return Ok(());
+                }
+                self.section = SECTIONS
+                    .into_iter()
+                    .find(|known| known.name() == section)
+                    .ok_or_else(|| {
+                        Diagnostic::error(format!(
+                            "Cannot place code or data in {} without an assembler, use -S or --emit=exe",
+                            section
+                        ))
+                    })?;
+            }
+            ".global" | ".globl" => self.globals.push(arguments.to_string()),
+            ".type" => match arguments.split_once(", ") {
+                Some((symbol, "@function")) => self.functions.push(symbol.to_string()),
+                Some(_) => {}
+                None => return Err(unknown()),
+            },
+            ".balign" => {
+                let align = number()?;
+                if !align.is_power_of_two() {
+                    return Err(unknown());
+                }
+                let section = self.section as usize;
+                self.alignments[section] = self.alignments[section].max(align);
+                // code is padded with nops, anything else with zeros
+                let fill = if self.section == Section::Text {
+                    0x90
+                } else {
+                    0
+                };
+                while !self.here().is_multiple_of(align) {
+                    self.push(&[fill]);
+                }
+            }
+            ".zero" | ".space" => {
+                let size = number()?;
+                self.push(&vec![0; size]);
+            }
+            ".byte" | ".short" | ".long" | ".quad" => {
+                let size = match name {
+                    ".byte" => 1,
+                    ".short" => 2,
+                    ".long" => 4,
+                    _ => 8,
+                };
+                for value in arguments.split(',') {
+                    let value = immediate(value.trim()).ok_or_else(unknown)?;
+                    self.push(&value.to_le_bytes()[..size]);
+                }
+            }
+            ".asciz" => {
+                let mut bytes = string(arguments)?;
+                bytes.push(0);
+                self.push(&bytes);
+            }
+            _ => return Err(unknown()),
+        }
+
+        Ok(())
+    }
+
+    // An instruction with a ModRM byte, reg goes in its reg field and rm is
+    // a register or a memory operand. The operand size decides the prefixes,
+    // 16 bits take 0x66 and 64 bits REX.W.
+    fn encode(
+        &mut self,
+        size: usize,
+        opcode: &[u8],
+        reg: u8,
+        rm: &Operand,
+        immediate: &[u8],
+        rex: bool,
+    ) -> Option<()> {
+        let mut bytes = Vec::new();
+        if size == 2 {
+            bytes.push(0x66);
+        }
+
+        let b = match rm {
+            Operand::Register { number, .. } => number >> 3,
+            Operand::Memory {
+                base: Base::Register(number),
+                ..
+            } => number >> 3,
+            _ => 0,
+        };
+        let prefix = 0x40 | ((size == 8) as u8) << 3 | (reg >> 3) << 2 | b;
+        if prefix != 0x40 || rex {
+            bytes.push(prefix);
+        }
+        bytes.extend_from_slice(opcode);
+
+        // where the displacement the linker fills in is
+        let mut relocation = None;
+        match rm {
+            Operand::Register { number, .. } => bytes.push(0xc0 | (reg & 7) << 3 | (number & 7)),
+            Operand::Memory {
+                base: Base::Register(number),
+                displacement,
+            } => {
+                let low = number & 7;
+                let displacement = i32::try_from(*displacement).ok()?;
+                // %rbp and %r13 have no form without a displacement
+                let mode = match displacement {
+                    0 if low != 5 => 0,
+                    -128..=127 => 1,
+                    _ => 2,
+                };
+                bytes.push(mode << 6 | (reg & 7) << 3 | low);
+                // %rsp and %r12 are only reached through a SIB byte
+                if low == 4 {
+                    bytes.push(0x24);
+                }
+                match mode {
+                    1 => bytes.push(displacement as u8),
+                    2 => bytes.extend_from_slice(&displacement.to_le_bytes()),
+                    _ => {}
+                }
+            }
+            Operand::Memory {
+                base: Base::Rip { symbol, got },
+                ..
+            } => {
+                bytes.push((reg & 7) << 3 | 5);
+                relocation = Some((bytes.len(), symbol.clone(), *got));
+                bytes.extend_from_slice(&[0; 4]);
+            }
+            _ => return None,
+        }
+        bytes.extend_from_slice(immediate);
+
+        // the displacement counts from the end of the instruction
+        if let Some((at, symbol, got)) = relocation {
+            self.relocations.push(Relocation {
+                offset: self.here() + at,
+                symbol,
+                kind: if got {
+                    R_X86_64_REX_GOTPCRELX
+                } else {
+                    R_X86_64_PC32
+                },
+                addend: at as i64 - bytes.len() as i64,
+            });
+        }
+        self.push(&bytes);
+        Some(())
+    }
+
+    // an instruction with the register added to its opcode
+    fn encode_short(&mut self, wide: bool, opcode: u8, number: u8, immediate: &[u8]) {
+        let prefix = 0x40 | (wide as u8) << 3 | number >> 3;
+        if prefix != 0x40 {
+            self.push(&[prefix]);
+        }
+        self.push(&[opcode + (number & 7)]);
+        self.push(immediate);
+    }
+
+    fn branch(&mut self, opcode: &[u8], target: &Operand) -> Option<()> {
+        let Operand::Target { symbol, plt } = target else {
+            return None;
+        };
+        self.push(opcode);
+        self.fixups.push(Fixup {
+            offset: self.here(),
+            symbol: symbol.clone(),
+            plt: *plt,
+            call: opcode == [0xe8],
+        });
+        self.push(&[0; 4]);
+        Some(())
+    }
+
+    fn instruction(&mut self, op: &str, operands: &[Operand]) -> Option<()> {
+        let rex = operands.iter().any(Operand::needs_rex);
+
+        match (op, operands) {
+            ("ret", []) => self.push(&[0xc3]),
+            ("leave", []) => self.push(&[0xc9]),
+            ("nop", []) => self.push(&[0x90]),
+            ("cqo", []) => self.push(&[0x48, 0x99]),
+            ("call", [target]) => self.branch(&[0xe8], target)?,
+            ("jmp", [target]) => self.branch(&[0xe9], target)?,
+            _ if op.starts_with('j') => {
+                let code = condition(&op[1..])?;
+                self.branch(&[0x0f, 0x80 | code], operands.first()?)?;
+            }
+            (_, [rm, Operand::Register { number, size: 8 }]) if op.starts_with("cmov") => {
+                let code = condition(&op[4..])?;
+                self.encode(8, &[0x0f, 0x40 | code], *number, rm, &[], rex)?;
+            }
+            (_, [rm]) if op.starts_with("set") => {
+                let code = condition(&op[3..])?;
+                if matches!(rm, Operand::Register { size, .. } if *size != 1) {
+                    return None;
+                }
+                self.encode(1, &[0x0f, 0x90 | code], 0, rm, &[], rex)?;
+            }
+            _ => {
+                return self.extension(op, operands, rex).or_else(|| {
+                    let (name, size) = sized(op)?;
+                    self.sized(name, size, operands, rex)
+                })
+            }
+        }
+
+        Some(())
+    }
+
+    // the moves that zero or sign extend a narrower value
+    fn extension(&mut self, op: &str, operands: &[Operand], rex: bool) -> Option<()> {
+        let (from, to, opcode): (usize, usize, &[u8]) = match op {
+            "movzbq" => (1, 8, &[0x0f, 0xb6]),
+            "movzbl" => (1, 4, &[0x0f, 0xb6]),
+            "movzwq" => (2, 8, &[0x0f, 0xb7]),
+            "movzwl" => (2, 4, &[0x0f, 0xb7]),
+            "movsbq" => (1, 8, &[0x0f, 0xbe]),
+            "movsbl" => (1, 4, &[0x0f, 0xbe]),
+            "movswq" => (2, 8, &[0x0f, 0xbf]),
+            "movswl" => (2, 4, &[0x0f, 0xbf]),
+            "movslq" => (4, 8, &[0x63]),
+            _ => return None,
+        };
+
+        let [source, Operand::Register { number, size }] = operands else {
+            return None;
+        };
+        if *size != to || matches!(source, Operand::Register { size, .. } if *size != from) {
+            return None;
+        }
+        // the source size is in the opcode, the 0x66 prefix is not needed
+        let size = if to == 8 { 8 } else { 4 };
+        self.encode(size, opcode, *number, source, &[], rex)
+    }
+
+    fn sized(&mut self, name: &str, size: usize, operands: &[Operand], rex: bool) -> Option<()> {
+        // every register has the size of the instruction, but for the shift
+        // count in %cl
+        let shift = matches!(name, "sal" | "shl" | "sar" | "shr");
+        let fits = operands
+            .iter()
+            .enumerate()
+            .all(|(i, operand)| match operand {
+                Operand::Register { size: s, .. } => *s == size || (shift && i == 0),
+                _ => true,
+            });
+        if !fits {
+            return None;
+        }
+
+        // the byte forms are one below the others
+        let byte = (size == 1) as u8;
+        let immediate = |value: i64| -> Option<Vec<u8>> {
+            match size {
+                1 => i8::try_from(value)
+                    .ok()
+                    .or_else(|| u8::try_from(value).ok().map(|v| v as i8))
+                    .map(|v| vec![v as u8]),
+                2 => i16::try_from(value)
+                    .ok()
+                    .or_else(|| u16::try_from(value).ok().map(|v| v as i16))
+                    .map(|v| v.to_le_bytes().to_vec()),
+                4 => i32::try_from(value)
+                    .ok()
+                    .or_else(|| u32::try_from(value).ok().map(|v| v as i32))
+                    .map(|v| v.to_le_bytes().to_vec()),
+                _ => i32::try_from(value).ok().map(|v| v.to_le_bytes().to_vec()),
+            }
+        };
+
+        match (name, operands) {
+            ("mov", [Operand::Register { number, .. }, rm]) => {
+                self.encode(size, &[0x89 - byte], *number, rm, &[], rex)?
+            }
+            ("mov", [rm @ Operand::Memory { .. }, Operand::Register { number, .. }]) => {
+                self.encode(size, &[0x8b - byte], *number, rm, &[], rex)?
+            }
+            // a constant that needs all 64 bits is only loaded into a register
+            ("mov", [Operand::Immediate(value), Operand::Register { number, .. }])
+                if size == 8 && i32::try_from(*value).is_err() =>
+            {
+                self.encode_short(true, 0xb8, *number, &value.to_le_bytes())
+            }
+            ("mov", [Operand::Immediate(value), Operand::Register { number, .. }]) if size == 4 => {
+                self.encode_short(false, 0xb8, *number, &immediate(*value)?)
+            }
+            ("mov", [Operand::Immediate(value), rm]) => {
+                self.encode(size, &[0xc7 - byte], 0, rm, &immediate(*value)?, rex)?
+            }
+            ("add" | "or" | "and" | "sub" | "xor" | "cmp", _) => {
+                let extension = match name {
+                    "add" => 0,
+                    "or" => 1,
+                    "and" => 4,
+                    "sub" => 5,
+                    "xor" => 6,
+                    _ => 7,
+                };
+                match operands {
+                    [Operand::Register { number, .. }, rm] => {
+                        self.encode(size, &[extension << 3 | (1 - byte)], *number, rm, &[], rex)?
+                    }
+                    [rm @ Operand::Memory { .. }, Operand::Register { number, .. }] => {
+                        self.encode(size, &[extension << 3 | (3 - byte)], *number, rm, &[], rex)?
+                    }
+                    // small constants take a single byte
+                    [Operand::Immediate(value), rm] => match i8::try_from(*value) {
+                        Ok(value) if size > 1 => {
+                            self.encode(size, &[0x83], extension, rm, &[value as u8], rex)?
+                        }
+                        _ => {
+                            let value = immediate(*value)?;
+                            self.encode(size, &[0x81 - byte], extension, rm, &value, rex)?
+                        }
+                    },
+                    _ => return None,
+                }
+            }
+            ("test", [Operand::Register { number, .. }, rm]) => {
+                self.encode(size, &[0x85 - byte], *number, rm, &[], rex)?
+            }
+            ("imul", [source, Operand::Register { number, .. }]) if size > 1 => {
+                self.encode(size, &[0x0f, 0xaf], *number, source, &[], rex)?
+            }
+            ("not" | "neg" | "mul" | "div" | "idiv", [rm]) => {
+                let extension = match name {
+                    "not" => 2,
+                    "neg" => 3,
+                    "mul" => 4,
+                    "div" => 6,
+                    _ => 7,
+                };
+                self.encode(size, &[0xf7 - byte], extension, rm, &[], rex)?
+            }
+            ("sal" | "shl" | "shr" | "sar", [count, rm]) => {
+                let extension = match name {
+                    "shr" => 5,
+                    "sar" => 7,
+                    _ => 4,
+                };
+                match count {
+                    Operand::Immediate(1) => {
+                        self.encode(size, &[0xd1 - byte], extension, rm, &[], rex)?
+                    }
+                    Operand::Immediate(value) => {
+                        let value = u8::try_from(*value).ok()?;
+                        self.encode(size, &[0xc1 - byte], extension, rm, &[value], rex)?
+                    }
+                    Operand::Register { number: 1, size: 1 } => {
+                        self.encode(size, &[0xd3 - byte], extension, rm, &[], rex)?
+                    }
+                    _ => return None,
+                }
+            }
+            ("lea", [rm @ Operand::Memory { .. }, Operand::Register { number, .. }])
+                if size > 1 =>
+            {
+                self.encode(size, &[0x8d], *number, rm, &[], rex)?
+            }
+            // pushes and pops are 64 bits without REX.W
+            ("push", [Operand::Register { number, .. }]) if size == 8 => {
+                self.encode_short(false, 0x50, *number, &[])
+            }
+            ("push", [rm @ Operand::Memory { .. }]) if size == 8 => {
+                self.encode(4, &[0xff], 6, rm, &[], rex)?
+            }
+            ("pop", [Operand::Register { number, .. }]) if size == 8 => {
+                self.encode_short(false, 0x58, *number, &[])
+            }
+            ("pop", [rm @ Operand::Memory { .. }]) if size == 8 => {
+                self.encode(4, &[0x8f], 0, rm, &[], rex)?
+            }
+            _ => return None,
+        }
+
+        Some(())
+    }
+
+    // Jumps, calls and addresses within the code go straight to their label,
+    // anything else is left to the linker, as is a call to a global or one
+    // through the PLT, another object may take the place of it
+    fn resolve(&mut self) {
+        let text = &mut self.contents[Section::Text as usize];
+        let globals = &self.globals;
+        let labels = &self.labels;
+        self.relocations
+            .retain(|relocation| match labels.get(&relocation.symbol) {
+                Some((Section::Text, address))
+                    if relocation.kind == R_X86_64_PC32
+                        && !globals.contains(&relocation.symbol) =>
+                {
+                    let displacement =
+                        *address as i64 + relocation.addend - relocation.offset as i64;
+                    text[relocation.offset..relocation.offset + 4]
+                        .copy_from_slice(&(displacement as i32).to_le_bytes());
+                    false
+                }
+                _ => true,
+            });
+
+        for fixup in std::mem::take(&mut self.fixups) {
+            match self.labels.get(&fixup.symbol) {
+                Some((Section::Text, address))
+                    if !((fixup.plt || fixup.call) && globals.contains(&fixup.symbol)) =>
+                {
+                    let displacement = *address as i64 - (fixup.offset as i64 + 4);
+                    text[fixup.offset..fixup.offset + 4]
+                        .copy_from_slice(&(displacement as i32).to_le_bytes());
+                }
+                _ => self.relocations.push(Relocation {
+                    offset: fixup.offset,
+                    symbol: fixup.symbol,
+                    kind: R_X86_64_PLT32,
+                    addend: -4,
+                }),
+            }
+        }
+    }
+
+    // Lays out the object: the header, the contents of the sections, the
+    // relocations, the symbols and their names, then the section headers.
+    // Labels starting with .L stay out of the symbol table, as they do with
+    // GNU as, and relocations against local labels go through the symbol of
+    // their section.
+    fn object(&self) -> Vec<u8> {
+        // what is not defined here comes from elsewhere
+        let global = |name: &String| self.globals.contains(name) || !self.labels.contains_key(name);
+        let locals = self
+            .order
+            .iter()
+            .filter(|name| !name.starts_with(".L") && !global(name))
+            .collect::<Vec<_>>();
+        let mut globals = self.globals.iter().collect::<Vec<_>>();
+        for relocation in &self.relocations {
+            let symbol = &relocation.symbol;
+            if !self.labels.contains_key(symbol) && !globals.contains(&symbol) {
+                globals.push(symbol);
+            }
+        }
+
+        // the null symbol and one for every section come first
+        let first = 1 + SECTIONS.len();
+        let mut strtab = vec![0];
+        let mut symtab = vec![0; 24];
+        for section in SECTIONS {
+            symtab.extend(symbol(0, 3, section.index() as u16, 0));
+        }
+        for name in locals.iter().chain(&globals) {
+            let name_offset = strtab.len() as u32;
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+
+            // STB_GLOBAL for the globals, STT_FUNC for the functions
+            let binding = if global(name) { 1 << 4 } else { 0 };
+            let kind = if self.functions.contains(name) { 2 } else { 0 };
+            let (index, value) = match self.labels.get(*name) {
+                Some((section, value)) => (section.index() as u16, *value as u64),
+                None => (0, 0),
+            };
+            symtab.extend(symbol(name_offset, binding | kind, index, value));
+        }
+        let index_of = |name: &String| {
+            locals
+                .iter()
+                .chain(&globals)
+                .position(|n| *n == name)
+                .map(|i| first + i)
+        };
+
+        let mut rela = Vec::new();
+        for relocation in &self.relocations {
+            let (index, addend) = match self.labels.get(&relocation.symbol) {
+                Some((section, value)) if !global(&relocation.symbol) => {
+                    (section.index(), relocation.addend + *value as i64)
+                }
+                _ => (index_of(&relocation.symbol).unwrap(), relocation.addend),
+            };
+            rela.extend((relocation.offset as u64).to_le_bytes());
+            rela.extend(((index as u64) << 32 | relocation.kind as u64).to_le_bytes());
+            rela.extend(addend.to_le_bytes());
+        }
+
+        let names = [
+            "",
+            ".text",
+            ".data",
+            ".bss",
+            ".rodata",
+            ".rela.text",
+            ".symtab",
+            ".strtab",
+            ".shstrtab",
+            ".note.GNU-stack",
+        ];
+        let mut shstrtab = Vec::new();
+        let mut name_offsets = Vec::new();
+        for name in names {
+            name_offsets.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+
+        let mut object = vec![0; 64];
+        let place = |object: &mut Vec<u8>, bytes: &[u8], align: usize| {
+            while !object.len().is_multiple_of(align) {
+                object.push(0);
+            }
+            let offset = object.len() as u64;
+            object.extend_from_slice(bytes);
+            offset
+        };
+
+        // section header: name, type, flags, offset, size, link, info,
+        // alignment and entry size
+        let mut headers = vec![[0u64; 9]];
+        for section in SECTIONS {
+            let i = section as usize;
+            let (kind, flags) = match section {
+                Section::Text => (1, 0x6),
+                Section::Data => (1, 0x3),
+                Section::Bss => (8, 0x3),
+                Section::Rodata => (1, 0x2),
+            };
+            // .bss takes no room in the file
+            let offset = match section {
+                Section::Bss => object.len() as u64,
+                _ => place(&mut object, &self.contents[i], self.alignments[i]),
+            };
+            let size = self.contents[i].len() as u64;
+            let align = self.alignments[i] as u64;
+            headers.push([0, kind, flags, offset, size, 0, 0, align, 0]);
+        }
+        let offset = place(&mut object, &rela, 8);
+        headers.push([0, 4, 0x40, offset, rela.len() as u64, 6, 1, 8, 24]);
+        let offset = place(&mut object, &symtab, 8);
+        let info = (first + locals.len()) as u64;
+        headers.push([0, 2, 0, offset, symtab.len() as u64, 7, info, 8, 24]);
+        let offset = place(&mut object, &strtab, 1);
+        headers.push([0, 3, 0, offset, strtab.len() as u64, 0, 0, 1, 0]);
+        let offset = place(&mut object, &shstrtab, 1);
+        headers.push([0, 3, 0, offset, shstrtab.len() as u64, 0, 0, 1, 0]);
+        // the stack need not be executable
+        headers.push([0, 1, 0, object.len() as u64, 0, 0, 0, 1, 0]);
+
+        let section_headers = place(&mut object, &[], 8);
+        for (header, name) in headers.iter().zip(name_offsets) {
+            let [_, kind, flags, offset, size, link, info, align, entry_size] = *header;
+            object.extend(name.to_le_bytes());
+            object.extend((kind as u32).to_le_bytes());
+            object.extend(flags.to_le_bytes());
+            object.extend(0u64.to_le_bytes());
+            object.extend(offset.to_le_bytes());
+            object.extend(size.to_le_bytes());
+            object.extend((link as u32).to_le_bytes());
+            object.extend((info as u32).to_le_bytes());
+            object.extend(align.to_le_bytes());
+            object.extend(entry_size.to_le_bytes());
+        }
+
+        // the ELF header, of a relocatable x86-64 object
+        let mut header = Vec::new();
+        header.extend_from_slice(b"\x7fELF");
+        header.extend_from_slice(&[2, 1, 1, 0]);
+        header.extend_from_slice(&[0; 8]);
+        header.extend(1u16.to_le_bytes());
+        header.extend(62u16.to_le_bytes());
+        header.extend(1u32.to_le_bytes());
+        header.extend(0u64.to_le_bytes());
+        header.extend(0u64.to_le_bytes());
+        header.extend(section_headers.to_le_bytes());
+        header.extend(0u32.to_le_bytes());
+        header.extend(64u16.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(0u16.to_le_bytes());
+        header.extend(64u16.to_le_bytes());
+        header.extend((headers.len() as u16).to_le_bytes());
+        header.extend(8u16.to_le_bytes());
+        object[..64].copy_from_slice(&header);
+
+        object
+    }
+}
+
+// an entry of the symbol table
+fn symbol(name: u32, info: u8, section: u16, value: u64) -> Vec<u8> {
+    let mut entry = Vec::new();
+    entry.extend(name.to_le_bytes());
+    entry.push(info);
+    entry.push(0);
+    entry.extend(section.to_le_bytes());
+    entry.extend(value.to_le_bytes());
+    entry.extend(0u64.to_le_bytes());
+    entry
+}
@@ -0,0 +1,172 @@
+use crate::{
+    ast::{signed_operation, LiteralValue, Node, Span},
+    ir,
+    lexer::TokenType,
+    types::Type,
+    visit::{walk_node_mut, VisitorMut},
+};
+
+// Evaluates constant subexpressions at compile time. Values are computed the
+// way the generated code would compute them in a 64-bit register, so folding
+// never changes what a program prints.
+pub fn fold(mut nodes: Vec<Node>) -> Vec<Node> {
+    for node in &mut nodes {
+        Folder.visit_node_mut(node);
+    }
+
+    nodes
+}
+
+struct Folder;
+
+impl VisitorMut for Folder {
+    fn visit_node_mut(&mut self, node: &mut Node) {
+        // children first, so constants bubble up through the whole expression
+        walk_node_mut(self, node);
+
+        if let Some(folded) = folded(node) {
+            *node = folded;
+        }
+    }
+}
+
+fn empty(span: Span) -> Node {
+    Node::CompoundStmt {
+        statements: Vec::new(),
+        span,
+    }
+}
+
+// what a node whose children are already folded can be replaced with
+fn folded(node: &mut Node) -> Option<Node> {
+    let span = node.span();
+    match node {
+        Node::BinaryExpr {
+            left,
+            operator,
+            right,
+            ty,
+            ..
+        } => {
+            let signed = signed_operation(operator.token_type, left, right);
+            let value = binary(
+                operator.token_type,
+                constant(left)?,
+                constant(right)?,
+                signed,
+            )?;
+            Some(literal(value, ty.clone(), span))
+        }
+        Node::UnaryExpr {
+            operator,
+            right,
+            ty,
+            ..
+        } => {
+            let value = constant(right)?;
+            let value = match operator.token_type {
+                TokenType::Sub => value.wrapping_neg(),
+                TokenType::Invert => !value,
+                TokenType::LogicalNot => (value == 0) as u64,
+                _ => return None,
+            };
+            Some(literal(value, ty.clone(), span))
+        }
+        Node::WidenExpr { right, ty, .. } => {
+            let value = right
+                .ty()?
+                .widening(ty)
+                .iter()
+                .fold(constant(right)?, ir::cast);
+            Some(literal(value, ty.clone(), span))
+        }
+        Node::ScaleExpr {
+            right, size, ty, ..
+        } => Some(literal(
+            constant(right)?.wrapping_mul(*size as u64),
+            ty.clone(),
+            span,
+        )),
+        Node::ToBool { expr, .. } => Some(literal((constant(expr)? != 0) as u64, Type::U8, span)),
+        // only the branch that is taken is kept
+        Node::IfStmt {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => match constant(condition)? {
+            0 => Some(
+                else_branch
+                    .take()
+                    .map_or_else(|| empty(span), |branch| *branch),
+            ),
+            _ => Some(std::mem::replace(then_branch.as_mut(), empty(span))),
+        },
+        Node::WhileStmt { condition, .. } => match constant(condition)? {
+            0 => Some(empty(span)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn constant(node: &Node) -> Option<u64> {
+    match node {
+        Node::LiteralExpr { value, .. } => match value {
+            LiteralValue::U8(value) => Some(*value as u64),
+            LiteralValue::U16(value) => Some(*value as u64),
+            LiteralValue::U32(value) => Some(*value as u64),
+            LiteralValue::U64(value) => Some(*value),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// the node keeps the type the parser gave the expression, the value may be wider
+pub fn literal(value: u64, ty: Type, span: Span) -> Node {
+    let value = if value <= u8::MAX as u64 {
+        LiteralValue::U8(value as u8)
+    } else if value <= u16::MAX as u64 {
+        LiteralValue::U16(value as u16)
+    } else if value <= u32::MAX as u64 {
+        LiteralValue::U32(value as u32)
+    } else {
+        LiteralValue::U64(value)
+    };
+
+    Node::LiteralExpr { value, ty, span }
+}
+
+// comparisons, division and right shifts depend on the signedness of the operands
+pub fn binary(operator: TokenType, a: u64, b: u64, signed: bool) -> Option<u64> {
+    let (signed_a, signed_b) = (a as i64, b as i64);
+
+    let value = match operator {
+        TokenType::Add => a.wrapping_add(b),
+        TokenType::Sub => a.wrapping_sub(b),
+        TokenType::Mul => a.wrapping_mul(b),
+        // leave the division by zero to fault at runtime
+        TokenType::Div if signed => signed_a.checked_div(signed_b)? as u64,
+        TokenType::Div => a.checked_div(b)?,
+        TokenType::Equal => (a == b) as u64,
+        TokenType::NotEqual => (a != b) as u64,
+        TokenType::LessThan if signed => (signed_a < signed_b) as u64,
+        TokenType::LessThan => (a < b) as u64,
+        TokenType::LessThanOrEqual if signed => (signed_a <= signed_b) as u64,
+        TokenType::LessThanOrEqual => (a <= b) as u64,
+        TokenType::GreaterThan if signed => (signed_a > signed_b) as u64,
+        TokenType::GreaterThan => (a > b) as u64,
+        TokenType::GreaterThanOrEqual if signed => (signed_a >= signed_b) as u64,
+        TokenType::GreaterThanOrEqual => (a >= b) as u64,
+        TokenType::Ampersand => a & b,
+        TokenType::Or => a | b,
+        TokenType::Xor => a ^ b,
+        TokenType::LeftShift => a << (b & 63),
+        TokenType::RightShift if signed => (signed_a >> (b & 63)) as u64,
+        TokenType::RightShift => a >> (b & 63),
+        _ => return None,
+    };
+
+    Some(value)
+}
@@ -0,0 +1,250 @@
+use crate::{
+    intern::Name,
+    lexer::{Token, TokenType},
+};
+
+// Prints a program back out in the canonical layout: one statement per line,
+// two spaces of indentation per block, binary operators spaced and unary
+// ones not, and a blank line between functions. It works on the tokens
+// rather than the syntax tree, which has lost the comments and the `for`
+// loops by then, so the source has to parse before it gets here.
+pub fn format(tokens: &[Token]) -> String {
+    let mut formatter = Formatter {
+        out: String::new(),
+        indent: 0,
+        parens: 0,
+        line_start: true,
+        item_end: false,
+        previous: None,
+        previous_line: 0,
+        prefix: false,
+        attribute: false,
+        lambda: false,
+    };
+    for (i, token) in tokens.iter().enumerate() {
+        formatter.token(token, &tokens[i + 1..]);
+    }
+
+    let mut out = formatter.out.trim_end().to_string();
+    out.push('\n');
+    out
+}
+
+struct Formatter<'a> {
+    out: String,
+    indent: usize,
+    // the semicolons of a `for` header don't end a line
+    parens: usize,
+    line_start: bool,
+    // a function just ended, the next item gets a blank line before it
+    item_end: bool,
+    previous: Option<&'a Token>,
+    // the line of the source the previous token ended on
+    previous_line: usize,
+    // the previous token was a prefix operator, which sticks to its operand
+    prefix: bool,
+    // inside `#[...]`, which goes on a line of its own
+    attribute: bool,
+    // between the `|`s around the parameters of a lambda, which stick to them
+    lambda: bool,
+}
+
+// whether the token ends an operand, so an operator after it is binary
+fn ends_operand(token: &Token) -> bool {
+    matches!(
+        token.token_type,
+        TokenType::Identifier
+            | TokenType::Integer
+            | TokenType::String
+            | TokenType::RightParen
+            | TokenType::RightBracket
+    )
+}
+
+// whether a top level item starting with the token is a function, which may
+// have attributes in front of it
+fn starts_function(token: &Token, rest: &[Token]) -> bool {
+    match token.token_type {
+        TokenType::Fn | TokenType::Pub => true,
+        TokenType::Attribute => rest
+            .iter()
+            .map(|token| token.token_type)
+            .scan(true, |inside, token_type| {
+                let item = !*inside && token_type != TokenType::Attribute;
+                *inside = token_type != TokenType::RightBracket;
+                Some((item, token_type))
+            })
+            .find(|(item, _)| *item)
+            .is_some_and(|(_, token_type)| matches!(token_type, TokenType::Fn | TokenType::Pub)),
+        _ => false,
+    }
+}
+
+fn last_line(token: &Token) -> usize {
+    let lexeme = token.lexeme.map(Name::text).unwrap_or_default();
+    token.line + lexeme.matches('\n').count()
+}
+
+impl<'a> Formatter<'a> {
+    fn newline(&mut self) {
+        self.out.push('\n');
+        self.line_start = true;
+    }
+
+    // starts the line a token goes on, keeping a blank line from the source
+    fn start_line(&mut self, token: &Token, rest: &[Token]) {
+        let after_brace = self
+            .previous
+            .is_some_and(|previous| previous.token_type == TokenType::LeftBrace);
+        let after_attribute = self
+            .previous
+            .is_some_and(|previous| previous.token_type == TokenType::RightBracket);
+        let blank = (token.line > self.previous_line + 1
+            && token.token_type != TokenType::RightBrace)
+            || self.item_end
+            || (self.indent == 0 && !after_attribute && starts_function(token, rest));
+        if blank && !self.out.is_empty() && !after_brace && !self.out.ends_with("\n\n") {
+            self.out.push('\n');
+        }
+        self.out.push_str(&"  ".repeat(self.indent));
+        self.line_start = false;
+        self.item_end = false;
+    }
+
+    fn space_before(&self, token: &Token) -> bool {
+        let Some(previous) = self.previous else {
+            return false;
+        };
+        if self.prefix {
+            return false;
+        }
+        if previous.token_type == TokenType::Attribute {
+            return false;
+        }
+        match token.token_type {
+            TokenType::RightParen
+            | TokenType::RightBracket
+            | TokenType::LeftBracket
+            | TokenType::Comma
+            | TokenType::SemiColon
+            | TokenType::Colon
+            | TokenType::DotDot => false,
+            TokenType::Or if self.lambda => false,
+            // a call, a function declaration or a closure type, but `if (`
+            // and `while (`
+            TokenType::LeftParen => {
+                !matches!(previous.token_type, TokenType::Identifier | TokenType::Fn)
+            }
+            TokenType::Inc | TokenType::Dec => !ends_operand(previous),
+            _ => !matches!(
+                previous.token_type,
+                TokenType::LeftParen | TokenType::LeftBracket | TokenType::DotDot
+            ),
+        }
+    }
+
+    fn is_prefix(&self, token: &Token) -> bool {
+        let operand_before = self.previous.is_some_and(ends_operand);
+        match token.token_type {
+            TokenType::LogicalNot | TokenType::Invert => true,
+            TokenType::Or => self.lambda,
+            TokenType::Sub
+            | TokenType::Mul
+            | TokenType::Ampersand
+            | TokenType::Inc
+            | TokenType::Dec => !operand_before,
+            _ => false,
+        }
+    }
+
+    fn token(&mut self, token: &'a Token, rest: &[Token]) {
+        let next = rest.first();
+        let lexeme = token.lexeme.map(Name::text).unwrap_or_default();
+        match token.token_type {
+            TokenType::Eof => return,
+            // a directive has its line to itself
+            TokenType::Comment | TokenType::Directive => {
+                // a comment after code stays on its line, even where the
+                // code ended the line already
+                let comment = token.token_type == TokenType::Comment;
+                if comment && self.previous.is_some() && token.line == self.previous_line {
+                    if self.line_start {
+                        self.out.pop();
+                    }
+                    self.out.push(' ');
+                } else {
+                    if !self.line_start {
+                        self.newline();
+                    }
+                    self.start_line(token, rest);
+                }
+                // a directive isn't indented, whatever block it is in
+                if !comment {
+                    let indented = self.out.trim_end_matches(' ').len();
+                    self.out.truncate(indented);
+                }
+                self.out.push_str(lexeme.trim_end());
+                self.newline();
+                self.previous_line = token.line;
+                return;
+            }
+            TokenType::LeftBrace => {
+                if self.line_start {
+                    self.start_line(token, rest);
+                } else {
+                    self.out.push(' ');
+                }
+                self.out.push('{');
+                self.indent += 1;
+                self.newline();
+            }
+            TokenType::RightBrace => {
+                self.indent = self.indent.saturating_sub(1);
+                let empty = self
+                    .previous
+                    .is_some_and(|previous| previous.token_type == TokenType::LeftBrace);
+                if empty && self.out.ends_with("{\n") {
+                    self.out.pop();
+                } else {
+                    if !self.line_start {
+                        self.newline();
+                    }
+                    self.start_line(token, rest);
+                }
+                self.out.push('}');
+                self.line_start = false;
+                // `} else {` stays on one line
+                if !next.is_some_and(|next| next.token_type == TokenType::Else) {
+                    self.newline();
+                    self.item_end = self.indent == 0;
+                }
+            }
+            _ => {
+                if self.line_start {
+                    self.start_line(token, rest);
+                } else if self.space_before(token) {
+                    self.out.push(' ');
+                }
+                self.out.push_str(&token.text());
+
+                match token.token_type {
+                    TokenType::LeftParen => self.parens += 1,
+                    TokenType::RightParen => self.parens = self.parens.saturating_sub(1),
+                    TokenType::SemiColon if self.parens == 0 => self.newline(),
+                    TokenType::Or if self.lambda => self.lambda = false,
+                    TokenType::Or if !self.previous.is_some_and(ends_operand) => self.lambda = true,
+                    TokenType::Attribute => self.attribute = true,
+                    TokenType::RightBracket if self.attribute => {
+                        self.attribute = false;
+                        self.newline();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        self.prefix = self.is_prefix(token);
+        self.previous = Some(token);
+        self.previous_line = last_line(token);
+    }
+}
@@ -0,0 +1,426 @@
+use std::{cell::RefCell, ops::Range, rc::Rc};
+
+use crate::{
+    ast::Node,
+    diagnostic::Diagnostic,
+    intern::Name,
+    lexer::{Lexer, Token, TokenType},
+    parser::{Parser, StorageClass, Symbol},
+    preprocess::{preprocess, Includes},
+};
+
+// A change an editor makes: the bytes in the range of the source replaced
+// with the text
+#[derive(Debug, Clone)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+// What the second pass made of an item, kept for as long as its tokens stay
+// as they were
+struct Parsed {
+    nodes: Vec<Node>,
+    references: Vec<(Token, Rc<RefCell<Symbol>>)>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// A global, a constant or a function, with what follows it up to the next
+// one. A new item starts after every `;` or `}` outside of braces, at its
+// first token, where the lexer can start again.
+struct Item {
+    start: usize,
+    line: usize,
+    column: usize,
+    tokens: Vec<Token>,
+    // what lexing it failed with, such an item goes on to the end of the
+    // source
+    errors: Vec<Diagnostic>,
+    parsed: Option<Parsed>,
+}
+
+// A source kept lexed and parsed between the edits an editor makes to it.
+// An edit lexes again only the items it touches, and parsing afterwards
+// parses again only the functions among them. The first pass still goes over
+// every global, constant and signature, skipping the bodies, and when one of
+// those changes every function is parsed again too.
+pub struct Document {
+    source: String,
+    items: Vec<Item>,
+    // the line and column the source ends on, where Eof is
+    end: (usize, usize),
+    // how many string literals have been labelled, an item lexed again
+    // labels its own after them
+    labels: usize,
+    // what the first pass parsed when the functions kept were parsed
+    outline: Vec<(TokenType, Option<Name>)>,
+    references: Vec<(Token, Rc<RefCell<Symbol>>)>,
+    // where `#include` looks for files
+    includes: Includes,
+}
+
+impl Document {
+    pub fn new(source: String) -> Self {
+        let mut document = Document {
+            source,
+            items: Vec::new(),
+            end: (1, 1),
+            labels: 0,
+            outline: Vec::new(),
+            references: Vec::new(),
+            includes: Includes::default(),
+        };
+        let (items, end) = document.lex(0, (1, 1), document.source.len());
+        document.items = items;
+        document.end = end;
+        document
+    }
+
+    // look for the files of `#include` as the compiler would
+    pub fn with_includes(mut self, includes: Includes) -> Self {
+        self.includes = includes;
+        self
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    // Lexes the items the edit touches again, from the start of the first up
+    // to where the one after the last starts, and moves the items after them
+    // along. A range that isn't within the source, or doesn't start and end
+    // between two chars, leaves the document as it was.
+    pub fn edit(&mut self, edit: Edit) -> Result<(), Diagnostic> {
+        let Edit { range, text } = edit;
+        let length = self.source.len();
+        if range.start > range.end || range.end > length {
+            return Err(Diagnostic::error(format!(
+                "Edit of bytes {}..{} is outside the {} of the source",
+                range.start, range.end, length
+            )));
+        }
+        if !self.source.is_char_boundary(range.start) || !self.source.is_char_boundary(range.end) {
+            return Err(Diagnostic::error(format!(
+                "Edit of bytes {}..{} splits a character of the source",
+                range.start, range.end
+            )));
+        }
+        self.source.replace_range(range.clone(), &text);
+        let moved = |offset: usize| offset + text.len() - range.len();
+
+        // text put in front of an item can join its first token
+        let first = self.item_at(range.start);
+        let mut last = self.item_at(range.end);
+        let (start, position) = {
+            let item = &self.items[first];
+            (item.start, (item.line, item.column))
+        };
+        loop {
+            let (end, old_end) = match self.items.get(last + 1) {
+                Some(next) => (next.start, (next.line, next.column)),
+                None => (length, self.end),
+            };
+            let (items, new_end) = self.lex(start, position, moved(end));
+            // the items lexed have to end where the next one still starts,
+            // otherwise everything up to the end of the source is lexed again
+            if last + 1 < self.items.len() && !ends_cleanly(&self.source[start..moved(end)], &items)
+            {
+                last = self.items.len() - 1;
+                continue;
+            }
+
+            let after = self.items.split_off(last + 1);
+            self.items.truncate(first);
+            self.items.extend(items);
+            for mut item in after {
+                item.start = moved(item.start);
+                let mut changed = shift(&mut item.line, &mut item.column, old_end, new_end);
+                for token in &mut item.tokens {
+                    changed |= shift(&mut token.line, &mut token.column, old_end, new_end);
+                }
+                for error in &mut item.errors {
+                    shift(&mut error.line, &mut error.column, old_end, new_end);
+                }
+                if changed {
+                    item.parsed = None;
+                }
+                self.items.push(item);
+            }
+            shift(&mut self.end.0, &mut self.end.1, old_end, new_end);
+            return Ok(());
+        }
+    }
+
+    // Every token of the source, as lexing all of it gives them but for the
+    // labels of the strings, or the errors lexing it failed with
+    pub fn tokens(&self) -> Result<Vec<Token>, Vec<Diagnostic>> {
+        let errors: Vec<Diagnostic> = self
+            .items
+            .iter()
+            .flat_map(|item| item.errors.iter().cloned())
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        let mut tokens: Vec<Token> = self
+            .items
+            .iter()
+            .flat_map(|item| item.tokens.iter().cloned())
+            .collect();
+        tokens.push(Token {
+            token_type: TokenType::Eof,
+            lexeme: None,
+            line: self.end.0,
+            column: self.end.1,
+            value: None,
+            trivia: None,
+        });
+        Ok(tokens)
+    }
+
+    // The syntax tree of the source, as `Parser::parse` gives it, with the
+    // functions no edit touched since the last time kept as they were
+    pub fn parse(&mut self) -> Result<Vec<Node>, Vec<Diagnostic>> {
+        self.references.clear();
+        let tokens = self.tokens()?;
+        // a macro can change any item after it
+        if tokens
+            .iter()
+            .any(|token| token.token_type == TokenType::Directive)
+        {
+            return self.parse_all();
+        }
+        let outline = outline(&tokens);
+        if outline != self.outline {
+            self.outline = outline;
+            for item in &mut self.items {
+                item.parsed = None;
+            }
+        }
+
+        let mut parser = Parser::new(tokens);
+        parser.declare();
+        let mut nodes = parser.nodes().to_vec();
+        let mut diagnostics = parser.diagnostics().to_vec();
+        self.references = parser.references().to_vec();
+        let mut first = 0;
+        for item in &mut self.items {
+            let tokens = first..first + item.tokens.len();
+            first = tokens.end;
+            if item.parsed.is_none() {
+                let (n, r, d) = (
+                    parser.nodes().len(),
+                    parser.references().len(),
+                    parser.diagnostics().len(),
+                );
+                // recovering from an error took the parser into the items
+                // after this one, which only parsing them all together gets
+                // right
+                if !parser.define(tokens) {
+                    return self.parse_all();
+                }
+                item.parsed = Some(Parsed {
+                    nodes: parser.nodes()[n..].to_vec(),
+                    references: parser.references()[r..].to_vec(),
+                    diagnostics: parser.diagnostics()[d..].to_vec(),
+                });
+            }
+
+            let parsed = item.parsed.as_ref().unwrap();
+            nodes.extend(parsed.nodes.iter().cloned());
+            // a function kept refers to the globals as they were declared
+            // then, which are the same but for where they are
+            for (token, symbol) in &parsed.references {
+                let global = symbol.borrow().class == StorageClass::Global;
+                let name = symbol.borrow().name;
+                // an overloaded function is the one of them named the same
+                let link_name = symbol.borrow().link_name();
+                let declared = if global {
+                    parser
+                        .symbols()
+                        .overloads(name)
+                        .into_iter()
+                        .find(|overload| overload.borrow().link_name() == link_name)
+                        .or_else(|| parser.symbols().find(name))
+                } else {
+                    None
+                };
+                self.references
+                    .push((token.clone(), declared.unwrap_or_else(|| symbol.clone())));
+            }
+            for diagnostic in &parsed.diagnostics {
+                if !diagnostics.contains(diagnostic) {
+                    diagnostics.push(diagnostic.clone());
+                }
+            }
+        }
+
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        Ok(nodes)
+    }
+
+    // which symbol each identifier stands for, as far as the last parse got
+    pub fn references(&self) -> &[(Token, Rc<RefCell<Symbol>>)] {
+        &self.references
+    }
+
+    fn parse_all(&mut self) -> Result<Vec<Node>, Vec<Diagnostic>> {
+        for item in &mut self.items {
+            item.parsed = None;
+        }
+        let mut parser = Parser::new(preprocess(self.tokens()?, &self.includes, &[])?);
+        let nodes = parser.parse().cloned();
+        self.references = parser.references().to_vec();
+        nodes
+    }
+
+    // the item the byte is in, or the one that ends with it
+    fn item_at(&self, offset: usize) -> usize {
+        self.items.partition_point(|item| item.start <= offset) - 1
+    }
+
+    // Lexes the source from the start of an item up to the end, from where
+    // the item is, into the items found there, and where the end is
+    fn lex(
+        &mut self,
+        start: usize,
+        position: (usize, usize),
+        end: usize,
+    ) -> (Vec<Item>, (usize, usize)) {
+        let text = &self.source[start..end];
+        let mut items = vec![Item {
+            start,
+            line: position.0,
+            column: position.1,
+            tokens: Vec::new(),
+            errors: Vec::new(),
+            parsed: None,
+        }];
+        let mut lexer =
+            Lexer::new(text.to_string()).with_position(position.0, position.1, self.labels);
+        let mut tokens = match lexer.scan_tokens() {
+            Ok(tokens) => tokens.clone(),
+            Err(errors) => {
+                items[0].errors = errors;
+                let mut end = position;
+                for c in text.chars() {
+                    end = step(end, c);
+                }
+                return (items, end);
+            }
+        };
+        let eof = tokens.pop().unwrap();
+        self.labels += tokens
+            .iter()
+            .filter(|token| token.token_type == TokenType::String)
+            .count();
+
+        let mut depth = 0usize;
+        let mut chars = text.char_indices().peekable();
+        let mut at = position;
+        for token in tokens {
+            let closed = items.last().unwrap().tokens.last().is_some_and(|last| {
+                depth == 0
+                    && matches!(
+                        last.token_type,
+                        TokenType::SemiColon | TokenType::RightBrace
+                    )
+            });
+            if closed {
+                // the byte the token starts at, the lexer only counts lines
+                // and columns
+                let mut offset = text.len();
+                while let Some(&(i, c)) = chars.peek() {
+                    if at == (token.line, token.column) {
+                        offset = i;
+                        break;
+                    }
+                    at = step(at, c);
+                    chars.next();
+                }
+                items.push(Item {
+                    start: start + offset,
+                    line: token.line,
+                    column: token.column,
+                    tokens: Vec::new(),
+                    errors: Vec::new(),
+                    parsed: None,
+                });
+            }
+            match token.token_type {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            items.last_mut().unwrap().tokens.push(token);
+        }
+        (items, (eof.line, eof.column))
+    }
+}
+
+// where the lexer is after the character
+fn step((line, column): (usize, usize), c: char) -> (usize, usize) {
+    match c {
+        '\n' => (line + 1, 1),
+        _ => (line, column + 1),
+    }
+}
+
+// Moves a position after the end of what was lexed again along with that
+// end, by as many lines, and on the line it ended on by as many columns too.
+// Whether it moved.
+fn shift(line: &mut usize, column: &mut usize, old: (usize, usize), new: (usize, usize)) -> bool {
+    let before = (*line, *column);
+    if *line == old.0 {
+        *column = *column - old.1 + new.1;
+    }
+    *line = *line - old.0 + new.0;
+    (*line, *column) != before
+}
+
+// Whether the items lexed again end where the one after them can still
+// start: after a `;` or a `}` outside of braces, with no error and no
+// comment running on into it
+fn ends_cleanly(text: &str, items: &[Item]) -> bool {
+    let tokens: Vec<&Token> = items.iter().flat_map(|item| &item.tokens).collect();
+    let depth = tokens
+        .iter()
+        .fold(0usize, |depth, token| match token.token_type {
+            TokenType::LeftBrace => depth + 1,
+            TokenType::RightBrace => depth.saturating_sub(1),
+            _ => depth,
+        });
+    let closed = tokens.last().is_none_or(|last| {
+        depth == 0
+            && matches!(
+                last.token_type,
+                TokenType::SemiColon | TokenType::RightBrace
+            )
+    });
+    let last_line = text.rsplit('\n').next().unwrap_or_default();
+    closed && items.iter().all(|item| item.errors.is_empty()) && !last_line.contains("//")
+}
+
+// The tokens outside of the bodies of the functions, what the first pass
+// parses, by what they are rather than where they are
+fn outline(tokens: &[Token]) -> Vec<(TokenType, Option<Name>)> {
+    let mut depth = 0usize;
+    tokens
+        .iter()
+        .filter(|token| match token.token_type {
+            TokenType::LeftBrace => {
+                depth += 1;
+                false
+            }
+            TokenType::RightBrace => {
+                depth = depth.saturating_sub(1);
+                false
+            }
+            _ => depth == 0,
+        })
+        .map(|token| (token.token_type, token.lexeme))
+        .collect()
+}
@@ -0,0 +1,56 @@
+use std::{cell::RefCell, collections::HashMap, fmt, rc::Rc};
+
+// An identifier interned, two of them spell the same name exactly when they
+// are the same number
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Name(u32);
+
+// the names interned so far, by number and by spelling
+#[derive(Default)]
+struct Interner {
+    names: Vec<Rc<str>>,
+    numbers: HashMap<Rc<str>, Name>,
+}
+
+// One for the whole process rather than one per compilation, since a Name is
+// only a number and the symbols and trees a Document keeps from one edit to
+// the next have to go on spelling what they did. It keeps the text of every
+// token it has seen, so the language server grows with the different
+// spellings typed into it, not with how many times it compiles them.
+thread_local! {
+    static INTERNER: RefCell<Interner> = RefCell::default();
+}
+
+// the name spelled so, interned the first time it is seen
+pub fn intern(text: &str) -> Name {
+    INTERNER.with(|interner| {
+        let mut interner = interner.borrow_mut();
+        if let Some(&name) = interner.numbers.get(text) {
+            return name;
+        }
+
+        let name = Name(interner.names.len() as u32);
+        let text: Rc<str> = Rc::from(text);
+        interner.names.push(text.clone());
+        interner.numbers.insert(text, name);
+        name
+    })
+}
+
+impl Name {
+    pub fn text(self) -> Rc<str> {
+        INTERNER.with(|interner| interner.borrow().names[self.0 as usize].clone())
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.text())
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text())
+    }
+}
@@ -0,0 +1,601 @@
+use std::fmt;
+
+use crate::types::Type;
+
+// A virtual register, there is an unlimited supply of them. Each one is
+// written by exactly one instruction and CodeGen maps it to a real register.
+pub type VReg = usize;
+pub type Label = usize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    UDiv,
+    And,
+    Or,
+    Xor,
+    Shl,
+    // arithmetic, the sign bit is shifted in
+    Shr,
+    // logical, zeros are shifted in
+    UShr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    LogicalNot,
+    ToBool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    // the unsigned orderings
+    Ult,
+    Ule,
+    Ugt,
+    Uge,
+}
+
+impl Cond {
+    pub fn negate(&self) -> Self {
+        match self {
+            Cond::Eq => Cond::Ne,
+            Cond::Ne => Cond::Eq,
+            Cond::Lt => Cond::Ge,
+            Cond::Le => Cond::Gt,
+            Cond::Gt => Cond::Le,
+            Cond::Ge => Cond::Lt,
+            Cond::Ult => Cond::Uge,
+            Cond::Ule => Cond::Ugt,
+            Cond::Ugt => Cond::Ule,
+            Cond::Uge => Cond::Ult,
+        }
+    }
+
+    // the same ordering of unsigned values
+    pub fn unsigned(&self) -> Self {
+        match self {
+            Cond::Lt => Cond::Ult,
+            Cond::Le => Cond::Ule,
+            Cond::Gt => Cond::Ugt,
+            Cond::Ge => Cond::Uge,
+            cond => *cond,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    // a global variable or string, by its label
+    Global(String),
+    // a local or parameter, by its offset from the frame pointer
+    Local(isize),
+    // whatever the register points at
+    Indirect(VReg),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inst {
+    Const {
+        dst: VReg,
+        value: u64,
+    },
+    Copy {
+        dst: VReg,
+        src: VReg,
+    },
+    // dst = src truncated to ty and extended back, what a store and a load
+    // through a variable of that type would leave
+    Cast {
+        dst: VReg,
+        src: VReg,
+        ty: Type,
+    },
+    Binary {
+        op: BinOp,
+        dst: VReg,
+        left: VReg,
+        right: VReg,
+    },
+    Unary {
+        op: UnOp,
+        dst: VReg,
+        src: VReg,
+    },
+    // dst = left <cond> right ? 1 : 0
+    Compare {
+        cond: Cond,
+        dst: VReg,
+        left: VReg,
+        right: VReg,
+    },
+    // dst = left <cond> right ? then : otherwise, without a branch
+    Select {
+        cond: Cond,
+        dst: VReg,
+        left: VReg,
+        right: VReg,
+        then: VReg,
+        otherwise: VReg,
+    },
+    Load {
+        dst: VReg,
+        addr: Address,
+        ty: Type,
+    },
+    Store {
+        src: VReg,
+        addr: Address,
+        ty: Type,
+    },
+    // dst = the address itself
+    Lea {
+        dst: VReg,
+        addr: Address,
+    },
+    Call {
+        dst: VReg,
+        name: String,
+        args: Vec<VReg>,
+    },
+    // hand the value back to the caller and leave through the epilogue
+    Return {
+        src: VReg,
+        ty: Type,
+    },
+    Jump {
+        target: Label,
+    },
+    // jump to target if left <cond> right, fall through otherwise
+    Branch {
+        cond: Cond,
+        left: VReg,
+        right: VReg,
+        target: Label,
+    },
+    Label(Label),
+    // only exists while a function is in SSA form, dst is the value coming
+    // in from whichever predecessor block control arrived from
+    Phi {
+        dst: VReg,
+        args: Vec<(Label, VReg)>,
+    },
+}
+
+impl Inst {
+    // the registers this instruction reads
+    pub fn uses(&self) -> Vec<VReg> {
+        let mut uses = match self {
+            Inst::Const { .. } | Inst::Jump { .. } | Inst::Label(_) => vec![],
+            Inst::Copy { src, .. }
+            | Inst::Cast { src, .. }
+            | Inst::Unary { src, .. }
+            | Inst::Return { src, .. } => vec![*src],
+            Inst::Binary { left, right, .. }
+            | Inst::Compare { left, right, .. }
+            | Inst::Branch { left, right, .. } => vec![*left, *right],
+            Inst::Select {
+                left,
+                right,
+                then,
+                otherwise,
+                ..
+            } => vec![*left, *right, *then, *otherwise],
+            Inst::Load { .. } | Inst::Lea { .. } => vec![],
+            Inst::Store { src, .. } => vec![*src],
+            Inst::Call { args, .. } => args.clone(),
+            Inst::Phi { args, .. } => args.iter().map(|(_, arg)| *arg).collect(),
+        };
+
+        match self {
+            Inst::Load { addr, .. } | Inst::Store { addr, .. } | Inst::Lea { addr, .. } => {
+                if let Address::Indirect(register) = addr {
+                    uses.push(*register);
+                }
+            }
+            _ => {}
+        }
+
+        uses
+    }
+
+    // the register this instruction writes
+    pub fn def(&self) -> Option<VReg> {
+        match self {
+            Inst::Const { dst, .. }
+            | Inst::Copy { dst, .. }
+            | Inst::Cast { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Unary { dst, .. }
+            | Inst::Compare { dst, .. }
+            | Inst::Select { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Lea { dst, .. }
+            | Inst::Call { dst, .. }
+            | Inst::Phi { dst, .. } => Some(*dst),
+            _ => None,
+        }
+    }
+
+    // rewrites every register this instruction reads
+    pub fn map_uses(&mut self, mut f: impl FnMut(VReg) -> VReg) {
+        match self {
+            Inst::Const { .. } | Inst::Jump { .. } | Inst::Label(_) => {}
+            Inst::Copy { src, .. }
+            | Inst::Cast { src, .. }
+            | Inst::Unary { src, .. }
+            | Inst::Return { src, .. }
+            | Inst::Store { src, .. } => *src = f(*src),
+            Inst::Binary { left, right, .. }
+            | Inst::Compare { left, right, .. }
+            | Inst::Branch { left, right, .. } => {
+                *left = f(*left);
+                *right = f(*right);
+            }
+            Inst::Select {
+                left,
+                right,
+                then,
+                otherwise,
+                ..
+            } => {
+                *left = f(*left);
+                *right = f(*right);
+                *then = f(*then);
+                *otherwise = f(*otherwise);
+            }
+            Inst::Load { .. } | Inst::Lea { .. } => {}
+            Inst::Call { args, .. } => {
+                for arg in args {
+                    *arg = f(*arg);
+                }
+            }
+            Inst::Phi { args, .. } => {
+                for (_, arg) in args {
+                    *arg = f(*arg);
+                }
+            }
+        }
+
+        match self {
+            Inst::Load { addr, .. } | Inst::Store { addr, .. } | Inst::Lea { addr, .. } => {
+                if let Address::Indirect(register) = addr {
+                    *register = f(*register);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // ends a basic block, control does not simply fall through to the next instruction
+    pub fn is_terminator(&self) -> bool {
+        matches!(
+            self,
+            Inst::Jump { .. } | Inst::Branch { .. } | Inst::Return { .. }
+        )
+    }
+}
+
+// Constant evaluation mirrors the machine: 64-bit wrapping arithmetic, shift
+// counts masked to six bits.
+impl BinOp {
+    pub fn evaluate(&self, a: u64, b: u64) -> Option<u64> {
+        let (signed_a, signed_b) = (a as i64, b as i64);
+
+        let value = match self {
+            BinOp::Add => a.wrapping_add(b),
+            BinOp::Sub => a.wrapping_sub(b),
+            BinOp::Mul => a.wrapping_mul(b),
+            // leave the division by zero to fault at runtime
+            BinOp::Div => signed_a.checked_div(signed_b)? as u64,
+            BinOp::UDiv => a.checked_div(b)?,
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Xor => a ^ b,
+            BinOp::Shl => a << (b & 63),
+            BinOp::Shr => (signed_a >> (b & 63)) as u64,
+            BinOp::UShr => a >> (b & 63),
+        };
+
+        Some(value)
+    }
+}
+
+impl UnOp {
+    pub fn evaluate(&self, value: u64) -> u64 {
+        match self {
+            UnOp::Neg => value.wrapping_neg(),
+            UnOp::Not => !value,
+            UnOp::LogicalNot => (value == 0) as u64,
+            UnOp::ToBool => (value != 0) as u64,
+        }
+    }
+}
+
+impl Cond {
+    pub fn evaluate(&self, a: u64, b: u64) -> bool {
+        let (signed_a, signed_b) = (a as i64, b as i64);
+        match self {
+            Cond::Eq => a == b,
+            Cond::Ne => a != b,
+            Cond::Lt => signed_a < signed_b,
+            Cond::Le => signed_a <= signed_b,
+            Cond::Gt => signed_a > signed_b,
+            Cond::Ge => signed_a >= signed_b,
+            Cond::Ult => a < b,
+            Cond::Ule => a <= b,
+            Cond::Ugt => a > b,
+            Cond::Uge => a >= b,
+        }
+    }
+}
+
+pub fn cast(value: u64, ty: &Type) -> u64 {
+    match ty {
+        Type::U8 | Type::Char => value as u8 as u64,
+        Type::I8 => value as i8 as u64,
+        Type::U16 => value as u16 as u64,
+        Type::I16 => value as i16 as u64,
+        Type::U32 => value as u32 as u64,
+        Type::I32 => value as i32 as u64,
+        _ => value,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Param {
+    pub offset: isize,
+    pub ty: Type,
+}
+
+// Where a function or global goes in the object file, set with the
+// `#[section(".boot")]` and `#[align(4096)]` attributes. Without them it goes
+// in the usual section on the boundary its type needs.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Placement {
+    pub section: Option<String>,
+    pub align: Option<usize>,
+    // `#[cold]`, a function that rarely runs, which goes apart from the
+    // others where the target has a section for that
+    pub cold: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub params: Vec<Param>,
+    pub stack_size: usize,
+    pub insts: Vec<Inst>,
+    pub placement: Placement,
+    // `#[inline]`, a hint that calls to it are better off inlined
+    pub inline: bool,
+    // declared `pub`, which a shared library exports
+    pub public: bool,
+}
+
+// Whether the function is `fn main(argc, argv)`, an integer and a `**u8`,
+// which is called with the command line. A main taking anything else is
+// given zeros, or whatever the C runtime leaves in the registers.
+pub fn takes_command_line(name: &str, params: &[Param]) -> bool {
+    let argv = Type::U8.pointer_to().and_then(|ty| ty.pointer_to());
+    match params {
+        [argc, argv_param] => {
+            name == "main" && argc.ty.is_int() && Some(&argv_param.ty) == argv.as_ref()
+        }
+        _ => false,
+    }
+}
+
+impl Function {
+    // the highest label in use, new ones are numbered from here
+    pub fn last_label(&self) -> Label {
+        self.insts
+            .iter()
+            .filter_map(|inst| match inst {
+                Inst::Label(label) => Some(*label),
+                _ => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Global {
+    pub name: String,
+    pub ty: Type,
+    pub placement: Placement,
+    // the value it starts with, cut down to its type, or zero
+    pub init: Option<u64>,
+    // defined by something the program is linked with, not by the program
+    pub external: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StringLiteral {
+    pub label: String,
+    pub value: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Module {
+    pub globals: Vec<Global>,
+    pub strings: Vec<StringLiteral>,
+    pub functions: Vec<Function>,
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::UDiv => "udiv",
+            BinOp::And => "and",
+            BinOp::Or => "or",
+            BinOp::Xor => "xor",
+            BinOp::Shl => "shl",
+            BinOp::Shr => "shr",
+            BinOp::UShr => "ushr",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for UnOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            UnOp::Neg => "neg",
+            UnOp::Not => "not",
+            UnOp::LogicalNot => "lnot",
+            UnOp::ToBool => "bool",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Cond::Eq => "eq",
+            Cond::Ne => "ne",
+            Cond::Lt => "lt",
+            Cond::Le => "le",
+            Cond::Gt => "gt",
+            Cond::Ge => "ge",
+            Cond::Ult => "ult",
+            Cond::Ule => "ule",
+            Cond::Ugt => "ugt",
+            Cond::Uge => "uge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Global(name) => write!(f, "@{}", name),
+            Address::Local(offset) => write!(f, "[fp{:+}]", offset),
+            Address::Indirect(register) => write!(f, "[v{}]", register),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inst::Const { dst, value } => write!(f, "  v{} = const {}", dst, value),
+            Inst::Copy { dst, src } => write!(f, "  v{} = v{}", dst, src),
+            Inst::Cast { dst, src, ty } => write!(f, "  v{} = cast.{} v{}", dst, ty, src),
+            Inst::Binary {
+                op,
+                dst,
+                left,
+                right,
+            } => write!(f, "  v{} = {} v{}, v{}", dst, op, left, right),
+            Inst::Unary { op, dst, src } => write!(f, "  v{} = {} v{}", dst, op, src),
+            Inst::Compare {
+                cond,
+                dst,
+                left,
+                right,
+            } => write!(f, "  v{} = cmp.{} v{}, v{}", dst, cond, left, right),
+            Inst::Select {
+                cond,
+                dst,
+                left,
+                right,
+                then,
+                otherwise,
+            } => write!(
+                f,
+                "  v{} = select.{} v{}, v{} ? v{} : v{}",
+                dst, cond, left, right, then, otherwise
+            ),
+            Inst::Load { dst, addr, ty } => write!(f, "  v{} = load.{} {}", dst, ty, addr),
+            Inst::Store { src, addr, ty } => write!(f, "  store.{} {}, v{}", ty, addr, src),
+            Inst::Lea { dst, addr } => write!(f, "  v{} = lea {}", dst, addr),
+            Inst::Call { dst, name, args } => {
+                let args = args
+                    .iter()
+                    .map(|arg| format!("v{}", arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "  v{} = call {}({})", dst, name, args)
+            }
+            Inst::Return { src, ty } => write!(f, "  ret.{} v{}", ty, src),
+            Inst::Jump { target } => write!(f, "  jump L{}", target),
+            Inst::Branch {
+                cond,
+                left,
+                right,
+                target,
+            } => write!(f, "  branch.{} v{}, v{} -> L{}", cond, left, right, target),
+            Inst::Label(label) => write!(f, "L{}:", label),
+            Inst::Phi { dst, args } => {
+                let args = args
+                    .iter()
+                    .map(|(label, arg)| format!("[L{}: v{}]", label, arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "  v{} = phi {}", dst, args)
+            }
+        }
+    }
+}
+
+impl Module {
+    // whether the global is an extern one, which the backends reach through
+    // the GOT as they do the C library's stderr, since it may well be in a
+    // shared library
+    pub fn is_external(&self, name: &str) -> bool {
+        self.globals
+            .iter()
+            .any(|global| global.external && global.name == name)
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for global in &self.globals {
+            match global.init {
+                Some(init) => writeln!(f, "global @{}: {} = {}", global.name, global.ty, init)?,
+                None if global.external => {
+                    writeln!(f, "extern global @{}: {}", global.name, global.ty)?
+                }
+                None => writeln!(f, "global @{}: {}", global.name, global.ty)?,
+            }
+        }
+        for string in &self.strings {
+            writeln!(f, "string @{} = {:?}", string.label, string.value)?;
+        }
+
+        for function in &self.functions {
+            let params = function
+                .params
+                .iter()
+                .map(|param| format!("[fp{:+}]: {}", param.offset, param.ty))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(f, "\nfn {}({}):", function.name, params)?;
+            for inst in &function.insts {
+                writeln!(f, "{}", inst)?;
+            }
+        }
+
+        Ok(())
+    }
+}
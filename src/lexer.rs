@@ -1,21 +1,69 @@
 use std::collections::HashMap;
 
-use crate::utils::RandomGenerator;
+use crate::{
+    diagnostic::{Diagnostic, Result},
+    intern::{intern, Name},
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Literal {
     Integer(u64),
-    Identifier(String),
+    Identifier(Name),
     String { value: String, label: String },
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Token {
     pub token_type: TokenType,
-    pub lexeme: Option<String>,
+    // the text of the token, interned
+    pub lexeme: Option<Name>,
     pub line: usize,
     pub column: usize,
     pub value: Option<Literal>,
+    // the whitespace and comments in front of the token, as they are in the
+    // source, when lexing keeps them
+    pub trivia: Option<String>,
+}
+
+impl Token {
+    // The token as it has to be written in the source, which for an
+    // identifier spelled like a keyword is its raw form, `r#for`
+    pub fn text(&self) -> String {
+        let lexeme = self.lexeme.map(Name::text).unwrap_or_default();
+        if self.token_type == TokenType::Identifier && is_keyword(&lexeme) {
+            return format!("r#{}", lexeme);
+        }
+        lexeme.to_string()
+    }
+
+    // The comment lines right in front of the token, without their `//`,
+    // as long as no blank line comes between them and it
+    pub fn doc_comment(&self) -> Option<String> {
+        let trivia = self.trivia.as_deref()?;
+        let mut lines: Vec<&str> = trivia.lines().map(str::trim).collect();
+        // what is left of the line the token is on
+        if !trivia.ends_with('\n') {
+            lines.pop();
+        }
+        let doc: Vec<&str> = lines
+            .iter()
+            .rev()
+            .map_while(|line| line.strip_prefix("//"))
+            .map(str::trim)
+            .collect();
+        if doc.is_empty() {
+            return None;
+        }
+        Some(doc.into_iter().rev().collect::<Vec<_>>().join("\n"))
+    }
+
+    // the name an identifier spells
+    pub fn name(&self) -> Option<Name> {
+        match &self.value {
+            Some(Literal::Identifier(name)) => Some(*name),
+            _ => self.lexeme,
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
@@ -24,12 +72,21 @@ pub enum TokenType {
     Integer,
     String,
 
+    // `'outer`, which names a loop
+    Label,
+
     // Keywords
+    Break,
+    Const,
+    Continue,
     Else,
+    Extern,
     Fn,
     For,
     If,
+    In,
     Let,
+    Pub,
     Return,
     U8,
     U16,
@@ -76,78 +133,141 @@ pub enum TokenType {
     RightShift,
     Inc,
     Dec,
+    // `..`, between the bounds of a range
+    DotDot,
+    // `#[`, which opens an attribute
+    Attribute,
+    // a whole `#define ...` line, for the preprocessor
+    Directive,
+
+    // only produced for the formatter, the parser never sees them
+    Comment,
 
     Eof,
 }
 
+const KEYWORDS: &[(&str, TokenType)] = &[
+    ("break", TokenType::Break),
+    ("const", TokenType::Const),
+    ("continue", TokenType::Continue),
+    ("else", TokenType::Else),
+    ("extern", TokenType::Extern),
+    ("fn", TokenType::Fn),
+    ("for", TokenType::For),
+    ("if", TokenType::If),
+    ("in", TokenType::In),
+    ("let", TokenType::Let),
+    ("pub", TokenType::Pub),
+    ("return", TokenType::Return),
+    ("u8", TokenType::U8),
+    ("u16", TokenType::U16),
+    ("u32", TokenType::U32),
+    ("u64", TokenType::U64),
+    ("i8", TokenType::I8),
+    ("i16", TokenType::I16),
+    ("i32", TokenType::I32),
+    ("i64", TokenType::I64),
+    ("while", TokenType::While),
+    ("char", TokenType::Char),
+];
+
+pub fn is_keyword(text: &str) -> bool {
+    KEYWORDS.iter().any(|(keyword, _)| *keyword == text)
+}
+
 pub struct Lexer {
     tokens: Vec<Token>,
+    // the token the last call to scan_token found, if it found one
+    scanned: Option<Token>,
+    // whether Eof has been handed out
+    ended: bool,
     source: String,
+    // byte offsets into the source, the columns count chars
     start: usize,
+    start_line: usize,
+    start_column: usize,
     current: usize,
     line: usize,
     column: usize,
     keywords: HashMap<String, TokenType>,
-    string_labels: Vec<String>,
-    rng: RandomGenerator,
+    // how many string literals have been labelled so far
+    string_labels: usize,
+    comments: bool,
+    // what has been skipped since the last token, when trivia is kept
+    trivia: Option<String>,
 }
 
 impl Lexer {
     pub fn new(source: String) -> Self {
         Self {
             tokens: Vec::new(),
+            scanned: None,
+            ended: false,
             source,
             start: 0,
+            start_line: 1,
+            start_column: 1,
             current: 0,
             line: 1,
             column: 1,
-            keywords: {
-                let mut keywords = HashMap::new();
-                keywords.insert(String::from("else"), TokenType::Else);
-                keywords.insert(String::from("fn"), TokenType::Fn);
-                keywords.insert(String::from("for"), TokenType::For);
-                keywords.insert(String::from("if"), TokenType::If);
-                keywords.insert(String::from("let"), TokenType::Let);
-                keywords.insert(String::from("return"), TokenType::Return);
-                keywords.insert(String::from("u8"), TokenType::U8);
-                keywords.insert(String::from("u16"), TokenType::U16);
-                keywords.insert(String::from("u32"), TokenType::U32);
-                keywords.insert(String::from("u64"), TokenType::U64);
-                keywords.insert(String::from("i8"), TokenType::I8);
-                keywords.insert(String::from("i16"), TokenType::I16);
-                keywords.insert(String::from("i32"), TokenType::I32);
-                keywords.insert(String::from("i64"), TokenType::I64);
-                keywords.insert(String::from("while"), TokenType::While);
-                keywords.insert(String::from("char"), TokenType::Char);
-                keywords
-            },
-            string_labels: Vec::new(),
-            rng: RandomGenerator::new(),
+            keywords: KEYWORDS
+                .iter()
+                .map(|(keyword, token_type)| (keyword.to_string(), *token_type))
+                .collect(),
+            string_labels: 0,
+            comments: false,
+            trivia: None,
         }
     }
 
-    pub fn scan_tokens(&mut self) -> &Vec<Token> {
-        while !self.is_at_end() {
-            self.start = self.current;
-            self.scan_token();
+    // keep comments as tokens instead of skipping them
+    pub fn with_comments(mut self) -> Self {
+        self.comments = true;
+        self
+    }
+
+    // Keep the whitespace and comments in front of every token as its
+    // trivia, with what follows the last one on Eof, so the trivia and the
+    // text of the tokens give back the source exactly
+    pub fn with_trivia(mut self) -> Self {
+        self.trivia = Some(String::new());
+        self
+    }
+
+    // Lexes a part of a larger source, from where it is in the whole, with
+    // the string literals labelled on from the labels already taken
+    pub fn with_position(mut self, line: usize, column: usize, labels: usize) -> Self {
+        self.line = line;
+        self.column = column;
+        self.string_labels = labels;
+        self
+    }
+
+    // Lexes the whole source at once. The parser can take the lexer as an
+    // iterator instead, which lexes each token only when it is asked for.
+    pub fn scan_tokens(&mut self) -> std::result::Result<&Vec<Token>, Vec<Diagnostic>> {
+        let mut tokens = Vec::new();
+        let mut diagnostics = Vec::new();
+        for token in self.by_ref() {
+            match token {
+                Ok(token) => tokens.push(token),
+                Err(diagnostic) => diagnostics.push(diagnostic),
+            }
         }
 
-        self.tokens.push(Token {
-            token_type: TokenType::Eof,
-            lexeme: None,
-            line: self.line,
-            column: self.column,
-            value: None,
-        });
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
 
-        &self.tokens
+        self.tokens = tokens;
+        Ok(&self.tokens)
     }
 
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
 
-    fn scan_token(&mut self) {
+    fn scan_token(&mut self) -> Result<()> {
         let c = self.advance();
 
         match c {
@@ -171,6 +291,9 @@ impl Lexer {
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
                     }
+                    if self.comments {
+                        self.add_token(TokenType::Comment);
+                    }
                 } else {
                     self.add_token(TokenType::Div);
                 }
@@ -230,6 +353,14 @@ impl Lexer {
                     self.add_token(TokenType::Or);
                 }
             }
+            '#' if self.match_char('[') => self.add_token(TokenType::Attribute),
+            '#' if starts_identifier(self.peek()) => {
+                while self.peek() != '\n' && !self.is_at_end() {
+                    self.advance();
+                }
+                self.add_token(TokenType::Directive);
+            }
+            '.' if self.match_char('.') => self.add_token(TokenType::DotDot),
             '^' => self.add_token(TokenType::Xor),
             '~' => self.add_token(TokenType::Invert),
             ' ' | '\t' | '\r' => {}
@@ -237,17 +368,30 @@ impl Lexer {
                 self.line += 1;
                 self.column = 1;
             }
-            '\'' => self.character(),
-            '"' => self.string(),
-            c if c.is_ascii_digit() => self.number(),
-            c if c.is_alphabetic() || c == '_' => self.identifier(),
-            _ => panic!("Unexpected character: {}", c),
+            // a name after the quote with no quote closing it is a label
+            '\'' if starts_identifier(self.peek()) && self.peek_next() != '\'' => self.label(),
+            '\'' => self.character()?,
+            '"' => self.string()?,
+            c if c.is_ascii_digit() => self.number()?,
+            'r' if self.peek() == '#' && starts_identifier(self.peek_next()) => {
+                self.raw_identifier()
+            }
+            c if starts_identifier(c) => self.identifier(),
+            _ => return Err(self.error(format!("Unexpected character: {}", c))),
         }
+
+        Ok(())
+    }
+
+    // point at the whole token scanned so far
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        let length = self.source[self.start..self.current].chars().count();
+        Diagnostic::new(message, self.start_line, self.start_column).with_length(length)
     }
 
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.peek();
+        self.current += c.len_utf8();
         self.column += 1;
         c
     }
@@ -258,32 +402,41 @@ impl Lexer {
 
     fn add_token_literal(&mut self, token_type: TokenType, none: Option<Literal>) {
         let text = &self.source[self.start..self.current];
-        self.tokens.push(Token {
+        self.scanned = Some(Token {
             token_type,
-            lexeme: Some(text.to_string()),
-            line: self.line,
-            column: self.column,
+            lexeme: Some(intern(text)),
+            line: self.start_line,
+            column: self.start_column,
             value: none,
+            trivia: None,
         });
     }
 
-    fn number(&mut self) {
+    fn number(&mut self) -> Result<()> {
         while self.peek().is_ascii_digit() {
             self.advance();
         }
 
         let text = &self.source[self.start..self.current];
-        let value = text.parse::<u64>().unwrap();
+        let value = match text.parse::<u64>() {
+            Ok(value) => value,
+            Err(_) => {
+                return Err(self
+                    .error("Integer literal is too large")
+                    .with_label(format!("does not fit in u64 (max {})", u64::MAX)))
+            }
+        };
 
         self.add_token_literal(TokenType::Integer, Some(Literal::Integer(value)));
+        Ok(())
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
+        self.source[self.current..].chars().next().unwrap_or('\0')
+    }
 
-        self.source.chars().nth(self.current).unwrap()
+    fn peek_next(&self) -> char {
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn identifier(&mut self) {
@@ -295,28 +448,40 @@ impl Lexer {
         let token_type = self.keywords.get(text).unwrap_or(&TokenType::Identifier);
 
         if *token_type == TokenType::Identifier {
-            self.add_token_literal(*token_type, Some(Literal::Identifier(text.to_string())));
+            self.add_token_literal(*token_type, Some(Literal::Identifier(intern(text))));
         } else {
             self.add_token(*token_type);
         }
     }
 
-    fn match_char(&mut self, arg: char) -> bool {
-        if self.is_at_end() {
-            return false;
+    // `r#` and a name, which is an identifier even when the name is a
+    // keyword, with the `r#` left out of its lexeme
+    fn raw_identifier(&mut self) {
+        self.advance();
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
+            self.advance();
         }
 
-        if self.source.chars().nth(self.current).unwrap() != arg {
+        self.start += 2;
+        let text = &self.source[self.start..self.current];
+        self.add_token_literal(
+            TokenType::Identifier,
+            Some(Literal::Identifier(intern(text))),
+        );
+    }
+
+    fn match_char(&mut self, arg: char) -> bool {
+        if self.is_at_end() || self.peek() != arg {
             return false;
         }
 
-        self.current += 1;
+        self.current += arg.len_utf8();
         self.column += 1;
         true
     }
 
-    fn escape_char(&self, c: char) -> char {
-        match c {
+    fn escape_char(&self, c: char) -> Result<char> {
+        let c = match c {
             'a' => '\x07',
             'b' => '\x08',
             'f' => '\x0c',
@@ -327,46 +492,65 @@ impl Lexer {
             '\'' => '\'',
             '\\' => '\\',
             '"' => '"',
-            _ => panic!("Unexpected escape character: {}", c),
+            _ => return Err(self.error(format!("Unexpected escape character: {}", c))),
+        };
+
+        Ok(c)
+    }
+
+    fn label(&mut self) {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
+            self.advance();
         }
+        self.add_token(TokenType::Label);
     }
 
-    fn character(&mut self) {
+    fn character(&mut self) -> Result<()> {
+        if self.is_at_end() {
+            return Err(self.error("Unterminated character literal"));
+        }
+
         let mut c = self.advance();
-        if c == '\\' {
+        if c == '\\' && !self.is_at_end() {
             c = self.advance();
-            c = self.escape_char(c);
+            c = self.escape_char(c)?;
         }
 
-        if self.advance() != '\'' {
-            panic!("Expected closing quote");
+        if self.peek() != '\'' {
+            return Err(self.error("Expected closing quote"));
         }
+        self.advance();
 
         self.add_token_literal(TokenType::Integer, Some(Literal::Integer(c as u64)));
+        Ok(())
     }
 
-    fn string(&mut self) {
+    fn string(&mut self) -> Result<()> {
         let mut str = String::new();
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
+                str.push(self.advance());
                 self.line += 1;
                 self.column = 1;
             } else if self.peek() == '\\' {
                 self.advance();
+                if self.is_at_end() {
+                    break;
+                }
                 let c = self.advance();
-                str.push(self.escape_char(c));
+                str.push(self.escape_char(c)?);
             } else {
                 str.push(self.advance());
             }
         }
 
         if self.is_at_end() {
-            panic!("Unterminated string");
+            return Err(self.error("Unterminated string"));
         }
 
         self.advance();
 
-        let label = self.label_for_str(str.clone());
+        let label = self.label_for_str();
         self.add_token_literal(
             TokenType::String,
             Some(Literal::String {
@@ -374,23 +558,57 @@ impl Lexer {
                 label: label.clone(),
             }),
         );
+        Ok(())
     }
 
-    fn label_for_str(&mut self, s: String) -> String {
-        let mut label = String::from("str_");
-        for (i, c) in s.char_indices() {
-            if i > 4 {
-                if self.string_labels.contains(&label) {
-                    // add random hex digit to label
-                    label.push_str(&format!("{:x}", self.rng.random() % 16));
-                    continue;
-                } else {
-                    break;
-                }
+    // Every literal gets a label of its own, CodeGen emits identical ones
+    // once. A block of a function is labelled `name_n`, and a name can't
+    // have a dot, so the two never meet.
+    fn label_for_str(&mut self) -> String {
+        self.string_labels += 1;
+        format!("str.{}", self.string_labels - 1)
+    }
+}
+
+fn starts_identifier(c: char) -> bool {
+    c.is_alphabetic() || c == '_'
+}
+
+// Tokens are lexed one at a time as they are asked for, with Eof last. An
+// error comes in their place and lexing goes on after it.
+impl Iterator for Lexer {
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Result<Token>> {
+        while !self.is_at_end() {
+            self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column;
+            let scanned = self.scan_token();
+            if let Some(mut token) = self.scanned.take() {
+                token.trivia = self.trivia.as_mut().map(std::mem::take);
+                return Some(Ok(token));
+            }
+            // nothing but whitespace or a comment, or an error
+            if let Some(trivia) = &mut self.trivia {
+                trivia.push_str(&self.source[self.start..self.current]);
             }
-            label.push_str(&format!("{:x}", c as u32));
+            if let Err(diagnostic) = scanned {
+                return Some(Err(diagnostic));
+            }
+        }
+
+        if self.ended {
+            return None;
         }
-        self.string_labels.push(label.clone());
-        label
+        self.ended = true;
+        Some(Ok(Token {
+            token_type: TokenType::Eof,
+            lexeme: None,
+            line: self.line,
+            column: self.column,
+            value: None,
+            trivia: self.trivia.take(),
+        }))
     }
 }
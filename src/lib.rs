@@ -0,0 +1,52 @@
+// The Crust compiler as a library. `Compiler` is what other programs use to
+// compile source into assembly, an object file or an executable, and the
+// modules below are the stages it goes through, for the command line and
+// the tools built on them.
+
+mod aarch64;
+pub mod asm;
+pub mod ast;
+pub mod attributes;
+mod cfg;
+mod checker;
+pub mod checks;
+pub mod codegen;
+mod compiler;
+mod consteval;
+pub mod diagnostic;
+pub mod dump;
+mod elf;
+mod fold;
+pub mod format;
+pub mod incremental;
+pub mod intern;
+pub mod ir;
+mod json;
+pub mod lexer;
+pub mod link;
+pub mod lint;
+mod llvm;
+mod lower;
+pub mod lsp;
+pub mod manifest;
+pub mod parser;
+pub mod passes;
+mod peephole;
+pub mod preprocess;
+pub mod printer;
+mod regalloc;
+pub mod render;
+mod riscv64;
+mod runtime;
+pub mod semantic;
+pub mod sources;
+pub mod ssa;
+mod stack;
+pub mod symtable;
+pub mod target;
+pub mod timing;
+pub mod types;
+mod visit;
+mod wasm32;
+
+pub use compiler::{Artifacts, Compiler, Diagnostics, Options};
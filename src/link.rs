@@ -0,0 +1,136 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::diagnostic::{Diagnostic, Result};
+use crate::target::{Arch, Os, Target};
+use crate::timing;
+
+// The C compiler that assembles and links for a target, `$CC` when it is set
+fn driver(target: Target) -> Result<String> {
+    if let Ok(cc) = std::env::var("CC") {
+        return Ok(cc);
+    }
+    let driver = target.linker().ok_or_else(|| {
+        Diagnostic::error("cannot link WebAssembly, write the text format and use wat2wasm")
+    })?;
+    Ok(driver.to_string())
+}
+
+// A file in the temporary directory that is removed when it goes out of
+// scope, so the assembly is cleaned up whether linking worked or not
+struct TempFile(PathBuf);
+
+// numbers the files, so compilations running at once in a program that
+// embeds the compiler don't write over each other's
+static FILES: AtomicUsize = AtomicUsize::new(0);
+
+impl TempFile {
+    fn new(extension: &str) -> Self {
+        let number = FILES.fetch_add(1, Ordering::Relaxed);
+        let name = format!("crust-{}-{}.{}", std::process::id(), number, extension);
+        TempFile(std::env::temp_dir().join(name))
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+impl TempFile {
+    fn write(extension: &str, contents: &str) -> Result<Self> {
+        let file = TempFile::new(extension);
+        std::fs::write(&file.0, contents)
+            .map_err(|e| Diagnostic::error(format!("cannot write {}: {}", file.0.display(), e)))?;
+        Ok(file)
+    }
+}
+
+// Assembles and links the generated assembly and the runtime into an
+// executable, or a shared library when `shared` is set. `flags` are the `-L`
+// and `-l` options of the command line, they come after the assembly so
+// libraries can resolve the functions it calls.
+pub fn link(
+    assembly: &str,
+    runtime: &str,
+    target: Target,
+    pic: bool,
+    shared: bool,
+    output: &Path,
+    flags: &[String],
+) -> Result<()> {
+    let driver = driver(target)?;
+    let source = TempFile::write("s", assembly)?;
+    let runtime = TempFile::write("s", runtime)?;
+
+    let mut command = Command::new(&driver);
+    command
+        .arg("-o")
+        .arg(output)
+        .arg(&source.0)
+        .arg(&runtime.0)
+        .args(flags);
+    if target.os == Os::Linux {
+        command.args(["-z", "noexecstack"]);
+    }
+    if shared {
+        command.arg(match target.os {
+            Os::Macos => "-dynamiclib",
+            _ => "-shared",
+        });
+    }
+    // absolute addresses of the x86-64 code only link into a fixed executable
+    if target.arch == Arch::X86_64 && target.os == Os::Linux && !pic {
+        command.arg("-no-pie");
+    }
+
+    let status = timing::time("linking", || command.status())
+        .map_err(|e| Diagnostic::error(format!("cannot run {}: {}", driver, e)))?;
+    if !status.success() {
+        return Err(Diagnostic::error(format!("{} failed: {}", driver, status)));
+    }
+    Ok(())
+}
+
+// Links into a temporary file and returns its contents, for a caller that
+// has no file of its own to write to
+pub fn link_bytes(
+    assembly: &str,
+    runtime: &str,
+    target: Target,
+    pic: bool,
+    shared: bool,
+    flags: &[String],
+) -> Result<Vec<u8>> {
+    let output = TempFile::new(if shared { "so" } else { "out" });
+    link(assembly, runtime, target, pic, shared, &output.0, flags)?;
+    std::fs::read(&output.0)
+        .map_err(|e| Diagnostic::error(format!("cannot read {}: {}", output.0.display(), e)))
+}
+
+// Links the program into a temporary executable and runs it with `arguments`,
+// its output going straight to ours. Returns the exit code, a program killed
+// by a signal has none and is an error.
+pub fn run(
+    assembly: &str,
+    runtime: &str,
+    target: Target,
+    pic: bool,
+    flags: &[String],
+    arguments: &[String],
+) -> Result<i32> {
+    let program = TempFile::new("out");
+    link(assembly, runtime, target, pic, false, &program.0, flags)?;
+    // the time it takes to compile, not to run the program
+    timing::report();
+
+    let status = Command::new(&program.0)
+        .args(arguments)
+        .status()
+        .map_err(|e| Diagnostic::error(format!("cannot run the program: {}", e)))?;
+    status
+        .code()
+        .ok_or_else(|| Diagnostic::error(format!("the program was stopped: {}", status)))
+}
@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+
+use crate::diagnostic::{Diagnostic, Severity};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    UnusedVariables,
+    UnreachableCode,
+    UnusedAttributes,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Allow,
+    Warn,
+}
+
+const LINTS: [Lint; 3] = [
+    Lint::UnusedVariables,
+    Lint::UnreachableCode,
+    Lint::UnusedAttributes,
+];
+
+impl Lint {
+    // the name used on the command line, e.g. `-A unused-variables`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "unused-variables",
+            Lint::UnreachableCode => "unreachable-code",
+            Lint::UnusedAttributes => "unused-attributes",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        LINTS.iter().find(|lint| lint.name() == name).copied()
+    }
+}
+
+// The level of every lint, everything warns unless told otherwise
+pub struct Lints {
+    levels: HashMap<Lint, Level>,
+}
+
+impl Default for Lints {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Lints {
+    pub fn new() -> Self {
+        Self {
+            levels: LINTS.iter().map(|lint| (*lint, Level::Warn)).collect(),
+        }
+    }
+
+    pub fn set(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    // turn a finding into a warning, or drop it if the lint is allowed
+    pub fn report(&self, lint: Lint, diagnostic: Diagnostic) -> Option<Diagnostic> {
+        match self.levels[&lint] {
+            Level::Allow => None,
+            Level::Warn => Some(
+                diagnostic
+                    .with_severity(Severity::Warning)
+                    .with_code(lint.name()),
+            ),
+        }
+    }
+}
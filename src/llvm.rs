@@ -0,0 +1,772 @@
+use std::collections::HashMap;
+
+use crate::{
+    codegen::{self, Backend},
+    diagnostic::{Diagnostic, Result},
+    ir::{Address, BinOp, Cond, Function, Inst, Module, UnOp, VReg},
+    target::{Os, Target},
+    types::Type,
+};
+
+// printint prints printf's %d of the low 32 bits, printu64, printi64 and
+// printhex all 64 with %llu, %lld and %llx, printchar its %c and printstr its
+// %s, readint returns what scanf reads, or 0. exit is C's own,
+// called with the status in an i64 like any other function, and so are getenv,
+// strlen, malloc and the functions on files with the pointers in and out of them. A failed
+// assert flushes what was printed, writes where it is to stderr and aborts.
+// rand steps a xorshift generator from the seed the native runtimes start
+// from. The hooks of --instrument-functions count the calls into the record
+// of the function, linked onto a list the first time, and crust.report writes
+// the list to stderr.
+const HELPERS: &str = "@.printint = private unnamed_addr constant [4 x i8] c\"%d\\0A\\00\"
+@.printchar = private unnamed_addr constant [3 x i8] c\"%c\\00\"
+@.printstr = private unnamed_addr constant [3 x i8] c\"%s\\00\"
+@.readint = private unnamed_addr constant [5 x i8] c\"%llu\\00\"
+@.printu64 = private unnamed_addr constant [6 x i8] c\"%llu\\0A\\00\"
+@.printi64 = private unnamed_addr constant [6 x i8] c\"%lld\\0A\\00\"
+@.printhex = private unnamed_addr constant [8 x i8] c\"0x%llx\\0A\\00\"
+@crust.random = private global i64 88172645463325252
+@.profile = private unnamed_addr constant [27 x i8] c\"%s: %llu calls, %llu deep\\0A\\00\"
+@crust.profile.first = private global ptr null
+@crust.profile.last = private global ptr null
+
+declare i32 @printf(ptr, ...)
+declare i32 @scanf(ptr, ...)
+declare i64 @exit(i64)
+declare i64 @getenv(i64)
+declare i64 @strlen(i64)
+declare i64 @malloc(i64)
+declare i64 @fopen(i64, i64)
+declare i64 @fread(i64, i64, i64, i64)
+declare i64 @fwrite(i64, i64, i64, i64)
+declare i64 @fclose(i64)
+declare i32 @fflush(ptr)
+declare i32 @fputs(ptr, ptr)
+declare i32 @fprintf(ptr, ptr, ...)
+declare void @abort()
+
+define i64 @printint(i64 %value) {
+  %1 = trunc i64 %value to i32
+  %2 = call i32 (ptr, ...) @printf(ptr @.printint, i32 %1)
+  ret i64 0
+}
+
+define i64 @printu64(i64 %value) {
+  %1 = call i32 (ptr, ...) @printf(ptr @.printu64, i64 %value)
+  ret i64 0
+}
+
+define i64 @printi64(i64 %value) {
+  %1 = call i32 (ptr, ...) @printf(ptr @.printi64, i64 %value)
+  ret i64 0
+}
+
+define i64 @printhex(i64 %value) {
+  %1 = call i32 (ptr, ...) @printf(ptr @.printhex, i64 %value)
+  ret i64 0
+}
+
+define i64 @printchar(i64 %value) {
+  %1 = trunc i64 %value to i32
+  %2 = call i32 (ptr, ...) @printf(ptr @.printchar, i32 %1)
+  ret i64 0
+}
+
+define i64 @printstr(i64 %value) {
+  %1 = inttoptr i64 %value to ptr
+  %2 = call i32 (ptr, ...) @printf(ptr @.printstr, ptr %1)
+  ret i64 0
+}
+
+define i64 @readint() {
+  %1 = alloca i64
+  store i64 0, ptr %1
+  %2 = call i32 (ptr, ...) @scanf(ptr @.readint, ptr %1)
+  %3 = load i64, ptr %1
+  ret i64 %3
+}
+
+define i64 @crust.panic(i64 %message) {
+  %1 = inttoptr i64 %message to ptr
+  %2 = call i32 @fflush(ptr null)
+  %3 = call ptr @crust.stderr()
+  %4 = call i32 @fputs(ptr %1, ptr %3)
+  call void @abort()
+  unreachable
+}
+
+define i64 @rand() {
+  %1 = load i64, ptr @crust.random
+  %2 = shl i64 %1, 13
+  %3 = xor i64 %1, %2
+  %4 = lshr i64 %3, 7
+  %5 = xor i64 %3, %4
+  %6 = shl i64 %5, 17
+  %7 = xor i64 %5, %6
+  store i64 %7, ptr @crust.random
+  ret i64 %7
+}
+
+define i64 @crust.enter(i64 %record, i64 %name) {
+  %1 = inttoptr i64 %record to ptr
+  %2 = load i64, ptr %1
+  %3 = icmp eq i64 %2, 0
+  br i1 %3, label %link, label %count
+link:
+  %4 = getelementptr i64, ptr %1, i64 4
+  store i64 %name, ptr %4
+  %5 = load ptr, ptr @crust.profile.last
+  %6 = icmp eq ptr %5, null
+  %7 = getelementptr i64, ptr %5, i64 3
+  %8 = select i1 %6, ptr @crust.profile.first, ptr %7
+  store ptr %1, ptr %8
+  store ptr %1, ptr @crust.profile.last
+  br label %count
+count:
+  %9 = add i64 %2, 1
+  store i64 %9, ptr %1
+  %10 = getelementptr i64, ptr %1, i64 1
+  %11 = load i64, ptr %10
+  %12 = add i64 %11, 1
+  store i64 %12, ptr %10
+  %13 = getelementptr i64, ptr %1, i64 2
+  %14 = load i64, ptr %13
+  %15 = icmp ugt i64 %12, %14
+  %16 = select i1 %15, i64 %12, i64 %14
+  store i64 %16, ptr %13
+  ret i64 0
+}
+
+define i64 @crust.exit(i64 %record) {
+  %1 = inttoptr i64 %record to ptr
+  %2 = getelementptr i64, ptr %1, i64 1
+  %3 = load i64, ptr %2
+  %4 = sub i64 %3, 1
+  store i64 %4, ptr %2
+  ret i64 0
+}
+
+define i64 @crust.report() {
+  %1 = call i32 @fflush(ptr null)
+  %2 = load ptr, ptr @crust.profile.first
+  br label %next
+next:
+  %3 = phi ptr [ %2, %0 ], [ %13, %print ]
+  %4 = icmp eq ptr %3, null
+  br i1 %4, label %done, label %print
+print:
+  %5 = call ptr @crust.stderr()
+  %6 = getelementptr i64, ptr %3, i64 4
+  %7 = load ptr, ptr %6
+  %8 = load i64, ptr %3
+  %9 = getelementptr i64, ptr %3, i64 2
+  %10 = load i64, ptr %9
+  %11 = call i32 (ptr, ptr, ...) @fprintf(ptr %5, ptr @.profile, ptr %7, i64 %8, i64 %10)
+  %12 = getelementptr i64, ptr %3, i64 3
+  %13 = load ptr, ptr %12
+  br label %next
+done:
+  ret i64 0
+}
+";
+
+// Where the C library of the target keeps stderr, the global of its own
+// name, a differently named one on macOS, and what a function returns on
+// Windows
+fn stderr(target: Target) -> &'static str {
+    match target.os {
+        Os::Windows => {
+            "declare ptr @__acrt_iob_func(i32)
+
+define private ptr @crust.stderr() {
+  %1 = call ptr @__acrt_iob_func(i32 2)
+  ret ptr %1
+}
+"
+        }
+        Os::Macos => {
+            "@__stderrp = external global ptr
+
+define private ptr @crust.stderr() {
+  %1 = load ptr, ptr @__stderrp
+  ret ptr %1
+}
+"
+        }
+        _ => {
+            "@stderr = external global ptr
+
+define private ptr @crust.stderr() {
+  %1 = load ptr, ptr @stderr
+  ret ptr %1
+}
+"
+        }
+    }
+}
+
+// clock_ms in milliseconds from the timespec clock_gettime fills in, or
+// from what Windows counts in them itself
+fn clock(target: Target) -> String {
+    if target.os == Os::Windows {
+        return String::from(
+            "declare i64 @GetTickCount64()
+
+define i64 @clock_ms() {
+  %1 = call i64 @GetTickCount64()
+  ret i64 %1
+}
+",
+        );
+    }
+    format!(
+        "declare i32 @clock_gettime(i32, ptr)
+
+define i64 @clock_ms() {{
+  %1 = alloca [2 x i64]
+  %2 = call i32 @clock_gettime(i32 {}, ptr %1)
+  %3 = load i64, ptr %1
+  %4 = getelementptr i64, ptr %1, i64 1
+  %5 = load i64, ptr %4
+  %6 = mul i64 %3, 1000
+  %7 = udiv i64 %5, 1000000
+  %8 = add i64 %6, %7
+  ret i64 %8
+}}
+",
+        codegen::monotonic_clock(target)
+    )
+}
+
+fn predicate(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "eq",
+        Cond::Ne => "ne",
+        Cond::Lt => "slt",
+        Cond::Le => "sle",
+        Cond::Gt => "sgt",
+        Cond::Ge => "sge",
+        Cond::Ult => "ult",
+        Cond::Ule => "ule",
+        Cond::Ugt => "ugt",
+        Cond::Uge => "uge",
+    }
+}
+
+// The integer type a value of the source type is kept in memory as, and
+// whether it is extended back to 64 bits by its sign
+fn integer(ty: &Type) -> Result<(&'static str, bool)> {
+    match ty {
+        Type::U8 | Type::Char => Ok(("i8", false)),
+        Type::I8 => Ok(("i8", true)),
+        Type::U16 => Ok(("i16", false)),
+        Type::I16 => Ok(("i16", true)),
+        Type::U32 => Ok(("i32", false)),
+        Type::I32 => Ok(("i32", true)),
+        Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => Ok(("i64", false)),
+        _ => Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+    }
+}
+
+// A string as an LLVM constant writes it, anything but printable ASCII as
+// hex escapes
+fn escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'"' | b'\\' => format!("\\{:02X}", byte),
+            0x20..=0x7e => (byte as char).to_string(),
+            _ => format!("\\{:02X}", byte),
+        })
+        .collect()
+}
+
+// Generates textual LLVM IR, for clang or llc to optimize and compile for
+// whatever they support. Every value is an i64 and every virtual register
+// an alloca, which mem2reg turns back into SSA form, since the module is out
+// of it by now and registers may be written more than once. Locals live in
+// a frame allocated on entry, at the same offsets as on the machine.
+pub struct CodeGen {
+    module: Module,
+    target: Target,
+    ir: String,
+    // the value names of the function being written, numbered from here
+    temporaries: usize,
+    // the frame size of the function being written
+    frame_size: usize,
+    // whether the block being written has ended, anything after it needs a
+    // block of its own
+    terminated: bool,
+    // the pooled string every string literal's label refers to
+    strings: HashMap<String, String>,
+    // how many parameters every function takes
+    arities: HashMap<String, usize>,
+}
+
+impl Backend for CodeGen {
+    fn generate(&mut self) -> Result<String> {
+        self.line(&format!("target triple = \"{}\"", self.target.triple()));
+        self.line("");
+        self.data();
+        self.ir.push_str(HELPERS);
+        self.line("");
+        self.ir.push_str(stderr(self.target));
+        self.line("");
+        self.ir.push_str(&clock(self.target));
+
+        self.arities = self
+            .module
+            .functions
+            .iter()
+            .map(|function| (function.name.clone(), function.params.len()))
+            .chain(
+                [
+                    "printint",
+                    "printu64",
+                    "printi64",
+                    "printhex",
+                    "printchar",
+                    "printstr",
+                    "exit",
+                    "getenv",
+                    "strlen",
+                    "malloc",
+                    "fclose",
+                    "crust.panic",
+                    "crust.exit",
+                ]
+                .map(|name| (name.to_string(), 1)),
+            )
+            .chain(
+                [
+                    ("fopen", 2),
+                    ("crust.enter", 2),
+                    ("fread", 4),
+                    ("fwrite", 4),
+                ]
+                .map(|(name, arity)| (name.to_string(), arity)),
+            )
+            .chain(
+                ["readint", "rand", "clock_ms", "crust.report"].map(|name| (name.to_string(), 0)),
+            )
+            .collect();
+
+        for function in self.module.functions.clone() {
+            self.line("");
+            self.function(&function)?;
+        }
+
+        Ok(std::mem::take(&mut self.ir))
+    }
+}
+
+impl CodeGen {
+    pub fn new(module: Module, target: Target) -> Self {
+        Self {
+            module,
+            target,
+            ir: String::new(),
+            temporaries: 0,
+            frame_size: 0,
+            terminated: false,
+            strings: HashMap::new(),
+            arities: HashMap::new(),
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.ir.push_str(line);
+        self.ir.push('\n');
+    }
+
+    fn inst(&mut self, inst: &str) {
+        self.ir.push_str("  ");
+        self.line(inst);
+    }
+
+    // a fresh name for the value of an instruction
+    fn temporary(&mut self) -> String {
+        self.temporaries += 1;
+        format!("%t{}", self.temporaries)
+    }
+
+    // Globals are byte arrays on the boundary their type needs, or the one
+    // `#[align]` asks for if that is more, zeroed unless they have an initial
+    // value, and identical string literals share a single constant
+    fn data(&mut self) {
+        for global in self.module.globals.clone() {
+            let size = global.ty.storage_size();
+            let align = global.placement.align.unwrap_or(1).max(global.ty.align());
+            // an extern one is only declared, for the linker to find
+            if global.external {
+                self.line(&format!(
+                    "@{} = external global [{} x i8], align {}",
+                    global.name, size, align
+                ));
+                continue;
+            }
+            let section = match &global.placement.section {
+                Some(name) => format!(", section \"{}\"", escape(name)),
+                None => String::new(),
+            };
+            let init = match global.init {
+                Some(value) => format!(
+                    "c\"{}\"",
+                    value.to_le_bytes()[..size]
+                        .iter()
+                        .map(|byte| format!("\\{:02X}", byte))
+                        .collect::<String>()
+                ),
+                None => String::from("zeroinitializer"),
+            };
+            self.line(&format!(
+                "@{} = global [{} x i8] {}{}, align {}",
+                global.name, size, init, section, align
+            ));
+        }
+
+        let mut pool: HashMap<String, String> = HashMap::new();
+        for string in self.module.strings.clone() {
+            let label = pool
+                .entry(string.value.clone())
+                .or_insert(string.label.clone())
+                .clone();
+            if label == string.label {
+                self.line(&format!(
+                    "@.{} = private unnamed_addr constant [{} x i8] c\"{}\\00\"",
+                    label,
+                    string.value.len() + 1,
+                    escape(&string.value)
+                ));
+            }
+            self.strings.insert(string.label, label);
+        }
+        self.line("");
+    }
+
+    fn function(&mut self, function: &Function) -> Result<()> {
+        let params = (0..function.params.len())
+            .map(|i| format!("i64 %p{}", i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut placement = String::new();
+        if function.inline {
+            placement.push_str(" inlinehint");
+        }
+        if function.placement.cold {
+            placement.push_str(" cold");
+        }
+        if let Some(name) = &function.placement.section {
+            placement.push_str(&format!(" section \"{}\"", escape(name)));
+        }
+        if let Some(align) = function.placement.align {
+            placement.push_str(&format!(" align {}", align));
+        }
+        self.line(&format!(
+            "define i64 @{}({}){} {{",
+            function.name, params, placement
+        ));
+        self.temporaries = 0;
+        self.terminated = false;
+
+        // the frame holds the locals, kept a multiple of 16
+        self.frame_size = (function.stack_size + 15) & !15;
+        self.inst(&format!(
+            "%frame = alloca [{} x i8], align 16",
+            self.frame_size
+        ));
+        let mut vregs = function
+            .insts
+            .iter()
+            .flat_map(|inst| inst.uses().into_iter().chain(inst.def()))
+            .collect::<Vec<_>>();
+        vregs.sort();
+        vregs.dedup();
+        for vreg in vregs {
+            self.inst(&format!("%v{} = alloca i64", vreg));
+        }
+
+        // every parameter is copied to its slot
+        for (i, param) in function.params.iter().enumerate() {
+            let address = self.address(&Address::Local(param.offset));
+            let (ty, _) = integer(&param.ty)?;
+            let value = self.truncate(&format!("%p{}", i), ty);
+            self.inst(&format!("store {} {}, ptr {}", ty, value, address));
+        }
+
+        for inst in &function.insts {
+            self.instruction(inst)?;
+        }
+
+        // falling off the end returns nothing in particular
+        if !self.terminated {
+            self.inst("ret i64 0");
+        }
+        self.line("}");
+
+        Ok(())
+    }
+
+    fn get(&mut self, vreg: VReg) -> String {
+        let value = self.temporary();
+        self.inst(&format!("{} = load i64, ptr %v{}", value, vreg));
+        value
+    }
+
+    fn set(&mut self, vreg: VReg, value: &str) {
+        self.inst(&format!("store i64 {}, ptr %v{}", value, vreg));
+    }
+
+    // the value cut down to an integer type narrower than 64 bits
+    fn truncate(&mut self, value: &str, ty: &str) -> String {
+        if ty == "i64" {
+            return value.to_string();
+        }
+        let truncated = self.temporary();
+        self.inst(&format!("{} = trunc i64 {} to {}", truncated, value, ty));
+        truncated
+    }
+
+    // a narrower value extended back to 64 bits
+    fn extend(&mut self, value: &str, ty: &str, signed: bool) -> String {
+        if ty == "i64" {
+            return value.to_string();
+        }
+        let extended = self.temporary();
+        let op = if signed { "sext" } else { "zext" };
+        self.inst(&format!("{} = {} {} {} to i64", extended, op, ty, value));
+        extended
+    }
+
+    // what a store and a load through a variable of the type would leave
+    fn cast(&mut self, value: &str, ty: &Type) -> String {
+        let Ok((integer, signed)) = integer(ty) else {
+            return value.to_string();
+        };
+        let truncated = self.truncate(value, integer);
+        self.extend(&truncated, integer, signed)
+    }
+
+    // a pointer to what the address refers to
+    fn address(&mut self, address: &Address) -> String {
+        match address {
+            Address::Global(name) => match self.strings.get(name) {
+                Some(label) => format!("@.{}", label),
+                None => format!("@{}", name),
+            },
+            Address::Local(offset) => {
+                let pointer = self.temporary();
+                self.inst(&format!(
+                    "{} = getelementptr i8, ptr %frame, i64 {}",
+                    pointer,
+                    self.frame_size as isize + offset
+                ));
+                pointer
+            }
+            Address::Indirect(vreg) => {
+                let value = self.get(*vreg);
+                let pointer = self.temporary();
+                self.inst(&format!("{} = inttoptr i64 {} to ptr", pointer, value));
+                pointer
+            }
+        }
+    }
+
+    fn compare(&mut self, cond: Cond, left: VReg, right: VReg) -> String {
+        let left = self.get(left);
+        let right = self.get(right);
+        let result = self.temporary();
+        self.inst(&format!(
+            "{} = icmp {} i64 {}, {}",
+            result,
+            predicate(cond),
+            left,
+            right
+        ));
+        result
+    }
+
+    fn instruction(&mut self, inst: &Inst) -> Result<()> {
+        // anything unreachable after a jump or a return starts a block of
+        // its own
+        if self.terminated && !matches!(inst, Inst::Label(_)) {
+            self.temporaries += 1;
+            self.line(&format!("dead{}:", self.temporaries));
+            self.terminated = false;
+        }
+
+        match inst {
+            Inst::Const { dst, value } => self.set(*dst, &(*value as i64).to_string()),
+            Inst::Copy { dst, src } => {
+                let value = self.get(*src);
+                self.set(*dst, &value);
+            }
+            Inst::Cast { dst, src, ty } => {
+                let value = self.get(*src);
+                let value = self.cast(&value, ty);
+                self.set(*dst, &value);
+            }
+            Inst::Binary {
+                op,
+                dst,
+                left,
+                right,
+            } => {
+                let left = self.get(*left);
+                let mut right = self.get(*right);
+                // the machine masks shift counts to six bits, where LLVM
+                // makes anything wider poison
+                if matches!(op, BinOp::Shl | BinOp::Shr | BinOp::UShr) {
+                    let count = self.temporary();
+                    self.inst(&format!("{} = and i64 {}, 63", count, right));
+                    right = count;
+                }
+                let instruction = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "sdiv",
+                    BinOp::UDiv => "udiv",
+                    BinOp::And => "and",
+                    BinOp::Or => "or",
+                    BinOp::Xor => "xor",
+                    BinOp::Shl => "shl",
+                    BinOp::Shr => "ashr",
+                    BinOp::UShr => "lshr",
+                };
+                let result = self.temporary();
+                self.inst(&format!(
+                    "{} = {} i64 {}, {}",
+                    result, instruction, left, right
+                ));
+                self.set(*dst, &result);
+            }
+            Inst::Unary { op, dst, src } => {
+                let value = self.get(*src);
+                let result = self.temporary();
+                match op {
+                    UnOp::Neg => self.inst(&format!("{} = sub i64 0, {}", result, value)),
+                    UnOp::Not => self.inst(&format!("{} = xor i64 {}, -1", result, value)),
+                    UnOp::LogicalNot | UnOp::ToBool => {
+                        let cond = if *op == UnOp::LogicalNot { "eq" } else { "ne" };
+                        let flag = self.temporary();
+                        self.inst(&format!("{} = icmp {} i64 {}, 0", flag, cond, value));
+                        self.inst(&format!("{} = zext i1 {} to i64", result, flag));
+                    }
+                }
+                self.set(*dst, &result);
+            }
+            Inst::Compare {
+                cond,
+                dst,
+                left,
+                right,
+            } => {
+                let flag = self.compare(*cond, *left, *right);
+                let result = self.temporary();
+                self.inst(&format!("{} = zext i1 {} to i64", result, flag));
+                self.set(*dst, &result);
+            }
+            Inst::Select {
+                cond,
+                dst,
+                left,
+                right,
+                then,
+                otherwise,
+            } => {
+                let flag = self.compare(*cond, *left, *right);
+                let (then, otherwise) = (self.get(*then), self.get(*otherwise));
+                let result = self.temporary();
+                self.inst(&format!(
+                    "{} = select i1 {}, i64 {}, i64 {}",
+                    result, flag, then, otherwise
+                ));
+                self.set(*dst, &result);
+            }
+            Inst::Load { dst, addr, ty } => {
+                let (integer, signed) = integer(ty)?;
+                let pointer = self.address(addr);
+                let value = self.temporary();
+                self.inst(&format!("{} = load {}, ptr {}", value, integer, pointer));
+                let value = self.extend(&value, integer, signed);
+                self.set(*dst, &value);
+            }
+            Inst::Store { src, addr, ty } => {
+                let (integer, _) = integer(ty)?;
+                let pointer = self.address(addr);
+                let value = self.get(*src);
+                let value = self.truncate(&value, integer);
+                self.inst(&format!("store {} {}, ptr {}", integer, value, pointer));
+            }
+            Inst::Lea { dst, addr } => {
+                let pointer = self.address(addr);
+                let value = self.temporary();
+                self.inst(&format!("{} = ptrtoint ptr {} to i64", value, pointer));
+                self.set(*dst, &value);
+            }
+            // A call has to pass exactly the parameters a function takes.
+            // Arguments beyond them are left out and missing ones are 0, as
+            // they would be left in registers nobody looks at.
+            Inst::Call { dst, name, args } => {
+                let arity = self.arities.get(name).copied().unwrap_or(args.len());
+                let mut values = Vec::new();
+                for arg in args.iter().take(arity) {
+                    let value = self.get(*arg);
+                    values.push(format!("i64 {}", value));
+                }
+                values.resize(arity, "i64 0".to_string());
+                let result = self.temporary();
+                self.inst(&format!(
+                    "{} = call i64 @{}({})",
+                    result,
+                    name,
+                    values.join(", ")
+                ));
+                self.set(*dst, &result);
+            }
+            Inst::Return { src, ty } => {
+                if !ty.is_int() && !matches!(ty, Type::Pointer { .. } | Type::Closure { .. }) {
+                    return Err(Diagnostic::error(format!("Unexpected type {:?}", ty)));
+                }
+                let value = self.get(*src);
+                let value = self.cast(&value, ty);
+                self.inst(&format!("ret i64 {}", value));
+                self.terminated = true;
+            }
+            Inst::Jump { target } => {
+                self.inst(&format!("br label %L{}", target));
+                self.terminated = true;
+            }
+            // the fall through continues in a block of its own
+            Inst::Branch {
+                cond,
+                left,
+                right,
+                target,
+            } => {
+                let flag = self.compare(*cond, *left, *right);
+                self.temporaries += 1;
+                let next = format!("next{}", self.temporaries);
+                self.inst(&format!(
+                    "br i1 {}, label %L{}, label %{}",
+                    flag, target, next
+                ));
+                self.line(&format!("{}:", next));
+            }
+            // the block before falls through into the one a label starts
+            Inst::Label(label) => {
+                if !self.terminated {
+                    self.inst(&format!("br label %L{}", label));
+                }
+                self.line(&format!("L{}:", label));
+                self.terminated = false;
+            }
+            Inst::Phi { .. } => {
+                return Err(Diagnostic::error("Unexpected phi outside of SSA form"));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1408 @@
+use std::{cell::RefCell, collections::HashMap, path::Path, rc::Rc};
+
+use crate::{
+    ast::{is_arithmetic, never_returns, signed_operation, LiteralValue, Node, Span},
+    attributes,
+    cfg::Cfg,
+    checks::{Check, Checks},
+    consteval,
+    diagnostic::{Diagnostic, Result},
+    intern::{intern, Name},
+    ir::{
+        takes_command_line, Address, BinOp, Cond, Function, Global, Inst, Label, Module, Param,
+        Placement, StringLiteral, UnOp, VReg,
+    },
+    lexer::{Token, TokenType},
+    parser::{StorageClass, Symbol},
+    types::Type,
+    visit::{walk_node, Visitor},
+};
+
+// Whether printint's %d would print the argument wrong: a u32 above the
+// range of an int, or 64 bits of it
+fn prints_whole(arg: &Node) -> bool {
+    matches!(
+        arg.ty(),
+        Some(Type::U32 | Type::U64 | Type::I64 | Type::Pointer { .. })
+    )
+}
+
+// Turns the checked and folded tree into three-address code. Variables stay
+// in memory, every value an expression computes gets a fresh virtual register.
+// `files` are the files the source was read from, each with the line of the
+// source it starts on, for an assert or a runtime check to say where it is.
+// With `instrument` every function calls the profiler in the runtime as it
+// is entered and left.
+pub fn lower(
+    nodes: &[Node],
+    files: &[(String, usize)],
+    checks: &Checks,
+    instrument: bool,
+) -> std::result::Result<Module, Vec<Diagnostic>> {
+    let mut lowering = Lowering {
+        files: files.to_vec(),
+        checks: checks.clone(),
+        instrument,
+        module: Module::default(),
+        insts: Vec::new(),
+        vregs: 0,
+        labels: 0,
+        loops: Vec::new(),
+        lambdas: lambdas(nodes),
+        symbols: symbols(nodes, files),
+        result: None,
+        tuple: None,
+        diagnostics: Vec::new(),
+    };
+
+    for node in nodes {
+        lowering.top_level(node).map_err(|e| vec![e])?;
+    }
+
+    if !lowering.diagnostics.is_empty() {
+        return Err(lowering.diagnostics);
+    }
+
+    Ok(lowering.module)
+}
+
+// The lambdas of the program with the type of the closures each makes and
+// the result of the function made of its body, which a closure of a wider
+// result widens
+fn lambdas(nodes: &[Node]) -> Vec<(String, Type, Type)> {
+    struct Lambdas(Vec<(String, Type)>);
+    impl Visitor for Lambdas {
+        fn visit_node(&mut self, node: &Node) {
+            match node {
+                Node::Lambda { function, ty, .. } => self.0.push((function.clone(), ty.clone())),
+                _ => walk_node(self, node),
+            }
+        }
+    }
+
+    let mut lambdas = Lambdas(Vec::new());
+    for node in nodes {
+        lambdas.visit_node(node);
+    }
+    lambdas
+        .0
+        .into_iter()
+        .filter_map(|(function, ty)| {
+            let ret = nodes.iter().find_map(|node| match node {
+                Node::FnDecl {
+                    name,
+                    return_type: Some(ret),
+                    environment: Some(_),
+                    ..
+                } if *name == function => Some(ret.clone()),
+                _ => None,
+            })?;
+            Some((function, ty, ret))
+        })
+        .collect()
+}
+
+// What the functions of the program are called in the object file, by the
+// name the parser gave them. One only the program calls is mangled with the
+// module it is in, the name of its file, and how many parameters it takes,
+// `list.push.2`, so that it collides neither with a function of another file
+// nor with one of the C library. main, a pub function and one with
+// #[no_mangle] keep their name for C to find them by.
+pub fn symbols(nodes: &[Node], files: &[(String, usize)]) -> HashMap<String, String> {
+    nodes
+        .iter()
+        .filter_map(|node| match node {
+            Node::FnDecl {
+                name,
+                return_type,
+                params,
+                attributes,
+                public,
+                environment: None,
+                span,
+                ..
+            } if name != "main" && !public && !attributes::has(attributes, "no_mangle") => {
+                // not counting the address a function returning several
+                // values is given
+                let arity = match return_type {
+                    Some(Type::Tuple(_)) => params.len() - 1,
+                    _ => params.len(),
+                };
+                let symbol = format!("{}.{}.{}", module(files, span.start.0), name, arity);
+                Some((name.clone(), symbol))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// the module of a line of the source, the name of the file it is in as far
+// as it can go in a symbol
+fn module(files: &[(String, usize)], line: usize) -> String {
+    let file = files
+        .iter()
+        .rev()
+        .find(|(_, first)| *first <= line)
+        .and_then(|(file, _)| Path::new(file).file_stem())
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut module: String = file
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if !module.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        module.insert_str(0, "source");
+    }
+    module
+}
+
+struct Lowering {
+    files: Vec<(String, usize)>,
+    checks: Checks,
+    instrument: bool,
+    module: Module,
+    // the body of the function being lowered
+    insts: Vec<Inst>,
+    vregs: usize,
+    labels: usize,
+    // the loops being lowered, innermost last, by label and with where a
+    // break and a continue jump to
+    loops: Vec<(Option<Name>, Label, Label)>,
+    // The functions made of the lambdas of the program, the type of their
+    // closures and what the functions return. The number a closure's
+    // environment starts with is the place of its lambda here, counting from
+    // 1, and a call through one compares it with those of every lambda of
+    // the type to call the one it is.
+    lambdas: Vec<(String, Type, Type)>,
+    // the symbols of the functions that are mangled
+    symbols: HashMap<String, String>,
+    // the slot of the parameter a function returning several values is
+    // given the address to leave them at in
+    result: Option<isize>,
+    // where the values of the call of the TupleAssign being lowered are
+    tuple: Option<VReg>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+fn symbol_name(symbol: &Rc<RefCell<Symbol>>) -> String {
+    symbol.borrow().identifier.lexeme.unwrap().to_string()
+}
+
+fn constant(value: &LiteralValue) -> Option<u64> {
+    match value {
+        LiteralValue::U8(value) => Some(*value as u64),
+        LiteralValue::U16(value) => Some(*value as u64),
+        LiteralValue::U32(value) => Some(*value as u64),
+        LiteralValue::U64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn comparison(token_type: TokenType, signed: bool) -> Option<Cond> {
+    let cond = match token_type {
+        TokenType::Equal => Cond::Eq,
+        TokenType::NotEqual => Cond::Ne,
+        TokenType::LessThan => Cond::Lt,
+        TokenType::LessThanOrEqual => Cond::Le,
+        TokenType::GreaterThan => Cond::Gt,
+        TokenType::GreaterThanOrEqual => Cond::Ge,
+        _ => return None,
+    };
+
+    Some(if signed { cond } else { cond.unsigned() })
+}
+
+impl Lowering {
+    fn vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    fn label(&mut self) -> Label {
+        self.labels += 1;
+        self.labels
+    }
+
+    fn emit(&mut self, inst: Inst) {
+        self.insts.push(inst);
+    }
+
+    fn constant(&mut self, value: u64) -> VReg {
+        let dst = self.vreg();
+        self.emit(Inst::Const { dst, value });
+        dst
+    }
+
+    fn top_level(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::VarDecl {
+                symbol,
+                is_local: false,
+                ty,
+                attributes,
+                external,
+                init,
+                ..
+            } => self.global(
+                symbol,
+                ty,
+                &attributes::placement(attributes),
+                *init,
+                *external,
+            ),
+            Node::VarDeclMany {
+                symbols,
+                is_local: false,
+                ty,
+                attributes,
+                external,
+                ..
+            } => {
+                let placement = attributes::placement(attributes);
+                for symbol in symbols {
+                    self.global(symbol, ty, &placement, None, *external);
+                }
+            }
+            Node::FnDecl {
+                identifier,
+                name,
+                body,
+                stack_size,
+                return_type,
+                params,
+                attributes,
+                public,
+                environment,
+                ..
+            } => {
+                let name = name.clone();
+                let params = params
+                    .iter()
+                    .map(|param| Param {
+                        offset: param.borrow().offset.unwrap(),
+                        ty: param.borrow().ty.clone().unwrap(),
+                    })
+                    .collect::<Vec<_>>();
+
+                // registers and labels are numbered per function
+                self.vregs = 0;
+                self.labels = 0;
+                self.result = match return_type {
+                    Some(Type::Tuple(_)) => Some(params[0].offset),
+                    _ => None,
+                };
+                if takes_command_line(&name, &params) {
+                    self.command_line(&params[0]);
+                }
+                if let Some(environment) = environment {
+                    self.captures(&params[0], &environment.captures);
+                }
+                self.statement(body)?;
+
+                // ensure that every path through a function with a return type returns a value
+                let mut labels = self.labels;
+                if return_type.is_some() && Cfg::build(&self.insts, &mut labels).falls_off_end() {
+                    self.diagnostics.push(
+                        Diagnostic::at(
+                            identifier,
+                            format!(
+                                "Function {} does not return a value",
+                                identifier.lexeme.unwrap()
+                            ),
+                        )
+                        .with_label("not all paths return a value"),
+                    );
+                }
+                // the value main returns is the exit status, and running off
+                // the end of one without a return type exits with 0, as in C,
+                // rather than with what the last call left behind
+                if name == "main" && return_type.is_none() {
+                    let src = self.vreg();
+                    self.emit(Inst::Const { dst: src, value: 0 });
+                    self.emit(Inst::Return { src, ty: Type::U8 });
+                }
+                if self.instrument {
+                    self.profile(&name);
+                }
+                self.module.functions.push(Function {
+                    name: self.symbol(&name),
+                    params,
+                    stack_size: *stack_size,
+                    insts: std::mem::take(&mut self.insts),
+                    placement: attributes::placement(attributes),
+                    inline: attributes::has(attributes, "inline"),
+                    public: *public,
+                });
+            }
+            _ => {
+                return Err(Diagnostic::error(format!(
+                    "Unexpected top level node {:?}",
+                    node
+                )))
+            }
+        }
+
+        Ok(())
+    }
+
+    // Has the function call crust.enter first thing and crust.exit before
+    // every return, with its record: a u64[5] of how many calls there were,
+    // how deeply they are nested now and at most, the next record and the
+    // name, which the runtime fills in. It prints the records of the
+    // functions that were called once main returns or exit is called.
+    fn profile(&mut self, name: &str) {
+        let record = format!("crust.profile.{}", name);
+        self.module.globals.push(Global {
+            name: record.clone(),
+            ty: Type::Array {
+                ty: Box::new(Type::U64),
+                count: 5,
+            },
+            placement: Placement::default(),
+            init: None,
+            external: false,
+        });
+        let label = format!("profile_{}", self.module.strings.len());
+        self.module.strings.push(StringLiteral {
+            label: label.clone(),
+            value: name.to_string(),
+        });
+
+        let body = std::mem::take(&mut self.insts);
+        let (address, text) = (self.address(&record), self.address(&label));
+        self.hook("crust.enter", vec![address, text]);
+        for inst in body {
+            match &inst {
+                Inst::Return { .. } => {
+                    let address = self.address(&record);
+                    self.hook("crust.exit", vec![address]);
+                    if name == "main" {
+                        self.hook("crust.report", Vec::new());
+                    }
+                }
+                Inst::Call { name, .. } if name == "exit" => self.hook("crust.report", Vec::new()),
+                _ => {}
+            }
+            self.emit(inst);
+        }
+    }
+
+    // A lambda's copies of the variables it captured, from its environment,
+    // which the first parameter points at
+    fn captures(&mut self, env: &Param, captures: &[Rc<RefCell<Symbol>>]) {
+        let base = self.vreg();
+        self.emit(Inst::Load {
+            dst: base,
+            addr: Address::Local(env.offset),
+            ty: Type::U64,
+        });
+        for (i, capture) in captures.iter().enumerate() {
+            let field = self.field(base, i + 1);
+            let ty = capture.borrow().ty.clone().unwrap();
+            let value = self.vreg();
+            self.emit(Inst::Load {
+                dst: value,
+                addr: Address::Indirect(field),
+                ty: ty.clone(),
+            });
+            self.emit(Inst::Store {
+                src: value,
+                addr: Address::Local(capture.borrow().offset.unwrap()),
+                ty,
+            });
+        }
+    }
+
+    // the address of the slot of an environment, 8 bytes each
+    fn field(&mut self, env: VReg, slot: usize) -> VReg {
+        let offset = self.constant(8 * slot as u64);
+        let dst = self.vreg();
+        self.emit(Inst::Binary {
+            op: BinOp::Add,
+            dst,
+            left: env,
+            right: offset,
+        });
+        dst
+    }
+
+    fn address(&mut self, global: &str) -> VReg {
+        let dst = self.vreg();
+        self.emit(Inst::Lea {
+            dst,
+            addr: Address::Global(global.to_string()),
+        });
+        dst
+    }
+
+    fn hook(&mut self, hook: &str, args: Vec<VReg>) {
+        let dst = self.vreg();
+        self.emit(Inst::Call {
+            dst,
+            name: hook.to_string(),
+            args,
+        });
+    }
+
+    // main is called with the command line as C's `int argc, char **argv`,
+    // which only fills the low half of argc's register. A wider argc is cut
+    // down to it before the body reads it.
+    fn command_line(&mut self, argc: &Param) {
+        if argc.ty.size() <= 4 {
+            return;
+        }
+        let dst = self.vreg();
+        self.emit(Inst::Load {
+            dst,
+            addr: Address::Local(argc.offset),
+            ty: Type::U32,
+        });
+        self.emit(Inst::Store {
+            src: dst,
+            addr: Address::Local(argc.offset),
+            ty: argc.ty.clone(),
+        });
+    }
+
+    fn global(
+        &mut self,
+        symbol: &Rc<RefCell<Symbol>>,
+        ty: &Type,
+        placement: &Placement,
+        init: Option<u64>,
+        external: bool,
+    ) {
+        self.module.globals.push(Global {
+            name: symbol_name(symbol),
+            ty: ty.clone(),
+            placement: placement.clone(),
+            init,
+            external,
+        });
+    }
+
+    fn statement(&mut self, node: &Node) -> Result<()> {
+        match node {
+            // locals only take up stack space, which the parser already counted
+            Node::VarDecl { .. } | Node::VarDeclMany { .. } => {}
+            Node::CompoundStmt { statements, .. } => {
+                for statement in statements {
+                    self.statement(statement)?;
+                }
+            }
+            Node::IfStmt {
+                condition,
+                then_branch,
+                else_branch,
+                ..
+            } => {
+                let false_label = self.label();
+                self.condition(condition, false_label)?;
+                self.statement(then_branch)?;
+
+                match else_branch {
+                    Some(else_branch) => {
+                        let end_label = self.label();
+                        self.emit(Inst::Jump { target: end_label });
+                        self.emit(Inst::Label(false_label));
+                        self.statement(else_branch)?;
+                        self.emit(Inst::Label(end_label));
+                    }
+                    None => self.emit(Inst::Label(false_label)),
+                }
+            }
+            Node::WhileStmt {
+                condition,
+                body,
+                step,
+                label,
+                ..
+            } => {
+                let start_label = self.label();
+                let next_label = self.label();
+                let end_label = self.label();
+
+                self.emit(Inst::Label(start_label));
+                self.condition(condition, end_label)?;
+                let name = label.as_ref().and_then(|label| label.lexeme);
+                self.loops.push((name, end_label, next_label));
+                self.statement(body)?;
+                self.loops.pop();
+                self.emit(Inst::Label(next_label));
+                if let Some(step) = step {
+                    self.statement(step)?;
+                }
+                self.emit(Inst::Jump {
+                    target: start_label,
+                });
+                self.emit(Inst::Label(end_label));
+            }
+            Node::BreakStmt { label, .. } => {
+                let (target, _) = self.enclosing_loop(label);
+                self.emit(Inst::Jump { target });
+            }
+            Node::ContinueStmt { label, .. } => {
+                let (_, target) = self.enclosing_loop(label);
+                self.emit(Inst::Jump { target });
+            }
+            Node::ReturnStmt { expr, fn_name, .. } => {
+                let src = self.expression(expr)?;
+                // several values are returned in memory, and their address
+                // in the register
+                let ty = match fn_name.borrow().ty.clone().unwrap() {
+                    Type::Tuple(_) => Type::U64,
+                    ty => ty,
+                };
+                self.emit(Inst::Return { src, ty });
+            }
+            Node::TupleAssign {
+                call, assignments, ..
+            } => {
+                self.tuple = Some(self.expression(call)?);
+                for assignment in assignments {
+                    self.expression(assignment)?;
+                }
+            }
+            // an expression statement, its value is thrown away
+            _ => {
+                self.expression(node)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // the loop a break or continue is for, which the parser made sure exists
+    // and where its break and continue jump to
+    fn enclosing_loop(&self, label: &Option<Token>) -> (Label, Label) {
+        let found = match label {
+            Some(label) => self
+                .loops
+                .iter()
+                .rev()
+                .find(|(name, _, _)| *name == label.lexeme),
+            None => self.loops.last(),
+        };
+        let (_, end, next) = found.unwrap();
+        (*end, *next)
+    }
+
+    // jumps to false_label when the condition does not hold
+    fn condition(&mut self, condition: &Node, false_label: Label) -> Result<()> {
+        match condition {
+            Node::BinaryExpr {
+                left,
+                operator,
+                right,
+                ..
+            } if comparison(operator.token_type, false).is_some() => {
+                let signed = signed_operation(operator.token_type, left, right);
+                let cond = comparison(operator.token_type, signed).unwrap();
+                let left = self.expression(left)?;
+                let right = self.expression(right)?;
+                self.emit(Inst::Branch {
+                    cond: cond.negate(),
+                    left,
+                    right,
+                    target: false_label,
+                });
+            }
+            Node::LiteralExpr { value, .. } if constant(value).is_some() => {
+                if constant(value) == Some(0) {
+                    self.emit(Inst::Jump {
+                        target: false_label,
+                    });
+                }
+            }
+            // a condition is tested for truth anyway
+            Node::ToBool { expr, .. } => self.condition(expr, false_label)?,
+            _ => self.truthiness(condition, false_label)?,
+        }
+
+        Ok(())
+    }
+
+    fn truthiness(&mut self, expr: &Node, false_label: Label) -> Result<()> {
+        let left = self.expression(expr)?;
+        let right = self.constant(0);
+        self.emit(Inst::Branch {
+            cond: Cond::Eq,
+            left,
+            right,
+            target: false_label,
+        });
+
+        Ok(())
+    }
+
+    // where a variable or a dereference lives, and the type stored there
+    fn lvalue(&mut self, node: &Node) -> Result<(Address, Type)> {
+        match node {
+            Node::LiteralExpr {
+                value: LiteralValue::Identifier(symbol),
+                ..
+            } => {
+                let symbol = symbol.borrow();
+                let address = match symbol.class {
+                    StorageClass::Global => {
+                        Address::Global(symbol.identifier.lexeme.unwrap().to_string())
+                    }
+                    StorageClass::Local | StorageClass::Param => {
+                        Address::Local(symbol.offset.unwrap())
+                    }
+                };
+
+                Ok((address, symbol.ty.clone().unwrap()))
+            }
+            Node::UnaryExpr {
+                operator,
+                right,
+                ty,
+                ..
+            } if operator.token_type == TokenType::Mul => {
+                let pointer = self.expression(right)?;
+                // an array is never at zero, only a pointer can be null
+                if matches!(right.ty(), Some(Type::Pointer { .. }))
+                    && self.checks.enabled(Check::Null)
+                {
+                    self.check_nonzero(node.span(), pointer, "null pointer dereference");
+                }
+                Ok((Address::Indirect(pointer), ty.clone()))
+            }
+            _ => Err(Diagnostic::error(format!("Cannot assign to {:?}", node))),
+        }
+    }
+
+    fn expression(&mut self, node: &Node) -> Result<VReg> {
+        let dst = match node {
+            Node::LiteralExpr { value, ty, .. } => match value {
+                LiteralValue::U8(_)
+                | LiteralValue::U16(_)
+                | LiteralValue::U32(_)
+                | LiteralValue::U64(_) => self.constant(constant(value).unwrap()),
+                LiteralValue::Identifier(_) => {
+                    let (addr, _) = self.lvalue(node)?;
+                    let dst = self.vreg();
+                    // an array used as a value is the address of its first element
+                    match ty {
+                        Type::Array { .. } => self.emit(Inst::Lea { dst, addr }),
+                        _ => self.emit(Inst::Load {
+                            dst,
+                            addr,
+                            ty: ty.clone(),
+                        }),
+                    }
+                    dst
+                }
+                LiteralValue::String { value, label } => {
+                    if !self.module.strings.iter().any(|s| &s.label == label) {
+                        self.module.strings.push(StringLiteral {
+                            label: label.clone(),
+                            value: value.clone(),
+                        });
+                    }
+
+                    let dst = self.vreg();
+                    self.emit(Inst::Lea {
+                        dst,
+                        addr: Address::Global(label.clone()),
+                    });
+                    dst
+                }
+            },
+            Node::BinaryExpr {
+                left,
+                operator,
+                right,
+                ty,
+                ..
+            } => {
+                let signed = signed_operation(operator.token_type, left, right);
+                let op = match operator.token_type {
+                    TokenType::Add => Some(BinOp::Add),
+                    TokenType::Sub => Some(BinOp::Sub),
+                    TokenType::Mul => Some(BinOp::Mul),
+                    TokenType::Div if signed => Some(BinOp::Div),
+                    TokenType::Div => Some(BinOp::UDiv),
+                    TokenType::Ampersand => Some(BinOp::And),
+                    TokenType::Or => Some(BinOp::Or),
+                    TokenType::Xor => Some(BinOp::Xor),
+                    TokenType::LeftShift => Some(BinOp::Shl),
+                    TokenType::RightShift if signed => Some(BinOp::Shr),
+                    TokenType::RightShift => Some(BinOp::UShr),
+                    TokenType::LogicalAnd => {
+                        return Err(Diagnostic::error("Logical && is not supported yet"))
+                    }
+                    TokenType::LogicalOr => {
+                        return Err(Diagnostic::error("Logical || is not supported yet"))
+                    }
+                    _ => None,
+                };
+
+                let array = left.ty();
+                let left = self.expression(left)?;
+                let right = match array {
+                    Some(Type::Array { count, .. })
+                        if op == Some(BinOp::Add) && self.checks.enabled(Check::Bounds) =>
+                    {
+                        self.checked_index(operator, node.span(), &array.unwrap(), count, right)?
+                    }
+                    _ => self.expression(right)?,
+                };
+                if matches!(op, Some(BinOp::Div | BinOp::UDiv)) && self.checks.enabled(Check::Div) {
+                    self.check_nonzero(node.span(), right, "division by zero");
+                }
+                let dst = self.vreg();
+                match (op, comparison(operator.token_type, signed)) {
+                    (Some(op), _) => self.emit(Inst::Binary {
+                        op,
+                        dst,
+                        left,
+                        right,
+                    }),
+                    (None, Some(cond)) => self.emit(Inst::Compare {
+                        cond,
+                        dst,
+                        left,
+                        right,
+                    }),
+                    (None, None) => {
+                        return Err(Diagnostic::at(operator, "Unexpected binary operator"))
+                    }
+                }
+                if let Some(op @ (BinOp::Add | BinOp::Sub | BinOp::Mul)) = op {
+                    if ty.is_int() && self.checks.enabled(Check::Overflow) {
+                        self.check_overflow(node.span(), op, ty, [left, right, dst]);
+                    }
+                }
+                dst
+            }
+            Node::UnaryExpr {
+                operator, right, ..
+            } => match operator.token_type {
+                TokenType::Ampersand => {
+                    let addr = match &**right {
+                        Node::LiteralExpr {
+                            value: LiteralValue::Identifier(_),
+                            ..
+                        } => self.lvalue(right)?.0,
+                        _ => {
+                            return Err(Diagnostic::at(
+                                operator,
+                                "Cannot take the address of an expression",
+                            ))
+                        }
+                    };
+                    let dst = self.vreg();
+                    self.emit(Inst::Lea { dst, addr });
+                    dst
+                }
+                TokenType::Mul => {
+                    let (addr, ty) = self.lvalue(node)?;
+                    let dst = self.vreg();
+                    self.emit(Inst::Load { dst, addr, ty });
+                    dst
+                }
+                TokenType::Sub | TokenType::Invert | TokenType::LogicalNot => {
+                    let op = match operator.token_type {
+                        TokenType::Sub => UnOp::Neg,
+                        TokenType::Invert => UnOp::Not,
+                        _ => UnOp::LogicalNot,
+                    };
+                    let src = self.expression(right)?;
+                    let dst = self.vreg();
+                    self.emit(Inst::Unary { op, dst, src });
+                    dst
+                }
+                _ => return Err(Diagnostic::at(operator, "Unexpected unary operator")),
+            },
+            Node::ScaleExpr { right, size, .. } => {
+                let left = self.expression(right)?;
+                let right = self.constant(*size as u64);
+                let dst = self.vreg();
+                self.emit(Inst::Binary {
+                    op: BinOp::Mul,
+                    dst,
+                    left,
+                    right,
+                });
+                dst
+            }
+            Node::WidenExpr { right, ty, .. } => {
+                let from = right.ty().unwrap();
+                let mut src = self.expression(right)?;
+                for ty in from.widening(ty) {
+                    let dst = self.vreg();
+                    self.emit(Inst::Cast { dst, src, ty });
+                    src = dst;
+                }
+                src
+            }
+            Node::ToBool { expr, .. } => {
+                let src = self.expression(expr)?;
+                let dst = self.vreg();
+                self.emit(Inst::Unary {
+                    op: UnOp::ToBool,
+                    dst,
+                    src,
+                });
+                dst
+            }
+            Node::AssignStmt { left, expr, .. } => {
+                let (addr, ty) = self.lvalue(left)?;
+                let src = self.expression(expr)?;
+                self.emit(Inst::Store { src, addr, ty });
+                src
+            }
+            Node::FnCall {
+                identifier, args, ..
+            } if identifier.lexeme == Some(intern("assert")) => {
+                self.assert(node.span(), &args[0])?
+            }
+            Node::FnCall {
+                identifier, args, ..
+            } if is_arithmetic(identifier) => self.arithmetic(identifier, args)?,
+            Node::FnCall {
+                identifier, args, ..
+            } if identifier.lexeme == Some(intern("printint")) && prints_whole(&args[0]) => {
+                self.print_whole(&args[0])?
+            }
+            Node::FnCall {
+                identifier,
+                name,
+                args,
+                result,
+                ..
+            } => {
+                let mut args = args
+                    .iter()
+                    .map(|arg| self.expression(arg))
+                    .collect::<Result<Vec<_>>>()?;
+                // a function returning several values is given the address
+                // of the slot to leave them at before the arguments
+                let values = result.map(|result| {
+                    let dst = self.vreg();
+                    self.emit(Inst::Lea {
+                        dst,
+                        addr: Address::Local(result),
+                    });
+                    dst
+                });
+                args.splice(0..0, values);
+                let dst = self.vreg();
+                // len of a string calls the C library's strlen, whatever
+                // the program has called strlen
+                let name = if identifier.lexeme == Some(intern("len")) {
+                    name.clone()
+                } else {
+                    self.symbol(name)
+                };
+                self.emit(Inst::Call { dst, name, args });
+                // control doesn't come back from exit, ending the block there
+                // makes whatever follows unreachable and the return paths
+                // see it, and the backends make it a tail call
+                if never_returns(identifier) {
+                    self.emit(Inst::Return {
+                        src: dst,
+                        ty: Type::U64,
+                    });
+                }
+                values.unwrap_or(dst)
+            }
+            Node::TupleExpr { elements, .. } => {
+                let values = self.vreg();
+                self.emit(Inst::Load {
+                    dst: values,
+                    addr: Address::Local(self.result.unwrap()),
+                    ty: Type::U64,
+                });
+                for (i, element) in elements.iter().enumerate() {
+                    let src = self.expression(element)?;
+                    let field = self.field(values, i);
+                    self.emit(Inst::Store {
+                        src,
+                        addr: Address::Indirect(field),
+                        ty: element.ty().unwrap(),
+                    });
+                }
+                values
+            }
+            Node::TupleElement { index, ty, .. } => {
+                let field = self.field(self.tuple.unwrap(), *index);
+                let dst = self.vreg();
+                self.emit(Inst::Load {
+                    dst,
+                    addr: Address::Indirect(field),
+                    ty: ty.clone(),
+                });
+                dst
+            }
+            Node::Lambda {
+                function, captures, ..
+            } => {
+                // Every closure gets an environment of its own from the C
+                // library's malloc, so one made again, in a loop, leaves the
+                // values of those made before alone, and it lives on after
+                // the function it was made in returns. It is never freed.
+                let size = self.constant(8 * (captures.len() as u64 + 1));
+                let dst = self.vreg();
+                self.emit(Inst::Call {
+                    dst,
+                    name: String::from("malloc"),
+                    args: vec![size],
+                });
+                let id = self
+                    .lambdas
+                    .iter()
+                    .position(|(name, _, _)| name == function);
+                let id = self.constant(id.unwrap() as u64 + 1);
+                self.emit(Inst::Store {
+                    src: id,
+                    addr: Address::Indirect(dst),
+                    ty: Type::U64,
+                });
+                for (i, capture) in captures.iter().enumerate() {
+                    let ty = capture.borrow().ty.clone().unwrap();
+                    let value = self.vreg();
+                    self.emit(Inst::Load {
+                        dst: value,
+                        addr: Address::Local(capture.borrow().offset.unwrap()),
+                        ty,
+                    });
+                    let field = self.field(dst, i + 1);
+                    self.emit(Inst::Store {
+                        src: value,
+                        addr: Address::Indirect(field),
+                        ty: Type::U64,
+                    });
+                }
+                dst
+            }
+            Node::ClosureCall {
+                callee,
+                args,
+                ty,
+                result,
+                ..
+            } => self.closure_call(callee, args, ty, *result)?,
+            Node::PostIncStmt { left, .. } => self.increment(left, BinOp::Add, true)?,
+            Node::PostDecStmt { left, .. } => self.increment(left, BinOp::Sub, true)?,
+            Node::PreIncStmt { right, .. } => self.increment(right, BinOp::Add, false)?,
+            Node::PreDecStmt { right, .. } => self.increment(right, BinOp::Sub, false)?,
+            _ => {
+                return Err(Diagnostic::error(format!(
+                    "Unexpected expression {:?}",
+                    node
+                )))
+            }
+        };
+
+        Ok(dst)
+    }
+
+    // Calls the lambda the closure was made by, with its environment and the
+    // arguments, by comparing the number the environment starts with against
+    // each lambda of the closure's type in turn. A closure that was never
+    // given a value gives back 0.
+    fn closure_call(
+        &mut self,
+        callee: &Node,
+        args: &[Node],
+        ty: &Type,
+        result: isize,
+    ) -> Result<VReg> {
+        let closure = callee.ty().unwrap();
+        let env = self.expression(callee)?;
+        let mut values = vec![env];
+        for arg in args {
+            values.push(self.expression(arg)?);
+        }
+
+        let id = self.vreg();
+        self.emit(Inst::Load {
+            dst: id,
+            addr: Address::Indirect(env),
+            ty: Type::U64,
+        });
+        let zero = self.constant(0);
+        self.emit(Inst::Store {
+            src: zero,
+            addr: Address::Local(result),
+            ty: ty.clone(),
+        });
+
+        let end = self.label();
+        let candidates = self
+            .lambdas
+            .iter()
+            .enumerate()
+            .filter(|(_, (_, lambda, _))| *lambda == closure)
+            .map(|(i, (name, _, ret))| (i as u64 + 1, name.clone(), ret.clone()))
+            .collect::<Vec<_>>();
+        for (number, name, ret) in candidates {
+            let next = self.label();
+            let number = self.constant(number);
+            self.emit(Inst::Branch {
+                cond: Cond::Ne,
+                left: id,
+                right: number,
+                target: next,
+            });
+            let mut dst = self.vreg();
+            self.emit(Inst::Call {
+                dst,
+                name,
+                args: values.clone(),
+            });
+            if ret != *ty {
+                for cast in ret.widening(ty) {
+                    let src = dst;
+                    dst = self.vreg();
+                    self.emit(Inst::Cast { dst, src, ty: cast });
+                }
+            }
+            self.emit(Inst::Store {
+                src: dst,
+                addr: Address::Local(result),
+                ty: ty.clone(),
+            });
+            self.emit(Inst::Jump { target: end });
+            self.emit(Inst::Label(next));
+        }
+        self.emit(Inst::Label(end));
+
+        let dst = self.vreg();
+        self.emit(Inst::Load {
+            dst,
+            addr: Address::Local(result),
+            ty: ty.clone(),
+        });
+        Ok(dst)
+    }
+
+    // An assert tests its condition where it is, and only when it fails calls
+    // into the runtime, which prints where the assert is and aborts
+    // min and max pick one argument or the other, and abs the argument or
+    // its negation, by comparing them without a branch. Unsigned values are
+    // compared as such, and are their own magnitude.
+    fn arithmetic(&mut self, identifier: &Token, args: &[Node]) -> Result<VReg> {
+        let signed = args
+            .iter()
+            .any(|arg| arg.ty().is_some_and(|ty| ty.is_signed()));
+        let values = args
+            .iter()
+            .map(|arg| self.expression(arg))
+            .collect::<Result<Vec<_>>>()?;
+        let name = identifier.lexeme.map(Name::text);
+        let (cond, left, right, then, otherwise) = match name.as_deref() {
+            Some("abs") if !signed => return Ok(values[0]),
+            Some("abs") => {
+                let zero = self.constant(0);
+                let negated = self.vreg();
+                self.emit(Inst::Unary {
+                    op: UnOp::Neg,
+                    dst: negated,
+                    src: values[0],
+                });
+                (Cond::Lt, values[0], zero, negated, values[0])
+            }
+            Some("min") => (Cond::Lt, values[0], values[1], values[0], values[1]),
+            _ => (Cond::Gt, values[0], values[1], values[0], values[1]),
+        };
+        let dst = self.vreg();
+        self.emit(Inst::Select {
+            cond: if signed { cond } else { cond.unsigned() },
+            dst,
+            left,
+            right,
+            then,
+            otherwise,
+        });
+        Ok(dst)
+    }
+
+    // printint of a value %d would cut short, cut down to its type and
+    // printed in full
+    fn print_whole(&mut self, arg: &Node) -> Result<VReg> {
+        let ty = arg.ty().unwrap();
+        let mut src = self.expression(arg)?;
+        if ty == Type::U32 {
+            let dst = self.vreg();
+            self.emit(Inst::Cast {
+                dst,
+                src,
+                ty: Type::U32,
+            });
+            src = dst;
+        }
+        let name = if ty.is_signed() {
+            "printi64"
+        } else {
+            "printu64"
+        };
+        let dst = self.vreg();
+        self.emit(Inst::Call {
+            dst,
+            name: String::from(name),
+            args: vec![src],
+        });
+        Ok(dst)
+    }
+
+    fn assert(&mut self, at: Span, condition: &Node) -> Result<VReg> {
+        let failed = self.label();
+        let end = self.label();
+        self.condition(condition, failed)?;
+        self.emit(Inst::Jump { target: end });
+
+        self.emit(Inst::Label(failed));
+        self.panic(at, "assertion failed");
+
+        self.emit(Inst::Label(end));
+        let dst = self.vreg();
+        self.emit(Inst::Const { dst, value: 0 });
+        Ok(dst)
+    }
+
+    // A divisor or a pointer that is zero stops the program where it is,
+    // instead of the processor faulting on it
+    fn check_nonzero(&mut self, at: Span, value: VReg, what: &str) {
+        let end = self.label();
+        let zero = self.constant(0);
+        self.emit(Inst::Branch {
+            cond: Cond::Ne,
+            left: value,
+            right: zero,
+            target: end,
+        });
+        self.panic(at, what);
+        self.emit(Inst::Label(end));
+    }
+
+    // The offset of an element of an array, `array[index]` having become
+    // `array + index * size`, with an index past its end stopping the
+    // program. A constant index that is in the array needs no test.
+    fn checked_index(
+        &mut self,
+        operator: &Token,
+        at: Span,
+        array: &Type,
+        count: u64,
+        offset: &Node,
+    ) -> Result<VReg> {
+        let size = array.size() as u64;
+        let (index, scale) = match offset {
+            Node::ScaleExpr { right, size, .. } => (&**right, *size as u64),
+            // the elements are bytes, or the index was folded into the offset
+            _ => (offset, 1),
+        };
+        let limit = count * size / scale;
+        if consteval::evaluate(index, operator).is_ok_and(|index| index < limit) {
+            return self.expression(offset);
+        }
+
+        let index = self.expression(index)?;
+        let end = self.label();
+        let limit = self.constant(limit);
+        self.emit(Inst::Branch {
+            cond: Cond::Ult,
+            left: index,
+            right: limit,
+            target: end,
+        });
+        self.panic(at, &format!("index out of bounds of {}", array));
+        self.emit(Inst::Label(end));
+
+        if scale == 1 {
+            return Ok(index);
+        }
+        let scale = self.constant(scale);
+        Ok(self.binary(BinOp::Mul, index, scale))
+    }
+
+    // An addition, subtraction or multiplication whose result doesn't fit
+    // its type stops the program. Narrower types are computed in 64 bits
+    // without losing anything, so the result only has to survive being cut
+    // down to the type; 64 bit ones are tested on what the operation did.
+    fn check_overflow(&mut self, at: Span, op: BinOp, ty: &Type, values: [VReg; 3]) {
+        let [left, right, result] = values;
+        let failed = self.label();
+        let end = self.label();
+        if ty.size() < 8 {
+            let cut = self.vreg();
+            self.emit(Inst::Cast {
+                dst: cut,
+                src: result,
+                ty: ty.clone(),
+            });
+            self.emit(Inst::Branch {
+                cond: Cond::Eq,
+                left: cut,
+                right: result,
+                target: end,
+            });
+        } else {
+            let signed = ty.is_signed();
+            match op {
+                // the sign of the result differs from that of both operands
+                // of an addition, or from the left one and the negated right
+                // one of a subtraction
+                BinOp::Add | BinOp::Sub if signed => {
+                    let a = self.binary(BinOp::Xor, left, result);
+                    let b = if op == BinOp::Add {
+                        self.binary(BinOp::Xor, right, result)
+                    } else {
+                        self.binary(BinOp::Xor, left, right)
+                    };
+                    let sign = self.binary(BinOp::And, a, b);
+                    let zero = self.constant(0);
+                    self.emit(Inst::Branch {
+                        cond: Cond::Ge,
+                        left: sign,
+                        right: zero,
+                        target: end,
+                    });
+                }
+                // the sum wrapped around below the left operand, or the
+                // right one was more than there was to take away
+                BinOp::Add | BinOp::Sub => {
+                    let (low, high) = if op == BinOp::Add {
+                        (result, left)
+                    } else {
+                        (left, right)
+                    };
+                    self.emit(Inst::Branch {
+                        cond: Cond::Uge,
+                        left: low,
+                        right: high,
+                        target: end,
+                    });
+                }
+                // a product that wrapped around doesn't divide back into the
+                // right operand, except that -1 times the lowest value has
+                // to be caught before the division faults on it
+                _ => {
+                    let zero = self.constant(0);
+                    self.emit(Inst::Branch {
+                        cond: Cond::Eq,
+                        left,
+                        right: zero,
+                        target: end,
+                    });
+                    if signed {
+                        let divide = self.label();
+                        let minus_one = self.constant(u64::MAX);
+                        self.emit(Inst::Branch {
+                            cond: Cond::Ne,
+                            left,
+                            right: minus_one,
+                            target: divide,
+                        });
+                        let lowest = self.constant(1 << 63);
+                        self.emit(Inst::Branch {
+                            cond: Cond::Eq,
+                            left: right,
+                            right: lowest,
+                            target: failed,
+                        });
+                        self.emit(Inst::Jump { target: end });
+                        self.emit(Inst::Label(divide));
+                    }
+                    let op = if signed { BinOp::Div } else { BinOp::UDiv };
+                    let quotient = self.binary(op, result, left);
+                    self.emit(Inst::Branch {
+                        cond: Cond::Eq,
+                        left: quotient,
+                        right,
+                        target: end,
+                    });
+                }
+            }
+        }
+
+        self.emit(Inst::Label(failed));
+        let what = match op {
+            BinOp::Add => "addition",
+            BinOp::Sub => "subtraction",
+            _ => "multiplication",
+        };
+        self.panic(at, &format!("{} {} overflowed", ty, what));
+        self.emit(Inst::Label(end));
+    }
+
+    fn binary(&mut self, op: BinOp, left: VReg, right: VReg) -> VReg {
+        let dst = self.vreg();
+        self.emit(Inst::Binary {
+            op,
+            dst,
+            left,
+            right,
+        });
+        dst
+    }
+
+    // writes where `at` is and what went wrong to stderr and aborts
+    fn panic(&mut self, at: Span, what: &str) {
+        let label = format!("panic_{}", self.module.strings.len());
+        self.module.strings.push(StringLiteral {
+            label: label.clone(),
+            value: format!("{}: {}\n", self.location(at), what),
+        });
+        let message = self.vreg();
+        self.emit(Inst::Lea {
+            dst: message,
+            addr: Address::Global(label),
+        });
+        let dst = self.vreg();
+        self.emit(Inst::Call {
+            dst,
+            name: String::from("crust.panic"),
+            args: vec![message],
+        });
+        // it doesn't come back, as with exit
+        self.emit(Inst::Return {
+            src: dst,
+            ty: Type::U64,
+        });
+    }
+
+    // what a function is called in the object file
+    fn symbol(&self, name: &str) -> String {
+        self.symbols
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    // the file a node starts in and its line there
+    fn location(&self, span: Span) -> String {
+        let line = span.start.0;
+        match self.files.iter().rev().find(|(_, first)| *first <= line) {
+            Some((file, first)) => format!("{}:{}", file, line - first + 1),
+            None => format!("<source>:{}", line),
+        }
+    }
+
+    // ++ and --, evaluating to the old value when post is set
+    fn increment(&mut self, node: &Node, op: BinOp, post: bool) -> Result<VReg> {
+        let (addr, ty) = self.lvalue(node)?;
+
+        let old = self.vreg();
+        self.emit(Inst::Load {
+            dst: old,
+            addr: addr.clone(),
+            ty: ty.clone(),
+        });
+        let one = self.constant(1);
+        let new = self.vreg();
+        self.emit(Inst::Binary {
+            op,
+            dst: new,
+            left: old,
+            right: one,
+        });
+        self.emit(Inst::Store { src: new, addr, ty });
+
+        Ok(if post { old } else { new })
+    }
+}
@@ -0,0 +1,540 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io::{BufRead, Write},
+    panic::{self, AssertUnwindSafe},
+    rc::Rc,
+};
+
+use crate::{
+    checker::Checker,
+    checks::Checks,
+    diagnostic::{Diagnostic, Severity},
+    incremental::{Document, Edit},
+    json::Json,
+    lexer::{Lexer, Token, TokenType},
+    lint::Lints,
+    lower,
+    parser::{Symbol, SymbolType},
+    preprocess::Includes,
+    semantic::{self, Kind, SemanticToken},
+};
+
+// What the editor gets to know about a document: the errors and warnings
+// compiling it would report, the symbol each identifier refers to, and what
+// to highlight them as
+struct Analysis {
+    diagnostics: Vec<Diagnostic>,
+    references: Vec<(Token, Rc<RefCell<Symbol>>)>,
+    semantic: Vec<SemanticToken>,
+}
+
+// the document as it is now, with the functions no edit touched parsed once
+fn analyze(document: &mut Document) -> Analysis {
+    let diagnostics = match document.parse() {
+        Ok(nodes) => {
+            let lints = Lints::new();
+            let mut diagnostics = Checker::new(&lints).check(&nodes);
+            if let Err(errors) = lower::lower(&nodes, &[], &Checks::new(), false) {
+                diagnostics.extend(errors);
+            }
+            diagnostics
+        }
+        Err(diagnostics) => diagnostics,
+    };
+    let tokens = document.tokens().unwrap_or_default();
+
+    Analysis {
+        diagnostics,
+        references: document.references().to_vec(),
+        semantic: semantic::classify(&tokens, document.references()),
+    }
+}
+
+// The byte of the source at a position of LSP, whose character counts the
+// chars of the line as the columns do everywhere here. A character past the
+// end of the line is its end.
+fn offset(source: &str, position: &Json) -> Option<usize> {
+    let line = position.get("line")?.as_usize()?;
+    let character = position.get("character")?.as_usize()?;
+    let start = match line {
+        0 => 0,
+        _ => source.match_indices('\n').nth(line - 1)?.0 + 1,
+    };
+    let text = source[start..].split('\n').next().unwrap_or_default();
+    let column = text
+        .char_indices()
+        .nth(character)
+        .map_or(text.len(), |(i, _)| i);
+    Some(start + column)
+}
+
+// LSP counts lines and columns from 0, the compiler from 1
+fn range(line: usize, column: usize, length: usize) -> Json {
+    let position = |character: usize| {
+        Json::object(vec![
+            ("line", line.saturating_sub(1).into()),
+            ("character", character.into()),
+        ])
+    };
+    let start = column.saturating_sub(1);
+    Json::object(vec![
+        ("start", position(start)),
+        ("end", position(start + length)),
+    ])
+}
+
+fn token_length(token: &Token) -> usize {
+    token.text().chars().count().max(1)
+}
+
+fn diagnostic(diagnostic: &Diagnostic) -> Json {
+    let severity = match diagnostic.severity {
+        Severity::Error => 1,
+        Severity::Warning => 2,
+    };
+    let mut fields = vec![
+        (
+            "range",
+            range(diagnostic.line, diagnostic.column, diagnostic.length),
+        ),
+        ("severity", Json::from(severity as usize)),
+        ("source", Json::string("crust")),
+        ("message", Json::string(diagnostic.message.clone())),
+    ];
+    if let Some(code) = &diagnostic.code {
+        fields.push(("code", Json::string(code.clone())));
+    }
+    Json::object(fields)
+}
+
+// how a symbol is declared, shown when hovering over it
+fn signature(symbol: &Symbol) -> String {
+    let name = symbol
+        .identifier
+        .lexeme
+        .map(|name| name.to_string())
+        .unwrap_or_default();
+    match symbol.structure {
+        SymbolType::Variable => match &symbol.ty {
+            Some(ty) => format!("{}: {}", name, ty),
+            None => name,
+        },
+        SymbolType::Constant(value) => match &symbol.ty {
+            Some(ty) if ty.is_signed() => format!("const {}: {} = {}", name, ty, value as i64),
+            Some(ty) => format!("const {}: {} = {}", name, ty, value),
+            None => name,
+        },
+        SymbolType::Function => {
+            let params: Vec<String> = symbol
+                .params
+                .iter()
+                .flatten()
+                .map(|param| signature(&param.borrow()))
+                .collect();
+            let return_type = match &symbol.ty {
+                Some(ty) => format!(": {}", ty),
+                None => String::new(),
+            };
+            format!("fn {}({}){}", name, params.join(", "), return_type)
+        }
+    }
+}
+
+// The comments above where a symbol is declared, in front of its `fn`,
+// `extern`, `let` or `const` and the attributes before them
+fn doc(tokens: &[Token], identifier: &Token) -> Option<String> {
+    let mut i = tokens
+        .iter()
+        .position(|token| token.line == identifier.line && token.column == identifier.column)?;
+    while let Some(previous) = i.checked_sub(1).map(|j| &tokens[j]) {
+        match previous.token_type {
+            TokenType::Fn
+            | TokenType::Pub
+            | TokenType::Extern
+            | TokenType::Let
+            | TokenType::Const => i -= 1,
+            // the end of an attribute
+            TokenType::RightBracket => {
+                i = tokens[..i]
+                    .iter()
+                    .rposition(|token| token.token_type == TokenType::Attribute)?
+            }
+            _ => break,
+        }
+    }
+    tokens[i].doc_comment()
+}
+
+struct Server {
+    // the documents open in the editor, as it has them
+    sources: HashMap<String, Document>,
+    documents: HashMap<String, Analysis>,
+    shutdown: bool,
+}
+
+impl Server {
+    fn send(&self, message: Json) {
+        let body = message.to_string();
+        let mut stdout = std::io::stdout().lock();
+        let _ = write!(stdout, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = stdout.flush();
+    }
+
+    fn respond(&self, id: Json, result: Json) {
+        self.send(Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("id", id),
+            ("result", result),
+        ]));
+    }
+
+    fn fail(&self, id: Json, code: f64, message: String) {
+        self.send(Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("id", id),
+            (
+                "error",
+                Json::object(vec![
+                    ("code", Json::Number(code)),
+                    ("message", Json::string(message)),
+                ]),
+            ),
+        ]));
+    }
+
+    fn publish(&self, uri: &str) {
+        let diagnostics = match self.documents.get(uri) {
+            Some(analysis) => analysis.diagnostics.iter().map(diagnostic).collect(),
+            None => Vec::new(),
+        };
+        self.send(Json::object(vec![
+            ("jsonrpc", Json::string("2.0")),
+            ("method", Json::string("textDocument/publishDiagnostics")),
+            (
+                "params",
+                Json::object(vec![
+                    ("uri", Json::string(uri)),
+                    ("diagnostics", Json::Array(diagnostics)),
+                ]),
+            ),
+        ]));
+    }
+
+    // the symbol named by the identifier under the cursor of a request
+    fn symbol_at(&self, params: &Json) -> Option<Rc<RefCell<Symbol>>> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        let position = params.get("position")?;
+        let line = position.get("line")?.as_usize()? + 1;
+        let character = position.get("character")?.as_usize()? + 1;
+
+        let (_, symbol) = self
+            .documents
+            .get(uri)?
+            .references
+            .iter()
+            .find(|(token, _)| {
+                token.line == line
+                    && token.column <= character
+                    && character <= token.column + token_length(token)
+            })?;
+        Some(symbol.clone())
+    }
+
+    fn definition(&self, params: &Json) -> Json {
+        let Some(symbol) = self.symbol_at(params) else {
+            return Json::Null;
+        };
+        let symbol = symbol.borrow();
+        let identifier = &symbol.identifier;
+        // builtins like printint are declared nowhere in the source
+        if identifier.line == 0 {
+            return Json::Null;
+        }
+        let uri = params.get("textDocument").and_then(|d| d.get("uri"));
+        Json::object(vec![
+            ("uri", uri.cloned().unwrap_or(Json::Null)),
+            (
+                "range",
+                range(identifier.line, identifier.column, token_length(identifier)),
+            ),
+        ])
+    }
+
+    fn hover(&self, params: &Json) -> Json {
+        let Some(symbol) = self.symbol_at(params) else {
+            return Json::Null;
+        };
+        let mut value = format!("```crust\n{}\n```", signature(&symbol.borrow()));
+        // lexed again with the comments, for the ones above the declaration
+        let source = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str)
+            .and_then(|uri| self.sources.get(uri))
+            .map_or("", Document::source);
+        let mut lexer = Lexer::new(source.to_string()).with_trivia();
+        let tokens = lexer.scan_tokens().map_or(&[][..], |tokens| tokens);
+        if let Some(doc) = doc(tokens, &symbol.borrow().identifier) {
+            value = format!("{}\n\n{}", value, doc);
+        }
+        Json::object(vec![(
+            "contents",
+            Json::object(vec![
+                ("kind", Json::string("markdown")),
+                ("value", Json::string(value)),
+            ]),
+        )])
+    }
+
+    // Semantic tokens go out as five numbers each: the line relative to the
+    // one before, the column relative to it on the same line, the length,
+    // and the indexes into the token types and modifiers of the legend
+    fn semantic_tokens(&self, params: &Json) -> Json {
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str);
+        let Some(analysis) = uri.and_then(|uri| self.documents.get(uri)) else {
+            return Json::Null;
+        };
+
+        let mut data = Vec::new();
+        let (mut line, mut column) = (1, 1);
+        for token in &analysis.semantic {
+            let delta = if token.line == line {
+                token.column - column
+            } else {
+                token.column - 1
+            };
+            let (kind, modifiers) = match token.kind {
+                Kind::Function => (0, 0),
+                Kind::Constant => (1, 3),
+                Kind::Global => (1, 1),
+                Kind::Local => (1, 0),
+                Kind::Parameter => (2, 0),
+                Kind::Type => (3, 0),
+            };
+            data.extend([token.line - line, delta, token.length, kind, modifiers].map(Json::from));
+            (line, column) = (token.line, token.column);
+        }
+        Json::object(vec![("data", Json::Array(data))])
+    }
+
+    // the document at `uri`, whose `#include`s are looked for next to it
+    fn open(&mut self, uri: &str, text: &str) {
+        let includes = Includes {
+            files: uri
+                .strip_prefix("file://")
+                .map(|path| (path.to_string(), 1))
+                .into_iter()
+                .collect(),
+            paths: Vec::new(),
+        };
+        let document = Document::new(text.to_string()).with_includes(includes);
+        self.sources.insert(uri.to_string(), document);
+        self.analyze(uri);
+    }
+
+    // Makes the changes to the document in order, each a range replaced or
+    // the whole text when it has none. A change that doesn't fit the
+    // document as the server has it is reported on it, the ones after it
+    // are left out.
+    fn change(&mut self, uri: &str, changes: &[Json]) {
+        let Some(document) = self.sources.get_mut(uri) else {
+            return;
+        };
+        for change in changes {
+            let text = change.get("text").and_then(Json::as_str).unwrap_or("");
+            let source = document.source();
+            let (start, end) = match change.get("range") {
+                Some(range) => (
+                    range.get("start").and_then(|start| offset(source, start)),
+                    range.get("end").and_then(|end| offset(source, end)),
+                ),
+                None => (Some(0), Some(source.len())),
+            };
+            let edited = match (start, end) {
+                (Some(start), Some(end)) => document.edit(Edit {
+                    range: start..end,
+                    text: text.to_string(),
+                }),
+                _ => Err(Diagnostic::error(
+                    "Change of lines past the end of the document",
+                )),
+            };
+            if let Err(error) = edited {
+                let analysis = Analysis {
+                    diagnostics: vec![error],
+                    references: Vec::new(),
+                    semantic: Vec::new(),
+                };
+                self.documents.insert(uri.to_string(), analysis);
+                self.publish(uri);
+                return;
+            }
+        }
+        self.analyze(uri);
+    }
+
+    fn analyze(&mut self, uri: &str) {
+        if let Some(document) = self.sources.get_mut(uri) {
+            self.documents.insert(uri.to_string(), analyze(document));
+        }
+        self.publish(uri);
+    }
+
+    fn handle(&mut self, message: &Json) {
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let uri = params
+            .get("textDocument")
+            .and_then(|document| document.get("uri"))
+            .and_then(Json::as_str)
+            .unwrap_or("")
+            .to_string();
+
+        let result = match method {
+            "initialize" => Json::object(vec![(
+                "capabilities",
+                Json::object(vec![
+                    // only the ranges that changed are sent
+                    ("textDocumentSync", 2.into()),
+                    ("definitionProvider", Json::Bool(true)),
+                    ("hoverProvider", Json::Bool(true)),
+                    (
+                        "semanticTokensProvider",
+                        Json::object(vec![
+                            (
+                                "legend",
+                                Json::object(vec![
+                                    (
+                                        "tokenTypes",
+                                        Json::Array(
+                                            ["function", "variable", "parameter", "type"]
+                                                .map(Json::string)
+                                                .to_vec(),
+                                        ),
+                                    ),
+                                    (
+                                        "tokenModifiers",
+                                        Json::Array(
+                                            ["global", "readonly"].map(Json::string).to_vec(),
+                                        ),
+                                    ),
+                                ]),
+                            ),
+                            ("full", Json::Bool(true)),
+                        ]),
+                    ),
+                ]),
+            )]),
+            "textDocument/definition" => self.definition(&params),
+            "textDocument/hover" => self.hover(&params),
+            "textDocument/semanticTokens/full" => self.semantic_tokens(&params),
+            "shutdown" => {
+                self.shutdown = true;
+                Json::Null
+            }
+            "textDocument/didOpen" => {
+                let text = params.get("textDocument").and_then(|d| d.get("text"));
+                self.open(&uri, text.and_then(Json::as_str).unwrap_or(""));
+                return;
+            }
+            "textDocument/didChange" => {
+                let changes = params.get("contentChanges").and_then(Json::as_array);
+                self.change(&uri, changes.map_or(&[][..], Vec::as_slice));
+                return;
+            }
+            "textDocument/didClose" => {
+                self.sources.remove(&uri);
+                self.documents.remove(&uri);
+                self.publish(&uri);
+                return;
+            }
+            _ => {
+                // notifications nobody handles are dropped, requests get an error
+                if let Some(id) = message.get("id") {
+                    self.fail(id.clone(), -32601.0, format!("unknown method {}", method));
+                }
+                return;
+            }
+        };
+
+        if let Some(id) = message.get("id") {
+            self.respond(id.clone(), result);
+        }
+    }
+}
+
+// reads one message, a `Content-Length` header and the JSON after it
+fn receive(input: &mut impl BufRead) -> Option<Json> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    // a message that isn't JSON is skipped rather than ending the session
+    Some(Json::parse(&String::from_utf8_lossy(&body)).unwrap_or(Json::Null))
+}
+
+// Serves the Language Server Protocol over stdin and stdout until the editor
+// says exit, returning the exit code
+pub fn serve() -> i32 {
+    let mut server = Server {
+        sources: HashMap::new(),
+        documents: HashMap::new(),
+        shutdown: false,
+    };
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+
+    while let Some(message) = receive(&mut input) {
+        if message.get("method").and_then(Json::as_str) == Some("exit") {
+            break;
+        }
+        // The compiler failing on one message doesn't end the session. A
+        // request gets the error as its answer, and a document being
+        // opened or changed gets it as its only diagnostic.
+        let handled = panic::catch_unwind(AssertUnwindSafe(|| server.handle(&message)));
+        if let Err(panic) = handled {
+            let error = Diagnostic::internal(panic);
+            if let Some(id) = message.get("id") {
+                server.fail(id.clone(), -32603.0, error.message);
+            } else if let Some(uri) = message
+                .get("params")
+                .and_then(|params| params.get("textDocument"))
+                .and_then(|document| document.get("uri"))
+                .and_then(Json::as_str)
+            {
+                server.documents.insert(
+                    uri.to_string(),
+                    Analysis {
+                        diagnostics: vec![error],
+                        references: Vec::new(),
+                        semantic: Vec::new(),
+                    },
+                );
+                server.publish(uri);
+            }
+        }
+    }
+
+    if server.shutdown {
+        0
+    } else {
+        1
+    }
+}
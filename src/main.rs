@@ -1,228 +1,501 @@
+use std::collections::HashMap;
 use std::io::Write;
+use std::path::Path;
 
-use ast::Node;
-use codegen::CodeGen;
-
-mod ast;
-mod codegen;
-mod lexer;
-mod parser;
-mod types;
-mod utils;
-
-fn _print_node(node: Node, ident: u8) {
-    for _ in 0..ident {
-        print!("  ");
-    }
-
-    match node {
-        Node::BinaryExpr {
-            left,
-            operator,
-            right,
-            ..
-        } => {
-            println!("BinaryExpr");
-            _print_node(*left, ident + 1);
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("operator: {:?}", operator);
-            _print_node(*right, ident + 1);
-        }
-        Node::UnaryExpr {
-            operator,
-            right,
-            ty,
-        } => {
-            println!("UnaryExpr");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("operator: {:?}", operator);
-            _print_node(*right, ident + 1);
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("ty: {:?}", ty)
-        }
-        Node::WidenExpr { right, .. } => {
-            println!("WidenExpr");
-            _print_node(*right, ident + 1);
-        }
-        Node::ScaleExpr { right, size, .. } => {
-            println!("ScaleExpr");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("size: {}", size);
-            _print_node(*right, ident + 1);
-        }
-        Node::LiteralExpr { value, .. } => {
-            println!("LiteralExpr");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("value: {:?}", value);
-        }
-        Node::VarDecl { symbol, ty, .. } => {
-            println!("GlobalVar");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!(
-                "{}: {:?}",
-                symbol.borrow().identifier.lexeme.as_ref().unwrap(),
-                ty
+use crust::asm::Syntax;
+use crust::checks::Check;
+use crust::codegen::Emit;
+use crust::diagnostic::Diagnostic;
+use crust::intern::Name;
+use crust::lint::{Level, Lint, Lints};
+use crust::manifest::Manifest;
+use crust::passes::{Pass, Passes};
+use crust::render::Format;
+use crust::sources::Sources;
+use crust::target::Target;
+use crust::{
+    dump, format, lexer, link, lsp, printer, semantic, ssa, symtable, timing, Compiler, Options,
+};
+
+fn report(sources: &Sources, format: Format, diagnostics: Vec<Diagnostic>) -> ! {
+    for diagnostic in &diagnostics {
+        eprintln!("{}", sources.render(format, diagnostic));
+    }
+
+    if diagnostics.len() > 1 && format == Format::Human {
+        eprintln!(
+            "error: aborting due to {} previous errors",
+            diagnostics.len()
+        );
+    }
+
+    std::process::exit(1);
+}
+
+const USAGE: &str = "Usage: crust lsp | crust fmt [--check] <source> | crust build [<manifest>] [<option>...] | crust [run|check] [-o <file>] [-S|-c] [--print-symbols] [--dump-tokens] [--dump-ast[=text|dot]] [--dump-ir] [--dump-all] [--semantic-tokens] [--time-passes] [--stack-usage] [-O0|-O1|-O2] [-f[no-]<pass>] [--syntax=att|intel] [--pic|--no-pic] [--target=<triple>] [--emit=asm|obj|exe|dylib|llvm-ir|runtime] [--error-format=human|json] [-D <name>[=<value>]] [-I <dir>] [-L <dir>] [-l <library>] [-W <lint>] [-A <lint>] [--checks=<check>,...] [--instrument-functions] <source> [<argument>...]";
+
+fn usage(message: &str) -> ! {
+    eprintln!("error: {}", message);
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+// the value of an option written as a separate argument, like `-o out.s`
+fn value(option: &str, args: &mut impl Iterator<Item = String>) -> String {
+    args.next()
+        .unwrap_or_else(|| usage(&format!("{} needs an argument", option)))
+}
+
+fn lint(name: &str) -> Lint {
+    Lint::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown lint: {}", name);
+        std::process::exit(1);
+    })
+}
+
+fn runtime_check(name: &str) -> Check {
+    Check::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown runtime check: {}", name);
+        std::process::exit(1);
+    })
+}
+
+fn pass(name: &str) -> Pass {
+    Pass::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown optimization pass: {}", name);
+        std::process::exit(1);
+    })
+}
+
+fn syntax(name: &str) -> Syntax {
+    Syntax::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown assembly syntax: {}", name);
+        std::process::exit(1);
+    })
+}
+
+fn error_format(name: &str) -> Format {
+    Format::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown error format: {}", name);
+        std::process::exit(1);
+    })
+}
+
+fn emit(name: &str) -> Emit {
+    Emit::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown output kind: {}", name);
+        std::process::exit(1);
+    })
+}
+
+fn target(name: &str) -> Target {
+    Target::from_name(name).unwrap_or_else(|| {
+        eprintln!("error: unknown target: {}", name);
+        std::process::exit(1);
+    })
+}
+
+// `-DNAME` defines the macro as 1, `-DNAME=text` as the text
+fn define(definition: &str) -> (String, String) {
+    let (name, text) = definition.split_once('=').unwrap_or((definition, "1"));
+    let named = name.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if !named {
+        eprintln!("error: not the name of a macro: {}", name);
+        std::process::exit(1);
+    }
+    (name.to_string(), text.to_string())
+}
+
+// The command line a manifest stands for, `crust build` compiles the
+// sources it lists as if they had been given that way
+fn manifest(path: &str, format: Format) -> Vec<String> {
+    let text = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        eprintln!("error: cannot read {}: {}", path, e);
+        std::process::exit(1);
+    });
+    let manifest = Manifest::parse(&text).unwrap_or_else(|e| {
+        eprintln!("{}", format.render(&e, path, &text));
+        std::process::exit(1);
+    });
+    let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+    manifest.arguments(directory)
+}
+
+fn write(path: &str, contents: &[u8]) {
+    let written = std::fs::File::create(path).and_then(|mut f| f.write_all(contents));
+    if let Err(e) = written {
+        eprintln!("error: cannot write {}: {}", path, e);
+        std::process::exit(1);
+    }
+}
+
+fn print_symbols(
+    symbols: &symtable::SymbolTable,
+    mangled: &HashMap<String, String>,
+    target: Target,
+) {
+    println!("functions:");
+    for function in symbols.functions(target) {
+        let return_type = match &function.return_type {
+            Some(ty) => ty.to_string(),
+            None => String::from("()"),
+        };
+        let end_label = match mangled.get(&function.name) {
+            Some(symbol) => target.label(symbol, "end"),
+            None => function.end_label,
+        };
+        println!("  {}: {} ({})", function.name, return_type, end_label);
+    }
+
+    println!("globals:");
+    for global in symbols.globals() {
+        println!("  {}: {} ({} bytes)", global.name, global.ty, global.size);
+    }
+}
+
+fn format_tokens(tokens: &[lexer::Token]) -> String {
+    tokens
+        .iter()
+        .map(|token| {
+            format!(
+                "{}:{}\t{:?}\t{}\n",
+                token.line,
+                token.column,
+                token.token_type,
+                token.lexeme.map(Name::text).unwrap_or_default()
             )
-        }
-        Node::VarDeclMany { symbols, ty, .. } => {
-            println!("GlobalVarMany");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            for symbol in symbols {
-                println!(
-                    "{}: {:?}",
-                    symbol.borrow().identifier.lexeme.as_ref().unwrap(),
-                    ty
-                )
-            }
-        }
-        Node::AssignStmt { left, expr } => {
-            println!("AssignStmt");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("left:");
-            _print_node(*left, ident + 2);
-            for _ in 0..=ident {
-                print!("  ");
+        })
+        .collect()
+}
+
+fn main() {
+    let mut files = Vec::new();
+    let mut symbols = false;
+    let mut dump_tokens = false;
+    let mut dump_ast = false;
+    // the tree as a Graphviz graph instead of indented text
+    let mut dot = false;
+    let mut dump_ir = false;
+    let mut dump_all = false;
+    let mut semantic_tokens = false;
+    let mut stack_usage = false;
+    let mut lints = Lints::new();
+    let mut level = 2;
+    let mut format = Format::Human;
+    let mut path = None;
+    // arguments after the source file are the program's own with `crust run`
+    let mut arguments = Vec::new();
+    let mut options = Options::default();
+    // passes switched on or off by hand, which wins over the level
+    let mut overrides = Vec::new();
+    let mut args = std::env::args()
+        .skip(1)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .peekable();
+    // `crust run` links and runs the program, `crust check` only looks for
+    // errors and stops before anything is generated, `crust fmt` rewrites
+    // the source in the canonical layout, `crust build` compiles what a
+    // manifest lists, and `crust lsp` serves editors instead of compiling
+    let command = match args.peek().map(String::as_str) {
+        Some("run" | "check" | "fmt" | "build" | "lsp") => args.next(),
+        _ => None,
+    };
+    if command.as_deref() == Some("lsp") {
+        std::process::exit(lsp::serve());
+    }
+    let build = command.as_deref() == Some("build");
+    if build {
+        // the options after the manifest come after its own, and win
+        let path = match args.peek() {
+            Some(path) if path.ends_with(".toml") => args.next().unwrap(),
+            _ => String::from("zc.toml"),
+        };
+        let format = match args
+            .clone()
+            .find_map(|arg| arg.strip_prefix("--error-format=").map(String::from))
+        {
+            Some(name) => error_format(&name),
+            None => Format::Human,
+        };
+        let mut arguments = manifest(&path, format);
+        arguments.extend(args);
+        args = arguments.into_iter().peekable();
+    }
+    let run = command.as_deref() == Some("run");
+    let check = command.as_deref() == Some("check");
+    let fmt = command.as_deref() == Some("fmt");
+    // `crust fmt --check` only tells whether the file is formatted
+    let mut fmt_check = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            _ if run && !files.is_empty() => arguments.push(arg),
+            "--check" if fmt => fmt_check = true,
+            "--print-symbols" => symbols = true,
+            "--dump-tokens" => dump_tokens = true,
+            "--dump-ast" | "--dump-ast=text" => dump_ast = true,
+            "--dump-ast=dot" => {
+                dump_ast = true;
+                dot = true;
             }
-            println!("expr:");
-            _print_node(*expr, ident + 2);
-        }
-        Node::CompoundStmt { statements } => {
-            println!("CompoundStmt");
-            for _ in 0..=ident {
-                print!("  ");
+            "--dump-ir" => dump_ir = true,
+            "--dump-all" => dump_all = true,
+            "--semantic-tokens" => semantic_tokens = true,
+            "--time-passes" => timing::enable(),
+            "--stack-usage" => stack_usage = true,
+            "--instrument-functions" => options.instrument = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
             }
-            println!("statements:");
-            for statement in statements {
-                _print_node(statement, ident + 2);
+            "-o" => path = Some(value("-o", &mut args)),
+            "-S" => options.emit = Emit::Asm,
+            "-c" => options.emit = Emit::Obj,
+            "-L" | "-l" => options
+                .libraries
+                .push(format!("{}{}", arg, value(&arg, &mut args))),
+            "-D" => options.defines.push(define(&value("-D", &mut args))),
+            "-I" => options.include_paths.push(value("-I", &mut args)),
+            "-W" => lints.set(lint(&value("-W", &mut args)), Level::Warn),
+            "-A" => lints.set(lint(&value("-A", &mut args)), Level::Allow),
+            "-O0" => level = 0,
+            "-O1" => level = 1,
+            "-O2" => level = 2,
+            "--target" => options.target = target(&value("--target", &mut args)),
+            "--pic" => options.pic = true,
+            "--no-pic" => options.pic = false,
+            _ if arg.starts_with("--target=") => options.target = target(&arg[9..]),
+            _ if arg.starts_with("--error-format=") => format = error_format(&arg[15..]),
+            _ if arg.starts_with("--emit=") => options.emit = emit(&arg[7..]),
+            _ if arg.starts_with("--checks=") => {
+                for name in arg[9..].split(',') {
+                    options.checks.enable(runtime_check(name));
+                }
             }
+            _ if arg.starts_with("--syntax=") => options.syntax = syntax(&arg[9..]),
+            _ if arg.starts_with("-fno-") => overrides.push((pass(&arg[5..]), false)),
+            _ if arg.starts_with("-f") => overrides.push((pass(&arg[2..]), true)),
+            _ if arg.starts_with("-L") || arg.starts_with("-l") => options.libraries.push(arg),
+            _ if arg.starts_with("-D") => options.defines.push(define(&arg[2..])),
+            _ if arg.starts_with("-I") => options.include_paths.push(arg[2..].to_string()),
+            _ if arg.starts_with("-o") => path = Some(arg[2..].to_string()),
+            _ if arg.starts_with("-W") => lints.set(lint(&arg[2..]), Level::Warn),
+            _ if arg.starts_with("-A") => lints.set(lint(&arg[2..]), Level::Allow),
+            _ if arg.starts_with('-') => usage(&format!("unknown option: {}", arg)),
+            _ if !build && !files.is_empty() => usage("more than one input file"),
+            _ => files.push(arg),
         }
-        Node::IfStmt {
-            condition,
-            then_branch,
-            else_branch,
-        } => {
-            println!("IfStmt");
-            _print_node(*condition, ident + 1);
-            _print_node(*then_branch, ident + 1);
-            if let Some(else_branch) = else_branch {
-                _print_node(*else_branch, ident + 1);
+    }
+
+    let mut passes = Passes::new(level);
+    for (pass, enabled) in overrides {
+        passes.set(pass, enabled);
+    }
+    options.passes = passes;
+    options.lints = lints;
+    let compiler = Compiler::new(options);
+    if let Err(e) = compiler.validate() {
+        eprintln!("error: {}", e.message);
+        std::process::exit(1);
+    }
+
+    // the runtime is the same for every program, none has to be given
+    if compiler.options().emit == Emit::Runtime {
+        let runtime = compiler.runtime().unwrap_or_else(|e| {
+            eprintln!("error: {}", e.message);
+            std::process::exit(1);
+        });
+        write(
+            &path.unwrap_or_else(|| Emit::Runtime.output().to_string()),
+            runtime.as_bytes(),
+        );
+        return;
+    }
+
+    if files.is_empty() {
+        usage("no input file");
+    }
+    let sources = Sources::read(&files).unwrap_or_else(|e| {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
+    });
+    let file = sources.file().to_string();
+    let source = sources.text().to_string();
+    let compiler = compiler.with_files(sources.origins());
+    let options = compiler.options();
+
+    let mut dump = dump_all.then(|| dump::Dump::new(&file));
+
+    // the tokens are only kept when they are shown, or else the parser
+    // reads them from the lexer as it goes if it can
+    let tokens = (dump.is_some() || dump_tokens || semantic_tokens).then(|| {
+        compiler
+            .lex(&source)
+            .unwrap_or_else(|e| report(&sources, format, e))
+    });
+
+    if let (Some(dump), Some(tokens)) = (&mut dump, &tokens) {
+        dump.write("tokens", &format_tokens(tokens));
+    }
+
+    if let (true, Some(tokens)) = (dump_tokens, &tokens) {
+        print!("{}", format_tokens(tokens));
+        return;
+    }
+
+    let (parser, nodes) = match &tokens {
+        Some(tokens) => compiler
+            .preprocess(tokens.clone())
+            .and_then(|tokens| compiler.parse(tokens)),
+        None => compiler.parse_source(&source),
+    }
+    .unwrap_or_else(|e| report(&sources, format, e));
+
+    if fmt {
+        let mut lexer = lexer::Lexer::new(source.clone()).with_comments();
+        let tokens = lexer
+            .scan_tokens()
+            .unwrap_or_else(|e| report(&sources, format, e));
+        let formatted = format::format(tokens);
+        if fmt_check {
+            if formatted != source {
+                eprintln!("error: {} is not formatted", file);
+                std::process::exit(1);
             }
+        } else if formatted != source {
+            std::fs::write(&file, formatted).unwrap_or_else(|e| {
+                eprintln!("error: cannot write {}: {}", file, e);
+                std::process::exit(1);
+            });
         }
-        Node::WhileStmt { condition, body } => {
-            println!("WhileStmt");
-            _print_node(*condition, ident + 1);
-            _print_node(*body, ident + 1);
-        }
-        Node::FnDecl {
-            identifier,
-            body,
-            return_type,
-            ..
-        } => {
-            println!("FnDecl");
-            for _ in 0..=ident {
-                print!("  ");
-            }
+        return;
+    }
+
+    if symbols {
+        let mangled = compiler.symbols(&nodes);
+        print_symbols(parser.symbols(), &mangled, options.target);
+        return;
+    }
+
+    if let (true, Some(tokens)) = (semantic_tokens, &tokens) {
+        for token in semantic::classify(tokens, parser.references()) {
             println!(
-                "{}(): {:?}",
-                identifier.lexeme.unwrap(),
-                return_type.unwrap()
+                "{}:{}\t{}\t{}",
+                token.line,
+                token.column,
+                token.length,
+                token.kind.name()
             );
-
-            _print_node(*body, ident + 1);
-        }
-        Node::FnCall {
-            identifier, args, ..
-        } => {
-            println!("FnCall");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("identifier: {:?}", identifier);
-            for arg in args {
-                _print_node(arg, ident + 1);
-            }
-        }
-        Node::ReturnStmt { expr, fn_name } => {
-            println!("ReturnStmt");
-            for _ in 0..=ident {
-                print!("  ");
-            }
-            println!("fn_name: {:?}", fn_name);
-            _print_node(*expr, ident + 1);
-        }
-        Node::PostIncStmt { left } => {
-            println!("PostIncStmt");
-            _print_node(*left, ident + 1);
-        }
-        Node::PostDecStmt { left } => {
-            println!("PostDecStmt");
-            _print_node(*left, ident + 1);
-        }
-        Node::PreIncStmt { right } => {
-            println!("PreIncStmt");
-            _print_node(*right, ident + 1);
-        }
-        Node::PreDecStmt { right } => {
-            println!("PreDecStmt");
-            _print_node(*right, ident + 1);
-        }
-        Node::ToBool { expr } => {
-            println!("ToBool");
-            _print_node(*expr, ident + 1);
         }
+        return;
     }
-}
 
-const DEBUG: bool = false;
-const DEBUG_TEST_FILE: &str = "tests/test27";
+    if let Some(dump) = &mut dump {
+        dump.write("parsed.ast", &printer::dump_ast(&nodes));
+    }
 
-fn main() {
-    let source = if !DEBUG {
-        let args: Vec<String> = std::env::args().collect();
-        if args.len() != 2 {
-            println!("Usage: crust <source>");
-            std::process::exit(1);
-        }
+    if dump_ast && dot {
+        print!("{}", printer::dump_dot(&nodes));
+        return;
+    }
 
-        std::fs::read_to_string(&args[1]).expect("Failed to read file")
-    } else {
-        std::fs::read_to_string(DEBUG_TEST_FILE).expect("Failed to read file")
-    };
+    if dump_ast {
+        print!("{}", printer::dump_ast(&nodes));
+        return;
+    }
+
+    for warning in compiler.check(&nodes) {
+        eprintln!("{}", sources.render(format, &warning));
+    }
 
-    let mut lexer = lexer::Lexer::new(source);
-    let tokens = lexer.scan_tokens();
-    let mut parser = parser::Parser::new(tokens.clone());
-    let nodes = parser.parse();
+    // some errors only come up when the tree is lowered
+    if check {
+        compiler
+            .lower(&nodes)
+            .unwrap_or_else(|e| report(&sources, format, e));
+        timing::report();
+        return;
+    }
 
-    if DEBUG {
-        for node in nodes.clone() {
-            _print_node(node, 0);
+    let nodes = compiler.fold(nodes);
+    let mut module = compiler
+        .lower(&nodes)
+        .unwrap_or_else(|e| report(&sources, format, e));
+
+    if let Some(dump) = &mut dump {
+        dump.write("checked.ast", &printer::dump_ast(&nodes));
+        dump.write("lowered.ir", &module.to_string());
+        // the IR with one more of the passes switched on each time
+        let mut so_far = Passes::new(0);
+        for pass in options.passes.iter().filter(Pass::on_ir) {
+            so_far.set(pass, true);
+            let mut optimized = module.clone();
+            ssa::optimize(&mut optimized, &so_far);
+            dump.write(&format!("{}.ir", pass.name()), &optimized.to_string());
         }
     }
+    compiler.optimize(&mut module);
+
+    if dump_ir {
+        print!("{}", module);
+        timing::report();
+        return;
+    }
 
-    let mut codegen = CodeGen::new(nodes.clone());
-    let assembly = codegen.generate();
+    if stack_usage {
+        let usage = compiler
+            .stack_usage(module)
+            .unwrap_or_else(|e| report(&sources, format, vec![e]));
+        print!("{}", usage);
+        timing::report();
+        return;
+    }
+
+    let assembly = compiler
+        .generate(module)
+        .unwrap_or_else(|e| report(&sources, format, vec![e]));
+    if let Some(dump) = &mut dump {
+        dump.write(Emit::Asm.output(), &assembly);
+    }
+    let runtime = || {
+        compiler
+            .runtime()
+            .unwrap_or_else(|e| report(&sources, format, vec![e]))
+    };
+    if run {
+        let code = link::run(
+            &assembly,
+            &runtime(),
+            options.target,
+            options.pic,
+            &options.libraries,
+            &arguments,
+        )
+        .unwrap_or_else(|e| report(&sources, format, vec![e]));
+        std::process::exit(code);
+    }
+
+    let path = path.unwrap_or_else(|| options.emit.output().to_string());
+    // an executable is linked where it goes, to keep it executable
+    if matches!(options.emit, Emit::Exe | Emit::Dylib) {
+        link::link(
+            &assembly,
+            &runtime(),
+            options.target,
+            options.pic,
+            options.emit == Emit::Dylib,
+            Path::new(&path),
+            &options.libraries,
+        )
+        .unwrap_or_else(|e| report(&sources, format, vec![e]));
+        timing::report();
+        return;
+    }
+    let contents = compiler
+        .emit(&assembly)
+        .unwrap_or_else(|e| report(&sources, format, vec![e]));
 
-    // write assembly to file
-    let mut output_file = std::fs::File::create("out.s").expect("Failed to create file");
-    output_file
-        .write_all(assembly.as_bytes())
-        .expect("Failed to write to file");
+    write(&path, &contents);
+    timing::report();
 }
@@ -0,0 +1,209 @@
+use std::path::Path;
+
+use crate::diagnostic::{Diagnostic, Result};
+
+// What `crust build` reads from zc.toml: the program to build, the files it
+// is compiled from and how. It is a small part of TOML, keys set to strings
+// or to arrays of strings, with `#` comments:
+//
+// name = "hello"
+// sources = ["main.cr", "list.cr"]
+// target = "aarch64-linux"
+// flags = ["-O1", "-lm"]
+pub struct Manifest {
+    pub name: String,
+    pub sources: Vec<String>,
+    pub target: Option<String>,
+    pub flags: Vec<String>,
+}
+
+enum Value {
+    String(String),
+    Array(Vec<String>),
+}
+
+struct Reader<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl Reader<'_> {
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, message: impl Into<String>) -> Diagnostic {
+        Diagnostic::new(message, self.line, self.column)
+    }
+
+    // spaces and comments, and the ends of lines too inside an array
+    fn blank(&mut self, newlines: bool) {
+        while let Some(c) = self.peek() {
+            match c {
+                '#' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.next();
+                    }
+                }
+                '\n' if !newlines => return,
+                c if c.is_whitespace() => {
+                    self.next();
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn key(&mut self) -> String {
+        let mut key = String::new();
+        while let Some(c) = self
+            .peek()
+            .filter(|c| c.is_alphanumeric() || "_-".contains(*c))
+        {
+            key.push(c);
+            self.next();
+        }
+        key
+    }
+
+    fn string(&mut self) -> Result<String> {
+        if self.next() != Some('"') {
+            return Err(self.error("Expected a string"));
+        }
+        let mut text = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(text),
+                Some('\\') => match self.next() {
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    Some(c @ ('"' | '\\')) => text.push(c),
+                    _ => return Err(self.error("Unknown escape in string")),
+                },
+                Some('\n') | None => return Err(self.error("Unterminated string")),
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        if self.peek() != Some('[') {
+            return self.string().map(Value::String);
+        }
+        self.next();
+        let mut items = Vec::new();
+        loop {
+            self.blank(true);
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.string()?);
+            self.blank(true);
+            match self.peek() {
+                Some(',') => {
+                    self.next();
+                }
+                Some(']') => {}
+                _ => return Err(self.error("Expected , or ] in array")),
+            }
+        }
+    }
+}
+
+impl Manifest {
+    // Reads the manifest from `text`, with errors pointing into it
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut reader = Reader {
+            chars: text.chars().peekable(),
+            line: 1,
+            column: 1,
+        };
+        let mut name = None;
+        let mut sources = None;
+        let mut target = None;
+        let mut flags = Vec::new();
+
+        loop {
+            reader.blank(true);
+            if reader.peek().is_none() {
+                break;
+            }
+            let (line, column) = (reader.line, reader.column);
+            let key = reader.key();
+            if key.is_empty() {
+                return Err(reader.error("Expected a key"));
+            }
+            reader.blank(false);
+            if reader.next() != Some('=') {
+                return Err(reader.error(format!("Expected = after {}", key)));
+            }
+            reader.blank(false);
+            let value = reader.value()?;
+            reader.blank(false);
+            if reader.peek().is_some_and(|c| c != '\n') {
+                return Err(reader.error("Expected the end of the line"));
+            }
+
+            let at = |message: String| {
+                Diagnostic::new(message, line, column).with_length(key.chars().count())
+            };
+            match (key.as_str(), value) {
+                ("name", Value::String(value)) => name = Some(value),
+                ("target", Value::String(value)) => target = Some(value),
+                ("sources", Value::Array(value)) => sources = Some(value),
+                ("flags", Value::Array(value)) => flags = value,
+                ("name" | "target", _) => return Err(at(format!("{} must be a string", key))),
+                ("sources" | "flags", _) => {
+                    return Err(at(format!("{} must be an array of strings", key)))
+                }
+                _ => {
+                    return Err(at(format!("Unknown key {}", key))
+                        .with_label("expected name, sources, target or flags"))
+                }
+            }
+        }
+
+        let name = name.ok_or_else(|| Diagnostic::error("The manifest has no name"))?;
+        let sources = match sources {
+            Some(sources) if !sources.is_empty() => sources,
+            _ => return Err(Diagnostic::error("The manifest lists no sources")),
+        };
+        Ok(Manifest {
+            name,
+            sources,
+            target,
+            flags,
+        })
+    }
+
+    // The command line it stands for. The sources and the output are named
+    // relative to the directory of the manifest, and an executable is built
+    // unless the flags say otherwise.
+    pub fn arguments(&self, directory: &Path) -> Vec<String> {
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+        let mut arguments = vec![
+            String::from("--emit=exe"),
+            String::from("-o"),
+            path(&self.name),
+        ];
+        if let Some(target) = &self.target {
+            arguments.push(format!("--target={}", target));
+        }
+        arguments.extend(self.flags.iter().cloned());
+        arguments.extend(self.sources.iter().map(|source| path(source)));
+        arguments
+    }
+}
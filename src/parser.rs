@@ -1,9 +1,15 @@
-use core::panic;
-use std::{cell::RefCell, rc::Rc};
+use std::{cell::RefCell, collections::VecDeque, ops::Range, rc::Rc};
 
 use crate::{
-    ast::{LiteralValue, Node},
+    ast::{is_arithmetic, Environment, LiteralValue, Node, Span},
+    attributes::{self, Argument, Attribute},
+    consteval,
+    diagnostic::{Diagnostic, Result},
+    fold,
+    intern::{intern, Name},
+    ir,
     lexer::{Literal, Token, TokenType},
+    symtable::SymbolTable,
     types::Type,
 };
 
@@ -11,6 +17,8 @@ use crate::{
 pub enum SymbolType {
     Function,
     Variable,
+    // a `const`, replaced by its value wherever it is used
+    Constant(u64),
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -23,163 +31,711 @@ pub enum StorageClass {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Symbol {
     pub identifier: Token,
+    // the name of the identifier, which the symbol table looks it up by
+    pub name: Name,
     pub structure: SymbolType,
     pub class: StorageClass,
     pub ty: Option<Type>,
-    pub end_label: Option<String>,
     pub size: Option<usize>,
     pub offset: Option<isize>,
     pub params: Option<Vec<Rc<RefCell<Symbol>>>>,
+    // which of the functions of the same name this is, when there are several
+    overload: Option<usize>,
+}
+
+impl Symbol {
+    // The name the function has in the assembly. Functions sharing a name
+    // are told apart by the order they are declared in, `area.0`, `area.1`.
+    pub fn link_name(&self) -> String {
+        let name = self.identifier.lexeme.unwrap().to_string();
+        match self.overload {
+            Some(overload) => format!("{}.{}", name, overload),
+            None => name,
+        }
+    }
+
+    // the types of the parameters of a function
+    fn signature(&self) -> Vec<Type> {
+        self.params
+            .iter()
+            .flatten()
+            .map(|param| param.borrow().ty.clone().unwrap())
+            .collect()
+    }
+}
+
+// How the operands of a binary operator make its node
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operands {
+    // widened or scaled to agree, the result has the type they agree on
+    Arithmetic,
+    // the result is a u8 whatever they are
+    Byte,
+    // the right one is stored in the left, which has to be a variable
+    Assignment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Associativity {
+    // a - b - c is (a - b) - c
+    Left,
+    // a = b = c is a = (b = c)
+    Right,
+}
+
+struct Operator {
+    token_type: TokenType,
+    // the higher, the tighter it binds
+    precedence: u8,
+    associativity: Associativity,
+    operands: Operands,
+}
+
+const fn operator(
+    token_type: TokenType,
+    precedence: u8,
+    associativity: Associativity,
+    operands: Operands,
+) -> Operator {
+    Operator {
+        token_type,
+        precedence,
+        associativity,
+        operands,
+    }
+}
+
+// The binary operators the expression parser knows, a new one only needs an
+// entry here
+const OPERATORS: &[Operator] = &[
+    operator(
+        TokenType::Assign,
+        1,
+        Associativity::Right,
+        Operands::Assignment,
+    ),
+    operator(
+        TokenType::LogicalAnd,
+        2,
+        Associativity::Left,
+        Operands::Byte,
+    ),
+    operator(TokenType::LogicalOr, 2, Associativity::Left, Operands::Byte),
+    operator(TokenType::Or, 3, Associativity::Left, Operands::Arithmetic),
+    operator(TokenType::Xor, 3, Associativity::Left, Operands::Arithmetic),
+    operator(
+        TokenType::Ampersand,
+        3,
+        Associativity::Left,
+        Operands::Arithmetic,
+    ),
+    operator(TokenType::Equal, 4, Associativity::Left, Operands::Byte),
+    operator(TokenType::NotEqual, 4, Associativity::Left, Operands::Byte),
+    operator(TokenType::LessThan, 5, Associativity::Left, Operands::Byte),
+    operator(
+        TokenType::LessThanOrEqual,
+        5,
+        Associativity::Left,
+        Operands::Byte,
+    ),
+    operator(
+        TokenType::GreaterThan,
+        5,
+        Associativity::Left,
+        Operands::Byte,
+    ),
+    operator(
+        TokenType::GreaterThanOrEqual,
+        5,
+        Associativity::Left,
+        Operands::Byte,
+    ),
+    operator(
+        TokenType::LeftShift,
+        6,
+        Associativity::Left,
+        Operands::Arithmetic,
+    ),
+    operator(
+        TokenType::RightShift,
+        6,
+        Associativity::Left,
+        Operands::Arithmetic,
+    ),
+    operator(TokenType::Add, 7, Associativity::Left, Operands::Arithmetic),
+    operator(TokenType::Sub, 7, Associativity::Left, Operands::Arithmetic),
+    operator(TokenType::Mul, 8, Associativity::Left, Operands::Arithmetic),
+    operator(TokenType::Div, 8, Associativity::Left, Operands::Arithmetic),
+];
+
+// A lambda being parsed, whose body is a function of its own
+struct Closure {
+    // the depth of the scope of its parameters, anything declared further
+    // out and not global is captured
+    scope: usize,
+    // each variable it captured, and the local standing for it in the body
+    // at the same place in copies
+    captures: Vec<Rc<RefCell<Symbol>>>,
+    copies: Vec<Rc<RefCell<Symbol>>>,
+    // the size of the locals of the function around it so far
+    offset: usize,
+}
+
+// what the variables a `let` declares are called in errors
+fn class_name(is_local: bool) -> &'static str {
+    match is_local {
+        true => "locals",
+        false => "globals",
+    }
+}
+
+// An integer widened to a wider type. Arithmetic is done in the wider type
+// instead, its operands widened, so that it gives what the wider type holds
+// and not the narrow result cut short, whatever the value is used for. A
+// division or a right shift that would change from signed to unsigned or
+// back only has its result widened, the operands would divide differently.
+fn widen(node: Node, ty: Type) -> Node {
+    match node {
+        Node::BinaryExpr {
+            left,
+            operator,
+            right,
+            ty: from,
+            span,
+        } if from.is_int()
+            && left.ty().as_ref() == Some(&from)
+            && right.ty().as_ref() == Some(&from)
+            && match operator.token_type {
+                TokenType::Add
+                | TokenType::Sub
+                | TokenType::Mul
+                | TokenType::Ampersand
+                | TokenType::Or
+                | TokenType::Xor
+                | TokenType::LeftShift => true,
+                TokenType::Div | TokenType::RightShift => from.is_signed() == ty.is_signed(),
+                _ => false,
+            } =>
+        {
+            Node::BinaryExpr {
+                left: Box::new(widen(*left, ty.clone())),
+                operator,
+                right: Box::new(widen(*right, ty.clone())),
+                ty,
+                span,
+            }
+        }
+        Node::UnaryExpr {
+            operator,
+            right,
+            ty: from,
+            span,
+        } if from.is_int()
+            && right.ty().as_ref() == Some(&from)
+            && matches!(operator.token_type, TokenType::Sub | TokenType::Invert) =>
+        {
+            Node::UnaryExpr {
+                operator,
+                right: Box::new(widen(*right, ty.clone())),
+                ty,
+                span,
+            }
+        }
+        node => Node::WidenExpr {
+            span: node.span(),
+            right: Box::new(node),
+            ty,
+        },
+    }
 }
 
 pub struct Parser {
-    tokens: Vec<Token>,
+    // where the tokens come from, they are read as parsing gets to them
+    source: Box<dyn Iterator<Item = Result<Token>>>,
+    // the tokens read so far and not dropped, kept for the second pass to
+    // go over again
+    tokens: VecDeque<Token>,
+    // how many tokens the second pass is done with and dropped, the index
+    // of the first of tokens
+    dropped: usize,
+    // the errors of the source the tokens came from
+    lexing: Vec<Diagnostic>,
     current: usize,
     nodes: Vec<Node>,
-    symbols: Vec<Rc<RefCell<Symbol>>>,
+    symbols: SymbolTable,
     current_fn: Option<Rc<RefCell<Symbol>>>,
     local_offset: usize,
+    diagnostics: Vec<Diagnostic>,
+    // every identifier that names a symbol, declarations included
+    references: Vec<(Token, Rc<RefCell<Symbol>>)>,
+    // the labels of the loops the parser is in, innermost last
+    loops: Vec<Option<Token>>,
+    // the lambdas the parser is in, innermost last
+    closures: Vec<Closure>,
+    // how many lambdas the function has so far, which names the next
+    lambdas: usize,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self {
-            tokens,
+        Self::streaming(tokens.into_iter().map(Ok))
+    }
+
+    // A parser that reads the tokens from the iterator one at a time, a
+    // lexer given as is lexes each of them only when it is needed. The
+    // errors among them are reported with those of parsing.
+    pub fn streaming(source: impl Iterator<Item = Result<Token>> + 'static) -> Self {
+        let mut symbols = SymbolTable::new();
+        // the builtin functions, which are declared nowhere in the source
+        let builtin = |name: &str, params: Vec<Type>, ty: Type| {
+            let identifier = |name: &str| Token {
+                token_type: TokenType::Identifier,
+                lexeme: Some(intern(name)),
+                line: 0,
+                column: 0,
+                value: None,
+                trivia: None,
+            };
+            Rc::new(RefCell::new(Symbol {
+                identifier: identifier(name),
+                name: intern(name),
+                structure: SymbolType::Function,
+                class: StorageClass::Global,
+                ty: Some(ty),
+                size: None,
+                offset: None,
+                params: Some(
+                    params
+                        .into_iter()
+                        .map(|param| {
+                            Rc::new(RefCell::new(Symbol {
+                                identifier: identifier("x"),
+                                name: intern("x"),
+                                structure: SymbolType::Variable,
+                                class: StorageClass::Param,
+                                ty: Some(param),
+                                size: None,
+                                offset: None,
+                                params: None,
+                                overload: None,
+                            }))
+                        })
+                        .collect(),
+                ),
+                overload: None,
+            }))
+        };
+        let pointer = Type::U8.pointer_to().unwrap();
+        let builtins = [
+            // a u32, a 64-bit integer or a pointer is printed whole, as
+            // printu64 or printi64 would, the others with C's %d
+            builtin("printint", vec![Type::U8], Type::U8),
+            builtin("printu64", vec![Type::U64], Type::U8),
+            builtin("printi64", vec![Type::I64], Type::U8),
+            // in hex, after 0x
+            builtin("printhex", vec![Type::U64], Type::U8),
+            builtin("printchar", vec![Type::U8], Type::U8),
+            // prints the NUL terminated string the argument points at
+            builtin("printstr", vec![Type::U8.pointer_to().unwrap()], Type::U8),
+            // a number from stdin, 0 when there is none
+            builtin("readint", Vec::new(), Type::U64),
+            // the next of a pseudo-random sequence, the same every run
+            builtin("rand", Vec::new(), Type::U64),
+            // milliseconds from some point in the past, for timing
+            builtin("clock_ms", Vec::new(), Type::U64),
+            // the C library's files: fopen gives a FILE, or null when the
+            // file can't be opened, and fread and fwrite the number of items
+            // they moved
+            builtin(
+                "fopen",
+                vec![pointer.clone(), pointer.clone()],
+                pointer.clone(),
+            ),
+            builtin(
+                "fread",
+                vec![pointer.clone(), Type::U64, Type::U64, pointer.clone()],
+                Type::U64,
+            ),
+            builtin(
+                "fwrite",
+                vec![pointer.clone(), Type::U64, Type::U64, pointer.clone()],
+                Type::U64,
+            ),
+            builtin("fclose", vec![pointer.clone()], Type::I32),
+            // ends the program with the status, it never returns
+            builtin("exit", vec![Type::U8], Type::U8),
+            // aborts, saying where it is, when the condition is false
+            builtin("assert", vec![Type::U8], Type::U8),
+            // the value of an environment variable, null when it isn't set
+            builtin(
+                "getenv",
+                vec![Type::U8.pointer_to().unwrap()],
+                Type::U8.pointer_to().unwrap(),
+            ),
+            // the smaller and the larger of two integers and the magnitude of
+            // one, of the type of their arguments, computed in place
+            builtin("min", vec![Type::I64, Type::I64], Type::I64),
+            builtin("max", vec![Type::I64, Type::I64], Type::I64),
+            builtin("abs", vec![Type::I64], Type::I64),
+            // the number of elements of an array, or of the bytes of a string
+            // up to its NUL
+            builtin("len", vec![pointer.clone()], Type::U64),
+        ];
+        for builtin in builtins {
+            symbols.insert(builtin);
+        }
+
+        let mut parser = Self {
+            source: Box::new(source),
+            tokens: VecDeque::new(),
+            dropped: 0,
+            lexing: Vec::new(),
             current: 0,
             nodes: Vec::new(),
-            symbols: vec![
-                // builtin functions
-                // add print function
-                Rc::new(RefCell::new(Symbol {
-                    identifier: Token {
-                        token_type: TokenType::Identifier,
-                        lexeme: Some(String::from("printint")),
-                        line: 0,
-                        column: 0,
-                        value: None,
-                    },
-                    structure: SymbolType::Function,
-                    class: StorageClass::Global,
-                    ty: Some(Type::U8),
-                    end_label: None,
-                    size: None,
-                    offset: None,
-                    params: Some(vec![Rc::new(RefCell::new(Symbol {
-                        identifier: Token {
-                            token_type: TokenType::Identifier,
-                            lexeme: Some(String::from("x")),
-                            line: 0,
-                            column: 0,
-                            value: None,
-                        },
-                        structure: SymbolType::Variable,
-                        class: StorageClass::Param,
-                        ty: Some(Type::U8),
-                        end_label: None,
-                        size: None,
-                        offset: None,
-                        params: None,
-                    }))]),
-                })),
-                Rc::new(RefCell::new(Symbol {
-                    identifier: Token {
-                        token_type: TokenType::Identifier,
-                        lexeme: Some(String::from("printchar")),
-                        line: 0,
-                        column: 0,
-                        value: None,
-                    },
-                    structure: SymbolType::Function,
-                    class: StorageClass::Global,
-                    ty: Some(Type::U8),
-                    end_label: None,
-                    size: None,
-                    offset: None,
-                    params: Some(vec![Rc::new(RefCell::new(Symbol {
-                        identifier: Token {
-                            token_type: TokenType::Identifier,
-                            lexeme: Some(String::from("x")),
-                            line: 0,
-                            column: 0,
-                            value: None,
-                        },
-                        structure: SymbolType::Variable,
-                        class: StorageClass::Param,
-                        ty: Some(Type::U8),
-                        end_label: None,
-                        size: None,
-                        offset: None,
-                        params: None,
-                    }))]),
-                })),
-            ],
+            symbols,
             current_fn: None,
             local_offset: 0,
+            diagnostics: Vec::new(),
+            references: Vec::new(),
+            loops: Vec::new(),
+            closures: Vec::new(),
+            lambdas: 0,
+        };
+        parser.read();
+        parser
+    }
+
+    pub fn parse(&mut self) -> std::result::Result<&Vec<Node>, Vec<Diagnostic>> {
+        self.declare();
+        self.define(0..self.dropped + self.tokens.len());
+
+        // what is parsed after a token lexed wrong is likely wrong too, only
+        // the lexer's errors are reported, as when all is lexed first
+        if !self.lexing.is_empty() {
+            return Err(self.lexing.clone());
+        }
+        if !self.diagnostics.is_empty() {
+            return Err(self.diagnostics.clone());
         }
+
+        Ok(&self.nodes)
     }
 
-    pub fn parse(&mut self) -> &Vec<Node> {
-        // first pass
+    // The first pass: the globals, the constants and the signatures of the
+    // functions, which their bodies are parsed against in the second
+    pub fn declare(&mut self) {
+        self.current = 0;
         while !self.is_at_end() {
-            if self.match_token(vec![TokenType::Let]) {
-                let node = self.var_decl(false);
-                self.expect(vec![TokenType::SemiColon]).unwrap();
-                self.nodes.push(node);
-            } else if self.check(TokenType::Fn) {
-                self.fn_decl(true);
+            let attributes = match self.attributes() {
+                Ok(attributes) => attributes,
+                Err(diagnostic) => {
+                    self.report(diagnostic);
+                    self.synchronize_item();
+                    continue;
+                }
+            };
+            if self.check(TokenType::Let) || self.check(TokenType::Extern) {
+                match self.global_var_decl(attributes) {
+                    Ok(nodes) => self.nodes.extend(nodes),
+                    Err(diagnostic) => {
+                        self.report(diagnostic);
+                        self.synchronize();
+                    }
+                }
+            } else if self.match_token(vec![TokenType::Const]) {
+                if let Err(diagnostic) = self.const_decl() {
+                    self.report(diagnostic);
+                    self.synchronize();
+                }
+            } else if self.check(TokenType::Fn) || self.check(TokenType::Pub) {
+                if let Err(diagnostic) = self.fn_decl(true, attributes) {
+                    self.report(diagnostic);
+                    self.synchronize_item();
+                }
             } else {
                 self.advance();
             }
         }
+    }
 
-        // second pass
-        self.current = 0;
-        while !self.is_at_end() {
-            // skip global variables since we already parsed it in the first pass
-            if self.match_token(vec![TokenType::Let]) {
-                while !self.match_token(vec![TokenType::SemiColon]) {
+    // The second pass over the items whose tokens are in the range, after
+    // the first pass over all of them. An editor parses only the functions
+    // an edit touched again this way. It says whether parsing stopped within
+    // the range, recovering from an error can carry it past the end. The
+    // tokens of the items before are dropped, so the ranges have to come in
+    // order.
+    pub fn define(&mut self, tokens: Range<usize>) -> bool {
+        self.current = tokens.start;
+        while self.current < tokens.end && !self.is_at_end() {
+            self.drop_tokens();
+            let attributes = match self.attributes() {
+                Ok(attributes) => attributes,
+                Err(diagnostic) => {
+                    self.report(diagnostic);
+                    self.synchronize_item();
+                    continue;
+                }
+            };
+            // skip globals and constants since we already parsed them in the first pass
+            if self.match_token(vec![TokenType::Let, TokenType::Const, TokenType::Extern]) {
+                while !self.match_token(vec![TokenType::SemiColon]) && !self.is_at_end() {
                     self.advance();
                 }
                 continue;
             }
 
-            let node = self.fn_decl(false).unwrap();
-            self.nodes.push(node);
+            match self.fn_decl(false, attributes) {
+                Ok(Some(node)) => self.nodes.push(node),
+                Ok(None) => {}
+                Err(diagnostic) => {
+                    self.report(diagnostic);
+                    self.synchronize_item();
+                }
+            }
         }
+        self.current <= tokens.end
+    }
 
+    // the globals the first pass found and the functions the second parsed
+    pub fn nodes(&self) -> &[Node] {
         &self.nodes
     }
 
+    // the errors found so far, in both passes
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    // what the compilation unit declares, valid once parsing is done
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    // which symbol each identifier in the source stands for, as far as the
+    // parser got, for an editor to look up what is under the cursor
+    pub fn references(&self) -> &[(Token, Rc<RefCell<Symbol>>)] {
+        &self.references
+    }
+
+    // the globals a `let` declares, each placed as its attributes say
+    // `let` or `extern let` at the top level, an extern global being one
+    // the program uses but something it is linked with defines
+    fn global_var_decl(&mut self, attributes: Vec<Attribute>) -> Result<Vec<Node>> {
+        let external = self.match_token(vec![TokenType::Extern]);
+        if external && !self.check(TokenType::Let) {
+            return Err(Diagnostic::at(&self.peek(), "Only globals can be extern")
+                .with_label("expected let"));
+        }
+        self.expect(vec![TokenType::Let])?;
+        let mut nodes = match self.var_decl(false)? {
+            Node::CompoundStmt { statements, .. } => statements,
+            node => vec![node],
+        };
+        for node in &mut nodes {
+            if let Node::VarDecl {
+                symbol,
+                init: Some(_),
+                ..
+            } = node
+            {
+                if external {
+                    let identifier = symbol.borrow().identifier.clone();
+                    return Err(Diagnostic::at(
+                        &identifier,
+                        format!(
+                            "Extern global {} cannot be initialized",
+                            identifier.lexeme.unwrap()
+                        ),
+                    )
+                    .with_label("it is defined elsewhere"));
+                }
+            }
+            if let Node::VarDecl {
+                attributes: a,
+                external: e,
+                ..
+            }
+            | Node::VarDeclMany {
+                attributes: a,
+                external: e,
+                ..
+            } = node
+            {
+                *a = attributes.clone();
+                *e = external;
+            }
+        }
+        self.expect(vec![TokenType::SemiColon])?;
+        Ok(nodes)
+    }
+
+    // `const NAME: type = expression;`, whose value has to be known at compile
+    // time and fit in the type
+    fn const_decl(&mut self) -> Result<()> {
+        let identifier = self.expect(vec![TokenType::Identifier])?;
+        self.expect(vec![TokenType::Colon])?;
+        let ty = self.parse_type()?;
+        if !ty.is_int() {
+            return Err(
+                Diagnostic::at(&identifier, "Only integers can be constants")
+                    .with_label(format!("{} is not an integer", ty)),
+            );
+        }
+        self.expect(vec![TokenType::Assign])?;
+        let value = self.constant(&ty)?;
+        self.expect(vec![TokenType::SemiColon])?;
+
+        self.add_symbol(
+            identifier,
+            SymbolType::Constant(value),
+            StorageClass::Global,
+            Some(ty),
+            None,
+            None,
+        )?;
+        Ok(())
+    }
+
+    // The value of an expression that makes up all the tokens, the
+    // condition of `#if`, which is constant like the value of a `const`
+    pub fn directive_condition(&mut self) -> Result<u64> {
+        let start = self.peek();
+        let expr = self.expression()?;
+        if !self.is_at_end() {
+            return Err(
+                Diagnostic::at(&self.peek(), "The condition ends before this")
+                    .with_label("unexpected token"),
+            );
+        }
+        consteval::evaluate(&expr, &start)
+    }
+
+    // a constant expression stored as a value of the type
+    fn constant(&mut self, ty: &Type) -> Result<u64> {
+        let start = self.peek();
+        let expr = self.expression()?;
+        let value = consteval::evaluate(&expr, &start)?;
+        if ir::cast(value, ty) != value {
+            let shown = if ty.is_signed() {
+                (value as i64).to_string()
+            } else {
+                value.to_string()
+            };
+            return Err(Diagnostic::at(
+                &start,
+                format!("Constant {} does not fit in {}", shown, ty),
+            )
+            .with_label(format!("out of range for {}", ty)));
+        }
+        Ok(value)
+    }
+
+    fn report(&mut self, diagnostic: Diagnostic) {
+        // function headers are parsed in both passes, don't report their errors twice
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    // skip to the end of the broken statement: after a `;`, or before a `}` or `fn`
+    // skip the rest of a statement, with the blocks in it, so an error in the
+    // header of an `if` or a `while` skips its body and `else` too
+    fn synchronize(&mut self) {
+        let mut depth = 0;
+        while !self.is_at_end() {
+            if depth == 0 && self.match_token(vec![TokenType::SemiColon]) {
+                return;
+            }
+
+            if self.check(TokenType::Fn) || (depth == 0 && self.check(TokenType::RightBrace)) {
+                return;
+            }
+
+            match self.advance().token_type {
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace => {
+                    depth -= 1;
+                    if depth == 0 && !self.check(TokenType::Else) {
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // skip to the start of the next function
+    fn synchronize_item(&mut self) {
+        if self.check(TokenType::Fn) {
+            self.advance();
+        }
+
+        while !self.is_at_end() && !self.check(TokenType::Fn) {
+            self.advance();
+        }
+    }
+
     fn is_at_end(&self) -> bool {
         self.peek().token_type == TokenType::Eof
     }
 
-    fn compound_statement(&mut self) -> Node {
+    fn compound_statement(&mut self) -> Result<Node> {
+        self.symbols.enter_scope();
+        let node = self.block();
+        self.symbols.exit_scope();
+        node
+    }
+
+    fn block(&mut self) -> Result<Node> {
+        let start = self.current;
         let mut nodes = Vec::new();
 
-        self.expect(vec![TokenType::LeftBrace]).unwrap();
+        self.expect(vec![TokenType::LeftBrace])?;
 
-        while !self.check(TokenType::RightBrace) && !self.is_at_end() {
-            let node = self.single_statement();
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::Fn) && !self.is_at_end()
+        {
+            let declaration = self.check(TokenType::Let);
+            let node = match self.single_statement() {
+                Ok(node) => node,
+                Err(diagnostic) => {
+                    self.report(diagnostic);
+                    self.synchronize();
+                    continue;
+                }
+            };
             match node {
+                _ if declaration => {
+                    if let Err(diagnostic) = self.expect(vec![TokenType::SemiColon]) {
+                        self.report(diagnostic);
+                    }
+                }
                 Node::AssignStmt { .. }
+                | Node::TupleAssign { .. }
                 | Node::VarDecl { .. }
                 | Node::VarDeclMany { .. }
                 | Node::FnCall { .. }
-                | Node::ReturnStmt { .. } => {
-                    self.expect(vec![TokenType::SemiColon]).unwrap();
+                | Node::ReturnStmt { .. }
+                | Node::BreakStmt { .. }
+                | Node::ContinueStmt { .. } => {
+                    // the statement itself is fine, so keep going without skipping anything
+                    if let Err(diagnostic) = self.expect(vec![TokenType::SemiColon]) {
+                        self.report(diagnostic);
+                    }
                 }
                 _ => {}
             }
             nodes.push(node);
         }
 
-        self.expect(vec![TokenType::RightBrace]).unwrap();
+        self.expect(vec![TokenType::RightBrace])?;
 
-        Node::CompoundStmt { statements: nodes }
+        Ok(Node::CompoundStmt {
+            statements: nodes,
+            span: self.span(start),
+        })
     }
 
-    fn single_statement(&mut self) -> Node {
+    fn single_statement(&mut self) -> Result<Node> {
         if self.match_token(vec![TokenType::Let]) {
             self.var_decl(true)
         // } else if self.match_token(vec![TokenType::Identifier]) {
@@ -187,51 +743,86 @@ impl Parser {
         } else if self.match_token(vec![TokenType::If]) {
             self.if_statement()
         } else if self.match_token(vec![TokenType::While]) {
-            self.while_statement()
+            self.while_statement(None)
         } else if self.match_token(vec![TokenType::For]) {
-            self.for_statement()
-        } else if self.match_token(vec![TokenType::Fn]) {
-            self.fn_decl(false).unwrap()
+            self.for_statement(None)
+        } else if self.match_token(vec![TokenType::Label]) {
+            let label = self.previous(1);
+            self.expect(vec![TokenType::Colon])?;
+            match self
+                .expect(vec![TokenType::While, TokenType::For])?
+                .token_type
+            {
+                TokenType::While => self.while_statement(Some(label)),
+                _ => self.for_statement(Some(label)),
+            }
+        } else if self.match_token(vec![TokenType::Break, TokenType::Continue]) {
+            self.jump_statement()
+        } else if self.check(TokenType::Fn) {
+            let token = self.peek();
+            Err(Diagnostic::at(&token, "Nested functions are not supported"))
         } else if self.match_token(vec![TokenType::Return]) {
             self.return_statement()
         } else {
-            self.expression()
+            let start = self.current;
+            let expr = self.expression()?;
+            if self.check(TokenType::Comma) {
+                return self.tuple_assignment(start, expr);
+            }
+            Ok(expr)
         }
     }
 
-    fn parse_type(&mut self) -> Type {
+    fn parse_type(&mut self) -> Result<Type> {
         // a type of a variable is like these examples:
         // let x: int;
         // let y: u8;
         // let z: *u32; // pointer to u32
         // let a: **int; // pointer to pointer to int
 
+        // `fn(u32, u8): u64`, a closure taking the one and giving the other
+        if self.match_token(vec![TokenType::Fn]) {
+            self.expect(vec![TokenType::LeftParen])?;
+            let mut params = Vec::new();
+            while !self.check(TokenType::RightParen) {
+                params.push(self.parse_type()?);
+                if !self.match_token(vec![TokenType::Comma]) {
+                    break;
+                }
+            }
+            self.expect(vec![TokenType::RightParen])?;
+            self.expect(vec![TokenType::Colon])?;
+            let ret = Box::new(self.parse_type()?);
+            return Ok(Type::Closure { params, ret });
+        }
+
         let mut pointers_counter: u8 = 0;
         while self.match_token(vec![TokenType::Mul]) {
             pointers_counter += 1
         }
 
-        let ty_token = self
-            .expect(vec![
-                TokenType::U8,
-                TokenType::U16,
-                TokenType::U32,
-                TokenType::U64,
-                TokenType::I8,
-                TokenType::I16,
-                TokenType::I32,
-                TokenType::I64,
-                TokenType::Char,
-            ])
-            .unwrap();
+        let ty_token = self.expect(vec![
+            TokenType::U8,
+            TokenType::U16,
+            TokenType::U32,
+            TokenType::U64,
+            TokenType::I8,
+            TokenType::I16,
+            TokenType::I32,
+            TokenType::I64,
+            TokenType::Char,
+        ])?;
 
         let (is_array, size) = if self.match_token(vec![TokenType::LeftBracket]) {
-            let size_token = self.expect(vec![TokenType::Integer]).unwrap();
-            let size = match size_token.value {
-                Some(Literal::Integer(val)) => val,
-                _ => panic!("Expected integer"),
-            };
-            self.expect(vec![TokenType::RightBracket]).unwrap();
+            // the size can be any constant expression, `u8[N * 2]`
+            let start = self.peek();
+            let expr = self.expression()?;
+            let size = consteval::evaluate(&expr, &start)?;
+            if (size as i64) < 0 {
+                return Err(Diagnostic::at(&start, "Array size cannot be negative")
+                    .with_label(format!("{} elements", size as i64)));
+            }
+            self.expect(vec![TokenType::RightBracket])?;
             (true, size)
         } else {
             (false, 0)
@@ -247,11 +838,13 @@ impl Parser {
             TokenType::I32 => Type::I32,
             TokenType::I64 => Type::I64,
             TokenType::Char => Type::Char,
-            _ => panic!("Expected type"),
+            _ => return Err(Diagnostic::at(&ty_token, "Expected type")),
         };
 
         for _ in 0..pointers_counter {
-            ty = ty.pointer_to();
+            ty = ty
+                .pointer_to()
+                .ok_or_else(|| Diagnostic::at(&ty_token, "Cannot take pointer of type"))?;
         }
 
         if is_array {
@@ -261,10 +854,40 @@ impl Parser {
             };
         }
 
-        ty
+        Ok(ty)
+    }
+
+    // after the `let`
+    // `let` and what follows it, each name with the type after it, and
+    // groups of names of other types separated by commas:
+    // `let a, b: u8, c: u32 = 2;`. A local with an initial value is given it
+    // by an assignment right after it is declared, so a declaration of more
+    // than one group, or with one, is a CompoundStmt of all of them.
+    fn var_decl(&mut self, is_local: bool) -> Result<Node> {
+        let start = self.current - 1;
+        if is_local && self.match_token(vec![TokenType::LeftParen]) {
+            return self.destructuring(start);
+        }
+        let mut nodes = Vec::new();
+        loop {
+            self.declarators(is_local, &mut nodes)?;
+            if !self.match_token(vec![TokenType::Comma]) {
+                break;
+            }
+        }
+
+        match nodes.len() {
+            1 => Ok(nodes.pop().unwrap()),
+            _ => Ok(Node::CompoundStmt {
+                statements: nodes,
+                span: self.span(start),
+            }),
+        }
     }
 
-    fn var_decl(&mut self, is_local: bool) -> Node {
+    // names sharing a type, and the value the last of them starts with
+    fn declarators(&mut self, is_local: bool, nodes: &mut Vec<Node>) -> Result<()> {
+        let start = self.current;
         let mut identifiers = Vec::new();
         let class = if is_local {
             StorageClass::Local
@@ -279,299 +902,387 @@ impl Parser {
                 break;
             }
         }
-        self.expect(vec![TokenType::Colon]).unwrap();
-        let ty = self.parse_type();
+        self.expect(vec![TokenType::Colon])?;
+        let ty = self.parse_type()?;
+
+        // A global takes a constant, which the program starts with, and a
+        // local any expression. Either is parsed before the name is declared,
+        // so the name in it is still the one from outside.
+        let mut init = None;
+        let mut value = None;
+        let mut failed = None;
+        if self.match_token(vec![TokenType::Assign]) {
+            let assign = self.previous(1);
+            if identifiers.len() != 1 {
+                return Err(Diagnostic::at(
+                    &assign,
+                    format!("Cannot initialize several {} at once", class_name(is_local)),
+                )
+                .with_label("give each its own type and value"));
+            }
+            if is_local && matches!(ty, Type::Array { .. }) {
+                return Err(Diagnostic::at(&assign, "Arrays cannot be initialized")
+                    .with_label(format!("{} is an array", ty)));
+            }
+            if !is_local && !ty.is_int() {
+                return Err(
+                    Diagnostic::at(&assign, "Only integer globals can be initialized")
+                        .with_label(format!("{} is not an integer", ty)),
+                );
+            }
+            if is_local {
+                // a local whose value is wrong is still declared, so its uses
+                // don't fail as well
+                match self
+                    .expression()
+                    .and_then(|expr| self.coerce(expr, ty.clone(), &assign))
+                {
+                    Ok(expr) => value = Some((expr, assign)),
+                    Err(diagnostic) => failed = Some(diagnostic),
+                }
+            } else {
+                init = Some(self.constant(&ty)?);
+            }
+        }
 
-        if identifiers.clone().len() == 1 {
+        let mut symbols = Vec::new();
+        for identifier in &identifiers {
             let offset = if is_local {
                 Some(self.gen_offset(ty.clone()))
             } else {
                 None
             };
 
-            let structure = SymbolType::Variable;
-            let symbol = self.add_symbol(
-                identifiers[0].clone(),
-                structure,
+            symbols.push(self.add_symbol(
+                identifier.clone(),
+                SymbolType::Variable,
                 class.clone(),
                 Some(ty.clone()),
-                None,
                 offset,
                 None,
-            );
+            )?);
+        }
+        if let Some(diagnostic) = failed {
+            return Err(diagnostic);
+        }
 
-            Node::VarDecl {
-                symbol,
+        let span = self.span(start);
+        if symbols.len() == 1 {
+            let symbol = symbols.pop().unwrap();
+            nodes.push(Node::VarDecl {
+                symbol: symbol.clone(),
                 is_local,
                 ty: ty.clone(),
-            }
-        } else {
-            let mut symbols = Vec::new();
-            for identifier in &identifiers {
-                let offset = if is_local {
-                    Some(self.gen_offset(ty.clone()))
-                } else {
-                    None
+                attributes: Vec::new(),
+                external: false,
+                init,
+                span,
+            });
+
+            if let Some((expr, assign)) = value {
+                let left = Node::LiteralExpr {
+                    value: LiteralValue::Identifier(symbol),
+                    ty,
+                    span: Span::of(&identifiers[0]),
                 };
-
-                symbols.push(self.add_symbol(
-                    identifier.clone(),
-                    SymbolType::Variable,
-                    class.clone(),
-                    Some(ty.clone()),
-                    None,
-                    offset,
-                    None,
-                ));
+                self.assignable(&left, &assign)?;
+                nodes.push(Node::AssignStmt {
+                    left: Box::new(left),
+                    expr: Box::new(expr),
+                    span,
+                });
             }
-
-            Node::VarDeclMany {
+        } else {
+            nodes.push(Node::VarDeclMany {
                 symbols,
                 is_local,
-                ty: ty.clone(),
-            }
+                ty,
+                attributes: Vec::new(),
+                external: false,
+                span,
+            });
         }
+        Ok(())
     }
 
-    fn if_statement(&mut self) -> Node {
-        self.expect(vec![TokenType::LeftParen]).unwrap();
-        let mut expr = self.expression();
-        match &expr {
-            Node::BinaryExpr { operator, .. } => {
-                if operator.token_type != TokenType::Equal
-                    && operator.token_type != TokenType::NotEqual
-                    && operator.token_type != TokenType::LessThan
-                    && operator.token_type != TokenType::LessThanOrEqual
-                    && operator.token_type != TokenType::GreaterThan
-                    && operator.token_type != TokenType::GreaterThanOrEqual
-                {
-                    panic!(
-                        "Expected comparison operator at line {} column {}",
-                        operator.line, operator.column
-                    );
-                }
-            }
-            // _ => panic!(
-            //     "Expected comparison operator but got {:?} at line {} column {}",
-            //     expr,
-            //     self.previous(1).line,
-            //     self.previous(1).column
-            // ),
-            _ => {
-                expr = Node::ToBool {
-                    expr: Box::new(expr),
-                };
+    // `let (q, r) = divmod(x, y)` after the `(`, a local for each of the
+    // values of the call, of the type of its value
+    fn destructuring(&mut self, start: usize) -> Result<Node> {
+        let mut identifiers = Vec::new();
+        loop {
+            identifiers.push(self.expect(vec![TokenType::Identifier])?);
+            if !self.match_token(vec![TokenType::Comma]) {
+                break;
             }
         }
-        self.expect(vec![TokenType::RightParen]).unwrap();
-        let then_branch = self.compound_statement();
+        self.expect(vec![TokenType::RightParen])?;
+        let operator = self.expect(vec![TokenType::Assign])?;
+
+        // parsed before the names are declared, like any initializer
+        let call = self.expression()?;
+        let types = self.tuple_values(&call, &operator, identifiers.len())?;
+
+        let mut nodes = Vec::new();
+        let mut targets = Vec::new();
+        for (identifier, ty) in identifiers.into_iter().zip(&types) {
+            let offset = self.gen_offset(ty.clone());
+            let symbol = self.add_symbol(
+                identifier.clone(),
+                SymbolType::Variable,
+                StorageClass::Local,
+                Some(ty.clone()),
+                Some(offset),
+                None,
+            )?;
+            nodes.push(Node::VarDecl {
+                symbol: symbol.clone(),
+                is_local: true,
+                ty: ty.clone(),
+                attributes: Vec::new(),
+                external: false,
+                init: None,
+                span: Span::of(&identifier),
+            });
+            targets.push(Node::LiteralExpr {
+                value: LiteralValue::Identifier(symbol),
+                ty: ty.clone(),
+                span: Span::of(&identifier),
+            });
+        }
+        nodes.push(self.tuple_assign(start, call, types, targets, &operator)?);
+
+        Ok(Node::CompoundStmt {
+            statements: nodes,
+            span: self.span(start),
+        })
+    }
+
+    fn if_statement(&mut self) -> Result<Node> {
+        let start = self.current - 1;
+        self.expect(vec![TokenType::LeftParen])?;
+        let expr = self.condition()?;
+        self.expect(vec![TokenType::RightParen])?;
+        let then_branch = self.compound_statement()?;
         let else_branch = if self.match_token(vec![TokenType::Else]) {
-            Some(Box::new(self.compound_statement()))
+            Some(Box::new(self.compound_statement()?))
         } else {
             None
         };
 
-        Node::IfStmt {
+        Ok(Node::IfStmt {
             condition: Box::new(expr),
             then_branch: Box::new(then_branch),
             else_branch,
-        }
+            span: self.span(start),
+        })
     }
 
-    fn expression(&mut self) -> Node {
-        self.logical_expr()
+    fn expression(&mut self) -> Result<Node> {
+        self.binary(0)
     }
 
-    fn logical_expr(&mut self) -> Node {
-        let mut node = self.bitwise_expr();
-
-        while self.match_token(vec![TokenType::LogicalAnd, TokenType::LogicalOr]) {
-            let operator = self.previous(1);
-            let right = self.bitwise_expr();
-            node = Node::BinaryExpr {
-                left: Box::new(node),
-                operator,
-                right: Box::new(right),
-                ty: Type::U8,
-            };
-        }
-
-        node
-    }
+    // Parses operands joined by the binary operators in OPERATORS that bind
+    // at least as tightly as the precedence, climbing to the tighter ones
+    // for the right operand
+    fn binary(&mut self, precedence: u8) -> Result<Node> {
+        let start = self.current;
+        let mut left = self.unary()?;
 
-    fn bitwise_expr(&mut self) -> Node {
-        let mut node = self.equality();
+        while let Some(operator) = OPERATORS
+            .iter()
+            .find(|operator| operator.precedence >= precedence && self.check(operator.token_type))
+        {
+            let token = self.advance();
+            if operator.operands == Operands::Assignment {
+                self.assignable(&left, &token)?;
+            }
 
-        while self.match_token(vec![TokenType::Or, TokenType::Xor, TokenType::Ampersand]) {
-            let operator = self.previous(1);
-            let right = self.equality();
-            node = Node::BinaryExpr {
-                left: Box::new(node),
-                operator,
-                right: Box::new(right),
-                ty: Type::U8,
+            let right = self.binary(match operator.associativity {
+                Associativity::Left => operator.precedence + 1,
+                Associativity::Right => operator.precedence,
+            })?;
+            left = match operator.operands {
+                Operands::Arithmetic => self.binary_arith(left, token, right)?,
+                Operands::Byte => Node::BinaryExpr {
+                    left: Box::new(left),
+                    operator: token,
+                    right: Box::new(right),
+                    ty: Type::U8,
+                    span: self.span(start),
+                },
+                Operands::Assignment => {
+                    let ty = self.node_type(&left, &token)?;
+                    let expr = self.coerce(right, ty, &token)?;
+                    Node::AssignStmt {
+                        left: Box::new(left),
+                        expr: Box::new(expr),
+                        span: self.span(start),
+                    }
+                }
             };
         }
 
-        node
+        Ok(left)
     }
 
-    fn equality(&mut self) -> Node {
-        let mut node = self.comparison();
-
-        while self.match_token(vec![TokenType::Equal, TokenType::NotEqual]) {
-            let operator = self.previous(1);
-            let right = self.comparison();
-            node = Node::BinaryExpr {
-                left: Box::new(node),
-                operator,
-                right: Box::new(right),
-                ty: Type::U8,
-            };
+    // only a variable or what a pointer points at can be assigned to
+    fn assignable(&self, left: &Node, operator: &Token) -> Result<()> {
+        match left {
+            Node::LiteralExpr {
+                value: LiteralValue::Identifier(_),
+                ..
+            } => Ok(()),
+            Node::UnaryExpr { operator, .. } if operator.token_type == TokenType::Mul => Ok(()),
+            _ => Err(Diagnostic::at(operator, "Cannot assign to this expression")
+                .with_label("left side is not a variable")),
         }
-
-        node
     }
 
-    fn comparison(&mut self) -> Node {
-        let mut node = self.shift();
+    // convert a value to the type it is stored as, only widening is done implicitly
+    fn coerce(&self, expr: Node, ty: Type, token: &Token) -> Result<Node> {
+        let expr_ty = self.node_type(&expr, token)?;
 
-        while self.match_token(vec![
-            TokenType::LessThan,
-            TokenType::LessThanOrEqual,
-            TokenType::GreaterThan,
-            TokenType::GreaterThanOrEqual,
-        ]) {
-            let operator = self.previous(1);
-            let right = self.shift();
-            node = Node::BinaryExpr {
-                left: Box::new(node),
-                operator,
-                right: Box::new(right),
-                ty: Type::U8,
-            };
+        // a closure only goes where one of the same parameters and result does
+        if let Type::Closure { .. } = ty {
+            if expr_ty == ty {
+                return Ok(expr);
+            }
         }
 
-        node
-    }
-
-    fn shift(&mut self) -> Node {
-        let mut node = self.term();
-
-        while self.match_token(vec![TokenType::LeftShift, TokenType::RightShift]) {
-            let operator = self.previous(1);
-            let right = self.term();
-            node = Node::BinaryExpr {
-                left: Box::new(node),
-                operator,
-                right: Box::new(right),
-                ty: Type::U8,
-            };
+        // but a lambda makes closures of any result its body widens to, which
+        // a call through one widens the result of the body to
+        if let (
+            Node::Lambda {
+                function,
+                captures,
+                span,
+                ..
+            },
+            Type::Closure { params, ret },
+            Type::Closure {
+                params: from_params,
+                ret: from_ret,
+            },
+        ) = (&expr, &ty, &expr_ty)
+        {
+            if params == from_params
+                && ret.is_int()
+                && from_ret.is_int()
+                && from_ret.size() <= ret.size()
+            {
+                return Ok(Node::Lambda {
+                    function: function.clone(),
+                    captures: captures.clone(),
+                    ty,
+                    span: *span,
+                });
+            }
         }
 
-        node
-    }
-
-    fn term(&mut self) -> Node {
-        let mut left = self.factor();
-
-        while self.match_token(vec![TokenType::Add, TokenType::Sub]) {
-            let operator = self.previous(1);
-            let mut right = self.factor();
-
-            let temp_left =
-                self.modify_type(left.clone(), right.ty().unwrap(), Some(operator.token_type));
-
-            let temp_right =
-                self.modify_type(right.clone(), left.ty().unwrap(), Some(operator.token_type));
-
-            if temp_left.is_none() && temp_right.is_none() {
-                panic!(
-                    "Incompatible types at line {} column {}",
-                    operator.line, operator.column
-                );
+        // a string or array can be stored in a pointer to its elements
+        if let (
+            Type::Array { ty: element, .. },
+            Type::Pointer {
+                ty: pointee,
+                count: 1,
+            },
+        ) = (&expr_ty, &ty)
+        {
+            if element.is_int() && pointee.is_int() && element.size() == pointee.size() {
+                return Ok(expr);
             }
+        }
 
-            if temp_left.is_some() {
-                left = temp_left.unwrap();
-            }
+        if let Some(node) = self.modify_type(expr.clone(), ty.clone(), None) {
+            return Ok(node);
+        }
 
-            if temp_right.is_some() {
-                right = temp_right.unwrap();
+        if expr_ty.is_int() && ty.is_int() && expr_ty.size() == ty.size() {
+            // a constant such as `5` or `0 - 1` keeps its number in either
+            if let Ok(value) = consteval::evaluate(&expr, token) {
+                if ir::cast(value, &ty) == value {
+                    return Ok(expr);
+                }
             }
+            return Err(Diagnostic::at(
+                token,
+                format!("Signedness change from {} to {}", expr_ty, ty),
+            )
+            .with_label(format!("expected {}, found {}", ty, expr_ty)));
+        }
 
-            left = Node::BinaryExpr {
-                left: Box::new(left.clone()),
-                operator,
-                right: Box::new(right),
-                ty: left.ty().unwrap(),
-            };
+        if expr_ty.is_int() && ty.is_int() {
+            return Err(Diagnostic::at(
+                token,
+                format!("Narrowing conversion from {} to {}", expr_ty, ty),
+            )
+            .with_label(format!("expected {}, found {}", ty, expr_ty)));
         }
 
-        left
+        Err(Diagnostic::at(token, "Incompatible types")
+            .with_label(format!("expected {}, found {}", ty, expr_ty)))
     }
 
-    fn factor(&mut self) -> Node {
-        let mut left = self.unary();
-
-        while self.match_token(vec![TokenType::Mul, TokenType::Div]) {
-            let operator = self.previous(1);
-            let mut right = self.unary();
-
-            let temp_left =
-                self.modify_type(left.clone(), right.ty().unwrap(), Some(operator.token_type));
+    // widen/scale the operands of an arithmetic operator so both sides agree
+    fn binary_arith(&self, left: Node, operator: Token, right: Node) -> Result<Node> {
+        let left_ty = self.node_type(&left, &operator)?;
+        let right_ty = self.node_type(&right, &operator)?;
 
-            let temp_right =
-                self.modify_type(right.clone(), left.ty().unwrap(), Some(operator.token_type));
+        let temp_left = self.modify_type(left.clone(), right_ty, Some(operator.token_type));
+        let temp_right = self.modify_type(right.clone(), left_ty, Some(operator.token_type));
 
-            if temp_left.is_none() && temp_right.is_none() {
-                panic!(
-                    "Incompatible types at line {} column {}",
-                    operator.line, operator.column
-                );
-            }
+        if temp_left.is_none() && temp_right.is_none() {
+            return Err(
+                Diagnostic::at(&operator, "Incompatible types").with_label("mismatched types")
+            );
+        }
 
-            if temp_left.is_some() {
-                left = temp_left.unwrap();
-            }
+        let left = temp_left.unwrap_or(left);
+        let right = temp_right.unwrap_or(right);
+        let ty = self.node_type(&left, &operator)?;
+        let span = left.span().to(right.span());
 
-            if temp_right.is_some() {
-                right = temp_right.unwrap();
-            }
-
-            left = Node::BinaryExpr {
-                left: Box::new(left.clone()),
-                operator,
-                right: Box::new(right),
-                ty: left.ty().unwrap(),
-            };
-        }
+        Ok(Node::BinaryExpr {
+            left: Box::new(left),
+            operator,
+            right: Box::new(right),
+            ty,
+            span,
+        })
+    }
 
-        left
+    fn node_type(&self, node: &Node, token: &Token) -> Result<Type> {
+        node.ty()
+            .ok_or_else(|| Diagnostic::at(token, "Expected an expression with a value"))
     }
 
-    fn unary(&mut self) -> Node {
+    fn unary(&mut self) -> Result<Node> {
+        let start = self.current;
         if self.match_token(vec![
             TokenType::Sub,
             TokenType::LogicalNot,
             TokenType::Invert,
         ]) {
             let operator = self.previous(1);
-            let right = self.unary();
-            return Node::UnaryExpr {
+            let right = self.unary()?;
+            let ty = self.node_type(&right, &operator)?;
+            return Ok(Node::UnaryExpr {
                 operator,
-                right: Box::new(right.clone()),
-                ty: right.ty().unwrap(),
-            };
+                right: Box::new(right),
+                ty,
+                span: self.span(start),
+            });
         }
 
         self.prefix()
     }
 
-    fn prefix(&mut self) -> Node {
+    fn prefix(&mut self) -> Result<Node> {
+        let start = self.current;
         let mut node: Node;
         if self.match_token(vec![TokenType::Ampersand]) {
-            node = self.prefix();
+            let operator = self.previous(1);
+            node = self.prefix()?;
 
             // ensure that the node is an identifier
             match &node {
@@ -579,71 +1290,55 @@ impl Parser {
                     value: LiteralValue::Identifier(_),
                     ..
                 } => {}
-                _ => panic!("Expected identifier"),
+                _ => return Err(Diagnostic::at(&operator, "Expected identifier")),
             }
 
+            let ty = self
+                .node_type(&node, &operator)?
+                .pointer_to()
+                .ok_or_else(|| Diagnostic::at(&operator, "Cannot take address of this type"))?;
             node = Node::UnaryExpr {
-                operator: Token {
-                    token_type: TokenType::Ampersand,
-                    lexeme: None,
-                    line: self.previous(1).line,
-                    column: self.previous(1).column,
-                    value: None,
-                },
-                right: Box::new(node.clone()),
-                ty: node.ty().unwrap().pointer_to(),
+                operator,
+                right: Box::new(node),
+                ty,
+                span: self.span(start),
             };
         } else if self.match_token(vec![TokenType::Mul]) {
-            node = self.prefix();
+            let operator = self.previous(1);
+            node = self.prefix()?;
 
             // ensure that the node is an identifier or a dereference
             match &node {
                 Node::LiteralExpr { value, .. } => match value {
                     LiteralValue::Identifier(_) => {}
-                    _ => panic!("Expected identifier"),
+                    _ => return Err(Diagnostic::at(&operator, "Expected identifier")),
                 },
                 Node::UnaryExpr { operator, .. } => {
                     if operator.token_type != TokenType::Mul {
-                        panic!("Expected identifier");
+                        return Err(Diagnostic::at(operator, "Expected identifier"));
                     }
                 }
-                Node::AssignStmt { left, expr } => {
-                    return Node::AssignStmt {
-                        left: Box::new(Node::UnaryExpr {
-                            operator: Token {
-                                token_type: TokenType::Mul,
-                                lexeme: None,
-                                line: self.previous(1).line,
-                                column: self.previous(1).column,
-                                value: None,
-                            },
-                            right: left.clone(),
-                            ty: left.ty().unwrap(),
-                        }),
-                        expr: expr.clone(),
-                    };
+                _ => {
+                    return Err(Diagnostic::at(
+                        &operator,
+                        format!("Expected identifier, got {:?}", node),
+                    ))
                 }
-                _ => panic!(
-                    "Expected identifier at line {} column {}, got {:?}",
-                    self.previous(1).line,
-                    self.previous(1).column,
-                    node
-                ),
             }
 
+            let ty = self
+                .node_type(&node, &operator)?
+                .value_at()
+                .ok_or_else(|| Diagnostic::at(&operator, "Cannot dereference this type"))?;
             node = Node::UnaryExpr {
-                operator: Token {
-                    token_type: TokenType::Mul,
-                    lexeme: None,
-                    line: self.previous(1).line,
-                    column: self.previous(1).column,
-                    value: None,
-                },
-                right: Box::new(node.clone()),
-                ty: node.ty().unwrap().value_at(),
+                operator,
+                right: Box::new(node),
+                ty,
+                span: self.span(start),
             };
         } else if self.match_token(vec![TokenType::Inc]) {
-            node = self.prefix();
+            let operator = self.previous(1);
+            node = self.prefix()?;
 
             // ensure that the node is an identifier
             match &node {
@@ -651,14 +1346,16 @@ impl Parser {
                     value: LiteralValue::Identifier(_),
                     ..
                 } => {}
-                _ => panic!("Expected identifier"),
+                _ => return Err(Diagnostic::at(&operator, "Expected identifier")),
             }
 
             node = Node::PreIncStmt {
                 right: Box::new(node),
+                span: self.span(start),
             };
         } else if self.match_token(vec![TokenType::Dec]) {
-            node = self.prefix();
+            let operator = self.previous(1);
+            node = self.prefix()?;
 
             // ensure that the node is an identifier
             match &node {
@@ -666,96 +1363,92 @@ impl Parser {
                     value: LiteralValue::Identifier(_),
                     ..
                 } => {}
-                _ => panic!("Expected identifier"),
+                _ => return Err(Diagnostic::at(&operator, "Expected identifier")),
             }
 
             node = Node::PreDecStmt {
                 right: Box::new(node),
+                span: self.span(start),
             };
         } else {
-            node = self.primary();
+            node = self.primary()?;
         }
 
-        node
+        Ok(node)
     }
 
-    fn postfix(&mut self) -> Node {
+    // after the identifier
+    fn postfix(&mut self) -> Result<Node> {
+        let start = self.current - 1;
         let identifier = self.previous(1);
         match self.find_symbol(identifier.clone()) {
             Some(symbol) => {
                 // TODO: This is hacky, fix it
+                // a variable holding a closure, not a function returning one
+                let closure = symbol.borrow().structure == SymbolType::Variable
+                    && matches!(symbol.borrow().ty, Some(Type::Closure { .. }));
+                if closure && self.match_token(vec![TokenType::LeftParen]) {
+                    return self.closure_call(symbol, identifier);
+                }
                 if self.match_token(vec![TokenType::LeftParen]) {
                     if symbol.borrow().structure != SymbolType::Function {
-                        panic!(
-                            "Expected function at line {} column {}",
-                            identifier.line, identifier.column
-                        );
+                        return Err(Diagnostic::at(&identifier, "Expected function"));
                     }
                     return self.function_call();
-                } else if symbol.borrow().structure != SymbolType::Variable {
-                    panic!(
-                        "Expected variable at line {} column {} got {:?}",
-                        identifier.line,
-                        identifier.column,
-                        symbol.borrow().structure
-                    );
+                }
+                if let SymbolType::Constant(value) = symbol.borrow().structure {
+                    let ty = symbol.borrow().ty.clone().unwrap();
+                    return Ok(fold::literal(value, ty, Span::of(&identifier)));
+                }
+                if symbol.borrow().structure != SymbolType::Variable {
+                    return Err(Diagnostic::at(
+                        &identifier,
+                        format!("Expected variable got {:?}", symbol.borrow().structure),
+                    ));
                 }
 
                 let left = if self.match_token(vec![TokenType::LeftBracket]) {
-                    self.array_access()
+                    self.array_access()?
                 } else {
                     Node::LiteralExpr {
                         value: LiteralValue::Identifier(symbol.clone()),
                         ty: symbol.borrow().ty.as_ref().unwrap().clone(),
+                        span: Span::of(&identifier),
                     }
                 };
 
-                if self.match_token(vec![TokenType::Assign]) {
-                    let expr = self.expression();
-
-                    // expr = match self.modify_type(expr, symbol.ty.unwrap(), None) {
-                    //     Some(node) => node,
-                    //     None => panic!(
-                    //         "Incompatible types at line {} column {}",
-                    //         self.previous(1).line,
-                    //         self.previous(1).column
-                    //     ),
-                    // };
-
-                    Node::AssignStmt {
+                if self.match_token(vec![TokenType::Inc]) {
+                    Ok(Node::PostIncStmt {
                         left: Box::new(left),
-                        expr: Box::new(expr),
-                    }
-                } else if self.match_token(vec![TokenType::Inc]) {
-                    Node::PostIncStmt {
-                        left: Box::new(left),
-                    }
+                        span: self.span(start),
+                    })
                 } else if self.match_token(vec![TokenType::Dec]) {
-                    Node::PostDecStmt {
+                    Ok(Node::PostDecStmt {
                         left: Box::new(left),
-                    }
+                        span: self.span(start),
+                    })
                 } else {
-                    left
+                    Ok(left)
                 }
             }
-            None => panic!(
-                "Variable {} not declared at line {} column {}",
-                identifier.lexeme.clone().unwrap(),
-                identifier.line,
-                identifier.column
-            ),
+            None => Err(Diagnostic::at(
+                &identifier,
+                format!("Variable {} not declared", identifier.lexeme.unwrap()),
+            )
+            .with_label("not found in this scope")),
         }
     }
 
-    fn primary(&mut self) -> Node {
+    fn primary(&mut self) -> Result<Node> {
         if self.match_token(vec![TokenType::LeftParen]) {
-            let expr = self.expression();
-            self.expect(vec![TokenType::RightParen]).unwrap();
-            return expr;
+            let expr = self.expression()?;
+            self.expect(vec![TokenType::RightParen])?;
+            return Ok(expr);
         } else if self.match_token(vec![TokenType::Integer]) {
-            let val: u64 = match self.previous(1).value {
+            let token = self.previous(1);
+            let val: u64 = match token.value {
                 Some(Literal::Integer(val)) => val,
-                _ => panic!("Expected integer"),
+                _ => return Err(Diagnostic::at(&token, "Expected integer")),
             };
             let (value, ty) = if val <= u8::MAX as u64 {
                 (LiteralValue::U8(val as u8), Type::U8)
@@ -766,34 +1459,39 @@ impl Parser {
             } else {
                 (LiteralValue::U64(val), Type::U64)
             };
-            return Node::LiteralExpr { value, ty };
+            return Ok(Node::LiteralExpr {
+                value,
+                ty,
+                span: Span::of(&token),
+            });
         } else if self.match_token(vec![TokenType::Identifier]) {
             return self.postfix();
+        } else if self.match_token(vec![TokenType::Or, TokenType::LogicalOr]) {
+            return self.lambda();
         } else if self.match_token(vec![TokenType::String]) {
-            let val = match self.previous(1).value {
-                Some(Literal::String { value, .. }) => value,
-                _ => panic!("Expected string"),
+            let token = self.previous(1);
+            let span = Span::of(&token);
+            let (val, label) = match token.value {
+                Some(Literal::String { value, label }) => (value, label),
+                _ => return Err(Diagnostic::at(&token, "Expected string")),
             };
 
             let ty = Type::Array {
                 ty: Box::new(Type::U8),
                 count: val.len() as u64,
             };
-            let label = match self.previous(1).value {
-                Some(Literal::String { label, .. }) => label,
-                _ => panic!("Expected string"),
-            };
-            return Node::LiteralExpr {
+            return Ok(Node::LiteralExpr {
                 value: LiteralValue::String { value: val, label },
                 ty,
-            };
+                span,
+            });
         }
 
         let token = self.peek();
-        panic!(
-            "Unexpected token {:?} at line {} column {}",
-            token.token_type, token.line, token.column
-        );
+        Err(
+            Diagnostic::at(&token, format!("Unexpected token {:?}", token.token_type))
+                .with_label("expected an expression"),
+        )
     }
 
     fn match_token(&mut self, vec: Vec<TokenType>) -> bool {
@@ -807,20 +1505,19 @@ impl Parser {
         false
     }
 
-    fn expect(&mut self, tokens: Vec<TokenType>) -> Result<Token, String> {
+    fn expect(&mut self, tokens: Vec<TokenType>) -> Result<Token> {
         for token in &tokens {
             if self.check(*token) {
                 return Ok(self.advance());
             }
         }
 
-        Err(format!(
-            "Expected {:?} at line {} column {}, got {:?}",
-            tokens,
-            self.peek().line,
-            self.peek().column,
-            self.peek().token_type
-        ))
+        let token = self.peek();
+        Err(Diagnostic::at(
+            &token,
+            format!("Expected {:?}, got {:?}", tokens, token.token_type),
+        )
+        .with_label("unexpected token"))
     }
 
     fn check(&self, token_type: TokenType) -> bool {
@@ -832,219 +1529,641 @@ impl Parser {
     }
 
     fn peek(&self) -> Token {
-        self.tokens[self.current].clone()
+        self.token(self.current).clone()
+    }
+
+    fn token(&self, i: usize) -> &Token {
+        &self.tokens[i - self.dropped]
     }
 
     fn advance(&mut self) -> Token {
         if !self.is_at_end() {
             self.current += 1;
+            self.read();
         }
 
         self.previous(1)
     }
 
+    // Reads the token the parser is at, if it hasn't been read yet. A source
+    // that runs out early ends with an Eof where its last token was.
+    fn read(&mut self) {
+        while self.dropped + self.tokens.len() <= self.current {
+            let token = match self.source.next() {
+                Some(Ok(token)) => token,
+                Some(Err(diagnostic)) => {
+                    self.lexing.push(diagnostic);
+                    continue;
+                }
+                None => {
+                    let last = self.tokens.back();
+                    Token {
+                        token_type: TokenType::Eof,
+                        lexeme: None,
+                        line: last.map_or(1, |token| token.line),
+                        column: last.map_or(1, |token| token.column),
+                        value: None,
+                        trivia: None,
+                    }
+                }
+            };
+            self.tokens.push_back(token);
+        }
+    }
+
+    // Drops the tokens before the one the parser is at but the last, the
+    // second pass never goes back to an item it is done with
+    fn drop_tokens(&mut self) {
+        while self.dropped + 1 < self.current {
+            self.tokens.pop_front();
+            self.dropped += 1;
+        }
+    }
+
     fn previous(&self, i: usize) -> Token {
-        self.tokens[self.current - i].clone()
+        self.token(self.current - i).clone()
+    }
+
+    // from the token at the index to the last one consumed
+    fn span(&self, start: usize) -> Span {
+        let end = self.current.saturating_sub(1).max(start);
+        Span::new(self.token(start), self.token(end))
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn add_symbol(
         &mut self,
         identifier: Token,
         structure: SymbolType,
         class: StorageClass,
         ty: Option<Type>,
-        end_label: Option<String>,
         offset: Option<isize>,
         params: Option<Vec<Rc<RefCell<Symbol>>>>,
-    ) -> Rc<RefCell<Symbol>> {
-        let symbol = self.find_symbol(identifier.clone());
-        if symbol.is_some() {
-            let ty = if symbol.unwrap().borrow().structure == SymbolType::Variable {
-                "Variable"
-            } else {
-                "Function"
+    ) -> Result<Rc<RefCell<Symbol>>> {
+        let name = identifier.name().unwrap();
+        let overload = match &params {
+            Some(params) if structure == SymbolType::Function => {
+                self.overload(&identifier, params)?
+            }
+            _ => None,
+        };
+        let existing = match overload {
+            Some(_) => None,
+            None => self.symbols.find_declared(name, &class),
+        };
+        if let Some(symbol) = existing {
+            let ty = match symbol.borrow().structure {
+                SymbolType::Variable => "Variable",
+                SymbolType::Function => "Function",
+                SymbolType::Constant(_) => "Constant",
             };
 
-            panic!(
-                "{} {} already declared at line {} column {}",
-                ty,
-                identifier.lexeme.clone().unwrap(),
-                identifier.line,
-                identifier.column
-            );
+            return Err(Diagnostic::at(
+                &identifier,
+                format!("{} {} already declared", ty, identifier.lexeme.unwrap()),
+            )
+            .with_label("redeclared here"));
         }
 
         let symbol = Rc::new(RefCell::new(Symbol {
             identifier,
+            name,
             structure,
             class,
             ty,
-            end_label,
             size: None,
             offset,
             params,
+            overload,
         }));
 
-        self.symbols.push(symbol.clone());
+        self.symbols.insert(symbol.clone());
+        self.references
+            .push((symbol.borrow().identifier.clone(), symbol.clone()));
 
-        symbol
+        Ok(symbol)
     }
 
-    fn find_symbol(&self, identifier: Token) -> Option<Rc<RefCell<Symbol>>> {
-        let mut symbol: Option<Rc<RefCell<Symbol>>> = None;
-        for it in &self.symbols {
-            if it.borrow().identifier.lexeme.clone().unwrap() == identifier.lexeme.clone().unwrap()
-            {
-                symbol = Some(it.clone());
-                if it.borrow().class == StorageClass::Local {
-                    break;
-                }
-            }
+    // A function declared with a name a function already has is an overload
+    // of it, which the parameters tell apart from the others. Builtins and
+    // main can't be overloaded, their names are fixed.
+    fn overload(
+        &mut self,
+        identifier: &Token,
+        params: &[Rc<RefCell<Symbol>>],
+    ) -> Result<Option<usize>> {
+        let overloads = self.symbols.overloads(identifier.name().unwrap());
+        let Some(first) = overloads.first() else {
+            return Ok(None);
+        };
+        let fixed =
+            first.borrow().identifier.line == 0 || identifier.lexeme == Some(intern("main"));
+        if fixed {
+            return Ok(None);
         }
 
-        symbol
+        let signature = params
+            .iter()
+            .map(|param| param.borrow().ty.clone().unwrap())
+            .collect::<Vec<_>>();
+        if let Some(same) = overloads
+            .iter()
+            .find(|overload| overload.borrow().signature() == signature)
+        {
+            return Err(Diagnostic::at(
+                identifier,
+                format!(
+                    "Function {} already declared with these parameters",
+                    identifier.lexeme.unwrap()
+                ),
+            )
+            .with_label(format!(
+                "same parameters as on line {}",
+                same.borrow().identifier.line
+            )));
+        }
+
+        first.borrow_mut().overload.get_or_insert(0);
+        Ok(Some(overloads.len()))
     }
 
-    fn while_statement(&mut self) -> Node {
-        self.expect(vec![TokenType::LeftParen]).unwrap();
-        let mut expr = self.expression();
-        match &expr {
-            Node::BinaryExpr { operator, .. } => {
-                if operator.token_type != TokenType::Equal
-                    && operator.token_type != TokenType::NotEqual
-                    && operator.token_type != TokenType::LessThan
-                    && operator.token_type != TokenType::LessThanOrEqual
-                    && operator.token_type != TokenType::GreaterThan
-                    && operator.token_type != TokenType::GreaterThanOrEqual
-                {
-                    panic!(
-                        "Expected comparison operator at line {} column {}",
-                        operator.line, operator.column
-                    );
-                }
+    fn find_symbol(&mut self, identifier: Token) -> Option<Rc<RefCell<Symbol>>> {
+        let (scope, symbol) = self.symbols.find_scoped(identifier.name()?)?;
+        let symbol = self.capture(&identifier, scope, symbol);
+        self.references.push((identifier, symbol.clone()));
+        Some(symbol)
+    }
+
+    // A local of a function found from a lambda in it is captured: the
+    // lambda, and every lambda between them, gets a local of its own the
+    // value is copied to when the closure is made
+    fn capture(
+        &mut self,
+        identifier: &Token,
+        mut scope: usize,
+        mut symbol: Rc<RefCell<Symbol>>,
+    ) -> Rc<RefCell<Symbol>> {
+        if !matches!(
+            symbol.borrow().class,
+            StorageClass::Local | StorageClass::Param
+        ) {
+            return symbol;
+        }
+
+        for level in 0..self.closures.len() {
+            if self.closures[level].scope <= scope {
+                continue;
             }
-            // _ => panic!("Expected comparison operator"),
-            _ => {
-                expr = Node::ToBool {
-                    expr: Box::new(expr),
-                };
+            scope = self.closures[level].scope;
+
+            let closure = &self.closures[level];
+            let captured = closure
+                .captures
+                .iter()
+                .position(|outer| Rc::ptr_eq(outer, &symbol));
+            if let Some(i) = captured {
+                symbol = closure.copies[i].clone();
+                continue;
             }
+
+            let ty = symbol.borrow().ty.clone().unwrap();
+            if matches!(ty, Type::Array { .. }) {
+                self.report(
+                    Diagnostic::at(identifier, "Cannot capture an array")
+                        .with_label("only integers, pointers and closures are captured"),
+                );
+                return symbol;
+            }
+
+            // the locals of the lambda, or of the one inside it that is
+            // being parsed, which were put aside for it
+            let frame = match self.closures.get_mut(level + 1) {
+                Some(inner) => &mut inner.offset,
+                None => &mut self.local_offset,
+            };
+            *frame += ty.size().max(4);
+            let offset = -(*frame as isize);
+
+            let inner = Rc::new(RefCell::new(Symbol {
+                class: StorageClass::Local,
+                offset: Some(offset),
+                ..symbol.borrow().clone()
+            }));
+            self.symbols.insert_into(scope, inner.clone());
+            self.closures[level].captures.push(symbol);
+            self.closures[level].copies.push(inner.clone());
+            symbol = inner;
         }
-        self.expect(vec![TokenType::RightParen]).unwrap();
-        let body = self.compound_statement();
+        symbol
+    }
+
+    // The condition of an if, while or for. A comparison is kept as it is to
+    // be compiled into a compare and jump, any other integer is tested
+    // against zero.
+    fn condition(&mut self) -> Result<Node> {
+        let start = self.current;
+        let expr = self.expression()?;
+        let is_comparison = matches!(
+            &expr,
+            Node::BinaryExpr { operator, .. } if matches!(
+                operator.token_type,
+                TokenType::Equal
+                    | TokenType::NotEqual
+                    | TokenType::LessThan
+                    | TokenType::LessThanOrEqual
+                    | TokenType::GreaterThan
+                    | TokenType::GreaterThanOrEqual
+            )
+        );
+        if is_comparison {
+            return Ok(expr);
+        }
+
+        let ty = self.node_type(&expr, &self.previous(1))?;
+        if !ty.is_int() {
+            return Err(Diagnostic::at(
+                &self.previous(1),
+                format!("Expected an integer condition, found {}", ty),
+            ));
+        }
+
+        Ok(Node::ToBool {
+            expr: Box::new(expr),
+            span: self.span(start),
+        })
+    }
 
-        Node::WhileStmt {
+    // after the `while`, and the label in front of it
+    fn while_statement(&mut self, label: Option<Token>) -> Result<Node> {
+        let start = self.current - 1;
+        self.expect(vec![TokenType::LeftParen])?;
+        let expr = self.condition()?;
+        self.expect(vec![TokenType::RightParen])?;
+        let body = self.loop_body(&label)?;
+
+        Ok(Node::WhileStmt {
             condition: Box::new(expr),
             body: Box::new(body),
+            step: None,
+            label,
+            span: self.span(start),
+        })
+    }
+
+    // the body of a loop, which a break or continue in it can refer to
+    fn loop_body(&mut self, label: &Option<Token>) -> Result<Node> {
+        self.loops.push(label.clone());
+        let body = self.compound_statement();
+        self.loops.pop();
+        body
+    }
+
+    // `break` or `continue`, after it, with the label of the loop it is for
+    fn jump_statement(&mut self) -> Result<Node> {
+        let start = self.current - 1;
+        let keyword = self.previous(1);
+        let label = match self.match_token(vec![TokenType::Label]) {
+            true => Some(self.previous(1)),
+            false => None,
+        };
+
+        let name = keyword.lexeme.map(Name::text).unwrap_or_default();
+        match &label {
+            _ if self.loops.is_empty() => {
+                return Err(
+                    Diagnostic::at(&keyword, format!("{} outside of a loop", name))
+                        .with_label("not in a while or for"),
+                )
+            }
+            Some(label)
+                if !self
+                    .loops
+                    .iter()
+                    .flatten()
+                    .any(|outer| outer.lexeme == label.lexeme) =>
+            {
+                return Err(Diagnostic::at(
+                    label,
+                    format!("Unknown loop label {}", label.lexeme.unwrap()),
+                )
+                .with_label("no loop around it has this label"))
+            }
+            _ => {}
         }
+
+        let span = self.span(start);
+        Ok(match keyword.token_type {
+            TokenType::Break => Node::BreakStmt {
+                keyword,
+                label,
+                span,
+            },
+            _ => Node::ContinueStmt {
+                keyword,
+                label,
+                span,
+            },
+        })
     }
 
-    fn for_statement(&mut self) -> Node {
-        self.expect(vec![TokenType::LeftParen]).unwrap();
+    fn for_statement(&mut self, label: Option<Token>) -> Result<Node> {
+        // anything declared in the loop header is only visible inside the loop
+        self.symbols.enter_scope();
+        let node = self.for_loop(label);
+        self.symbols.exit_scope();
+        node
+    }
+
+    // A for loop is a while loop, with the initializer before it and the
+    // increment as its step
+    fn for_loop(&mut self, label: Option<Token>) -> Result<Node> {
+        let start = self.current - 1;
+        if !self.check(TokenType::LeftParen) {
+            return self.range_loop(start, label);
+        }
+        self.expect(vec![TokenType::LeftParen])?;
         let initializer = if self.match_token(vec![TokenType::SemiColon]) {
             None
         // } else if self.match_token(vec![TokenType::Let]) {
         //     Some(self.var_decl())
         } else if self.check(TokenType::Identifier) {
-            let node = self.expression();
-            self.expect(vec![TokenType::SemiColon]).unwrap();
+            let node = self.expression()?;
+            self.expect(vec![TokenType::SemiColon])?;
             Some(node)
         } else {
-            panic!("Expected identifier");
+            return Err(Diagnostic::at(&self.peek(), "Expected identifier"));
         };
 
         let condition = if self.check(TokenType::SemiColon) {
             Node::LiteralExpr {
                 value: LiteralValue::U8(1),
                 ty: Type::U8,
+                span: Span::of(&self.peek()),
             }
         } else {
-            let mut expr = self.expression();
-            match &expr {
-                Node::BinaryExpr { operator, .. } => {
-                    if operator.token_type != TokenType::Equal
-                        && operator.token_type != TokenType::NotEqual
-                        && operator.token_type != TokenType::LessThan
-                        && operator.token_type != TokenType::LessThanOrEqual
-                        && operator.token_type != TokenType::GreaterThan
-                        && operator.token_type != TokenType::GreaterThanOrEqual
-                    {
-                        panic!(
-                            "Expected comparison operator at line {} column {}",
-                            operator.line, operator.column
-                        );
-                    }
-                }
-                // _ => panic!("Expected comparison operator"),
-                _ => {
-                    expr = Node::ToBool {
-                        expr: Box::new(expr),
-                    };
-                }
-            }
-            expr
+            self.condition()?
         };
-        self.expect(vec![TokenType::SemiColon]).unwrap();
+        self.expect(vec![TokenType::SemiColon])?;
 
         let increment = if self.check(TokenType::RightParen) {
             None
         } else {
-            Some(self.single_statement())
+            Some(self.single_statement()?)
         };
-        self.expect(vec![TokenType::RightParen]).unwrap();
+        self.expect(vec![TokenType::RightParen])?;
 
-        let mut body = self.compound_statement();
-
-        if let Some(increment) = increment {
-            body = Node::CompoundStmt {
-                statements: vec![body, increment],
-            };
-        }
+        let body = self.loop_body(&label)?;
+        let span = self.span(start);
 
-        body = Node::WhileStmt {
+        let mut body = Node::WhileStmt {
             condition: Box::new(condition),
             body: Box::new(body),
+            step: increment.map(Box::new),
+            label,
+            span,
         };
 
         if let Some(initializer) = initializer {
             body = Node::CompoundStmt {
                 statements: vec![initializer, body],
+                span,
             };
         }
 
-        body
+        Ok(body)
+    }
+
+    // `for i in 0..n { ... }` after the `for`, a while loop over a new local
+    // counting from the start of the range up to its end, which is left out.
+    // The end is worked out once, before the loop, and both bounds are
+    // parsed before the local is declared.
+    fn range_loop(&mut self, start: usize, label: Option<Token>) -> Result<Node> {
+        let identifier = self.expect(vec![TokenType::Identifier])?;
+        let declared = if self.match_token(vec![TokenType::Colon]) {
+            Some(self.parse_type()?)
+        } else {
+            None
+        };
+        self.expect(vec![TokenType::In])?;
+        let from = self.expression()?;
+        let range = self.expect(vec![TokenType::DotDot])?;
+        let to = self.expression()?;
+
+        // a range that is wrong is reported and the loop parsed on, to go
+        // on to what follows it rather than stop in its body
+        let bounds = from.span().to(to.span());
+        let (ty, from, to) = match self.range(declared, &identifier, &range, &from, &to) {
+            Ok(range) => range,
+            Err(diagnostic) => {
+                self.report(diagnostic);
+                (Type::U64, from, to)
+            }
+        };
+
+        let name = intern(&format!("{}.end", identifier.lexeme.unwrap()));
+        let end = Rc::new(RefCell::new(Symbol {
+            identifier: Token {
+                lexeme: Some(name),
+                ..identifier.clone()
+            },
+            name,
+            structure: SymbolType::Variable,
+            class: StorageClass::Local,
+            ty: Some(ty.clone()),
+            size: None,
+            offset: Some(self.gen_offset(ty.clone())),
+            params: None,
+            overload: None,
+        }));
+        let offset = self.gen_offset(ty.clone());
+        let variable = self.add_symbol(
+            identifier.clone(),
+            SymbolType::Variable,
+            StorageClass::Local,
+            Some(ty.clone()),
+            Some(offset),
+            None,
+        )?;
+        let literal = |symbol: &Rc<RefCell<Symbol>>| Node::LiteralExpr {
+            value: LiteralValue::Identifier(symbol.clone()),
+            ty: ty.clone(),
+            span: Span::of(&identifier),
+        };
+
+        let body = self.loop_body(&label)?;
+        let span = self.span(start);
+        let condition = Node::BinaryExpr {
+            left: Box::new(literal(&variable)),
+            operator: Token {
+                token_type: TokenType::LessThan,
+                lexeme: None,
+                value: None,
+                trivia: None,
+                ..range.clone()
+            },
+            right: Box::new(literal(&end)),
+            ty: Type::U8,
+            span: bounds,
+        };
+        Ok(Node::CompoundStmt {
+            statements: vec![
+                Node::VarDecl {
+                    symbol: variable.clone(),
+                    is_local: true,
+                    ty: ty.clone(),
+                    attributes: Vec::new(),
+                    external: false,
+                    init: None,
+                    span: Span::of(&identifier),
+                },
+                Node::AssignStmt {
+                    left: Box::new(literal(&variable)),
+                    span: from.span(),
+                    expr: Box::new(from),
+                },
+                Node::AssignStmt {
+                    left: Box::new(literal(&end)),
+                    span: to.span(),
+                    expr: Box::new(to),
+                },
+                Node::WhileStmt {
+                    condition: Box::new(condition),
+                    body: Box::new(body),
+                    step: Some(Box::new(Node::PostIncStmt {
+                        left: Box::new(literal(&variable)),
+                        span: Span::of(&identifier),
+                    })),
+                    label,
+                    span,
+                },
+            ],
+            span,
+        })
+    }
+
+    // The type a range loop counts in and its bounds converted to it. Unless
+    // it is given, it is the wider of the types of the bounds but at least
+    // 32 bits, which a literal bound alone would leave at 8.
+    fn range(
+        &self,
+        declared: Option<Type>,
+        identifier: &Token,
+        range: &Token,
+        from: &Node,
+        to: &Node,
+    ) -> Result<(Type, Node, Node)> {
+        let ty = match declared {
+            Some(ty) => ty,
+            None => {
+                let (from, to) = (self.node_type(from, range)?, self.node_type(to, range)?);
+                match if from.size() > to.size() { from } else { to } {
+                    ty if !ty.is_int() || ty.size() >= 4 => ty,
+                    ty if ty.is_signed() => Type::I32,
+                    _ => Type::U32,
+                }
+            }
+        };
+        if !ty.is_int() {
+            return Err(Diagnostic::at(identifier, "A range is counted in integers")
+                .with_label(format!("{} is not an integer", ty)));
+        }
+        let from = self.coerce(from.clone(), ty.clone(), range)?;
+        let to = self.coerce(to.clone(), ty.clone(), range)?;
+        Ok((ty, from, to))
+    }
+
+    fn fn_decl(&mut self, first_pass: bool, attributes: Vec<Attribute>) -> Result<Option<Node>> {
+        // parameters get a scope of their own, the function itself is global
+        self.symbols.enter_scope();
+        let node = self.function(first_pass, attributes);
+        self.symbols.exit_scope();
+        node
+    }
+
+    // The attributes in front of a function or global, `#[section(".boot")]`
+    // for the section it goes in and `#[align(16)]` for its boundary
+    // The attributes in front of an item, each one the registry knows and
+    // with the argument it takes
+    fn attributes(&mut self) -> Result<Vec<Attribute>> {
+        let mut attributes = Vec::new();
+        while self.match_token(vec![TokenType::Attribute]) {
+            let name = self.expect(vec![TokenType::Identifier])?;
+            let Some(kind) = attributes::find(&name.lexeme.map(Name::text).unwrap_or_default())
+            else {
+                return Err(Diagnostic::at(
+                    &name,
+                    format!("Unknown attribute {}", name.lexeme.unwrap()),
+                )
+                .with_label(format!("expected {}", attributes::names())));
+            };
+            let argument = match kind.argument {
+                Argument::None => {
+                    if self.check(TokenType::LeftParen) {
+                        return Err(Diagnostic::at(
+                            &self.peek(),
+                            format!("Attribute {} takes no argument", kind.name),
+                        )
+                        .with_label("expected ]"));
+                    }
+                    None
+                }
+                Argument::Section => {
+                    self.expect(vec![TokenType::LeftParen])?;
+                    let token = self.expect(vec![TokenType::String])?;
+                    self.expect(vec![TokenType::RightParen])?;
+                    Some(token)
+                }
+                Argument::Alignment => {
+                    self.expect(vec![TokenType::LeftParen])?;
+                    let token = self.expect(vec![TokenType::Integer])?;
+                    if !matches!(token.value, Some(Literal::Integer(n)) if n.is_power_of_two()) {
+                        return Err(Diagnostic::at(&token, "Alignment must be a power of two")
+                            .with_label("not a power of two"));
+                    }
+                    self.expect(vec![TokenType::RightParen])?;
+                    Some(token)
+                }
+            };
+            self.expect(vec![TokenType::RightBracket])?;
+            attributes.push(Attribute { name, argument });
+        }
+        Ok(attributes)
     }
 
-    fn fn_decl(&mut self, first_pass: bool) -> Option<Node> {
-        self.expect(vec![TokenType::Fn]).unwrap();
-        let identifier = self.expect(vec![TokenType::Identifier]).unwrap();
-        self.expect(vec![TokenType::LeftParen]).unwrap();
+    fn function(&mut self, first_pass: bool, attributes: Vec<Attribute>) -> Result<Option<Node>> {
+        let start = self.current;
+        let public = self.match_token(vec![TokenType::Pub]);
+        if public && !self.check(TokenType::Fn) {
+            return Err(
+                Diagnostic::at(&self.peek(), "Only functions can be pub").with_label("expected fn")
+            );
+        }
+        self.expect(vec![TokenType::Fn])?;
+        let identifier = self.expect(vec![TokenType::Identifier])?;
+        self.expect(vec![TokenType::LeftParen])?;
         self.reset_offset();
-        let params = self.parse_params(first_pass);
-        self.expect(vec![TokenType::RightParen]).unwrap();
+        let params = self.parse_params(first_pass)?;
+        self.expect(vec![TokenType::RightParen])?;
         let mut ty: Option<Type> = None;
 
         if self.match_token(vec![TokenType::Colon]) {
-            ty = Some(self.parse_type());
+            ty = Some(self.return_type()?);
         }
 
         if first_pass {
-            let end_label = Some(format!("{}{}", identifier.lexeme.clone().unwrap(), "_end"));
-
             self.add_symbol(
                 identifier.clone(),
                 SymbolType::Function,
                 StorageClass::Global,
                 ty.clone(),
-                end_label,
                 None,
                 Some(params),
-            );
+            )?;
 
             // Skip the body
-            self.advance();
+            self.expect(vec![TokenType::LeftBrace])?;
             let mut braces = 1;
-            while braces != 0 {
+            while braces != 0 && !self.is_at_end() {
                 if self.check(TokenType::LeftBrace) {
                     braces += 1;
                 } else if self.check(TokenType::RightBrace) {
@@ -1053,61 +2172,122 @@ impl Parser {
                 self.advance();
             }
 
-            return None;
+            return Ok(None);
         }
 
-        self.current_fn = self.find_symbol(identifier.clone());
-        let body = self.compound_statement();
-        // delete local variables and
-        self.symbols.retain(|x| {
-            x.borrow().class != StorageClass::Local && x.borrow().class != StorageClass::Param
-        });
-        // ensure that the function returns a value if it has a return type in the last statement
-        if ty.is_some() {
-            match &body {
-                Node::CompoundStmt { statements } => {
-                    if statements.is_empty() {
-                        panic!(
-                            "Function {} does not return a value at line {} column {}",
-                            identifier.lexeme.clone().unwrap(),
-                            identifier.line,
-                            identifier.column
-                        );
-                    }
-
-                    let last = statements.last().unwrap();
-                    match last {
-                        Node::ReturnStmt { .. } => {}
-                        _ => panic!(
-                            "Function {} does not return a value at line {} column {}",
-                            identifier.lexeme.clone().unwrap(),
-                            identifier.line,
-                            identifier.column
+        // the overload with these parameters, declared by the first pass
+        let signature = params
+            .iter()
+            .map(|param| param.borrow().ty.clone().unwrap())
+            .collect::<Vec<_>>();
+        let function = self
+            .symbols
+            .overloads(identifier.name().unwrap())
+            .into_iter()
+            .find(|function| function.borrow().signature() == signature);
+        if let Some(function) = &function {
+            self.references.push((identifier.clone(), function.clone()));
+            // an overload is named after its order, it can't keep the name
+            // C would find it by
+            let fixed = attributes
+                .iter()
+                .find(|attribute| attribute.name.lexeme == Some(intern("no_mangle")))
+                .map(|attribute| (&attribute.name, "#[no_mangle]"))
+                .or(public.then_some((&identifier, "pub")));
+            if let (Some(_), Some((token, what))) = (function.borrow().overload, fixed) {
+                self.report(
+                    Diagnostic::at(
+                        token,
+                        format!(
+                            "Function {} is overloaded, it can't be {}",
+                            identifier.lexeme.unwrap(),
+                            what
                         ),
-                    }
-                }
-                _ => panic!(
-                    "Function {} does not return a value at line {} column {}",
-                    identifier.lexeme.clone().unwrap(),
-                    identifier.line,
-                    identifier.column
-                ),
+                    )
+                    .with_label("its symbol is named after the order of the overloads"),
+                );
             }
         }
+        let name = match &function {
+            Some(function) => function.borrow().link_name(),
+            None => identifier.lexeme.unwrap().to_string(),
+        };
+        // a function returning several values is given the address to
+        // leave them at before its parameters
+        let mut params = params;
+        if let Some(Type::Tuple(_)) = ty {
+            let result = Rc::new(RefCell::new(Symbol {
+                identifier: Token {
+                    lexeme: Some(intern("result")),
+                    ..identifier.clone()
+                },
+                name: intern("result"),
+                structure: SymbolType::Variable,
+                class: StorageClass::Param,
+                ty: Some(Type::U64),
+                size: None,
+                offset: Some(self.gen_offset(Type::U64)),
+                params: None,
+                overload: None,
+            }));
+            params.insert(0, result);
+        }
+        self.current_fn = function;
+        self.lambdas = 0;
+        let body = self.compound_statement()?;
 
         self.current_fn = None;
 
-        Some(Node::FnDecl {
+        Ok(Some(Node::FnDecl {
             identifier,
+            name,
             body: Box::new(body),
             stack_size: self.local_offset,
             return_type: ty,
             params,
-        })
+            attributes,
+            public,
+            environment: None,
+            span: self.span(start),
+        }))
+    }
+
+    // the type a function returns after the `:`, or the types in
+    // parentheses of the values it returns several of, `(u64, u64)`
+    fn return_type(&mut self) -> Result<Type> {
+        if !self.match_token(vec![TokenType::LeftParen]) {
+            return self.parse_type();
+        }
+
+        let opening = self.previous(1);
+        let mut types = Vec::new();
+        loop {
+            let start = self.peek();
+            let ty = self.parse_type()?;
+            if matches!(ty, Type::Array { .. }) {
+                return Err(Diagnostic::at(
+                    &start,
+                    "An array cannot be returned among several values",
+                )
+                .with_label(format!("{} is an array", ty)));
+            }
+            types.push(ty);
+            if !self.match_token(vec![TokenType::Comma]) {
+                break;
+            }
+        }
+        self.expect(vec![TokenType::RightParen])?;
+        if types.len() < 2 {
+            return Err(
+                Diagnostic::at(&opening, "A single return type takes no parentheses")
+                    .with_label("only several values are returned in parentheses"),
+            );
+        }
+        Ok(Type::Tuple(types))
     }
 
     fn modify_type(&self, node: Node, right_type: Type, op: Option<TokenType>) -> Option<Node> {
-        let left_type = node.ty().unwrap();
+        let left_type = node.ty()?;
 
         if left_type.is_int() && right_type.is_int() {
             if left_type == right_type {
@@ -1117,15 +2297,19 @@ impl Parser {
             let left_size = left_type.size();
             let right_size = right_type.size();
 
+            // the same bits read with the other signedness are another
+            // number, which an operator may mix but a stored value can't
+            if left_size == right_size {
+                return (op.is_some() || left_type.is_signed() == right_type.is_signed())
+                    .then_some(node);
+            }
+
             if left_size > right_size {
                 return None;
             }
 
             if right_size > left_size {
-                return Some(Node::WidenExpr {
-                    right: Box::new(node),
-                    ty: right_type,
-                });
+                return Some(widen(node, right_type));
             }
         }
 
@@ -1139,9 +2323,10 @@ impl Parser {
             && left_type.is_int()
             && right_type.is_ptr()
         {
-            let right_size = right_type.value_at().size();
+            let right_size = right_type.value_at()?.size();
             if right_size > 1 {
                 return Some(Node::ScaleExpr {
+                    span: node.span(),
                     right: Box::new(node),
                     size: right_size,
                     ty: right_type,
@@ -1154,127 +2339,621 @@ impl Parser {
         None
     }
 
-    fn function_call(&mut self) -> Node {
+    // after the identifier and the `(`
+    fn function_call(&mut self) -> Result<Node> {
+        let start = self.current - 2;
         let identifier = self.previous(2);
-        let symbol = self.find_symbol(identifier.clone());
-
-        if symbol.is_none() {
-            panic!(
-                "Function {} not declared at line {} column {}",
-                identifier.lexeme.clone().unwrap(),
-                identifier.line,
-                identifier.column
-            );
-        }
+        let symbol = match self.find_symbol(identifier.clone()) {
+            Some(symbol) => symbol,
+            None => {
+                return Err(Diagnostic::at(
+                    &identifier,
+                    format!("Function {} not declared", identifier.lexeme.unwrap()),
+                ))
+            }
+        };
 
-        let symbol = symbol.unwrap();
         if symbol.borrow().structure != SymbolType::Function {
-            panic!(
-                "Expected function at line {} column {}",
-                identifier.line, identifier.column
-            );
+            return Err(Diagnostic::at(&identifier, "Expected function"));
         }
 
-        let args = self.parse_args();
+        let args = self.parse_args()?;
 
-        self.expect(vec![TokenType::RightParen]).unwrap();
+        self.expect(vec![TokenType::RightParen])?;
 
-        let ty = symbol.borrow().ty.as_ref().unwrap().clone();
-        Node::FnCall {
+        if is_arithmetic(&identifier) {
+            return self.arithmetic_call(identifier, args, self.span(start));
+        }
+        if identifier.lexeme == Some(intern("len")) {
+            return self.length_call(identifier, args, self.span(start));
+        }
+
+        let symbol = self.resolve(&identifier, symbol, &args)?;
+        if let Some(reference) = self
+            .references
+            .iter_mut()
+            .rev()
+            .find(|(token, _)| *token == identifier)
+        {
+            reference.1 = symbol.clone();
+        }
+
+        // An argument converts to the type of its parameter as the value of
+        // an assignment does, an integer is widened to it, and a pointer
+        // parameter only takes a pointer of its type, or a string or array
+        // of it. The builtins take integers of any size whole.
+        let builtin = symbol.borrow().identifier.line == 0;
+        let params = symbol.borrow().params.clone().unwrap_or_default();
+        let args = args
+            .into_iter()
+            .zip(params)
+            .map(|(arg, param)| {
+                let ty = param.borrow().ty.clone().unwrap();
+                if !builtin || ty.is_ptr() || matches!(ty, Type::Closure { .. }) {
+                    self.coerce(arg, ty, &identifier)
+                } else {
+                    Ok(arg)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let ty = match symbol.borrow().ty.as_ref() {
+            Some(ty) => ty.clone(),
+            None => {
+                return Err(Diagnostic::at(
+                    &identifier,
+                    format!(
+                        "Function {} does not return a value",
+                        identifier.lexeme.unwrap()
+                    ),
+                ))
+            }
+        };
+        let name = symbol.borrow().link_name();
+        // several values are left in a slot of the caller, 8 bytes each
+        let result = matches!(ty, Type::Tuple(_)).then(|| {
+            self.local_offset = (self.local_offset + 7) & !7;
+            self.local_offset += ty.size();
+            -(self.local_offset as isize)
+        });
+        Ok(Node::FnCall {
+            identifier,
+            name,
+            args,
+            ty,
+            result,
+            span: self.span(start),
+        })
+    }
+
+    // The overload of the function the arguments call: of those taking as
+    // many arguments, the one converting them the least, and an error when
+    // none takes them or two are as good
+    fn resolve(
+        &self,
+        identifier: &Token,
+        symbol: Rc<RefCell<Symbol>>,
+        args: &[Node],
+    ) -> Result<Rc<RefCell<Symbol>>> {
+        if symbol.borrow().overload.is_none() {
+            return Ok(symbol);
+        }
+
+        let name = identifier.lexeme.unwrap().to_string();
+        let mut costs = Vec::new();
+        for overload in self.symbols.overloads(symbol.borrow().name) {
+            let signature = overload.borrow().signature();
+            if signature.len() != args.len() {
+                continue;
+            }
+            let cost = args
+                .iter()
+                .zip(&signature)
+                .map(|(arg, ty)| self.conversion(arg, ty, identifier))
+                .sum::<Option<usize>>();
+            if let Some(cost) = cost {
+                costs.push((cost, overload));
+            }
+        }
+
+        let describe = |args: &[Node]| {
+            args.iter()
+                .map(|arg| arg.ty().map_or(String::from("?"), |ty| ty.to_string()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let Some(best) = costs.iter().map(|(cost, _)| *cost).min() else {
+            return Err(Diagnostic::at(
+                identifier,
+                format!("No function {} takes ({})", name, describe(args)),
+            )
+            .with_label("no overload matches the arguments"));
+        };
+        let mut best = costs.into_iter().filter(|(cost, _)| *cost == best);
+        let (_, overload) = best.next().unwrap();
+        if let Some((_, other)) = best.next() {
+            return Err(Diagnostic::at(
+                identifier,
+                format!("Call of {} with ({}) is ambiguous", name, describe(args)),
+            )
+            .with_label(format!(
+                "the functions on lines {} and {} fit it equally well",
+                overload.borrow().identifier.line,
+                other.borrow().identifier.line
+            )));
+        }
+        Ok(overload)
+    }
+
+    // How far an argument is converted to be passed as the type, none for
+    // the same type, more for each size an integer is widened by and most
+    // for one cut down, or None when it can't be passed as it at all
+    fn conversion(&self, arg: &Node, ty: &Type, token: &Token) -> Option<usize> {
+        let arg_ty = arg.ty()?;
+        if arg_ty == *ty {
+            return Some(0);
+        }
+        self.coerce(arg.clone(), ty.clone(), token).ok()?;
+        if !arg_ty.is_int() || !ty.is_int() {
+            return Some(1);
+        }
+        let steps = |ty: &Type| ty.size().trailing_zeros() as usize;
+        Some(match steps(ty).checked_sub(steps(&arg_ty)) {
+            Some(widened) => 1 + widened,
+            None => 10,
+        })
+    }
+
+    // The number of elements of an array, known when compiling, or of the
+    // bytes of a string a pointer points at up to its NUL, which strlen
+    // counts when the program runs
+    fn length_call(&self, identifier: Token, args: Vec<Node>, span: Span) -> Result<Node> {
+        let [arg] = <[Node; 1]>::try_from(args).map_err(|args| {
+            Diagnostic::at(
+                &identifier,
+                format!("len takes 1 argument, not {}", args.len()),
+            )
+            .with_label("wrong number of arguments")
+        })?;
+        match self.node_type(&arg, &identifier)? {
+            Type::Array { count, .. } => Ok(Node::LiteralExpr {
+                value: LiteralValue::U64(count),
+                ty: Type::U64,
+                span,
+            }),
+            Type::Pointer { ty, count: 1 } if matches!(*ty, Type::U8 | Type::Char) => {
+                Ok(Node::FnCall {
+                    identifier,
+                    name: String::from("strlen"),
+                    args: vec![arg],
+                    ty: Type::U64,
+                    result: None,
+                    span,
+                })
+            }
+            ty => Err(
+                Diagnostic::at(&identifier, "len takes an array or a string")
+                    .with_label(format!("found {}", ty)),
+            ),
+        }
+    }
+
+    // min, max and abs take integers of any type, the arguments of min and
+    // max are made to agree as an operator's are, and give back that type
+    fn arithmetic_call(&self, identifier: Token, args: Vec<Node>, span: Span) -> Result<Node> {
+        let name = identifier.lexeme.unwrap().to_string();
+        let (arity, expected) = if name == "abs" {
+            (1, "1 argument")
+        } else {
+            (2, "2 arguments")
+        };
+        if args.len() != arity {
+            return Err(Diagnostic::at(
+                &identifier,
+                format!("{} takes {}, not {}", name, expected, args.len()),
+            )
+            .with_label("wrong number of arguments"));
+        }
+        for arg in &args {
+            if !self.node_type(arg, &identifier)?.is_int() {
+                return Err(
+                    Diagnostic::at(&identifier, format!("{} only takes integers", name))
+                        .with_label("expected integer arguments"),
+                );
+            }
+        }
+
+        let args = match <[Node; 2]>::try_from(args) {
+            Ok([left, right]) => {
+                let (left_ty, right_ty) = (left.ty().unwrap(), right.ty().unwrap());
+                let temp_left = self.modify_type(left.clone(), right_ty, None);
+                let temp_right = self.modify_type(right.clone(), left_ty, None);
+                vec![temp_left.unwrap_or(left), temp_right.unwrap_or(right)]
+            }
+            Err(args) => args,
+        };
+        let ty = args[0].ty().unwrap();
+        let name = identifier.lexeme.unwrap().to_string();
+        Ok(Node::FnCall {
             identifier,
+            name,
             args,
             ty,
+            result: None,
+            span,
+        })
+    }
+
+    // `|x: u32, y: u32| x + y` after the first `|`, or `|| 1` after the `||`.
+    // The body is made a function of its own, which takes the environment of
+    // the closure before the parameters and returns the value of the body.
+    fn lambda(&mut self) -> Result<Node> {
+        let start = self.current - 1;
+        let enclosing = match &self.current_fn {
+            Some(function) => function.borrow().link_name(),
+            None => {
+                return Err(Diagnostic::at(
+                    &self.previous(1),
+                    "Lambda outside of a function",
+                ))
+            }
+        };
+        let name = format!("{}.lambda.{}", enclosing, self.lambdas);
+        self.lambdas += 1;
+
+        self.closures.push(Closure {
+            scope: self.symbols.depth() + 1,
+            captures: Vec::new(),
+            copies: Vec::new(),
+            offset: self.local_offset,
+        });
+        self.symbols.enter_scope();
+        self.reset_offset();
+        let function = self.lambda_function(name.clone(), start);
+        self.symbols.exit_scope();
+        let closure = self.closures.pop().unwrap();
+        self.local_offset = closure.offset;
+        let mut function = function?;
+
+        let Node::FnDecl {
+            return_type: Some(ret),
+            params,
+            environment,
+            ..
+        } = &mut function
+        else {
+            unreachable!()
+        };
+        let ty = Type::Closure {
+            params: params[1..]
+                .iter()
+                .map(|param| param.borrow().ty.clone().unwrap())
+                .collect(),
+            ret: Box::new(ret.clone()),
+        };
+        *environment = Some(Environment {
+            ty: ty.clone(),
+            captures: closure.copies,
+        });
+        self.nodes.push(function);
+
+        Ok(Node::Lambda {
+            function: name,
+            captures: closure.captures,
+            ty,
+            span: self.span(start),
+        })
+    }
+
+    // the function a lambda's body is made, without its environment yet
+    fn lambda_function(&mut self, name: String, start: usize) -> Result<Node> {
+        let opening = self.previous(1);
+        let env = Rc::new(RefCell::new(Symbol {
+            identifier: Token {
+                lexeme: Some(intern("env")),
+                ..opening.clone()
+            },
+            name: intern("env"),
+            structure: SymbolType::Variable,
+            class: StorageClass::Param,
+            ty: Some(Type::U64),
+            size: None,
+            offset: Some(self.gen_offset(Type::U64)),
+            params: None,
+            overload: None,
+        }));
+        let mut params = vec![env];
+        if opening.token_type == TokenType::Or {
+            params.extend(self.parse_params(false)?);
+            self.expect(vec![TokenType::Or])?;
         }
+
+        let body = self.expression()?;
+        let ret = self.node_type(&body, &opening)?;
+        if matches!(ret, Type::Array { .. }) {
+            return Err(Diagnostic::at(&opening, "A lambda cannot return an array")
+                .with_label(format!("the body is {}", ret)));
+        }
+
+        let identifier = Token {
+            token_type: TokenType::Identifier,
+            lexeme: Some(intern(&name)),
+            ..opening.clone()
+        };
+        let fn_name = Rc::new(RefCell::new(Symbol {
+            identifier: identifier.clone(),
+            name: intern(&name),
+            structure: SymbolType::Function,
+            class: StorageClass::Global,
+            ty: Some(ret.clone()),
+            size: None,
+            offset: None,
+            params: Some(params.clone()),
+            overload: None,
+        }));
+        let span = self.span(start);
+        Ok(Node::FnDecl {
+            identifier,
+            name,
+            body: Box::new(Node::CompoundStmt {
+                statements: vec![Node::ReturnStmt {
+                    keyword: opening,
+                    expr: Box::new(body),
+                    fn_name,
+                    span,
+                }],
+                span,
+            }),
+            stack_size: self.local_offset,
+            return_type: Some(ret),
+            params,
+            attributes: Vec::new(),
+            public: false,
+            environment: None,
+            span,
+        })
     }
 
-    fn return_statement(&mut self) -> Node {
-        if self.current_fn.is_none() {
-            panic!("Return statement outside of function");
+    // after the identifier of a variable holding a closure and the `(`
+    fn closure_call(&mut self, symbol: Rc<RefCell<Symbol>>, identifier: Token) -> Result<Node> {
+        let start = self.current - 2;
+        let ty = symbol.borrow().ty.clone().unwrap();
+        let Type::Closure { params, ret } = ty.clone() else {
+            unreachable!()
+        };
+        let callee = Node::LiteralExpr {
+            value: LiteralValue::Identifier(symbol),
+            ty: ty.clone(),
+            span: Span::of(&identifier),
+        };
+
+        let args = self.parse_args()?;
+        self.expect(vec![TokenType::RightParen])?;
+        if args.len() != params.len() {
+            return Err(Diagnostic::at(
+                &identifier,
+                format!(
+                    "{} takes {} argument{}, not {}",
+                    ty,
+                    params.len(),
+                    if params.len() == 1 { "" } else { "s" },
+                    args.len()
+                ),
+            )
+            .with_label("wrong number of arguments"));
         }
+        let args = args
+            .into_iter()
+            .zip(params)
+            .map(|(arg, param)| self.coerce(arg, param, &identifier))
+            .collect::<Result<Vec<_>>>()?;
+
+        let result = self.gen_offset(Type::U64);
+        Ok(Node::ClosureCall {
+            callee: Box::new(callee),
+            args,
+            ty: *ret,
+            result,
+            span: self.span(start),
+        })
+    }
 
-        let fn_sym = self.current_fn.clone().unwrap();
+    fn return_statement(&mut self) -> Result<Node> {
+        let start = self.current - 1;
+        let token = self.previous(1);
+        let fn_sym = match self.current_fn.clone() {
+            Some(fn_sym) => fn_sym,
+            None => {
+                return Err(Diagnostic::at(
+                    &token,
+                    "Return statement outside of function",
+                ))
+            }
+        };
 
         if fn_sym.borrow().ty.is_none() {
-            panic!(
-                "Function {} has no return type",
-                fn_sym.borrow().identifier.lexeme.clone().unwrap()
-            );
+            return Err(Diagnostic::at(
+                &token,
+                format!(
+                    "Function {} has no return type",
+                    fn_sym.borrow().identifier.lexeme.unwrap()
+                ),
+            ));
         }
 
-        let mut expr = self.expression();
-
         let ty = fn_sym.borrow().ty.as_ref().unwrap().clone();
-        expr = match self.modify_type(expr, ty, None) {
-            Some(node) => node,
-            None => panic!(
-                "Incompatible types at line {} column {}",
-                self.previous(1).line,
-                self.previous(1).column
-            ),
+        let expr = match &ty {
+            Type::Tuple(types) => self.tuple(&fn_sym, types)?,
+            _ => {
+                let expr = self.expression()?;
+                self.coerce(expr, ty, &token)?
+            }
         };
 
-        Node::ReturnStmt {
+        Ok(Node::ReturnStmt {
+            keyword: token,
             expr: Box::new(expr),
             fn_name: fn_sym,
+            span: self.span(start),
+        })
+    }
+
+    // `(q, r)`, the values a function returning several returns
+    fn tuple(&mut self, fn_sym: &Rc<RefCell<Symbol>>, types: &[Type]) -> Result<Node> {
+        let start = self.current;
+        let opening = self.expect(vec![TokenType::LeftParen])?;
+        let elements = self.parse_args()?;
+        self.expect(vec![TokenType::RightParen])?;
+        if elements.len() != types.len() {
+            return Err(Diagnostic::at(
+                &opening,
+                format!(
+                    "Function {} returns {} values, not {}",
+                    fn_sym.borrow().identifier.lexeme.unwrap(),
+                    types.len(),
+                    elements.len()
+                ),
+            )
+            .with_label("wrong number of values"));
         }
+
+        let elements = elements
+            .into_iter()
+            .zip(types)
+            .map(|(element, ty)| self.coerce(element, ty.clone(), &opening))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Node::TupleExpr {
+            elements,
+            ty: Type::Tuple(types.to_vec()),
+            span: self.span(start),
+        })
     }
 
-    fn array_access(&mut self) -> Node {
-        let identifier = self.previous(2);
-        let symbol = self.find_symbol(identifier.clone());
-
-        if symbol.is_none() {
-            panic!(
-                "Variable {} not declared at line {} column {}",
-                identifier.lexeme.clone().unwrap(),
-                identifier.line,
-                identifier.column
-            );
+    // `q, r = divmod(x, y)` after the first variable, each variable given
+    // one of the values of the call in turn
+    fn tuple_assignment(&mut self, start: usize, first: Node) -> Result<Node> {
+        let mut targets = vec![first];
+        while self.match_token(vec![TokenType::Comma]) {
+            // above the precedence of `=`, which follows the last of them
+            targets.push(self.binary(2)?);
+        }
+        let operator = self.expect(vec![TokenType::Assign])?;
+        for target in &targets {
+            self.assignable(target, &operator)?;
+        }
+
+        let call = self.expression()?;
+        let types = self.tuple_values(&call, &operator, targets.len())?;
+        self.tuple_assign(start, call, types, targets, &operator)
+    }
+
+    // the types of the values of a call, as many as there are variables
+    // for them
+    fn tuple_values(&self, call: &Node, operator: &Token, count: usize) -> Result<Vec<Type>> {
+        let types = match call.ty() {
+            Some(Type::Tuple(types)) if matches!(call, Node::FnCall { .. }) => types,
+            ty => {
+                return Err(Diagnostic::spanning(
+                    call.span(),
+                    "Only a call returning several values can be assigned to several variables",
+                )
+                .with_label(match ty {
+                    Some(ty) => format!("this is {}", ty),
+                    None => String::from("this has no value"),
+                }))
+            }
+        };
+        if types.len() != count {
+            return Err(Diagnostic::at(
+                operator,
+                format!(
+                    "{} values cannot be assigned to {} variables",
+                    types.len(),
+                    count
+                ),
+            )
+            .with_label(format!("the call returns {}", Type::Tuple(types))));
+        }
+        Ok(types)
+    }
+
+    // the call and the assignment of each of its values to the variable in
+    // its place
+    fn tuple_assign(
+        &self,
+        start: usize,
+        call: Node,
+        types: Vec<Type>,
+        targets: Vec<Node>,
+        operator: &Token,
+    ) -> Result<Node> {
+        let mut assignments = Vec::new();
+        for (index, (target, ty)) in targets.into_iter().zip(types).enumerate() {
+            let element = Node::TupleElement {
+                index,
+                ty,
+                span: call.span(),
+            };
+            let ty = self.node_type(&target, operator)?;
+            assignments.push(Node::AssignStmt {
+                span: target.span(),
+                left: Box::new(target),
+                expr: Box::new(self.coerce(element, ty, operator)?),
+            });
         }
+        Ok(Node::TupleAssign {
+            call: Box::new(call),
+            assignments,
+            span: self.span(start),
+        })
+    }
+
+    // after the identifier and the `[`
+    fn array_access(&mut self) -> Result<Node> {
+        let start = self.current - 2;
+        let identifier = self.previous(2);
+        let symbol = match self.find_symbol(identifier.clone()) {
+            Some(symbol) => symbol,
+            None => {
+                return Err(Diagnostic::at(
+                    &identifier,
+                    format!("Variable {} not declared", identifier.lexeme.unwrap()),
+                ))
+            }
+        };
 
-        let symbol = symbol.unwrap();
         if symbol.borrow().structure != SymbolType::Variable {
-            panic!(
-                "Expected variable at line {} column {} got {:?}",
-                identifier.line,
-                identifier.column,
-                symbol.borrow().structure
-            );
+            return Err(Diagnostic::at(
+                &identifier,
+                format!("Expected variable got {:?}", symbol.borrow().structure),
+            ));
         }
 
-        let symbol = self.find_symbol(identifier.clone()).unwrap_or_else(|| {
-            panic!(
-                "Variable {} not declared at line {} column {}",
-                identifier.lexeme.clone().unwrap(),
-                identifier.line,
-                identifier.column
-            )
-        });
         let ty = symbol.borrow().ty.as_ref().unwrap().clone();
         let mut left = Node::LiteralExpr {
             value: LiteralValue::Identifier(symbol),
             ty,
+            span: Span::of(&identifier),
         };
 
-        let mut index = self.expression();
+        let mut index = self.expression()?;
 
-        self.expect(vec![TokenType::RightBracket]).unwrap();
+        self.expect(vec![TokenType::RightBracket])?;
 
-        if !index.ty().unwrap().is_int() {
-            panic!(
-                "Expected integer at line {} column {}",
-                self.previous(1).line,
-                self.previous(1).column
-            );
+        if !self.node_type(&index, &self.previous(1))?.is_int() {
+            return Err(Diagnostic::at(&self.previous(1), "Expected integer"));
         }
 
-        index = match self.modify_type(index, Type::U32, Some(TokenType::Add)) {
+        // any integer indexes, argv[i] with the u64 argc counting up to it
+        index = match self.modify_type(index, Type::U64, Some(TokenType::Add)) {
             Some(node) => node,
-            None => panic!(
-                "Incompatible types at line {} column {}",
-                self.previous(1).line,
-                self.previous(1).column
-            ),
+            None => return Err(Diagnostic::at(&self.previous(1), "Incompatible types")),
+        };
+
+        // the index counts elements, the address moves by their size
+        index = match self.modify_type(index, left.ty().unwrap(), Some(TokenType::Add)) {
+            Some(node) => node,
+            None => return Err(Diagnostic::at(&self.previous(1), "Cannot index this type")),
         };
 
         left = Node::BinaryExpr {
@@ -1285,22 +2964,31 @@ impl Parser {
                 line: self.previous(1).line,
                 column: self.previous(1).column,
                 value: None,
+                trivia: None,
             },
             right: Box::new(index),
             ty: left.ty().unwrap(),
+            span: self.span(start),
         };
 
-        Node::UnaryExpr {
+        let ty = left
+            .ty()
+            .and_then(|ty| ty.value_at())
+            .ok_or_else(|| Diagnostic::at(&identifier, "Cannot index this type"))?;
+
+        Ok(Node::UnaryExpr {
             operator: Token {
                 token_type: TokenType::Mul,
                 lexeme: None,
                 line: self.previous(1).line,
                 column: self.previous(1).column,
                 value: None,
+                trivia: None,
             },
-            right: Box::new(left.clone()),
-            ty: left.ty().unwrap(),
-        }
+            right: Box::new(left),
+            ty,
+            span: self.span(start),
+        })
     }
 
     fn gen_offset(&mut self, ty: Type) -> isize {
@@ -1313,43 +3001,39 @@ impl Parser {
         self.local_offset = 0;
     }
 
-    fn parse_params(&mut self, first_pass: bool) -> Vec<Rc<RefCell<Symbol>>> {
+    fn parse_params(&mut self, first_pass: bool) -> Result<Vec<Rc<RefCell<Symbol>>>> {
         let mut params = Vec::new();
 
-        let mut i = 0;
-        let mut local_offset = 16;
         while self.check(TokenType::Identifier) {
             let identifier = self.advance();
-            self.expect(vec![TokenType::Colon]).unwrap();
-            let ty = self.parse_type();
-            let offset: isize;
-            if i < 6 {
-                offset = self.gen_offset(ty.clone());
-            } else {
-                offset = local_offset;
-                local_offset += 8;
-            }
+            self.expect(vec![TokenType::Colon])?;
+            let ty = self.parse_type()?;
+            // every parameter gets a slot among the locals, the backend copies
+            // it there from wherever its calling convention passes it
+            let offset = self.gen_offset(ty.clone());
 
             let symbol = Rc::new(RefCell::new(Symbol {
+                name: identifier.name().unwrap(),
                 identifier,
                 structure: SymbolType::Variable,
                 class: StorageClass::Param,
                 ty: Some(ty.clone()),
-                end_label: None,
                 size: None,
                 offset: Some(offset),
                 params: None,
+                overload: None,
             }));
 
             if !first_pass {
                 // TODO: Merge it with the bellow symbol, make single creation of a symbol
-                self.symbols.push(symbol.clone());
+                self.symbols.insert(symbol.clone());
+                self.references
+                    .push((symbol.borrow().identifier.clone(), symbol.clone()));
                 // self.add_symbol(
                 //     identifier.clone(),
                 //     SymbolType::Variable,
                 //     StorageClass::Param,
                 //     Some(ty.clone()),
-                //     None,
                 //     Some(offset),
                 //     None,
                 // );
@@ -1360,18 +3044,16 @@ impl Parser {
             if !self.match_token(vec![TokenType::Comma]) {
                 break;
             }
-
-            i += 1;
         }
 
-        params
+        Ok(params)
     }
 
-    fn parse_args(&mut self) -> Vec<Node> {
+    fn parse_args(&mut self) -> Result<Vec<Node>> {
         let mut args = Vec::new();
 
         while !self.check(TokenType::RightParen) {
-            let expr = self.expression();
+            let expr = self.expression()?;
             args.push(expr);
 
             if !self.match_token(vec![TokenType::Comma]) {
@@ -1379,6 +3061,6 @@ impl Parser {
             }
         }
 
-        args
+        Ok(args)
     }
 }
@@ -0,0 +1,102 @@
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    ConstantFolding,
+    Mem2Reg,
+    CopyPropagation,
+    ConstantPropagation,
+    BranchFolding,
+    UnreachableBlocks,
+    StrengthReduction,
+    DeadCode,
+    TailCalls,
+    Peephole,
+}
+
+const PASSES: [Pass; 10] = [
+    Pass::ConstantFolding,
+    Pass::Mem2Reg,
+    Pass::CopyPropagation,
+    Pass::ConstantPropagation,
+    Pass::BranchFolding,
+    Pass::UnreachableBlocks,
+    Pass::StrengthReduction,
+    Pass::DeadCode,
+    Pass::TailCalls,
+    Pass::Peephole,
+];
+
+impl Pass {
+    // the name used on the command line, e.g. `-fno-peephole`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pass::ConstantFolding => "constant-folding",
+            Pass::Mem2Reg => "mem2reg",
+            Pass::CopyPropagation => "copy-propagation",
+            Pass::ConstantPropagation => "constant-propagation",
+            Pass::BranchFolding => "branch-folding",
+            Pass::UnreachableBlocks => "unreachable-blocks",
+            Pass::StrengthReduction => "strength-reduction",
+            Pass::DeadCode => "dead-code",
+            Pass::TailCalls => "tail-calls",
+            Pass::Peephole => "peephole",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        PASSES.iter().find(|pass| pass.name() == name).copied()
+    }
+
+    // whether the pass rewrites the IR, the others work on the syntax tree or
+    // in the backend
+    pub fn on_ir(&self) -> bool {
+        !matches!(
+            self,
+            Pass::ConstantFolding | Pass::TailCalls | Pass::Peephole
+        )
+    }
+
+    // the lowest optimization level the pass runs at
+    fn level(&self) -> u8 {
+        match self {
+            Pass::StrengthReduction | Pass::TailCalls => 2,
+            _ => 1,
+        }
+    }
+}
+
+// Which optimization passes run, picked by level and then adjusted one by one
+#[derive(Debug, Clone)]
+pub struct Passes {
+    enabled: HashSet<Pass>,
+}
+
+impl Passes {
+    pub fn new(level: u8) -> Self {
+        Self {
+            enabled: PASSES
+                .iter()
+                .filter(|pass| pass.level() <= level)
+                .copied()
+                .collect(),
+        }
+    }
+
+    pub fn set(&mut self, pass: Pass, enabled: bool) {
+        if enabled {
+            self.enabled.insert(pass);
+        } else {
+            self.enabled.remove(&pass);
+        }
+    }
+
+    pub fn enabled(&self, pass: Pass) -> bool {
+        self.enabled.contains(&pass)
+    }
+
+    // the passes that run, in the order of the pipeline
+    pub fn iter(&self) -> impl Iterator<Item = Pass> + '_ {
+        PASSES.iter().copied().filter(|pass| self.enabled(*pass))
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::HashSet;
+
+use crate::{asm::Line, target::Target};
+
+// Cleans up after CodeGen, which emits every instruction on its own without
+// looking at its neighbours. The rules only ever shorten or cheapen the code,
+// so they run until none of them finds anything left to do.
+pub fn optimize(mut lines: Vec<Line>, target: Target) -> Vec<Line> {
+    loop {
+        let mut changed = remove_self_moves(&mut lines);
+        changed |= zero_with_xor(&mut lines);
+        changed |= remove_jumps_to_next(&mut lines);
+        changed |= remove_unused_labels(&mut lines, target);
+        changed |= remove_redundant_compares(&mut lines);
+        if !changed {
+            return lines;
+        }
+    }
+}
+
+// Whether nothing reads the flags as they are after line `index`. CodeGen
+// reads flags right after it sets them, never on the other side of a jump or
+// a label, so the search can stop there.
+fn flags_dead_after(lines: &[Line], index: usize) -> bool {
+    for line in &lines[index + 1..] {
+        if line.reads_flags() {
+            return false;
+        }
+        if line.writes_flags() || line.is_control_flow() {
+            return true;
+        }
+    }
+
+    true
+}
+
+// the 32 bit half of a 64 bit register, writing it clears the upper half
+fn dword_register(register: &str) -> String {
+    if register[2..].starts_with(|c: char| c.is_ascii_digit()) {
+        format!("{}d", register)
+    } else {
+        format!("%e{}", &register[2..])
+    }
+}
+
+// movq %rX, %rX
+fn remove_self_moves(lines: &mut Vec<Line>) -> bool {
+    let count = lines.len();
+    lines.retain(|line| {
+        !matches!(line, Line::Inst { op, operands }
+            if op == "movq" && operands[0].starts_with('%') && operands[0] == operands[1])
+    });
+    lines.len() != count
+}
+
+// movq $0, %rX is longer than xorl %eX, %eX, which clobbers the flags though
+fn zero_with_xor(lines: &mut [Line]) -> bool {
+    let mut changed = false;
+    for index in 0..lines.len() {
+        let Line::Inst { op, operands } = &lines[index] else {
+            continue;
+        };
+        if op != "movq" || operands[0] != "$0" || !operands[1].starts_with('%') {
+            continue;
+        }
+        if !flags_dead_after(lines, index) {
+            continue;
+        }
+
+        let register = dword_register(&operands[1]);
+        lines[index] = Line::Inst {
+            op: "xorl".to_string(),
+            operands: vec![register.clone(), register],
+        };
+        changed = true;
+    }
+
+    changed
+}
+
+// a jump to the labels right after it goes where control would anyway
+fn remove_jumps_to_next(lines: &mut Vec<Line>) -> bool {
+    let count = lines.len();
+    let mut index = 0;
+    while index < lines.len() {
+        if let Line::Inst { op, operands } = &lines[index] {
+            if op.starts_with('j') {
+                let next_labels = lines[index + 1..]
+                    .iter()
+                    .map_while(|line| match line {
+                        Line::Label(name) => Some(name),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                if next_labels.contains(&&operands[0]) {
+                    lines.remove(index);
+                    continue;
+                }
+            }
+        }
+        index += 1;
+    }
+
+    lines.len() != count
+}
+
+// Block labels nothing jumps to any more. Only the labels CodeGen numbers
+// within a function are considered, like .Lmain_3, functions are reached from
+// other files too. Those are local to the target, L on Mach-O where the
+// symbols of the source start with an underscore instead.
+fn remove_unused_labels(lines: &mut Vec<Line>, target: Target) -> bool {
+    let local = target.local("");
+    let used = lines
+        .iter()
+        .flat_map(|line| match line {
+            Line::Inst { operands, .. } => operands.clone(),
+            _ => Vec::new(),
+        })
+        .collect::<HashSet<_>>();
+
+    let count = lines.len();
+    lines.retain(|line| match line {
+        Line::Label(name) => {
+            let block = name.starts_with(&local)
+                && name
+                    .rsplit_once('_')
+                    .is_some_and(|(_, n)| n.chars().all(|c| c.is_ascii_digit()));
+            !block || used.contains(name)
+        }
+        _ => true,
+    });
+    lines.len() != count
+}
+
+// A compare is redundant when nothing reads its result, or when the same
+// compare was made right before and only conditional jumps came in between.
+fn remove_redundant_compares(lines: &mut Vec<Line>) -> bool {
+    let is_compare = |line: &Line| line.is_inst("cmpq") || line.is_inst("testq");
+
+    let count = lines.len();
+    let mut index = 0;
+    while index < lines.len() {
+        if is_compare(&lines[index]) {
+            let repeated = lines[..index]
+                .iter()
+                .rev()
+                .find(|line| !(line.reads_flags() && line.is_control_flow()))
+                .is_some_and(|line| *line == lines[index]);
+            if repeated || flags_dead_after(lines, index) {
+                lines.remove(index);
+                continue;
+            }
+        }
+        index += 1;
+    }
+
+    lines.len() != count
+}
@@ -0,0 +1,703 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    diagnostic::Diagnostic,
+    intern::{intern, Name},
+    lexer::{Lexer, Literal, Token, TokenType},
+    parser::Parser,
+};
+
+// The preprocessor runs on the tokens between the lexer and the parser. The
+// lexer leaves every `#` line whole as a directive, and this takes them out
+// again: `#define` names a list of tokens, or a function of some, which
+// every use of the name after it stands for, until `#undef` forgets it.
+// `#include "file"` stands for the tokens of the file, preprocessed as well,
+// and `#ifdef`, `#ifndef` and `#if` keep the tokens up to their `#endif`
+// only if their condition holds, or else those after `#elif` or `#else`.
+#[derive(Debug, Clone)]
+struct Macro {
+    // the names of the parameters, for a function-like macro
+    params: Option<Vec<Name>>,
+    body: Vec<Token>,
+    line: usize,
+}
+
+// Where `#include` looks for the files it names
+#[derive(Debug, Clone, Default)]
+pub struct Includes {
+    // the files the source was read from, with the line each starts on, as
+    // a file is looked for next to the one including it first
+    pub files: Vec<(String, usize)>,
+    // the directories of `-I`, looked in after that in order
+    pub paths: Vec<String>,
+}
+
+// An `#if`, `#ifdef` or `#ifndef` whose `#endif` hasn't come yet
+struct Condition {
+    directive: Token,
+    // whether the tokens of the branch it is in are kept
+    kept: bool,
+    // whether one of its branches was kept so far, the others aren't
+    taken: bool,
+    // whether `#else` came already
+    otherwise: bool,
+}
+
+struct Preprocessor {
+    macros: HashMap<Name, Macro>,
+    // the string literals the lexer labelled, so the ones in the macros
+    // take labels of their own
+    labels: usize,
+    includes: Includes,
+    // the files being included, each with the name it was included by,
+    // from the source file the first of them is in
+    including: Vec<(PathBuf, String)>,
+    // every file included so far, which is only included the first time
+    included: Vec<PathBuf>,
+    // the conditions the tokens are in, innermost last
+    conditions: Vec<Condition>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// The tokens with the directives done and every macro expanded, the macros
+// of `defines` being defined before the first of them, each with its text
+pub fn preprocess(
+    tokens: Vec<Token>,
+    includes: &Includes,
+    defines: &[(String, String)],
+) -> Result<Vec<Token>, Vec<Diagnostic>> {
+    let labels = tokens
+        .iter()
+        .filter_map(|token| match &token.value {
+            Some(Literal::String { label, .. }) => label.strip_prefix("str.")?.parse().ok(),
+            _ => None,
+        })
+        .map(|label: usize| label + 1)
+        .max()
+        .unwrap_or(0);
+    let mut preprocessor = Preprocessor {
+        macros: HashMap::new(),
+        labels,
+        includes: includes.clone(),
+        including: Vec::new(),
+        included: Vec::new(),
+        conditions: Vec::new(),
+        diagnostics: Vec::new(),
+    };
+    for (name, text) in defines {
+        preprocessor.predefine(name, text);
+    }
+    let tokens = preprocessor.expand(&tokens, &[]);
+    preprocessor.unclosed(0);
+    if !preprocessor.diagnostics.is_empty() {
+        return Err(preprocessor.diagnostics);
+    }
+    Ok(tokens)
+}
+
+// what the name of a macro can be
+fn name(token: &Token) -> Option<Name> {
+    match token.token_type {
+        TokenType::Identifier => token.lexeme,
+        _ => None,
+    }
+}
+
+// a number the preprocessor worked out, where the token it stands for was
+fn number(token: &Token, value: u64) -> Token {
+    Token {
+        token_type: TokenType::Integer,
+        lexeme: Some(intern(&value.to_string())),
+        line: token.line,
+        column: token.column,
+        value: Some(Literal::Integer(value)),
+        trivia: None,
+    }
+}
+
+// whether two lists of tokens read the same, wherever they are
+fn same(a: &[Token], b: &[Token]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .all(|(a, b)| a.token_type == b.token_type && a.lexeme == b.lexeme)
+}
+
+impl Preprocessor {
+    // Expands the macros among the tokens, with the ones being expanded
+    // already left alone so that a macro using itself doesn't go on forever
+    fn expand(&mut self, tokens: &[Token], active: &[Name]) -> Vec<Token> {
+        let mut output = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            i += 1;
+            if token.token_type == TokenType::Directive {
+                if i > 1 && tokens[i - 2].line == token.line {
+                    self.diagnostics
+                        .push(Diagnostic::at(token, "A directive has to start its line"));
+                }
+                output.extend(self.directive(token));
+                continue;
+            }
+            // a condition that doesn't hold leaves out all but the end
+            if !self.kept() && token.token_type != TokenType::Eof {
+                continue;
+            }
+
+            let name = name(token).filter(|name| !active.contains(name));
+            let found = name
+                .as_ref()
+                .and_then(|name| self.macros.get(name))
+                .cloned();
+            let (Some(name), Some(found)) = (name, found) else {
+                output.push(token.clone());
+                continue;
+            };
+            let mut body = found.body;
+            if let Some(params) = found.params {
+                // a function-like macro without arguments is just a name
+                if tokens.get(i).map(|next| next.token_type) != Some(TokenType::LeftParen) {
+                    output.push(token.clone());
+                    continue;
+                }
+                let Some((mut args, end)) = self.arguments(token, tokens, i) else {
+                    return output;
+                };
+                i = end;
+                if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+                    args.clear();
+                }
+                if args.len() != params.len() {
+                    self.diagnostics.push(
+                        Diagnostic::at(
+                            token,
+                            format!(
+                                "Macro {} takes {} argument{}, not {}",
+                                name,
+                                params.len(),
+                                if params.len() == 1 { "" } else { "s" },
+                                args.len()
+                            ),
+                        )
+                        .with_label("wrong number of arguments"),
+                    );
+                    continue;
+                }
+                let args: Vec<Vec<Token>> =
+                    args.iter().map(|arg| self.expand(arg, active)).collect();
+                body = body
+                    .into_iter()
+                    .flat_map(|token| {
+                        match params.iter().position(|param| Some(*param) == token.lexeme) {
+                            Some(param) if token.token_type == TokenType::Identifier => {
+                                args[param].clone()
+                            }
+                            _ => vec![token],
+                        }
+                    })
+                    .collect();
+            }
+
+            // what the macro stands for is where it is used
+            for expanded in &mut body {
+                expanded.line = token.line;
+                expanded.column = token.column;
+                expanded.trivia = None;
+            }
+            let mut active = active.to_vec();
+            active.push(name);
+            output.extend(self.expand(&body, &active));
+        }
+        output
+    }
+
+    // The arguments of a function-like macro from the `(` at `start`, split
+    // at the commas outside any parentheses, and where they end
+    fn arguments(
+        &mut self,
+        call: &Token,
+        tokens: &[Token],
+        start: usize,
+    ) -> Option<(Vec<Vec<Token>>, usize)> {
+        let mut args = vec![Vec::new()];
+        let mut depth = 0;
+        for (i, token) in tokens.iter().enumerate().skip(start + 1) {
+            match token.token_type {
+                TokenType::LeftParen => depth += 1,
+                TokenType::RightParen if depth == 0 => return Some((args, i + 1)),
+                TokenType::RightParen => depth -= 1,
+                TokenType::Comma if depth == 0 => {
+                    args.push(Vec::new());
+                    continue;
+                }
+                TokenType::Directive | TokenType::Eof => break,
+                _ => {}
+            }
+            args.last_mut().unwrap().push(token.clone());
+        }
+        self.diagnostics.push(
+            Diagnostic::at(call, "The arguments of the macro are not closed")
+                .with_label("a `)` is missing"),
+        );
+        None
+    }
+
+    // the tokens of the text, without Eof, from where it is in the source
+    fn lex(
+        &mut self,
+        text: &str,
+        line: usize,
+        column: usize,
+    ) -> Result<Vec<Token>, Vec<Diagnostic>> {
+        let mut lexer = Lexer::new(text.to_string()).with_position(line, column, self.labels);
+        let mut tokens = lexer.scan_tokens()?.clone();
+        self.labels += tokens
+            .iter()
+            .filter(|token| token.token_type == TokenType::String)
+            .count();
+        tokens.pop();
+        Ok(tokens)
+    }
+
+    // Does what the directive says, giving back the tokens it stands for
+    fn directive(&mut self, directive: &Token) -> Vec<Token> {
+        let text = directive
+            .lexeme
+            .map(Name::text)
+            .unwrap_or_else(|| Rc::from("#"));
+        let mut tokens = match self.lex(&text[1..], directive.line, directive.column + 1) {
+            Ok(tokens) => tokens,
+            Err(diagnostics) => {
+                self.diagnostics.extend(diagnostics);
+                return Vec::new();
+            }
+        };
+
+        let keyword = tokens.remove(0);
+        let lexeme = keyword.lexeme.map(Name::text).unwrap_or_default();
+        let word = &*lexeme;
+        let condition = matches!(word, "if" | "ifdef" | "ifndef" | "elif" | "else" | "endif");
+        // where tokens are left out, so are the directives but those that
+        // tell where that ends
+        if !self.kept() && !condition {
+            return Vec::new();
+        }
+        match word {
+            "define" => self.define(directive, tokens),
+            "undef" => self.undef(directive, tokens),
+            "include" => return self.include(directive, tokens),
+            _ if condition => self.condition(directive, &keyword, tokens),
+            _ => self.diagnostics.push(
+                Diagnostic::at(&keyword, format!("Unknown directive #{}", keyword.text()))
+                    .with_label(
+                        "only #define, #undef, #include, #if, #ifdef, #ifndef, #elif, #else and #endif are known",
+                    ),
+            ),
+        }
+        Vec::new()
+    }
+
+    // whether the tokens here are kept, as every condition they are in holds
+    fn kept(&self) -> bool {
+        self.conditions.iter().all(|condition| condition.kept)
+    }
+
+    fn condition(&mut self, directive: &Token, keyword: &Token, tokens: Vec<Token>) {
+        let word = keyword.text();
+        let opens = matches!(word.as_str(), "if" | "ifdef" | "ifndef");
+        let last = if opens { None } else { self.conditions.pop() };
+        if !opens && last.is_none() {
+            self.diagnostics
+                .push(Diagnostic::at(keyword, format!("#{} without #if", word)));
+            return;
+        }
+        // the conditions of a branch that can't be kept aren't looked at
+        let outer = self.kept() && !last.as_ref().is_some_and(|last| last.taken);
+        let kept = match word.as_str() {
+            "ifdef" | "ifndef" => {
+                let defined = self.defined(keyword, &tokens);
+                outer && defined == (word == "ifdef")
+            }
+            "if" | "elif" => outer && self.evaluate(directive, keyword, tokens),
+            _ => {
+                if let Some(extra) = tokens.first() {
+                    self.diagnostics.push(Diagnostic::at(
+                        extra,
+                        format!("#{} takes nothing after it", word),
+                    ));
+                }
+                outer
+            }
+        };
+
+        let Some(mut last) = last else {
+            self.conditions.push(Condition {
+                directive: directive.clone(),
+                kept,
+                taken: kept,
+                otherwise: false,
+            });
+            return;
+        };
+        if word == "endif" {
+            return;
+        }
+        if last.otherwise {
+            self.diagnostics.push(
+                Diagnostic::at(keyword, format!("#{} after #else", word))
+                    .with_label("the conditions end with #else"),
+            );
+        }
+        last.kept = kept;
+        last.taken |= kept;
+        last.otherwise |= word == "else";
+        self.conditions.push(last);
+    }
+
+    // Reports the conditions that aren't closed, from the first of them
+    // on, at the end of a file
+    fn unclosed(&mut self, from: usize) {
+        for condition in self.conditions.drain(from..) {
+            self.diagnostics.push(
+                Diagnostic::at(&condition.directive, "The condition is not closed")
+                    .with_label("an #endif is missing"),
+            );
+        }
+    }
+
+    // whether the macro `#ifdef` or `#ifndef` names is defined
+    fn defined(&mut self, keyword: &Token, tokens: &[Token]) -> bool {
+        match tokens {
+            [identifier] if name(identifier).is_some() => {
+                self.macros.contains_key(&name(identifier).unwrap())
+            }
+            _ => {
+                self.diagnostics.push(Diagnostic::at(
+                    tokens.first().unwrap_or(keyword),
+                    format!("#{} takes only the name of a macro", keyword.text()),
+                ));
+                false
+            }
+        }
+    }
+
+    // Whether the condition of `#if` or `#elif` holds: a constant
+    // expression, after `defined NAME` and `defined(NAME)` are 1 or 0 as
+    // the macro is defined or not, the macros are expanded and any name
+    // left is 0
+    fn evaluate(&mut self, directive: &Token, keyword: &Token, tokens: Vec<Token>) -> bool {
+        if tokens.is_empty() {
+            self.diagnostics.push(Diagnostic::at(
+                keyword,
+                format!("#{} takes a condition", keyword.text()),
+            ));
+            return false;
+        }
+        let mut resolved = Vec::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let token = &tokens[i];
+            i += 1;
+            if name(token) != Some(intern("defined")) {
+                resolved.push(token.clone());
+                continue;
+            }
+            let parens = tokens.get(i).map(|token| token.token_type) == Some(TokenType::LeftParen);
+            let at = i + parens as usize;
+            let closed = !parens
+                || tokens.get(at + 1).map(|token| token.token_type) == Some(TokenType::RightParen);
+            let Some(macro_name) = tokens.get(at).and_then(name).filter(|_| closed) else {
+                self.diagnostics
+                    .push(Diagnostic::at(token, "defined takes the name of a macro"));
+                return false;
+            };
+            resolved.push(number(token, self.macros.contains_key(&macro_name) as u64));
+            i = at + 1 + parens as usize;
+        }
+
+        let mut expanded: Vec<Token> = self
+            .expand(&resolved, &[])
+            .into_iter()
+            .map(|token| match token.token_type {
+                TokenType::Identifier => number(&token, 0),
+                _ => token,
+            })
+            .collect();
+        let end = directive.column + directive.text().chars().count();
+        expanded.push(Token {
+            token_type: TokenType::Eof,
+            lexeme: None,
+            line: directive.line,
+            column: end,
+            value: None,
+            trivia: None,
+        });
+        match Parser::new(expanded).directive_condition() {
+            Ok(value) => value != 0,
+            Err(diagnostic) => {
+                self.diagnostics.push(diagnostic);
+                false
+            }
+        }
+    }
+
+    // The tokens of the file the directive names, which are all where the
+    // directive is, as the source it is in is the one shown for errors
+    fn include(&mut self, directive: &Token, tokens: Vec<Token>) -> Vec<Token> {
+        let name = match tokens.as_slice() {
+            [Token {
+                value: Some(Literal::String { value, .. }),
+                ..
+            }] => value.clone(),
+            _ => {
+                self.diagnostics.push(Diagnostic::at(
+                    tokens.first().unwrap_or(directive),
+                    "#include takes the name of a file in quotes",
+                ));
+                return Vec::new();
+            }
+        };
+
+        // the source file the directive is in starts the chain of includes
+        let outermost = self.including.is_empty();
+        if outermost {
+            let file = self
+                .includes
+                .files
+                .iter()
+                .rev()
+                .find(|(_, first)| *first <= directive.line);
+            if let Some((file, _)) = file {
+                let path = Path::new(file);
+                let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+                self.including.push((path, file.clone()));
+            }
+        }
+        let expanded = self.include_file(directive, &name);
+        if outermost {
+            self.including.clear();
+        }
+        expanded
+    }
+
+    fn include_file(&mut self, directive: &Token, name: &str) -> Vec<Token> {
+        let Some(path) = self.find(name) else {
+            self.diagnostics.push(
+                Diagnostic::at(directive, format!("Cannot find {}", name))
+                    .with_label("not next to the file including it, nor in a directory of -I"),
+            );
+            return Vec::new();
+        };
+        let cycle = self
+            .including
+            .iter()
+            .position(|(including, _)| *including == path);
+        if let Some(first) = cycle {
+            let chain: Vec<&str> = self.including[first..]
+                .iter()
+                .map(|(_, name)| name.as_str())
+                .chain([name])
+                .collect();
+            self.diagnostics.push(
+                Diagnostic::at(directive, format!("{} includes itself", name))
+                    .with_label(chain.join(" includes ")),
+            );
+            return Vec::new();
+        }
+        if self.included.contains(&path) {
+            return Vec::new();
+        }
+        self.included.push(path.clone());
+
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) => {
+                self.diagnostics.push(Diagnostic::at(
+                    directive,
+                    format!("Cannot read {}: {}", name, e),
+                ));
+                return Vec::new();
+            }
+        };
+        let before = self.diagnostics.len();
+        let mut expanded = match self.lex(&text, 1, 1) {
+            Ok(tokens) => {
+                self.including.push((path, name.to_string()));
+                let depth = self.conditions.len();
+                let expanded = self.expand(&tokens, &[]);
+                self.unclosed(depth);
+                self.including.pop();
+                expanded
+            }
+            Err(diagnostics) => {
+                self.diagnostics.extend(diagnostics);
+                Vec::new()
+            }
+        };
+
+        // an error in the file is shown at the directive, saying where
+        for diagnostic in &mut self.diagnostics[before..] {
+            let mut label = format!("in {}:{}:{}", name, diagnostic.line, diagnostic.column);
+            if let Some(inner) = &diagnostic.label {
+                label = format!("{}: {}", label, inner);
+            }
+            diagnostic.line = directive.line;
+            diagnostic.column = directive.column;
+            diagnostic.length = directive.text().chars().count();
+            diagnostic.label = Some(label);
+        }
+        for token in &mut expanded {
+            token.line = directive.line;
+            token.column = directive.column;
+            token.trivia = None;
+        }
+        expanded
+    }
+
+    // The file named, next to the one including it or else in a directory
+    // of `-I`, as the same file is found whichever way it is named
+    fn find(&self, name: &str) -> Option<PathBuf> {
+        let next_to = match self.including.last() {
+            Some((including, _)) => including.parent().unwrap_or(Path::new("")).to_path_buf(),
+            None => PathBuf::new(),
+        };
+        std::iter::once(next_to)
+            .chain(self.includes.paths.iter().map(PathBuf::from))
+            .map(|directory| directory.join(name))
+            .find(|path| path.is_file())
+            .map(|path| path.canonicalize().unwrap_or(path))
+    }
+
+    fn define(&mut self, directive: &Token, mut tokens: Vec<Token>) {
+        let Some(name) = tokens.first().and_then(name) else {
+            let at = tokens.first().unwrap_or(directive);
+            self.diagnostics
+                .push(Diagnostic::at(at, "#define takes the name of a macro"));
+            return;
+        };
+        let identifier = tokens.remove(0);
+
+        // the parameters are only those of `NAME(`, a `(` after a space
+        // starts the body
+        let right_after = tokens.first().is_some_and(|token| {
+            token.token_type == TokenType::LeftParen
+                && token.line == identifier.line
+                && token.column == identifier.column + identifier.text().chars().count()
+        });
+        let params = if right_after {
+            let Some(params) = self.params(&identifier, &mut tokens) else {
+                return;
+            };
+            Some(params)
+        } else {
+            None
+        };
+
+        let defined = Macro {
+            params,
+            body: tokens,
+            line: directive.line,
+        };
+        if let Some(first) = self.macros.get(&name) {
+            let again = first.params == defined.params && same(&first.body, &defined.body);
+            if !again {
+                self.diagnostics.push(
+                    Diagnostic::at(&identifier, format!("Macro {} already defined", name))
+                        .with_label(match first.line {
+                            0 => String::from("defined with -D"),
+                            line => format!("first defined on line {}", line),
+                        }),
+                );
+            }
+            return;
+        }
+        self.macros.insert(name, defined);
+    }
+
+    // A macro of `-DNAME=text`, which is on no line of the source
+    fn predefine(&mut self, name: &str, text: &str) {
+        match self.lex(text, 0, 0) {
+            Ok(body) => {
+                let defined = Macro {
+                    params: None,
+                    body,
+                    line: 0,
+                };
+                self.macros.insert(intern(name), defined);
+            }
+            Err(diagnostics) => {
+                for diagnostic in diagnostics {
+                    self.diagnostics.push(Diagnostic::error(format!(
+                        "-D{}={}: {}",
+                        name, text, diagnostic.message
+                    )));
+                }
+            }
+        }
+    }
+
+    // Takes the parameters in parentheses off the front of the tokens
+    fn params(&mut self, identifier: &Token, tokens: &mut Vec<Token>) -> Option<Vec<Name>> {
+        let close = tokens
+            .iter()
+            .position(|token| token.token_type == TokenType::RightParen);
+        let Some(close) = close else {
+            self.diagnostics.push(
+                Diagnostic::at(identifier, "The parameters of the macro are not closed")
+                    .with_label("a `)` is missing"),
+            );
+            return None;
+        };
+        let list: Vec<Token> = tokens.drain(..=close).collect();
+        let list = &list[1..list.len() - 1];
+
+        let mut params: Vec<Name> = Vec::new();
+        for (i, token) in list.iter().enumerate() {
+            let expected = if i % 2 == 0 {
+                name(token).is_some()
+            } else {
+                token.token_type == TokenType::Comma
+            };
+            let last = i + 1 == list.len();
+            if !expected || (last && token.token_type == TokenType::Comma) {
+                self.diagnostics.push(Diagnostic::at(
+                    token,
+                    "The parameters of a macro are names separated by commas",
+                ));
+                return None;
+            }
+            if let Some(param) = name(token) {
+                if params.contains(&param) {
+                    self.diagnostics.push(Diagnostic::at(
+                        token,
+                        format!(
+                            "Parameter {} of macro {} already declared",
+                            param,
+                            identifier.text()
+                        ),
+                    ));
+                    return None;
+                }
+                params.push(param);
+            }
+        }
+        Some(params)
+    }
+
+    fn undef(&mut self, directive: &Token, tokens: Vec<Token>) {
+        match tokens.as_slice() {
+            [identifier] if name(identifier).is_some() => {
+                self.macros.remove(&name(identifier).unwrap());
+            }
+            _ => self.diagnostics.push(Diagnostic::at(
+                tokens.first().unwrap_or(directive),
+                "#undef takes only the name of a macro",
+            )),
+        }
+    }
+}
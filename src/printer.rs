@@ -0,0 +1,250 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    ast::{LiteralValue, Node},
+    intern::Name,
+    lexer::TokenType,
+    parser::Symbol,
+    visit::{walk_node, Visitor},
+};
+
+// Prints the tree one node per line, children indented under their parent:
+//
+// FnDecl main(): u32
+//   CompoundStmt
+//     AssignStmt
+//       Identifier a: u32
+//       WidenExpr: u32
+//         Literal 7: u8
+pub fn dump_ast(nodes: &[Node]) -> String {
+    let mut printer = Printer {
+        out: String::new(),
+        depth: 0,
+    };
+    for node in nodes {
+        printer.visit_node(node);
+    }
+
+    printer.out
+}
+
+struct Printer {
+    out: String,
+    depth: usize,
+}
+
+// Writes the tree as a Graphviz graph, each node a box labelled like a line
+// of `dump_ast` with an edge to each of its children, for `dot -Tsvg`
+pub fn dump_dot(nodes: &[Node]) -> String {
+    let mut printer = DotPrinter {
+        out: String::from("digraph ast {\n  node [shape=box, fontname=\"monospace\"];\n"),
+        count: 0,
+        parents: Vec::new(),
+    };
+    for node in nodes {
+        printer.visit_node(node);
+    }
+
+    printer.out.push_str("}\n");
+    printer.out
+}
+
+struct DotPrinter {
+    out: String,
+    // nodes are numbered in the order they are visited
+    count: usize,
+    parents: Vec<usize>,
+}
+
+impl Visitor for DotPrinter {
+    fn visit_node(&mut self, node: &Node) {
+        let id = self.count;
+        self.count += 1;
+        let label = describe(node).replace('\\', "\\\\").replace('"', "\\\"");
+        self.out
+            .push_str(&format!("  n{} [label=\"{}\"];\n", id, label));
+        if let Some(parent) = self.parents.last() {
+            self.out.push_str(&format!("  n{} -> n{};\n", parent, id));
+        }
+
+        self.parents.push(id);
+        walk_node(self, node);
+        self.parents.pop();
+    }
+}
+
+impl Visitor for Printer {
+    fn visit_node(&mut self, node: &Node) {
+        let line = describe(node);
+        self.out.push_str(&"  ".repeat(self.depth));
+        self.out.push_str(&line);
+        self.out.push('\n');
+
+        self.depth += 1;
+        walk_node(self, node);
+        self.depth -= 1;
+    }
+}
+
+fn describe(node: &Node) -> String {
+    match node {
+        Node::BinaryExpr { operator, ty, .. } => {
+            format!("BinaryExpr {}: {}", operator_name(operator.token_type), ty)
+        }
+        Node::UnaryExpr { operator, ty, .. } => {
+            format!("UnaryExpr {}: {}", operator_name(operator.token_type), ty)
+        }
+        Node::WidenExpr { ty, .. } => format!("WidenExpr: {}", ty),
+        Node::ScaleExpr { size, ty, .. } => format!("ScaleExpr x{}: {}", size, ty),
+        Node::LiteralExpr { value, ty, .. } => match value {
+            LiteralValue::U8(value) => format!("Literal {}: {}", value, ty),
+            LiteralValue::U16(value) => format!("Literal {}: {}", value, ty),
+            LiteralValue::U32(value) => format!("Literal {}: {}", value, ty),
+            LiteralValue::U64(value) => format!("Literal {}: {}", value, ty),
+            LiteralValue::Identifier(symbol) => format!("Identifier {}: {}", name(symbol), ty),
+            LiteralValue::String { value, .. } => format!("String {:?}: {}", value, ty),
+        },
+        Node::VarDecl {
+            symbol,
+            is_local,
+            ty,
+            external,
+            init,
+            ..
+        } => format!(
+            "VarDecl {}: {}{}{}",
+            name(symbol),
+            ty,
+            init.map_or(String::new(), |init| format!(" = {}", init)),
+            storage(*is_local, *external)
+        ),
+        Node::VarDeclMany {
+            symbols,
+            is_local,
+            ty,
+            external,
+            ..
+        } => format!(
+            "VarDecl {}: {}{}",
+            symbols.iter().map(name).collect::<Vec<_>>().join(", "),
+            ty,
+            storage(*is_local, *external)
+        ),
+        Node::AssignStmt { .. } => String::from("AssignStmt"),
+        Node::CompoundStmt { .. } => String::from("CompoundStmt"),
+        Node::IfStmt { else_branch, .. } => {
+            if else_branch.is_some() {
+                String::from("IfStmt (with else)")
+            } else {
+                String::from("IfStmt")
+            }
+        }
+        Node::WhileStmt { label, .. } => match label {
+            Some(label) => format!(
+                "WhileStmt {}",
+                label.lexeme.map(Name::text).unwrap_or_default()
+            ),
+            None => String::from("WhileStmt"),
+        },
+        Node::BreakStmt { label, .. } => match label {
+            Some(label) => format!(
+                "BreakStmt {}",
+                label.lexeme.map(Name::text).unwrap_or_default()
+            ),
+            None => String::from("BreakStmt"),
+        },
+        Node::ContinueStmt { label, .. } => match label {
+            Some(label) => format!(
+                "ContinueStmt {}",
+                label.lexeme.map(Name::text).unwrap_or_default()
+            ),
+            None => String::from("ContinueStmt"),
+        },
+        Node::FnDecl {
+            name: function,
+            return_type,
+            params,
+            public,
+            ..
+        } => {
+            let params = params
+                .iter()
+                .map(|param| format!("{}: {}", name(param), param.borrow().ty.as_ref().unwrap()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            let public = if *public { "pub " } else { "" };
+            match return_type {
+                Some(ty) => format!("FnDecl {}{}({}): {}", public, function, params, ty),
+                None => format!("FnDecl {}{}({})", public, function, params),
+            }
+        }
+        Node::FnCall { name, ty, .. } => format!("FnCall {}: {}", name, ty),
+        Node::TupleExpr { ty, .. } => format!("TupleExpr: {}", ty),
+        Node::TupleAssign { .. } => String::from("TupleAssign"),
+        Node::TupleElement { index, ty, .. } => format!("TupleElement {}: {}", index, ty),
+        Node::Lambda {
+            function,
+            captures,
+            ty,
+            ..
+        } => match captures.is_empty() {
+            true => format!("Lambda {}: {}", function, ty),
+            false => format!(
+                "Lambda {} capturing {}: {}",
+                function,
+                captures.iter().map(name).collect::<Vec<_>>().join(", "),
+                ty
+            ),
+        },
+        Node::ClosureCall { ty, .. } => format!("ClosureCall: {}", ty),
+        Node::ReturnStmt { .. } => String::from("ReturnStmt"),
+        Node::PostIncStmt { .. } => String::from("PostIncStmt"),
+        Node::PostDecStmt { .. } => String::from("PostDecStmt"),
+        Node::PreIncStmt { .. } => String::from("PreIncStmt"),
+        Node::PreDecStmt { .. } => String::from("PreDecStmt"),
+        Node::ToBool { .. } => String::from("ToBool"),
+    }
+}
+
+fn name(symbol: &Rc<RefCell<Symbol>>) -> String {
+    symbol
+        .borrow()
+        .identifier
+        .lexeme
+        .map(|name| name.to_string())
+        .unwrap_or_default()
+}
+
+fn storage(is_local: bool, external: bool) -> &'static str {
+    match (is_local, external) {
+        (true, _) => "",
+        (false, false) => " (global)",
+        (false, true) => " (extern global)",
+    }
+}
+
+// the parser makes up some operator tokens without a lexeme, so go by the type
+fn operator_name(token_type: TokenType) -> &'static str {
+    match token_type {
+        TokenType::Add => "+",
+        TokenType::Sub => "-",
+        TokenType::Mul => "*",
+        TokenType::Div => "/",
+        TokenType::LessThan => "<",
+        TokenType::GreaterThan => ">",
+        TokenType::Ampersand => "&",
+        TokenType::Or => "|",
+        TokenType::Xor => "^",
+        TokenType::LogicalNot => "!",
+        TokenType::Invert => "~",
+        TokenType::Equal => "==",
+        TokenType::NotEqual => "!=",
+        TokenType::LessThanOrEqual => "<=",
+        TokenType::GreaterThanOrEqual => ">=",
+        TokenType::LogicalAnd => "&&",
+        TokenType::LogicalOr => "||",
+        TokenType::LeftShift => "<<",
+        TokenType::RightShift => ">>",
+        _ => "?",
+    }
+}
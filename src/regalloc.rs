@@ -0,0 +1,300 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    cfg::Cfg,
+    diagnostic::{Diagnostic, Result},
+    ir::{Address, Inst, VReg},
+};
+
+// Every instruction of the flattened function gets two positions: the even
+// one where it reads its operands and the odd one where it writes its result,
+// so a result can take the register of an operand that dies there.
+fn read(index: usize) -> usize {
+    2 * index
+}
+
+fn write(index: usize) -> usize {
+    2 * index + 1
+}
+
+// The positions a virtual register has to be in its register for. A spilled
+// one only needs a register at the single position it is read or written at.
+#[derive(Debug, Clone, Copy)]
+struct Interval {
+    vreg: VReg,
+    start: usize,
+    end: usize,
+    spilled: bool,
+}
+
+// Where the value of every virtual register is kept, by the index of the
+// instruction in the flattened function.
+#[derive(Default)]
+pub struct Allocation {
+    registers: HashMap<VReg, usize>,
+    // the registers a spilled value is loaded into and stored from
+    reloads: HashMap<(VReg, usize), usize>,
+    stores: HashMap<(VReg, usize), usize>,
+    // the stack slot of every spilled register, counted from 0
+    pub slots: HashMap<VReg, usize>,
+    // for finding what is live across a call
+    intervals: Vec<(Interval, usize)>,
+    // how many arguments a call passes in registers
+    arguments: usize,
+}
+
+impl Allocation {
+    // the register an instruction reads a virtual register from
+    pub fn register(&self, vreg: VReg, index: usize) -> usize {
+        match self.registers.get(&vreg) {
+            Some(register) => *register,
+            None => self.reloads[&(vreg, index)],
+        }
+    }
+
+    // the register an instruction writes a virtual register to
+    pub fn destination(&self, vreg: VReg, index: usize) -> usize {
+        match self.registers.get(&vreg) {
+            Some(register) => *register,
+            None => self.stores[&(vreg, index)],
+        }
+    }
+
+    // every register the function keeps a value in
+    pub fn used_registers(&self) -> HashSet<usize> {
+        self.registers
+            .values()
+            .chain(self.reloads.values())
+            .chain(self.stores.values())
+            .copied()
+            .collect()
+    }
+
+    // the virtual registers an instruction reads from registers
+    pub fn register_uses(&self, inst: &Inst) -> Vec<VReg> {
+        register_uses(inst, self.arguments, |vreg| self.slots.contains_key(vreg))
+    }
+
+    // the registers holding a value from before an instruction to after it
+    pub fn live_across(&self, index: usize) -> Vec<usize> {
+        let mut registers = self
+            .intervals
+            .iter()
+            .filter(|(interval, _)| interval.start <= read(index) && interval.end >= write(index))
+            .map(|(_, register)| *register)
+            .collect::<Vec<_>>();
+        registers.sort();
+        registers.dedup();
+        registers
+    }
+}
+
+// Poletto and Sarkar, "Linear Scan Register Allocation": the intervals are
+// visited by where they start, each takes a register no interval still live
+// holds. When there is none, the value that lives on the longest is spilled
+// for all of its life and the scan starts over, so it is in memory on every
+// path into a block. Calls pass their first `arguments` arguments in
+// registers.
+pub fn allocate(
+    cfg: &Cfg,
+    immediates: &HashMap<VReg, u64>,
+    pool: usize,
+    arguments: usize,
+) -> Result<Allocation> {
+    let hints = hints(cfg);
+    let mut spilled = HashSet::new();
+    loop {
+        let intervals = intervals(cfg, immediates, arguments, &spilled);
+        match scan(&intervals, &hints, pool) {
+            Ok(assigned) => {
+                let mut allocation = Allocation {
+                    arguments,
+                    ..Allocation::default()
+                };
+                let mut spilled = spilled.into_iter().collect::<Vec<_>>();
+                spilled.sort();
+                for (slot, vreg) in spilled.into_iter().enumerate() {
+                    allocation.slots.insert(vreg, slot);
+                }
+
+                for (interval, register) in assigned {
+                    if !interval.spilled {
+                        allocation.registers.insert(interval.vreg, register);
+                        allocation.intervals.push((interval, register));
+                    } else if interval.start % 2 == 0 {
+                        allocation
+                            .reloads
+                            .insert((interval.vreg, interval.start / 2), register);
+                    } else {
+                        allocation
+                            .stores
+                            .insert((interval.vreg, interval.start / 2), register);
+                    }
+                }
+
+                return Ok(allocation);
+            }
+            Err(Some(victim)) => {
+                spilled.insert(victim);
+            }
+            Err(None) => return Err(Diagnostic::error("No available register")),
+        }
+    }
+}
+
+fn scan(
+    intervals: &[Interval],
+    hints: &HashMap<VReg, VReg>,
+    pool: usize,
+) -> std::result::Result<Vec<(Interval, usize)>, Option<VReg>> {
+    let mut assigned: Vec<(Interval, usize)> = Vec::new();
+    let mut active: Vec<usize> = Vec::new();
+    let mut free = vec![true; pool];
+    let mut registers = HashMap::new();
+
+    for interval in intervals {
+        active.retain(|index| {
+            let (old, register) = assigned[*index];
+            if old.end < interval.start {
+                free[register] = true;
+            }
+            old.end >= interval.start
+        });
+
+        // the register of the operand that just died, saving a move
+        let hint = hints
+            .get(&interval.vreg)
+            .and_then(|operand| registers.get(operand))
+            .filter(|register| free[**register]);
+        let register = match hint.copied().or_else(|| free.iter().position(|free| *free)) {
+            Some(register) => register,
+            None => {
+                let victim = active
+                    .iter()
+                    .map(|index| assigned[*index].0)
+                    .chain([*interval])
+                    .filter(|interval| !interval.spilled)
+                    .max_by_key(|interval| (interval.end, interval.vreg));
+                return Err(victim.map(|interval| interval.vreg));
+            }
+        };
+
+        free[register] = false;
+        if !interval.spilled {
+            registers.insert(interval.vreg, register);
+        }
+        active.push(assigned.len());
+        assigned.push((*interval, register));
+    }
+
+    Ok(assigned)
+}
+
+// The live ranges over the positions of the flattened function. A value live
+// out of a block lives until its end, so one read on the next trip around a
+// loop stays alive until the jump back.
+fn intervals(
+    cfg: &Cfg,
+    immediates: &HashMap<VReg, u64>,
+    arguments: usize,
+    spilled: &HashSet<VReg>,
+) -> Vec<Interval> {
+    let liveness = cfg.liveness();
+    let mut ranges: HashMap<VReg, (usize, usize)> = HashMap::new();
+    let mut points = Vec::new();
+    let mut extend = |vreg: VReg, position: usize, points: &mut Vec<Interval>| {
+        if immediates.contains_key(&vreg) {
+            return;
+        }
+        if spilled.contains(&vreg) {
+            points.push(Interval {
+                vreg,
+                start: position,
+                end: position,
+                spilled: true,
+            });
+            return;
+        }
+        let range = ranges.entry(vreg).or_insert((position, position));
+        range.0 = range.0.min(position);
+        range.1 = range.1.max(position);
+    };
+
+    let mut index = 0;
+    for (block_index, block) in cfg.blocks.iter().enumerate() {
+        // the label
+        for vreg in &liveness.live_in[block_index] {
+            if !spilled.contains(vreg) {
+                extend(*vreg, read(index), &mut points);
+            }
+        }
+        for inst in &block.insts {
+            index += 1;
+            for vreg in register_uses(inst, arguments, |vreg| spilled.contains(vreg)) {
+                extend(vreg, read(index), &mut points);
+            }
+            // a value nobody reads still needs somewhere to be written to
+            if let Some(dst) = inst.def() {
+                extend(dst, write(index), &mut points);
+            }
+        }
+        for vreg in &liveness.live_out[block_index] {
+            if !spilled.contains(vreg) {
+                extend(*vreg, write(index), &mut points);
+            }
+        }
+        index += 1;
+    }
+
+    let mut intervals = ranges
+        .into_iter()
+        .map(|(vreg, (start, end))| Interval {
+            vreg,
+            start,
+            end,
+            spilled: false,
+        })
+        .chain(points)
+        .collect::<Vec<_>>();
+    intervals.sort_by_key(|interval| (interval.start, interval.vreg));
+    intervals
+}
+
+// The virtual registers an instruction needs in a register. A spilled
+// argument that goes on the stack is stored straight from its slot.
+fn register_uses(inst: &Inst, arguments: usize, spilled: impl Fn(&VReg) -> bool) -> Vec<VReg> {
+    let mut uses = match inst {
+        Inst::Call { args, .. } => args
+            .iter()
+            .enumerate()
+            .filter(|(i, arg)| *i < arguments || !spilled(arg))
+            .map(|(_, arg)| *arg)
+            .collect(),
+        _ => inst.uses(),
+    };
+    uses.sort();
+    uses.dedup();
+    uses
+}
+
+// the operand whose register a result would like to reuse
+fn hints(cfg: &Cfg) -> HashMap<VReg, VReg> {
+    let mut hints = HashMap::new();
+    for inst in cfg.blocks.iter().flat_map(|block| &block.insts) {
+        let (dst, operand) = match inst {
+            Inst::Copy { dst, src }
+            | Inst::Cast { dst, src, .. }
+            | Inst::Unary { dst, src, .. } => (*dst, *src),
+            Inst::Binary { dst, left, .. } => (*dst, *left),
+            Inst::Load {
+                dst,
+                addr: Address::Indirect(pointer),
+                ..
+            } => (*dst, *pointer),
+            _ => continue,
+        };
+        hints.entry(dst).or_insert(operand);
+    }
+
+    hints
+}
@@ -0,0 +1,122 @@
+use crate::diagnostic::Diagnostic;
+
+// How diagnostics are written to stderr, for people or for tools
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(Format::Human),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic, file: &str, source: &str) -> String {
+        match self {
+            Format::Human => render(diagnostic, file, source),
+            Format::Json => json(diagnostic, file),
+        }
+    }
+}
+
+// Renders a diagnostic the way rustc does:
+//
+// error: Variable y not declared
+//  --> main.cr:3:3
+//   |
+// 3 |   y = 4;
+//   |   ^ not declared
+pub fn render(diagnostic: &Diagnostic, file: &str, source: &str) -> String {
+    let mut out = format!("{}: {}", diagnostic.severity, diagnostic.message);
+    if let Some(code) = &diagnostic.code {
+        out.push_str(&format!(" [-W {}]", code));
+    }
+    out.push('\n');
+
+    if diagnostic.line == 0 {
+        return out;
+    }
+
+    let gutter = diagnostic.line.to_string().len();
+    let pad = " ".repeat(gutter);
+    out.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        pad, file, diagnostic.line, diagnostic.column
+    ));
+
+    let text = match source.lines().nth(diagnostic.line - 1) {
+        Some(text) => text,
+        None => return out,
+    };
+
+    out.push_str(&format!("{} |\n", pad));
+    out.push_str(&format!("{} | {}\n", diagnostic.line, text));
+
+    // keep tabs in the indentation so the caret lines up with the source
+    let indent: String = text
+        .chars()
+        .take(diagnostic.column.saturating_sub(1))
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let available = text.chars().count().saturating_sub(indent.chars().count());
+    let carets = "^".repeat(diagnostic.length.min(available).max(1));
+
+    out.push_str(&format!("{} | {}{}", pad, indent, carets));
+    if let Some(label) = &diagnostic.label {
+        out.push_str(&format!(" {}", label));
+    }
+    out.push('\n');
+
+    out
+}
+
+fn string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn optional(text: &Option<String>) -> String {
+    match text {
+        Some(text) => string(text),
+        None => String::from("null"),
+    }
+}
+
+// Renders a diagnostic as one line of JSON for editors and scripts:
+//
+// {"severity":"error","code":null,"message":"Variable y not declared","file":"main.cr","line":3,"column":3,"length":1,"label":"not declared"}
+//
+// A diagnostic without a location has null for its line and column.
+pub fn json(diagnostic: &Diagnostic, file: &str) -> String {
+    let (line, column) = match diagnostic.line {
+        0 => (String::from("null"), String::from("null")),
+        line => (line.to_string(), diagnostic.column.to_string()),
+    };
+    format!(
+        "{{\"severity\":{},\"code\":{},\"message\":{},\"file\":{},\"line\":{},\"column\":{},\"length\":{},\"label\":{}}}",
+        string(&diagnostic.severity.to_string()),
+        optional(&diagnostic.code),
+        string(&diagnostic.message),
+        string(file),
+        line,
+        column,
+        diagnostic.length,
+        optional(&diagnostic.label)
+    )
+}
@@ -0,0 +1,871 @@
+use std::collections::HashMap;
+
+use crate::{
+    asm::Line,
+    cfg::Cfg,
+    codegen::{self, Backend, Options},
+    diagnostic::{Diagnostic, Result},
+    ir::{Address, BinOp, Cond, Function, Inst, Module, UnOp, VReg},
+    passes::{Pass, Passes},
+    regalloc::{self, Allocation},
+    stack::Frame,
+    types::Type,
+};
+
+// t0 to t4 may be clobbered by a call, s1 to s11 have to be given back as
+// they were found. a0 to a7 carry the arguments and are never handed out, so
+// setting up a call cannot overwrite a value still to be moved. t5 and t6
+// are kept for addresses and values in between, s0 is the frame pointer.
+const REGISTER_NAMES: [&str; 16] = [
+    "t0", "t1", "t2", "t3", "t4", "s1", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10",
+    "s11",
+];
+const POOL_SIZE: usize = 16;
+// the registers in the pool from here on are callee saved
+const CALLEE_SAVED: usize = 5;
+
+const PARAM_REGISTERS: [&str; 8] = ["a0", "a1", "a2", "a3", "a4", "a5", "a6", "a7"];
+
+const SCRATCH: &str = "t5";
+const SCRATCH2: &str = "t6";
+
+// whether an offset fits the 12 bit signed immediate of a load, a store or
+// an addi
+fn fits(offset: isize) -> bool {
+    (-2048..2048).contains(&offset)
+}
+
+// There are no flags, a branch compares two registers itself. The ones
+// missing are the others with their operands swapped.
+fn branch_instruction(cond: Cond) -> (&'static str, bool) {
+    match cond {
+        Cond::Eq => ("beq", false),
+        Cond::Ne => ("bne", false),
+        Cond::Lt => ("blt", false),
+        Cond::Le => ("bge", true),
+        Cond::Gt => ("blt", true),
+        Cond::Ge => ("bge", false),
+        Cond::Ult => ("bltu", false),
+        Cond::Ule => ("bgeu", true),
+        Cond::Ugt => ("bltu", true),
+        Cond::Uge => ("bgeu", false),
+    }
+}
+
+fn shift_instruction(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Shl => "sll",
+        BinOp::Shr => "sra",
+        _ => "srl",
+    }
+}
+
+// Generates RV64 assembly following the standard calling convention: the
+// first eight arguments in a0 to a7, the rest on the stack, the result in a0
+// and the return address in ra.
+pub struct CodeGen {
+    module: Module,
+    passes: Passes,
+    options: Options,
+    data: String,
+    text: Vec<Line>,
+    // where the function's virtual registers are kept
+    allocation: Allocation,
+    // the instruction being generated, in the flattened function
+    index: usize,
+    // constants written straight into the instructions reading them
+    immediates: HashMap<VReg, u64>,
+    // the size of the function's locals, spilled values go below them
+    locals: isize,
+    // the callee saved registers the function uses, kept below the spills
+    saved: Vec<usize>,
+    // every function's frame and the calls it makes, for --stack-usage
+    frames: Vec<Frame>,
+    // the last label handed out, blocks without one are numbered on from it
+    // the label of every string literal, to the one its text is emitted under
+    strings: HashMap<String, String>,
+}
+
+impl Backend for CodeGen {
+    fn generate(&mut self) -> Result<String> {
+        let (data, strings) = codegen::data(&self.module, self.options);
+        self.data = data;
+        self.strings = strings;
+
+        self.text_section();
+        for function in self.module.functions.clone() {
+            self.function(&function)?;
+        }
+
+        // the peephole rules only know x86
+        Ok(self.combine())
+    }
+
+    fn frames(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.frames)
+    }
+}
+
+// The builtins for RISC-V, the runtime linked with every program
+pub fn runtime(options: Options) -> String {
+    let mut codegen = CodeGen::new(Module::default(), Passes::new(0), options);
+    codegen.runtime();
+    codegen.combine()
+}
+
+impl CodeGen {
+    pub fn new(module: Module, passes: Passes, options: Options) -> Self {
+        Self {
+            passes,
+            options,
+            module,
+            data: String::new(),
+            text: Vec::new(),
+            allocation: Allocation::default(),
+            index: 0,
+            immediates: HashMap::new(),
+            locals: 0,
+            saved: Vec::new(),
+            frames: Vec::new(),
+            strings: HashMap::new(),
+        }
+    }
+
+    // the print builtins hand their argument on to printf, and readint has
+    // scanf read a number below its frame
+    fn runtime(&mut self) {
+        self.data.push_str("\t.section\t.rodata\n");
+        self.data.push_str(".LC0:\n\t.asciz\t\"%d\\n\"\n");
+        self.data.push_str(".LC1:\n\t.asciz\t\"%c\"\n");
+        self.data.push_str(".LC2:\n\t.asciz\t\"%s\"\n");
+        self.data.push_str(".LC3:\n\t.asciz\t\"%llu\"\n");
+        self.data.push_str(".LC5:\n\t.asciz\t\"%llu\\n\"\n");
+        self.data.push_str(".LC6:\n\t.asciz\t\"%lld\\n\"\n");
+        self.data.push_str(".LC7:\n\t.asciz\t\"0x%llx\\n\"\n");
+        self.data.push_str(&format!(
+            ".LC9:\n\t.asciz\t\"{}\"\n",
+            codegen::PROFILE_FORMAT
+        ));
+
+        self.text_section();
+        for (name, format) in [
+            ("printint", ".LC0"),
+            ("printchar", ".LC1"),
+            ("printstr", ".LC2"),
+            ("printu64", ".LC5"),
+            ("printi64", ".LC6"),
+            ("printhex", ".LC7"),
+        ] {
+            self.builtin(name);
+            self.enter_frame();
+            self.emit("mv", &["a1", "a0"]);
+            self.emit("lla", &["a0", format]);
+            self.emit("call", &["printf"]);
+            self.emit("mv", &["sp", "s0"]);
+            self.emit("ld", &["ra", "8(sp)"]);
+            self.emit("ld", &["s0", "0(sp)"]);
+            self.emit("addi", &["sp", "sp", "16"]);
+            self.emit("ret", &[]);
+        }
+
+        self.builtin("readint");
+        self.enter_frame();
+        self.emit("addi", &["sp", "sp", "-16"]);
+        self.emit("sd", &["zero", "0(sp)"]);
+        self.emit("mv", &["a1", "sp"]);
+        self.emit("lla", &["a0", ".LC3"]);
+        self.emit("call", &["scanf"]);
+        self.emit("ld", &["a0", "0(sp)"]);
+        self.emit("mv", &["sp", "s0"]);
+        self.emit("ld", &["ra", "8(sp)"]);
+        self.emit("ld", &["s0", "0(sp)"]);
+        self.emit("addi", &["sp", "sp", "16"]);
+        self.emit("ret", &[]);
+
+        // a failed assert or check flushes what the program printed, writes
+        // where it is to stderr and aborts
+        self.builtin("crust.panic");
+        self.enter_frame();
+        self.emit("addi", &["sp", "sp", "-16"]);
+        self.emit("sd", &["a0", "0(sp)"]);
+        self.emit("li", &["a0", "0"]);
+        self.emit("call", &["fflush"]);
+        self.stderr("a1");
+        self.emit("ld", &["a0", "0(sp)"]);
+        self.emit("call", &["fputs"]);
+        self.emit("call", &["abort"]);
+
+        self.data.push_str(codegen::RANDOM_STATE);
+        self.builtin("rand");
+        self.emit("lla", &["a1", ".LC4"]);
+        self.emit("ld", &["a0", "0(a1)"]);
+        for (instruction, amount) in [("slli", "13"), ("srli", "7"), ("slli", "17")] {
+            self.emit(instruction, &["a2", "a0", amount]);
+            self.emit("xor", &["a0", "a0", "a2"]);
+        }
+        self.emit("sd", &["a0", "0(a1)"]);
+        self.emit("ret", &[]);
+
+        // clock_ms has clock_gettime fill in a timespec below its frame and
+        // counts its seconds and nanoseconds in milliseconds
+        self.builtin("clock_ms");
+        self.enter_frame();
+        self.emit("addi", &["sp", "sp", "-16"]);
+        self.emit("li", &["a0", codegen::monotonic_clock(self.options.target)]);
+        self.emit("mv", &["a1", "sp"]);
+        self.emit("call", &["clock_gettime"]);
+        self.emit("ld", &["a0", "0(sp)"]);
+        self.emit("ld", &["a1", "8(sp)"]);
+        self.emit("li", &["a2", "1000"]);
+        self.emit("mul", &["a0", "a0", "a2"]);
+        self.emit("li", &["a2", "1000000"]);
+        self.emit("divu", &["a1", "a1", "a2"]);
+        self.emit("add", &["a0", "a0", "a1"]);
+        self.emit("mv", &["sp", "s0"]);
+        self.emit("ld", &["ra", "8(sp)"]);
+        self.emit("ld", &["s0", "0(sp)"]);
+        self.emit("addi", &["sp", "sp", "16"]);
+        self.emit("ret", &[]);
+
+        self.profiler();
+    }
+
+    // The hooks --instrument-functions has every function call, with the
+    // function's record in a0: calls, depth, deepest, the next record and
+    // the function's name. crust.report writes every record to stderr.
+    fn profiler(&mut self) {
+        let label = |function: &str, name: &str| self.options.target.label(function, name);
+        let (counted, first) = (
+            label("crust.enter", "counted"),
+            label("crust.enter", "first"),
+        );
+        let kept = label("crust.enter", "kept");
+        let (next, done) = (label("crust.report", "next"), label("crust.report", "done"));
+        self.data.push_str(codegen::PROFILE_LIST);
+
+        // the first call links the record onto the last one, or makes it
+        // the first when there is none
+        self.builtin("crust.enter");
+        self.emit("ld", &["a2", "0(a0)"]);
+        self.emit("bnez", &["a2", &counted]);
+        self.emit("sd", &["a1", "32(a0)"]);
+        self.emit("lla", &["a3", ".LC8"]);
+        self.emit("ld", &["a4", "8(a3)"]);
+        self.emit("mv", &["a5", "a3"]);
+        self.emit("beqz", &["a4", &first]);
+        self.emit("addi", &["a5", "a4", "24"]);
+        self.label(&first);
+        self.emit("sd", &["a0", "0(a5)"]);
+        self.emit("sd", &["a0", "8(a3)"]);
+        self.label(&counted);
+        self.emit("addi", &["a2", "a2", "1"]);
+        self.emit("sd", &["a2", "0(a0)"]);
+        self.emit("ld", &["a2", "8(a0)"]);
+        self.emit("addi", &["a2", "a2", "1"]);
+        self.emit("sd", &["a2", "8(a0)"]);
+        self.emit("ld", &["a3", "16(a0)"]);
+        self.emit("bgeu", &["a3", "a2", &kept]);
+        self.emit("sd", &["a2", "16(a0)"]);
+        self.label(&kept);
+        self.emit("ret", &[]);
+
+        self.builtin("crust.exit");
+        self.emit("ld", &["a1", "8(a0)"]);
+        self.emit("addi", &["a1", "a1", "-1"]);
+        self.emit("sd", &["a1", "8(a0)"]);
+        self.emit("ret", &[]);
+
+        // the record is kept in s1 across the calls
+        self.builtin("crust.report");
+        self.enter_frame();
+        self.emit("addi", &["sp", "sp", "-16"]);
+        self.emit("sd", &["s1", "0(sp)"]);
+        self.emit("li", &["a0", "0"]);
+        self.emit("call", &["fflush"]);
+        self.emit("lla", &["s1", ".LC8"]);
+        self.emit("ld", &["s1", "0(s1)"]);
+        self.label(&next);
+        self.emit("beqz", &["s1", &done]);
+        self.stderr("a0");
+        self.emit("lla", &["a1", ".LC9"]);
+        self.emit("ld", &["a2", "32(s1)"]);
+        self.emit("ld", &["a3", "0(s1)"]);
+        self.emit("ld", &["a4", "16(s1)"]);
+        self.emit("call", &["fprintf"]);
+        self.emit("ld", &["s1", "24(s1)"]);
+        self.emit("j", &[&next]);
+        self.label(&done);
+        self.emit("ld", &["s1", "0(sp)"]);
+        self.emit("mv", &["sp", "s0"]);
+        self.emit("ld", &["ra", "8(sp)"]);
+        self.emit("ld", &["s0", "0(sp)"]);
+        self.emit("addi", &["sp", "sp", "16"]);
+        self.emit("ret", &[]);
+    }
+
+    // puts the C library's stderr in the register, la takes its address
+    // from the GOT with pic
+    fn stderr(&mut self, to: &str) {
+        self.directive(".option push");
+        self.directive(".option pic");
+        self.emit("la", &[to, "stderr"]);
+        self.directive(".option pop");
+        self.emit("ld", &[to, &format!("0({})", to)]);
+    }
+
+    fn text_section(&mut self) {
+        // with pic, la loads an address from the GOT, lla always works it
+        // out from the pc
+        if self.options.pic {
+            self.directive(".option pic");
+        }
+        self.directive(".text");
+        self.directive(".p2align 2");
+    }
+
+    // the data and text sections together
+    fn combine(&mut self) -> String {
+        let mut assembly = std::mem::take(&mut self.data);
+        for line in &self.text {
+            assembly.push_str(&format!("{}\n", line));
+        }
+        assembly
+    }
+
+    fn builtin(&mut self, name: &str) {
+        for directive in codegen::runtime_symbol(self.options.target, name) {
+            self.directive(&directive);
+        }
+        self.label(name);
+    }
+
+    fn emit(&mut self, op: &str, operands: &[&str]) {
+        self.text.push(Line::Inst {
+            op: op.to_string(),
+            operands: operands.iter().map(|operand| operand.to_string()).collect(),
+        });
+    }
+
+    fn label(&mut self, name: &str) {
+        self.text.push(Line::Label(name.to_string()));
+    }
+
+    fn directive(&mut self, directive: &str) {
+        self.text.push(Line::Directive(directive.to_string()));
+    }
+
+    // the register an instruction reads a virtual register from
+    fn register(&self, vreg: VReg) -> &'static str {
+        REGISTER_NAMES[self.allocation.register(vreg, self.index)]
+    }
+
+    // the register an instruction writes a virtual register to
+    fn destination(&self, vreg: VReg) -> &'static str {
+        REGISTER_NAMES[self.allocation.destination(vreg, self.index)]
+    }
+
+    // where a virtual register that did not get a register lives, from s0
+    fn spill_slot(&self, vreg: VReg) -> Option<isize> {
+        let slot = *self.allocation.slots.get(&vreg)? as isize;
+        Some(-self.locals - 8 * (slot + 1))
+    }
+
+    // where a callee saved register is kept while the function runs
+    fn save_slot(&self, index: usize) -> isize {
+        let slot = (self.allocation.slots.len() + index) as isize;
+        -self.locals - 8 * (slot + 1)
+    }
+
+    // li is expanded to however many instructions the value needs
+    fn constant(&mut self, to: &str, value: u64) {
+        self.emit("li", &[to, &(value as i64).to_string()]);
+    }
+
+    // puts s0 plus the offset into a register
+    fn frame_address(&mut self, to: &str, offset: isize) {
+        if fits(offset) {
+            self.emit("addi", &[to, "s0", &offset.to_string()]);
+        } else {
+            self.constant(to, offset as u64);
+            self.emit("add", &[to, "s0", to]);
+        }
+    }
+
+    // a slot of the frame, which a load or store only reaches by its offset
+    // within 2KB either side of s0
+    fn frame(&mut self, offset: isize) -> String {
+        if fits(offset) {
+            return format!("{}(s0)", offset);
+        }
+        self.frame_address(SCRATCH, offset);
+        format!("0({})", SCRATCH)
+    }
+
+    // string literals never leave the file, other globals are reached through
+    // the GOT in position independent code, and extern ones always
+    fn global_address(&mut self, to: &str, name: &str) {
+        match self.strings.get(name) {
+            Some(label) => self.emit("lla", &[to, &label.clone()]),
+            None if self.module.is_external(name) && !self.options.pic => {
+                self.directive(".option push");
+                self.directive(".option pic");
+                self.emit("la", &[to, name]);
+                self.directive(".option pop");
+            }
+            None => self.emit("la", &[to, name]),
+        }
+    }
+
+    fn operand(&mut self, address: &Address) -> String {
+        match address {
+            Address::Global(name) => {
+                self.global_address(SCRATCH, name);
+                format!("0({})", SCRATCH)
+            }
+            Address::Local(offset) => self.frame(*offset),
+            Address::Indirect(vreg) => format!("0({})", self.register(*vreg)),
+        }
+    }
+
+    fn function(&mut self, function: &Function) -> Result<()> {
+        let cfg = Cfg::build(&function.insts, &mut function.last_label());
+        self.immediates = codegen::immediates(&function.insts);
+        self.allocation =
+            regalloc::allocate(&cfg, &self.immediates, POOL_SIZE, PARAM_REGISTERS.len())?;
+        // rounded up to whole slots
+        self.locals = (function.stack_size as isize + 7) & !7;
+
+        self.function_preamble(function)?;
+        let insts = cfg.flatten();
+        // the return after a tail call, control never comes back to it
+        let mut skip = None;
+        for (i, inst) in insts.iter().enumerate() {
+            if skip == Some(i) {
+                continue;
+            }
+            self.index = i;
+
+            for vreg in self.allocation.register_uses(inst) {
+                if let Some(offset) = self.spill_slot(vreg) {
+                    let slot = self.frame(offset);
+                    self.emit("ld", &[self.register(vreg), &slot]);
+                }
+            }
+
+            match codegen::tail_call(&insts, i, PARAM_REGISTERS.len()) {
+                Some((name, args)) if self.passes.enabled(Pass::TailCalls) => {
+                    self.tail_call(name, args);
+                    skip = Some(i + 1);
+                }
+                _ => self.instruction(inst, function)?,
+            }
+
+            if let Some(dst) = inst.def() {
+                if let Some(offset) = self.spill_slot(dst) {
+                    let slot = self.frame(offset);
+                    self.emit("sd", &[self.destination(dst), &slot]);
+                }
+            }
+        }
+        self.function_postamble(function);
+        if codegen::function_section(self.options.target, &function.placement).is_some() {
+            self.directive(".text");
+        }
+
+        Ok(())
+    }
+
+    // saves the return address and the caller's frame pointer, and points s0
+    // at them
+    fn enter_frame(&mut self) {
+        self.emit("addi", &["sp", "sp", "-16"]);
+        self.emit("sd", &["ra", "8(sp)"]);
+        self.emit("sd", &["s0", "0(sp)"]);
+        self.emit("mv", &["s0", "sp"]);
+    }
+
+    fn function_preamble(&mut self, function: &Function) -> Result<()> {
+        for directive in codegen::place_function(self.options.target, &function.placement) {
+            self.directive(&directive);
+        }
+        let name = &function.name;
+        if self.options.exports(function) {
+            self.directive(&format!(".globl {}", name));
+        }
+        self.directive(&format!(".type\t{}, @function", name));
+        self.label(name);
+        self.enter_frame();
+
+        // The frame holds the locals, then the spilled values, then the
+        // callee saved registers, with sp kept a multiple of 16
+        let used = self.allocation.used_registers();
+        self.saved = (CALLEE_SAVED..POOL_SIZE)
+            .filter(|r| used.contains(r))
+            .collect();
+        let size = self.locals + 8 * (self.allocation.slots.len() + self.saved.len()) as isize;
+        let size = (size + 15) & !15;
+        // with the return address and s0 above it
+        self.frames.push(Frame {
+            name: function.name.clone(),
+            size: size as u64 + 16,
+            ..Frame::default()
+        });
+        if !fits(-size) {
+            self.constant(SCRATCH, size as u64);
+            self.emit("sub", &["sp", "sp", SCRATCH]);
+        } else if size > 0 {
+            self.emit("addi", &["sp", "sp", &(-size).to_string()]);
+        }
+
+        for (index, r) in self.saved.clone().into_iter().enumerate() {
+            let slot = self.frame(self.save_slot(index));
+            self.emit("sd", &[REGISTER_NAMES[r], &slot]);
+        }
+
+        // Copy the parameters to their slots, the first eight from their
+        // registers and the rest from where the caller stored them, above
+        // the saved return address and frame pointer
+        for (i, param) in function.params.iter().enumerate() {
+            let register = match PARAM_REGISTERS.get(i) {
+                Some(register) => *register,
+                None => {
+                    let stored = 16 + 8 * (i - PARAM_REGISTERS.len());
+                    self.emit("ld", &[SCRATCH2, &format!("{}(s0)", stored)]);
+                    SCRATCH2
+                }
+            };
+            let slot = self.frame(param.offset);
+            self.store(register, slot, &param.ty)?;
+        }
+
+        Ok(())
+    }
+
+    fn function_postamble(&mut self, function: &Function) {
+        self.label(&self.options.target.label(&function.name, "end"));
+        self.leave_frame();
+        self.emit("ret", &[]);
+    }
+
+    // gives the caller back its registers, its stack pointer and its frame
+    fn leave_frame(&mut self) {
+        for (index, r) in self.saved.clone().into_iter().enumerate() {
+            let slot = self.frame(self.save_slot(index));
+            self.emit("ld", &[REGISTER_NAMES[r], &slot]);
+        }
+
+        self.emit("mv", &["sp", "s0"]);
+        self.emit("ld", &["ra", "8(sp)"]);
+        self.emit("ld", &["s0", "0(sp)"]);
+        self.emit("addi", &["sp", "sp", "16"]);
+    }
+
+    fn instruction(&mut self, inst: &Inst, function: &Function) -> Result<()> {
+        match inst {
+            Inst::Const { dst, .. } if self.immediates.contains_key(dst) => {}
+            Inst::Const { dst, value } => {
+                let r = self.destination(*dst);
+                self.constant(r, *value);
+            }
+            Inst::Copy { dst, src } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                self.move_register(src, r);
+            }
+            Inst::Cast { dst, src, ty } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                self.cast(r, src, ty);
+            }
+            Inst::Binary {
+                op: op @ (BinOp::Shl | BinOp::Shr | BinOp::UShr),
+                dst,
+                left,
+                right,
+            } if self.immediates.contains_key(right) => {
+                let left = self.register(*left);
+                let r = self.destination(*dst);
+                let instruction = format!("{}i", shift_instruction(*op));
+                let amount = (self.immediates[right] & 63).to_string();
+                self.emit(&instruction, &[r, left, &amount]);
+            }
+            Inst::Binary {
+                op,
+                dst,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let r = self.destination(*dst);
+                let instruction = match op {
+                    BinOp::Add => "add",
+                    BinOp::Sub => "sub",
+                    BinOp::Mul => "mul",
+                    BinOp::Div => "div",
+                    BinOp::UDiv => "divu",
+                    BinOp::And => "and",
+                    BinOp::Or => "or",
+                    BinOp::Xor => "xor",
+                    BinOp::Shl | BinOp::Shr | BinOp::UShr => shift_instruction(*op),
+                };
+                self.emit(instruction, &[r, left, right]);
+            }
+            Inst::Unary { op, dst, src } => {
+                let src = self.register(*src);
+                let r = self.destination(*dst);
+                let instruction = match op {
+                    UnOp::Neg => "neg",
+                    UnOp::Not => "not",
+                    UnOp::LogicalNot => "seqz",
+                    UnOp::ToBool => "snez",
+                };
+                self.emit(instruction, &[r, src]);
+            }
+            Inst::Compare {
+                cond,
+                dst,
+                left,
+                right,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let r = self.destination(*dst);
+                self.compare(*cond, r, left, right);
+            }
+            // there is no conditional move, the comparison becomes a mask
+            // that picks the bits of one value or the other
+            Inst::Select {
+                cond,
+                dst,
+                left,
+                right,
+                then,
+                otherwise,
+            } => {
+                let (left, right) = (self.register(*left), self.register(*right));
+                let (then, otherwise) = (self.register(*then), self.register(*otherwise));
+                let r = self.destination(*dst);
+                self.compare(*cond, SCRATCH, left, right);
+                self.emit("neg", &[SCRATCH, SCRATCH]);
+                self.emit("xor", &[SCRATCH2, then, otherwise]);
+                self.emit("and", &[SCRATCH2, SCRATCH2, SCRATCH]);
+                self.emit("xor", &[r, otherwise, SCRATCH2]);
+            }
+            Inst::Load { dst, addr, ty } => {
+                let operand = self.operand(addr);
+                let r = self.destination(*dst);
+                self.load(r, operand, ty)?;
+            }
+            Inst::Store { src, addr, ty } => {
+                let operand = self.operand(addr);
+                self.store(self.register(*src), operand, ty)?;
+            }
+            Inst::Lea { dst, addr } => {
+                let r = self.destination(*dst);
+                match addr {
+                    Address::Global(name) => self.global_address(r, name),
+                    Address::Local(offset) => self.frame_address(r, *offset),
+                    Address::Indirect(vreg) => self.move_register(self.register(*vreg), r),
+                }
+            }
+            Inst::Call { dst, name, args } => {
+                self.function_call(name, args);
+                let r = self.destination(*dst);
+                self.emit("mv", &[r, "a0"]);
+            }
+            Inst::Return { src, ty } => {
+                self.return_value(self.register(*src), ty)?;
+
+                // leave through the epilogue, a return is not always the last statement
+                self.emit("j", &[&self.options.target.label(&function.name, "end")]);
+            }
+            Inst::Jump { target } => {
+                self.emit("j", &[&self.options.target.label(&function.name, target)]);
+            }
+            Inst::Branch {
+                cond,
+                left,
+                right,
+                target,
+            } => {
+                let (mut left, mut right) = (self.register(*left), self.register(*right));
+                let (instruction, swapped) = branch_instruction(*cond);
+                if swapped {
+                    std::mem::swap(&mut left, &mut right);
+                }
+                self.emit(
+                    instruction,
+                    &[
+                        left,
+                        right,
+                        &self.options.target.label(&function.name, target),
+                    ],
+                );
+            }
+            Inst::Label(label) => {
+                self.label(&self.options.target.label(&function.name, label));
+            }
+            Inst::Phi { .. } => {
+                return Err(Diagnostic::error("Unexpected phi outside of SSA form"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Sets a register to whether the comparison holds. slt is the only one
+    // there is, the others swap its operands or flip its result.
+    fn compare(&mut self, cond: Cond, r: &str, left: &str, right: &str) {
+        let less = match cond {
+            Cond::Ult | Cond::Ule | Cond::Ugt | Cond::Uge => "sltu",
+            _ => "slt",
+        };
+        match cond {
+            Cond::Eq | Cond::Ne => {
+                self.emit("xor", &[r, left, right]);
+                let instruction = if cond == Cond::Eq { "seqz" } else { "snez" };
+                self.emit(instruction, &[r, r]);
+            }
+            Cond::Lt | Cond::Ult => self.emit(less, &[r, left, right]),
+            Cond::Gt | Cond::Ugt => self.emit(less, &[r, right, left]),
+            Cond::Le | Cond::Ule => {
+                self.emit(less, &[r, right, left]);
+                self.emit("xori", &[r, r, "1"]);
+            }
+            Cond::Ge | Cond::Uge => {
+                self.emit(less, &[r, left, right]);
+                self.emit("xori", &[r, r, "1"]);
+            }
+        }
+    }
+
+    // the upper bits are shifted out and shifted back in as zeros or copies
+    // of the sign
+    fn cast(&mut self, r: &str, src: &str, ty: &Type) {
+        let (bits, signed) = match ty {
+            Type::U8 | Type::Char => return self.emit("andi", &[r, src, "255"]),
+            Type::I32 => return self.emit("sext.w", &[r, src]),
+            Type::I8 => (56, true),
+            Type::U16 => (48, false),
+            Type::I16 => (48, true),
+            Type::U32 => (32, false),
+            _ => return self.move_register(src, r),
+        };
+
+        let bits = bits.to_string();
+        self.emit("slli", &[r, src, &bits]);
+        self.emit(if signed { "srai" } else { "srli" }, &[r, r, &bits]);
+    }
+
+    fn move_register(&mut self, from: &str, to: &str) {
+        if from != to {
+            self.emit("mv", &[to, from]);
+        }
+    }
+
+    fn load(&mut self, r: &str, operand: String, ty: &Type) -> Result<()> {
+        let instruction = match ty {
+            Type::U8 | Type::Char => "lbu",
+            Type::I8 => "lb",
+            Type::U16 => "lhu",
+            Type::I16 => "lh",
+            Type::U32 => "lwu",
+            Type::I32 => "lw",
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => "ld",
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+        };
+
+        self.emit(instruction, &[r, &operand]);
+        Ok(())
+    }
+
+    fn store(&mut self, r: &str, operand: String, ty: &Type) -> Result<()> {
+        let instruction = match ty {
+            Type::U8 | Type::I8 | Type::Char => "sb",
+            Type::U16 | Type::I16 => "sh",
+            Type::U32 | Type::I32 => "sw",
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => "sd",
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+        };
+
+        self.emit(instruction, &[r, &operand]);
+        Ok(())
+    }
+
+    fn return_value(&mut self, r: &str, ty: &Type) -> Result<()> {
+        if !ty.is_int() && !matches!(ty, Type::Pointer { .. } | Type::Closure { .. }) {
+            return Err(Diagnostic::error(format!("Unexpected type {:?}", ty)));
+        }
+
+        self.cast("a0", r, ty);
+        Ok(())
+    }
+
+    fn function_call(&mut self, name: &str, args: &[VReg]) {
+        // the live registers the callee may clobber are kept on the stack
+        // across the call, above the arguments that go there
+        let saved = self
+            .allocation
+            .live_across(self.index)
+            .into_iter()
+            .filter(|r| *r < CALLEE_SAVED)
+            .collect::<Vec<_>>();
+        let stack_args = args.len().saturating_sub(PARAM_REGISTERS.len());
+        let size = (8 * (stack_args + saved.len()) + 15) & !15;
+        if size > 0 {
+            self.emit("addi", &["sp", "sp", &format!("-{}", size)]);
+        }
+        for (i, r) in saved.iter().enumerate() {
+            let slot = format!("{}(sp)", 8 * (stack_args + i));
+            self.emit("sd", &[REGISTER_NAMES[*r], &slot]);
+        }
+
+        // arguments beyond the eighth, spilled ones straight from their slot
+        for (i, arg) in args.iter().skip(PARAM_REGISTERS.len()).enumerate() {
+            let register = match self.spill_slot(*arg) {
+                Some(offset) => {
+                    let slot = self.frame(offset);
+                    self.emit("ld", &[SCRATCH2, &slot]);
+                    SCRATCH2
+                }
+                None => self.register(*arg),
+            };
+            self.emit("sd", &[register, &format!("{}(sp)", 8 * i)]);
+        }
+
+        for (arg, to) in args.iter().zip(PARAM_REGISTERS) {
+            self.emit("mv", &[to, self.register(*arg)]);
+        }
+
+        self.emit("call", &[name]);
+        let pushed = size as u64;
+        self.frames
+            .last_mut()
+            .unwrap()
+            .calls
+            .push((name.to_string(), pushed));
+
+        for (i, r) in saved.iter().enumerate() {
+            let slot = format!("{}(sp)", 8 * (stack_args + i));
+            self.emit("ld", &[REGISTER_NAMES[*r], &slot]);
+        }
+        if size > 0 {
+            self.emit("addi", &["sp", "sp", &size.to_string()]);
+        }
+    }
+
+    // Jumps to the function instead of calling it, once this frame is gone,
+    // so it returns straight to our caller and the stack does not grow.
+    fn tail_call(&mut self, name: &str, args: &[VReg]) {
+        for (arg, to) in args.iter().zip(PARAM_REGISTERS) {
+            self.emit("mv", &[to, self.register(*arg)]);
+        }
+
+        self.leave_frame();
+        self.emit("tail", &[name]);
+        self.frames
+            .last_mut()
+            .unwrap()
+            .tail_calls
+            .push(name.to_string());
+    }
+}
@@ -0,0 +1,22 @@
+use crate::{
+    aarch64, codegen,
+    diagnostic::{Diagnostic, Result},
+    riscv64,
+    target::Arch,
+};
+
+// The runtime, the builtins a program calls, printint and the rest, as
+// assembly of its own. The output of a compilation only has the program's
+// functions in it, and the driver assembles and links this along with them,
+// or writes it out as zrt.s with --emit=runtime for those linking by hand.
+// LLVM IR and WebAssembly carry theirs in the module, which is run whole.
+pub fn assembly(options: codegen::Options) -> Result<String> {
+    match options.target.arch {
+        Arch::X86_64 => Ok(codegen::runtime(options)),
+        Arch::Aarch64 => Ok(aarch64::runtime(options)),
+        Arch::Riscv64 => Ok(riscv64::runtime(options)),
+        Arch::Wasm32 => Err(Diagnostic::error(
+            "WebAssembly has no runtime of its own, the builtins are in every module",
+        )),
+    }
+}
@@ -0,0 +1,94 @@
+use std::{cell::RefCell, rc::Rc};
+
+use crate::{
+    lexer::{Token, TokenType},
+    parser::{StorageClass, Symbol, SymbolType},
+};
+
+// What an identifier or type keyword names, for editors to highlight by
+// meaning instead of by what the text looks like
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Function,
+    Constant,
+    Global,
+    Local,
+    Parameter,
+    Type,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Function => "function",
+            Kind::Constant => "constant",
+            Kind::Global => "global",
+            Kind::Local => "local",
+            Kind::Parameter => "parameter",
+            Kind::Type => "type",
+        }
+    }
+
+    fn of(symbol: &Symbol) -> Self {
+        match (&symbol.structure, &symbol.class) {
+            (SymbolType::Function, _) => Kind::Function,
+            (SymbolType::Constant(_), _) => Kind::Constant,
+            (_, StorageClass::Global) => Kind::Global,
+            (_, StorageClass::Local) => Kind::Local,
+            (_, StorageClass::Param) => Kind::Parameter,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticToken {
+    pub line: usize,
+    pub column: usize,
+    pub length: usize,
+    pub kind: Kind,
+}
+
+fn is_type(token_type: TokenType) -> bool {
+    matches!(
+        token_type,
+        TokenType::U8
+            | TokenType::U16
+            | TokenType::U32
+            | TokenType::U64
+            | TokenType::I8
+            | TokenType::I16
+            | TokenType::I32
+            | TokenType::I64
+            | TokenType::Char
+    )
+}
+
+// Classifies the type keywords among `tokens` and every identifier the
+// parser resolved in `references`, in the order they appear in the source.
+// An identifier the parser couldn't resolve is left out.
+pub fn classify(
+    tokens: &[Token],
+    references: &[(Token, Rc<RefCell<Symbol>>)],
+) -> Vec<SemanticToken> {
+    let types = tokens
+        .iter()
+        .filter(|token| is_type(token.token_type))
+        .map(|token| (token, Kind::Type));
+    let symbols = references
+        .iter()
+        .map(|(token, symbol)| (token, Kind::of(&symbol.borrow())));
+
+    let mut classified: Vec<SemanticToken> = types
+        .chain(symbols)
+        .map(|(token, kind)| SemanticToken {
+            line: token.line,
+            column: token.column,
+            length: token.text().chars().count().max(1),
+            kind,
+        })
+        .collect();
+    classified.sort_by_key(|token| (token.line, token.column));
+    // function headers are parsed twice, and so are their names
+    classified.dedup_by_key(|token| (token.line, token.column));
+    classified
+}
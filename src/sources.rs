@@ -0,0 +1,64 @@
+use crate::{diagnostic::Diagnostic, render::Format};
+
+// The files a program is compiled from, read one after the other as a single
+// source, so the functions and globals of each can be used in the others.
+// Lines are counted through all of them, and a diagnostic is taken back to
+// the file it is in before it is shown.
+pub struct Sources {
+    // every file with its text and the line of the whole it starts on
+    files: Vec<(String, String, usize)>,
+    text: String,
+}
+
+impl Sources {
+    pub fn read(files: &[String]) -> Result<Self, String> {
+        let mut sources = Sources {
+            files: Vec::new(),
+            text: String::new(),
+        };
+        for file in files {
+            let text = std::fs::read_to_string(file)
+                .map_err(|e| format!("cannot read {}: {}", file, e))?;
+            let first = sources.text.lines().count() + 1;
+            sources.text.push_str(&text);
+            // the next file starts on a line of its own
+            if !text.ends_with('\n') {
+                sources.text.push('\n');
+            }
+            sources.files.push((file.clone(), text, first));
+        }
+        Ok(sources)
+    }
+
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // the first file, which a single file compilation only has
+    pub fn file(&self) -> &str {
+        &self.files[0].0
+    }
+
+    // every file with the line of the whole it starts on
+    pub fn origins(&self) -> Vec<(String, usize)> {
+        self.files
+            .iter()
+            .map(|(file, _, first)| (file.clone(), *first))
+            .collect()
+    }
+
+    pub fn render(&self, format: Format, diagnostic: &Diagnostic) -> String {
+        let (file, text, first) = self
+            .files
+            .iter()
+            .rev()
+            .find(|(_, _, first)| *first <= diagnostic.line)
+            .unwrap_or(&self.files[0]);
+        let mut diagnostic = diagnostic.clone();
+        // a diagnostic without a place stays on line 0
+        if diagnostic.line != 0 {
+            diagnostic.line -= first - 1;
+        }
+        format.render(&diagnostic, file, text)
+    }
+}
@@ -0,0 +1,913 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    cfg::{BasicBlock, Cfg},
+    ir::{self, Address, Function, Inst, Label, Module, VReg},
+    passes::{Pass, Passes},
+    timing,
+    types::Type,
+};
+
+// Puts every function into SSA form, with the locals whose address is never
+// taken promoted from stack slots to virtual registers, optimizes it there and
+// translates it back to plain copies for CodeGen.
+pub fn optimize(module: &mut Module, passes: &Passes) {
+    for function in &mut module.functions {
+        let mut labels = function.last_label();
+        let mut ssa = timing::time("ssa construction", || {
+            Ssa::build(function, &mut labels, passes.enabled(Pass::Mem2Reg))
+        });
+        ssa.optimize(passes);
+        function.insts = timing::time("ssa destruction", || ssa.destruct(&mut labels));
+    }
+}
+
+struct Ssa {
+    cfg: Cfg,
+    vregs: usize,
+}
+
+fn resolve(replaced: &HashMap<VReg, VReg>, mut vreg: VReg) -> VReg {
+    while let Some(next) = replaced.get(&vreg) {
+        vreg = *next;
+    }
+    vreg
+}
+
+// the block of a phi and its arguments by predecessor block
+type Phi = (usize, Vec<(usize, VReg)>);
+
+// Braun et al., "Simple and Efficient Construction of Static Single Assignment
+// Form": a read of a variable looks backwards through the predecessors for its
+// current value, and places a phi where several of them meet.
+struct Builder<'a> {
+    cfg: &'a Cfg,
+    // the stack slots being promoted and the type stored in each
+    slots: HashMap<isize, Type>,
+    params: HashSet<isize>,
+    // the value a slot holds at the end of a block, as far as it is processed
+    current: HashMap<(isize, usize), VReg>,
+    sealed: Vec<bool>,
+    incomplete: Vec<Vec<(isize, VReg)>>,
+    phis: HashMap<VReg, Phi>,
+    // what a slot holds on entry to the function
+    initial: HashMap<isize, VReg>,
+    entry: Vec<Inst>,
+    vregs: usize,
+}
+
+impl Builder<'_> {
+    fn vreg(&mut self) -> VReg {
+        self.vregs += 1;
+        self.vregs - 1
+    }
+
+    fn phi(&mut self, block: usize) -> VReg {
+        let dst = self.vreg();
+        self.phis.insert(dst, (block, Vec::new()));
+        dst
+    }
+
+    fn read(&mut self, slot: isize, block: usize) -> VReg {
+        if let Some(value) = self.current.get(&(slot, block)) {
+            return *value;
+        }
+
+        let value = if !self.sealed[block] {
+            // not every predecessor is known yet, fill the phi in once they are
+            let phi = self.phi(block);
+            self.incomplete[block].push((slot, phi));
+            phi
+        } else if self.cfg.predecessors(block).is_empty() {
+            self.initial(slot)
+        } else if self.cfg.predecessors(block).len() == 1 {
+            self.read(slot, self.cfg.predecessors(block)[0])
+        } else {
+            // recorded before the operands are read, so a loop finds it again
+            let phi = self.phi(block);
+            self.current.insert((slot, block), phi);
+            self.add_operands(slot, phi);
+            phi
+        };
+
+        self.current.insert((slot, block), value);
+        value
+    }
+
+    fn add_operands(&mut self, slot: isize, phi: VReg) {
+        let block = self.phis[&phi].0;
+        for predecessor in self.cfg.predecessors(block).to_vec() {
+            let value = self.read(slot, predecessor);
+            self.phis
+                .get_mut(&phi)
+                .unwrap()
+                .1
+                .push((predecessor, value));
+        }
+    }
+
+    fn seal(&mut self, block: usize) {
+        for (slot, phi) in std::mem::take(&mut self.incomplete[block]) {
+            self.add_operands(slot, phi);
+        }
+        self.sealed[block] = true;
+    }
+
+    // parameters arrive in their slots, other locals start out as garbage
+    fn initial(&mut self, slot: isize) -> VReg {
+        if let Some(value) = self.initial.get(&slot) {
+            return *value;
+        }
+
+        let dst = self.vreg();
+        if self.params.contains(&slot) {
+            self.entry.push(Inst::Load {
+                dst,
+                addr: Address::Local(slot),
+                ty: self.slots[&slot].clone(),
+            });
+        } else {
+            self.entry.push(Inst::Const { dst, value: 0 });
+        }
+        self.initial.insert(slot, dst);
+        dst
+    }
+}
+
+// the slots only ever loaded and stored as a whole, never pointed into
+fn promotable_slots(insts: &[Inst]) -> HashMap<isize, Type> {
+    let mut slots = HashMap::new();
+    let mut escaped = HashSet::new();
+
+    for inst in insts {
+        match inst {
+            // a slot read or written as different types is left alone
+            Inst::Load {
+                addr: Address::Local(slot),
+                ty,
+                ..
+            }
+            | Inst::Store {
+                addr: Address::Local(slot),
+                ty,
+                ..
+            } if slots.get(slot).is_some_and(|old| old != ty) => {
+                escaped.insert(*slot);
+            }
+            Inst::Load {
+                addr: Address::Local(slot),
+                ty,
+                ..
+            }
+            | Inst::Store {
+                addr: Address::Local(slot),
+                ty,
+                ..
+            } => {
+                slots.insert(*slot, ty.clone());
+            }
+            Inst::Lea {
+                addr: Address::Local(slot),
+                ..
+            } => {
+                escaped.insert(*slot);
+            }
+            _ => {}
+        }
+    }
+
+    slots.retain(|slot, ty| !escaped.contains(slot) && !matches!(ty, Type::Array { .. }));
+    slots
+}
+
+impl Ssa {
+    fn build(function: &Function, labels: &mut Label, promote: bool) -> Self {
+        let mut cfg = Cfg::build(&function.insts, labels);
+        let count = cfg.blocks.len();
+
+        let mut builder = Builder {
+            cfg: &cfg,
+            slots: if promote {
+                promotable_slots(&function.insts)
+            } else {
+                HashMap::new()
+            },
+            params: function.params.iter().map(|param| param.offset).collect(),
+            current: HashMap::new(),
+            sealed: vec![false; count],
+            incomplete: vec![Vec::new(); count],
+            phis: HashMap::new(),
+            initial: HashMap::new(),
+            entry: Vec::new(),
+            vregs: function
+                .insts
+                .iter()
+                .flat_map(|inst| inst.def())
+                .max()
+                .map_or(0, |vreg| vreg + 1),
+        };
+
+        let mut replaced = HashMap::new();
+        let mut bodies = Vec::new();
+        for (index, block) in cfg.blocks.iter().enumerate() {
+            // blocks are visited in order, a block is complete once every
+            // predecessor before it is
+            for other in 0..=index {
+                if !builder.sealed[other] && cfg.predecessors(other).iter().all(|p| *p < index) {
+                    builder.seal(other);
+                }
+            }
+
+            let mut body = Vec::new();
+            for inst in &block.insts {
+                match inst {
+                    Inst::Load {
+                        dst,
+                        addr: Address::Local(slot),
+                        ..
+                    } if builder.slots.contains_key(slot) => {
+                        let value = builder.read(*slot, index);
+                        replaced.insert(*dst, value);
+                    }
+                    Inst::Store {
+                        src,
+                        addr: Address::Local(slot),
+                        ty,
+                    } if builder.slots.contains_key(slot) => {
+                        // the store would have cut the value down to the slot's size
+                        let dst = builder.vreg();
+                        body.push(Inst::Cast {
+                            dst,
+                            src: *src,
+                            ty: ty.clone(),
+                        });
+                        builder.current.insert((*slot, index), dst);
+                    }
+                    _ => body.push(inst.clone()),
+                }
+            }
+            bodies.push(body);
+        }
+
+        for index in 0..count {
+            if !builder.sealed[index] {
+                builder.seal(index);
+            }
+        }
+
+        let mut phis = std::mem::take(&mut builder.phis);
+        remove_trivial_phis(&mut phis, &mut replaced, &mut builder);
+
+        let mut phis = phis.into_iter().collect::<Vec<_>>();
+        phis.sort_by_key(|(dst, _)| *dst);
+
+        let vregs = builder.vregs;
+        let mut entry = std::mem::take(&mut builder.entry);
+        let labels = cfg
+            .blocks
+            .iter()
+            .map(|block| block.label)
+            .collect::<Vec<_>>();
+        // only the instructions change, the edges between the blocks stay
+        for (index, (block, body)) in cfg.blocks.iter_mut().zip(bodies).enumerate() {
+            let mut insts = Vec::new();
+            if index == 0 {
+                insts.append(&mut entry);
+            }
+            for (dst, (phi_block, args)) in &phis {
+                if *phi_block == index {
+                    insts.push(Inst::Phi {
+                        dst: *dst,
+                        args: args
+                            .iter()
+                            .map(|(predecessor, arg)| (labels[*predecessor], *arg))
+                            .collect(),
+                    });
+                }
+            }
+            insts.extend(body);
+
+            for inst in &mut insts {
+                inst.map_uses(|vreg| resolve(&replaced, vreg));
+            }
+            block.insts = insts;
+        }
+
+        Ssa { cfg, vregs }
+    }
+
+    fn optimize(&mut self, passes: &Passes) {
+        loop {
+            let mut changed = false;
+            if passes.enabled(Pass::CopyPropagation) {
+                changed |= timing::time(Pass::CopyPropagation.name(), || self.propagate_copies());
+            }
+            if passes.enabled(Pass::ConstantPropagation) {
+                changed |= timing::time(Pass::ConstantPropagation.name(), || {
+                    self.propagate_constants()
+                });
+            }
+            if passes.enabled(Pass::BranchFolding) {
+                changed |= timing::time(Pass::BranchFolding.name(), || self.fold_branches());
+            }
+            if passes.enabled(Pass::UnreachableBlocks) {
+                changed |= timing::time(Pass::UnreachableBlocks.name(), || {
+                    self.remove_unreachable_blocks()
+                });
+            }
+            if passes.enabled(Pass::StrengthReduction) {
+                changed |= timing::time(Pass::StrengthReduction.name(), || self.reduce_strength());
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        timing::time("rematerialization", || self.rematerialize_constants());
+        if passes.enabled(Pass::DeadCode) {
+            timing::time(Pass::DeadCode.name(), || self.remove_dead_code());
+        }
+    }
+
+    // Propagation leaves constants defined far from where they are read. Loading
+    // them again right before each use keeps them out of registers in between.
+    fn rematerialize_constants(&mut self) {
+        let values = self
+            .cfg
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .filter_map(|inst| match inst {
+                Inst::Const { dst, value } => Some((*dst, *value)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        for block in &mut self.cfg.blocks {
+            let mut insts = Vec::new();
+            for mut inst in std::mem::take(&mut block.insts) {
+                // phi arguments are copied at the end of the predecessor instead
+                if !matches!(inst, Inst::Const { .. } | Inst::Phi { .. }) {
+                    inst.map_uses(|vreg| match values.get(&vreg) {
+                        Some(value) => {
+                            self.vregs += 1;
+                            insts.push(Inst::Const {
+                                dst: self.vregs - 1,
+                                value: *value,
+                            });
+                            self.vregs - 1
+                        }
+                        None => vreg,
+                    });
+                }
+                insts.push(inst);
+            }
+            block.insts = insts;
+        }
+    }
+
+    fn replace_uses(&mut self, replaced: &HashMap<VReg, VReg>) {
+        for block in &mut self.cfg.blocks {
+            block.insts.retain(|inst| match inst.def() {
+                Some(dst) => !replaced.contains_key(&dst),
+                None => true,
+            });
+            for inst in &mut block.insts {
+                inst.map_uses(|vreg| resolve(replaced, vreg));
+            }
+        }
+    }
+
+    // uses of a copy read the original instead, which leaves the copy dead
+    fn propagate_copies(&mut self) -> bool {
+        let definitions = self
+            .cfg
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .filter_map(|inst| Some((inst.def()?, inst.clone())))
+            .collect::<HashMap<_, _>>();
+
+        let mut replaced = HashMap::new();
+        let mut replace = |dst: VReg, src: VReg| {
+            let src = resolve(&replaced, src);
+            if src != dst {
+                replaced.insert(dst, src);
+            }
+        };
+        for inst in self.cfg.blocks.iter().flat_map(|block| &block.insts) {
+            match inst {
+                Inst::Copy { dst, src } => replace(*dst, *src),
+                Inst::Cast { dst, src, ty } => {
+                    // a value that went through the same cast already fits
+                    let fits = match &definitions.get(src) {
+                        Some(Inst::Cast { ty: from, .. }) | Some(Inst::Load { ty: from, .. }) => {
+                            from == ty
+                        }
+                        _ => false,
+                    };
+                    if ty.size() == 8 || fits {
+                        replace(*dst, *src);
+                    }
+                }
+                Inst::Phi { dst, args } => {
+                    let mut values = args.iter().map(|(_, arg)| *arg).filter(|arg| arg != dst);
+                    if let Some(first) = values.next() {
+                        if values.all(|arg| arg == first) {
+                            replace(*dst, first);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.replace_uses(&replaced);
+        !replaced.is_empty()
+    }
+
+    // an instruction whose operands are all constants becomes one
+    fn propagate_constants(&mut self) -> bool {
+        let mut values = HashMap::new();
+        let mut changed = false;
+
+        for block in &mut self.cfg.blocks {
+            for inst in &mut block.insts {
+                let value = match inst {
+                    Inst::Const { dst, value } => {
+                        values.insert(*dst, *value);
+                        continue;
+                    }
+                    Inst::Copy { src, .. } => values.get(src).copied(),
+                    Inst::Cast { src, ty, .. } => values.get(src).map(|value| ir::cast(*value, ty)),
+                    Inst::Binary {
+                        op, left, right, ..
+                    } => match (values.get(left), values.get(right)) {
+                        (Some(left), Some(right)) => op.evaluate(*left, *right),
+                        _ => None,
+                    },
+                    Inst::Unary { op, src, .. } => values.get(src).map(|value| op.evaluate(*value)),
+                    Inst::Compare {
+                        cond, left, right, ..
+                    } => match (values.get(left), values.get(right)) {
+                        (Some(left), Some(right)) => Some(cond.evaluate(*left, *right) as u64),
+                        _ => None,
+                    },
+                    Inst::Select {
+                        cond,
+                        left,
+                        right,
+                        then,
+                        otherwise,
+                        ..
+                    } => match (values.get(left), values.get(right)) {
+                        (Some(left), Some(right)) if cond.evaluate(*left, *right) => {
+                            values.get(then).copied()
+                        }
+                        (Some(_), Some(_)) => values.get(otherwise).copied(),
+                        _ => None,
+                    },
+                    Inst::Phi { args, .. } => {
+                        let mut args = args.iter().map(|(_, arg)| values.get(arg));
+                        match args.next().flatten() {
+                            Some(first) if args.all(|arg| arg == Some(first)) => Some(*first),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+
+                if let (Some(value), Some(dst)) = (value, inst.def()) {
+                    values.insert(dst, value);
+                    *inst = Inst::Const { dst, value };
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    // a branch on constants always goes the same way
+    fn fold_branches(&mut self) -> bool {
+        let mut values = HashMap::new();
+        for inst in self.cfg.blocks.iter().flat_map(|block| &block.insts) {
+            if let Inst::Const { dst, value } = inst {
+                values.insert(*dst, *value);
+            }
+        }
+
+        let mut changed = false;
+        for block in &mut self.cfg.blocks {
+            if let Some(Inst::Branch {
+                cond,
+                left,
+                right,
+                target,
+            }) = block.insts.last()
+            {
+                let (Some(left), Some(right)) = (values.get(left), values.get(right)) else {
+                    continue;
+                };
+
+                if cond.evaluate(*left, *right) {
+                    let target = *target;
+                    *block.insts.last_mut().unwrap() = Inst::Jump { target };
+                } else {
+                    block.insts.pop();
+                }
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.cfg.connect();
+            self.prune_phis();
+        }
+
+        changed
+    }
+
+    // Multiplying or dividing by a power of two is a shift. Division rounds
+    // towards zero, so a negative dividend is biased by the divisor less one
+    // first, which the sign smeared over the low bits gives.
+    fn reduce_strength(&mut self) -> bool {
+        let values = self
+            .cfg
+            .blocks
+            .iter()
+            .flat_map(|block| &block.insts)
+            .filter_map(|inst| match inst {
+                Inst::Const { dst, value } => Some((*dst, *value)),
+                _ => None,
+            })
+            .collect::<HashMap<_, _>>();
+        let power = |vreg: &VReg| {
+            values
+                .get(vreg)
+                .filter(|value| value.is_power_of_two())
+                .map(|value| value.trailing_zeros() as u64)
+        };
+
+        let mut changed = false;
+        for block in &mut self.cfg.blocks {
+            let mut insts = Vec::new();
+            for inst in std::mem::take(&mut block.insts) {
+                match strength_reduced(&inst, power, &mut self.vregs) {
+                    Some(reduced) => {
+                        insts.extend(reduced);
+                        changed = true;
+                    }
+                    None => insts.push(inst),
+                }
+            }
+            block.insts = insts;
+        }
+
+        changed
+    }
+
+    fn remove_unreachable_blocks(&mut self) -> bool {
+        let changed = self.cfg.remove_unreachable();
+        if changed {
+            self.prune_phis();
+        }
+
+        changed
+    }
+
+    // a phi has no say for an edge that is gone
+    fn prune_phis(&mut self) {
+        let labels = self
+            .cfg
+            .blocks
+            .iter()
+            .map(|block| block.label)
+            .collect::<Vec<_>>();
+        for index in 0..labels.len() {
+            let incoming = self
+                .cfg
+                .predecessors(index)
+                .iter()
+                .map(|predecessor| labels[*predecessor])
+                .collect::<Vec<_>>();
+            for inst in &mut self.cfg.blocks[index].insts {
+                if let Inst::Phi { args, .. } = inst {
+                    args.retain(|(label, _)| incoming.contains(label));
+                }
+            }
+        }
+    }
+
+    fn remove_dead_code(&mut self) {
+        loop {
+            let used = self
+                .cfg
+                .blocks
+                .iter()
+                .flat_map(|block| &block.insts)
+                .flat_map(|inst| inst.uses())
+                .collect::<HashSet<_>>();
+
+            let mut changed = false;
+            for block in &mut self.cfg.blocks {
+                block.insts.retain(|inst| {
+                    // calls have side effects and division may fault
+                    let pure = !matches!(
+                        inst,
+                        Inst::Call { .. }
+                            | Inst::Binary {
+                                op: ir::BinOp::Div | ir::BinOp::UDiv,
+                                ..
+                            }
+                    );
+                    let dead = pure && inst.def().is_some_and(|dst| !used.contains(&dst));
+                    changed |= dead;
+                    !dead
+                });
+            }
+
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    // Each phi becomes a copy at the end of every predecessor. The copies of
+    // one edge go through temporaries, as the phis all read their arguments
+    // before any of them is written. An edge out of a branch into a block
+    // with several predecessors gets a block of its own for the copies.
+    fn destruct(mut self, labels: &mut Label) -> Vec<Inst> {
+        let mut inline: HashMap<usize, Vec<Inst>> = HashMap::new();
+        let mut detached = Vec::new();
+
+        for index in 0..self.cfg.blocks.len() {
+            let phis = self.cfg.blocks[index]
+                .insts
+                .iter()
+                .filter_map(|inst| match inst {
+                    Inst::Phi { dst, args } => Some((*dst, args.clone())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if phis.is_empty() {
+                continue;
+            }
+            self.cfg.blocks[index]
+                .insts
+                .retain(|inst| !matches!(inst, Inst::Phi { .. }));
+
+            let label = self.cfg.blocks[index].label;
+            for predecessor in self.cfg.predecessors(index).to_vec() {
+                let from = self.cfg.blocks[predecessor].label;
+                let mut copies = Vec::new();
+                let mut temporaries = Vec::new();
+                for (dst, args) in &phis {
+                    let Some((_, arg)) = args.iter().find(|(label, _)| *label == from) else {
+                        continue;
+                    };
+                    self.vregs += 1;
+                    let temporary = self.vregs - 1;
+                    copies.push(Inst::Copy {
+                        dst: temporary,
+                        src: *arg,
+                    });
+                    temporaries.push((*dst, temporary));
+                }
+                for (dst, temporary) in temporaries {
+                    copies.push(Inst::Copy {
+                        dst,
+                        src: temporary,
+                    });
+                }
+
+                let block = &mut self.cfg.blocks[predecessor];
+                match edge(block, label, predecessor, index) {
+                    Edge::Jump => {
+                        let jump = block.insts.pop().unwrap();
+                        block.insts.extend(copies);
+                        block.insts.push(jump);
+                    }
+                    Edge::FallThrough => block.insts.extend(copies),
+                    // both ways lead here, the branch does not matter anymore
+                    Edge::BranchBothWays => {
+                        block.insts.pop();
+                        block.insts.extend(copies);
+                    }
+                    Edge::BranchTaken => {
+                        *labels += 1;
+                        let split = *labels;
+                        if let Some(Inst::Branch { target, .. }) = block.insts.last_mut() {
+                            *target = split;
+                        }
+                        detached.push(Inst::Label(split));
+                        detached.extend(copies);
+                        detached.push(Inst::Jump { target: label });
+                    }
+                    Edge::BranchNotTaken => {
+                        *labels += 1;
+                        let split = *labels;
+                        let mut insts = vec![Inst::Label(split)];
+                        insts.extend(copies);
+                        inline.insert(predecessor, insts);
+                    }
+                }
+            }
+        }
+
+        let mut insts = Vec::new();
+        for (index, block) in self.cfg.blocks.into_iter().enumerate() {
+            insts.push(Inst::Label(block.label));
+            insts.extend(block.insts);
+            if let Some(split) = inline.remove(&index) {
+                insts.extend(split);
+            }
+        }
+        insts.extend(detached);
+
+        insts
+    }
+}
+
+enum Edge {
+    Jump,
+    FallThrough,
+    BranchBothWays,
+    BranchTaken,
+    BranchNotTaken,
+}
+
+// how control gets from the end of block `from` to block `to`, labelled `label`
+fn edge(block: &BasicBlock, label: Label, from: usize, to: usize) -> Edge {
+    match block.insts.last() {
+        Some(Inst::Jump { .. }) => Edge::Jump,
+        Some(Inst::Branch { target, .. }) => {
+            let falls_through = from + 1 == to;
+            match (*target == label, falls_through) {
+                (true, true) => Edge::BranchBothWays,
+                (true, false) => Edge::BranchTaken,
+                _ => Edge::BranchNotTaken,
+            }
+        }
+        _ => Edge::FallThrough,
+    }
+}
+
+fn remove_trivial_phis(
+    phis: &mut HashMap<VReg, Phi>,
+    replaced: &mut HashMap<VReg, VReg>,
+    builder: &mut Builder,
+) {
+    loop {
+        let mut dsts = phis.keys().copied().collect::<Vec<_>>();
+        dsts.sort();
+
+        let mut trivial = None;
+        for dst in &dsts {
+            let args = &phis[dst].1;
+            let mut same = None;
+            let mut unique = true;
+            for (_, arg) in args {
+                let arg = resolve(replaced, *arg);
+                if arg == *dst || Some(arg) == same {
+                    continue;
+                }
+                if same.is_some() {
+                    unique = false;
+                    break;
+                }
+                same = Some(arg);
+            }
+
+            if unique {
+                trivial = Some((*dst, same));
+                break;
+            }
+        }
+
+        let Some((dst, same)) = trivial else {
+            break;
+        };
+
+        // a phi that only refers to itself sits in a loop nothing enters
+        let same = same.unwrap_or_else(|| {
+            let value = builder.vreg();
+            builder.entry.push(Inst::Const {
+                dst: value,
+                value: 0,
+            });
+            value
+        });
+        phis.remove(&dst);
+        replaced.insert(dst, same);
+    }
+}
+
+// the shifts a multiplication or division by a power of two comes down to
+fn strength_reduced(
+    inst: &Inst,
+    power: impl Fn(&VReg) -> Option<u64>,
+    vregs: &mut usize,
+) -> Option<Vec<Inst>> {
+    let mut vreg = || {
+        *vregs += 1;
+        *vregs - 1
+    };
+
+    match *inst {
+        Inst::Binary {
+            op: ir::BinOp::Mul,
+            dst,
+            left,
+            right,
+        } => {
+            let (value, shift) = match (power(&left), power(&right)) {
+                (_, Some(shift)) => (left, shift),
+                (Some(shift), None) => (right, shift),
+                (None, None) => return None,
+            };
+            let amount = vreg();
+            Some(vec![
+                Inst::Const {
+                    dst: amount,
+                    value: shift,
+                },
+                Inst::Binary {
+                    op: ir::BinOp::Shl,
+                    dst,
+                    left: value,
+                    right: amount,
+                },
+            ])
+        }
+        Inst::Binary {
+            op: ir::BinOp::UDiv,
+            dst,
+            left,
+            right,
+        } => {
+            let amount = vreg();
+            Some(vec![
+                Inst::Const {
+                    dst: amount,
+                    value: power(&right)?,
+                },
+                Inst::Binary {
+                    op: ir::BinOp::UShr,
+                    dst,
+                    left,
+                    right: amount,
+                },
+            ])
+        }
+        Inst::Binary {
+            op: ir::BinOp::Div,
+            dst,
+            left,
+            right,
+        } => {
+            let shift = power(&right)?;
+            let (sign_amount, sign, mask, bias, biased, amount) =
+                (vreg(), vreg(), vreg(), vreg(), vreg(), vreg());
+            Some(vec![
+                Inst::Const {
+                    dst: sign_amount,
+                    value: 63,
+                },
+                Inst::Binary {
+                    op: ir::BinOp::Shr,
+                    dst: sign,
+                    left,
+                    right: sign_amount,
+                },
+                Inst::Const {
+                    dst: mask,
+                    value: (1 << shift) - 1,
+                },
+                Inst::Binary {
+                    op: ir::BinOp::And,
+                    dst: bias,
+                    left: sign,
+                    right: mask,
+                },
+                Inst::Binary {
+                    op: ir::BinOp::Add,
+                    dst: biased,
+                    left,
+                    right: bias,
+                },
+                Inst::Const {
+                    dst: amount,
+                    value: shift,
+                },
+                Inst::Binary {
+                    op: ir::BinOp::Shr,
+                    dst,
+                    left: biased,
+                    right: amount,
+                },
+            ])
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+// What a function takes of the stack, as the backend laid it out: its own
+// frame, with the return address and the saved frame pointer, and the calls
+// it makes with what it pushes for each, the registers kept across it and
+// the arguments that don't fit in registers
+#[derive(Debug, Default)]
+pub struct Frame {
+    pub name: String,
+    pub size: u64,
+    pub calls: Vec<(String, u64)>,
+    // a tail call leaves this frame before the callee runs
+    pub tail_calls: Vec<String>,
+}
+
+// The deepest the stack gets below a function's caller, with the chain of
+// calls that gets there, or the function that recurses when a cycle of
+// calls can be reached, which nothing bounds
+type Depth = std::result::Result<(u64, Vec<String>), String>;
+
+struct Analysis<'a> {
+    frames: HashMap<&'a str, &'a Frame>,
+    depths: HashMap<&'a str, Depth>,
+    // the functions being walked, a call back into one is recursion
+    walking: Vec<&'a str>,
+}
+
+impl<'a> Analysis<'a> {
+    fn depth(&mut self, name: &'a str) -> Depth {
+        if let Some(depth) = self.depths.get(name) {
+            return depth.clone();
+        }
+        // a function of the C library or the runtime, which isn't counted
+        let Some(frame) = self.frames.get(name).copied() else {
+            return Ok((0, Vec::new()));
+        };
+        if self.walking.contains(&name) {
+            return Err(name.to_string());
+        }
+
+        self.walking.push(name);
+        let mut deepest = (frame.size, Vec::new());
+        let mut cycle = None;
+        let calls = frame
+            .calls
+            .iter()
+            .map(|(callee, pushed)| (callee, frame.size + pushed));
+        let tail_calls = frame.tail_calls.iter().map(|callee| (callee, 0));
+        for (callee, below) in calls.chain(tail_calls) {
+            match self.depth(callee) {
+                Ok((depth, chain)) if below + depth > deepest.0 => deepest = (below + depth, chain),
+                Ok(_) => {}
+                Err(recursive) => cycle = Some(recursive),
+            }
+        }
+        self.walking.pop();
+
+        let depth = match cycle {
+            Some(recursive) => Err(recursive),
+            None => Ok((deepest.0, [vec![name.to_string()], deepest.1].concat())),
+        };
+        self.depths.insert(name, depth.clone());
+        depth
+    }
+}
+
+// The table `--stack-usage` prints: every function's frame and the most
+// stack a call to it can take, through the deepest chain of calls. What the
+// C library and the runtime take is on top of that.
+pub fn report(frames: &[Frame]) -> String {
+    let mut analysis = Analysis {
+        frames: frames
+            .iter()
+            .map(|frame| (frame.name.as_str(), frame))
+            .collect(),
+        depths: HashMap::new(),
+        walking: Vec::new(),
+    };
+
+    let mut report = format!(
+        "{:<24}{:>8}{:>12}  {}\n",
+        "function", "frame", "worst case", "deepest chain"
+    );
+    for frame in frames {
+        match analysis.depth(&frame.name) {
+            Ok((depth, chain)) => report.push_str(&format!(
+                "{:<24}{:>8}{:>12}  {}\n",
+                frame.name,
+                frame.size,
+                depth,
+                chain.join(" -> ")
+            )),
+            Err(recursive) => report.push_str(&format!(
+                "{:<24}{:>8}{:>12}  {} recurses\n",
+                frame.name, frame.size, "unbounded", recursive
+            )),
+        }
+    }
+    report
+}
@@ -0,0 +1,174 @@
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use crate::{
+    intern::Name,
+    parser::{StorageClass, Symbol, SymbolType},
+    target::Target,
+    types::Type,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionInfo {
+    pub name: String,
+    pub return_type: Option<Type>,
+    // the label of its epilogue in the assembly for the target
+    pub end_label: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GlobalInfo {
+    pub name: String,
+    pub ty: Type,
+    pub size: usize,
+}
+
+// A stack of scopes, the first one being the global scope. Lookups walk from
+// the innermost scope outwards, so locals shadow parameters and globals.
+pub struct SymbolTable {
+    scopes: Vec<Scope>,
+}
+
+// the symbols of a scope in the order they are declared, and by name, where
+// the overloads of a function share one
+#[derive(Default)]
+struct Scope {
+    symbols: Vec<Rc<RefCell<Symbol>>>,
+    names: HashMap<Name, Vec<Rc<RefCell<Symbol>>>>,
+}
+
+impl Scope {
+    fn insert(&mut self, symbol: Rc<RefCell<Symbol>>) {
+        let name = symbol.borrow().name;
+        self.names.entry(name).or_default().push(symbol.clone());
+        self.symbols.push(symbol);
+    }
+
+    // the first symbol declared with the name
+    fn find(&self, name: Name) -> Option<Rc<RefCell<Symbol>>> {
+        self.names.get(&name)?.first().cloned()
+    }
+}
+
+impl Default for SymbolTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self {
+            scopes: vec![Scope::default()],
+        }
+    }
+
+    pub fn enter_scope(&mut self) {
+        self.scopes.push(Scope::default());
+    }
+
+    pub fn exit_scope(&mut self) {
+        // never pop the global scope
+        if self.scopes.len() > 1 {
+            self.scopes.pop();
+        }
+    }
+
+    // globals always live in the outermost scope, everything else in the current one
+    pub fn insert(&mut self, symbol: Rc<RefCell<Symbol>>) {
+        let scope = if symbol.borrow().class == StorageClass::Global {
+            0
+        } else {
+            self.scopes.len() - 1
+        };
+
+        self.scopes[scope].insert(symbol);
+    }
+
+    // how many scopes deep the innermost one is, the global scope is 0
+    pub fn depth(&self) -> usize {
+        self.scopes.len() - 1
+    }
+
+    // a symbol declared in a scope further out than the current one, for a
+    // lambda to capture a variable of the function around it
+    pub fn insert_into(&mut self, scope: usize, symbol: Rc<RefCell<Symbol>>) {
+        self.scopes[scope].insert(symbol);
+    }
+
+    pub fn find(&self, name: Name) -> Option<Rc<RefCell<Symbol>>> {
+        self.find_scoped(name).map(|(_, symbol)| symbol)
+    }
+
+    // the symbol and the depth of the scope it is declared in
+    pub fn find_scoped(&self, name: Name) -> Option<(usize, Rc<RefCell<Symbol>>)> {
+        self.scopes
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(depth, scope)| Some((depth, scope.find(name)?)))
+    }
+
+    // every function declared with the name, one per signature
+    pub fn overloads(&self, name: Name) -> Vec<Rc<RefCell<Symbol>>> {
+        self.scopes[0]
+            .names
+            .get(&name)
+            .into_iter()
+            .flatten()
+            .filter(|symbol| symbol.borrow().structure == SymbolType::Function)
+            .cloned()
+            .collect()
+    }
+
+    // look only at the scope a new symbol of this class would be declared in
+    pub fn find_declared(&self, name: Name, class: &StorageClass) -> Option<Rc<RefCell<Symbol>>> {
+        let scope = if *class == StorageClass::Global {
+            &self.scopes[0]
+        } else {
+            self.scopes.last().unwrap()
+        };
+
+        scope.find(name)
+    }
+
+    // functions defined by the compilation unit, in declaration order (builtins are left out)
+    pub fn functions(&self, target: Target) -> Vec<FunctionInfo> {
+        self.scopes[0]
+            .symbols
+            .iter()
+            .filter_map(|symbol| {
+                let symbol = symbol.borrow();
+                // builtins are declared nowhere in the source
+                if symbol.structure != SymbolType::Function || symbol.identifier.line == 0 {
+                    return None;
+                }
+
+                Some(FunctionInfo {
+                    name: symbol.link_name(),
+                    return_type: symbol.ty.clone(),
+                    end_label: target.label(&symbol.link_name(), "end"),
+                })
+            })
+            .collect()
+    }
+
+    pub fn globals(&self) -> Vec<GlobalInfo> {
+        self.scopes[0]
+            .symbols
+            .iter()
+            .filter_map(|symbol| {
+                let symbol = symbol.borrow();
+                if symbol.structure != SymbolType::Variable {
+                    return None;
+                }
+
+                let ty = symbol.ty.clone()?;
+                Some(GlobalInfo {
+                    name: symbol.identifier.lexeme?.to_string(),
+                    size: ty.storage_size(),
+                    ty,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,145 @@
+// The processor the code runs on, each has a backend of its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arch {
+    X86_64,
+    Aarch64,
+    Riscv64,
+    Wasm32,
+}
+
+// The system the assembly is written for. They differ in how the object file
+// names symbols and lays out sections, and Windows in how functions are
+// called. WASI only comes with wasm32 and Windows with x86-64, so the other
+// native backends write ELF for anything but macOS.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Os {
+    Linux,
+    Macos,
+    Windows,
+    Wasi,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Target {
+    pub arch: Arch,
+    pub os: Os,
+}
+
+impl Target {
+    // The target the compiler itself was built for, which is what the
+    // assembly is written for unless --target says otherwise. A host the
+    // compiler has no backend for gets x86_64-linux.
+    pub fn host() -> Self {
+        let arch = if cfg!(target_arch = "aarch64") {
+            Arch::Aarch64
+        } else if cfg!(target_arch = "riscv64") {
+            Arch::Riscv64
+        } else {
+            Arch::X86_64
+        };
+        let os = if cfg!(target_os = "macos") && arch != Arch::Riscv64 {
+            Os::Macos
+        } else if cfg!(target_os = "windows") && arch == Arch::X86_64 {
+            Os::Windows
+        } else {
+            Os::Linux
+        };
+        Target { arch, os }
+    }
+
+    // Reads either the short name, like `aarch64-macos`, or a full triple of
+    // the architecture, the vendor, the system and the environment, like
+    // `aarch64-apple-darwin` or `x86_64-pc-windows-gnu`. The vendor is
+    // ignored, and so is the environment except that Windows code is written
+    // for the GNU toolchain and not for MSVC.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let mut parts = name.split('-');
+        let arch = match parts.next()? {
+            "x86_64" | "amd64" => Arch::X86_64,
+            "aarch64" | "arm64" => Arch::Aarch64,
+            "riscv64" | "riscv64gc" => Arch::Riscv64,
+            "wasm32" => Arch::Wasm32,
+            _ => return None,
+        };
+        let parts: Vec<&str> = parts.collect();
+        if parts.contains(&"msvc") {
+            return None;
+        }
+        let os = parts.iter().find_map(|part| match *part {
+            "linux" => Some("linux"),
+            "macos" | "macosx" | "darwin" => Some("macos"),
+            "windows" | "mingw32" => Some("windows"),
+            "wasi" => Some("wasi"),
+            _ => None,
+        })?;
+
+        // there is no macOS for RISC-V, Windows is only supported on x86-64,
+        // and WebAssembly runs on nothing but a WASI host
+        let os = match (arch, os) {
+            (Arch::Wasm32, "wasi") => Os::Wasi,
+            (Arch::Wasm32, _) => return None,
+            (_, "linux") => Os::Linux,
+            (Arch::X86_64 | Arch::Aarch64, "macos") => Os::Macos,
+            (Arch::X86_64, "windows") => Os::Windows,
+            _ => return None,
+        };
+
+        Some(Target { arch, os })
+    }
+
+    // the triple LLVM and the GNU tools know the target by
+    pub fn triple(&self) -> &'static str {
+        match (self.arch, self.os) {
+            (Arch::X86_64, Os::Macos) => "x86_64-apple-macosx",
+            (Arch::X86_64, Os::Windows) => "x86_64-w64-windows-gnu",
+            (Arch::X86_64, _) => "x86_64-pc-linux-gnu",
+            (Arch::Aarch64, Os::Macos) => "arm64-apple-macosx",
+            (Arch::Aarch64, _) => "aarch64-unknown-linux-gnu",
+            (Arch::Riscv64, _) => "riscv64-unknown-linux-gnu",
+            (Arch::Wasm32, _) => "wasm32-unknown-wasi",
+        }
+    }
+
+    // The C compiler that assembles and links for the target, the system
+    // compiler for the host and the usual name of the GNU cross compiler for
+    // the rest. WebAssembly has none.
+    pub fn linker(&self) -> Option<&'static str> {
+        if *self == Target::host() {
+            return Some("cc");
+        }
+        match (self.arch, self.os) {
+            (_, Os::Macos) => Some("cc"),
+            (Arch::X86_64, Os::Linux) => Some("x86_64-linux-gnu-gcc"),
+            (Arch::Aarch64, Os::Linux) => Some("aarch64-linux-gnu-gcc"),
+            (Arch::Riscv64, Os::Linux) => Some("riscv64-linux-gnu-gcc"),
+            (Arch::X86_64, Os::Windows) => Some("x86_64-w64-mingw32-gcc"),
+            _ => None,
+        }
+    }
+
+    // A name only the assembly knows, kept out of the symbol table so it
+    // can't collide with a function or global of the source. Mach-O keeps
+    // the names starting with L to itself, ELF and COFF those with .L.
+    pub fn local(&self, name: &str) -> String {
+        match self.os {
+            Os::Macos => format!("L{}", name),
+            _ => format!(".L{}", name),
+        }
+    }
+
+    // The label of a block of a function, or its epilogue with `end`. Blocks
+    // are numbered per function, so the labels of one stay the same whatever
+    // changes in another.
+    pub fn label(&self, function: &str, label: impl std::fmt::Display) -> String {
+        self.local(&format!("{}_{}", function, label))
+    }
+
+    // what a function or global of the source is called in the object file,
+    // Mach-O puts an underscore in front of every C symbol
+    pub fn symbol(&self, name: &str) -> String {
+        match self.os {
+            Os::Macos => format!("_{}", name),
+            _ => name.to_string(),
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+// The wall time of every stage of the compiler for `--time-passes`. The
+// stages are spread over the frontend, the optimizer and the backends, so
+// the times are collected on the side instead of being handed around.
+struct Timings {
+    start: Instant,
+    // in the order the stages first ran, a pass that runs once per function
+    // or per iteration adds up to a single entry
+    stages: Vec<(&'static str, Duration)>,
+}
+
+thread_local! {
+    static TIMINGS: RefCell<Option<Timings>> = const { RefCell::new(None) };
+}
+
+pub fn enable() {
+    TIMINGS.with(|timings| {
+        *timings.borrow_mut() = Some(Timings {
+            start: Instant::now(),
+            stages: Vec::new(),
+        })
+    });
+}
+
+// Runs `stage`, adding the time it took to `name` if timing is enabled
+pub fn time<T>(name: &'static str, stage: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = stage();
+    let elapsed = start.elapsed();
+
+    TIMINGS.with(|timings| {
+        if let Some(timings) = timings.borrow_mut().as_mut() {
+            match timings.stages.iter_mut().find(|(stage, _)| *stage == name) {
+                Some((_, total)) => *total += elapsed,
+                None => timings.stages.push((name, elapsed)),
+            }
+        }
+    });
+    result
+}
+
+// Prints the table to stderr. Stages can run inside each other, the peephole
+// optimizer as part of code generation, so the total is the time since
+// timing was enabled and not the sum of the rows.
+pub fn report() {
+    TIMINGS.with(|timings| {
+        let Some(timings) = timings.borrow_mut().take() else {
+            return;
+        };
+        let total = timings.start.elapsed();
+
+        eprintln!("{:<24}{:>12}{:>8}", "stage", "time", "share");
+        for (name, time) in &timings.stages {
+            let share = time.as_secs_f64() / total.as_secs_f64() * 100.0;
+            eprintln!("{:<24}{:>10.3}ms{:>7.1}%", name, millis(*time), share);
+        }
+        eprintln!("{:<24}{:>10.3}ms", "total", millis(total));
+    });
+}
+
+fn millis(time: Duration) -> f64 {
+    time.as_secs_f64() * 1000.0
+}
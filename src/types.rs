@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     U8,
@@ -11,6 +13,10 @@ pub enum Type {
     Char,
     Array { ty: Box<Type>, count: u64 },
     Pointer { ty: Box<Type>, count: u64 },
+    // a lambda with what it captured, held as the address of its environment
+    Closure { params: Vec<Type>, ret: Box<Type> },
+    // the values a function returns several of, 8 bytes apart in memory
+    Tuple(Vec<Type>),
 }
 
 impl Type {
@@ -19,13 +25,32 @@ impl Type {
             Type::U8 | Type::I8 | Type::Char => 1,
             Type::U16 | Type::I16 => 2,
             Type::U32 | Type::I32 => 4,
-            Type::U64 | Type::I64 | Type::Pointer { .. } => 8,
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => 8,
             Type::Array { ty, .. } => ty.size(),
+            Type::Tuple(types) => 8 * types.len(),
+        }
+    }
+
+    // the number of bytes a variable of this type occupies
+    pub fn storage_size(&self) -> usize {
+        match self {
+            Type::Array { ty, count } => ty.size() * *count as usize,
+            _ => self.size(),
         }
     }
 
-    pub fn pointer_to(&self) -> Self {
+    // the boundary a variable of this type has to start at, arrays are
+    // aligned like their elements
+    pub fn align(&self) -> usize {
         match self {
+            Type::Array { ty, .. } => ty.align(),
+            Type::Tuple(_) => 8,
+            _ => self.size(),
+        }
+    }
+
+    pub fn pointer_to(&self) -> Option<Self> {
+        let ty = match self {
             Type::U8 => Type::Pointer {
                 ty: Box::new(Type::U8),
                 count: 1,
@@ -66,24 +91,26 @@ impl Type {
                 ty: ty.clone(),
                 count: *count + 1,
             },
-            _ => panic!("Cannot take pointer of type {:?}", self),
-        }
+            _ => return None,
+        };
+
+        Some(ty)
     }
 
-    pub fn value_at(&self) -> Self {
+    pub fn value_at(&self) -> Option<Self> {
         match self {
             Type::Pointer { ty, count } => {
                 if *count == 1 {
-                    *ty.clone()
+                    Some(*ty.clone())
                 } else {
-                    Type::Pointer {
+                    Some(Type::Pointer {
                         ty: ty.clone(),
                         count: *count - 1,
-                    }
+                    })
                 }
             }
-            Type::Array { ty, .. } => *ty.clone(),
-            _ => panic!("Cannot dereference type {:?}", self),
+            Type::Array { ty, .. } => Some(*ty.clone()),
+            _ => None,
         }
     }
 
@@ -102,8 +129,50 @@ impl Type {
         )
     }
 
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Type::I8 | Type::I16 | Type::I32 | Type::I64)
+    }
+
+    // The casts that widen a value of this type to a wider one. It is
+    // extended from this type, zero or sign by its signedness, and a negative
+    // value sign extended to 64 bits is then cut down to an unsigned type that
+    // is still narrower than that.
+    pub fn widening(&self, to: &Type) -> Vec<Type> {
+        let mut casts = vec![self.clone()];
+        if self.is_signed() && !to.is_signed() && to.size() < 8 {
+            casts.push(to.clone());
+        }
+        casts
+    }
+
     pub fn is_ptr(&self) -> bool {
         // should array be here?
         matches!(self, Type::Pointer { .. } | Type::Array { .. })
     }
 }
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::U8 => write!(f, "u8"),
+            Type::U16 => write!(f, "u16"),
+            Type::U32 => write!(f, "u32"),
+            Type::U64 => write!(f, "u64"),
+            Type::I8 => write!(f, "i8"),
+            Type::I16 => write!(f, "i16"),
+            Type::I32 => write!(f, "i32"),
+            Type::I64 => write!(f, "i64"),
+            Type::Char => write!(f, "char"),
+            Type::Array { ty, count } => write!(f, "{}[{}]", ty, count),
+            Type::Pointer { ty, count } => write!(f, "{}{}", "*".repeat(*count as usize), ty),
+            Type::Closure { params, ret } => {
+                let params = params.iter().map(Type::to_string).collect::<Vec<_>>();
+                write!(f, "fn({}): {}", params.join(", "), ret)
+            }
+            Type::Tuple(types) => {
+                let types = types.iter().map(Type::to_string).collect::<Vec<_>>();
+                write!(f, "({})", types.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use crate::ast::Node;
+
+// Passes override `visit_node` for the nodes they care about and call
+// `walk_node` to carry on into the children of everything else.
+pub trait Visitor {
+    fn visit_node(&mut self, node: &Node) {
+        walk_node(self, node);
+    }
+}
+
+pub trait VisitorMut {
+    fn visit_node_mut(&mut self, node: &mut Node) {
+        walk_node_mut(self, node);
+    }
+}
+
+pub fn walk_node<V: Visitor + ?Sized>(visitor: &mut V, node: &Node) {
+    match node {
+        Node::BinaryExpr { left, right, .. } => {
+            visitor.visit_node(left);
+            visitor.visit_node(right);
+        }
+        Node::UnaryExpr { right, .. } => visitor.visit_node(right),
+        Node::WidenExpr { right, .. } => visitor.visit_node(right),
+        Node::ScaleExpr { right, .. } => visitor.visit_node(right),
+        Node::LiteralExpr { .. } => {}
+        Node::VarDecl { .. } => {}
+        Node::VarDeclMany { .. } => {}
+        Node::AssignStmt { left, expr, .. } => {
+            visitor.visit_node(left);
+            visitor.visit_node(expr);
+        }
+        Node::CompoundStmt { statements, .. } => {
+            for statement in statements {
+                visitor.visit_node(statement);
+            }
+        }
+        Node::IfStmt {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            visitor.visit_node(condition);
+            visitor.visit_node(then_branch);
+            if let Some(else_branch) = else_branch {
+                visitor.visit_node(else_branch);
+            }
+        }
+        Node::WhileStmt {
+            condition,
+            body,
+            step,
+            ..
+        } => {
+            visitor.visit_node(condition);
+            visitor.visit_node(body);
+            if let Some(step) = step {
+                visitor.visit_node(step);
+            }
+        }
+        Node::BreakStmt { .. } => {}
+        Node::ContinueStmt { .. } => {}
+        Node::FnDecl { body, .. } => visitor.visit_node(body),
+        Node::FnCall { args, .. } => {
+            for arg in args {
+                visitor.visit_node(arg);
+            }
+        }
+        Node::TupleExpr { elements, .. } => {
+            for element in elements {
+                visitor.visit_node(element);
+            }
+        }
+        Node::TupleAssign {
+            call, assignments, ..
+        } => {
+            visitor.visit_node(call);
+            for assignment in assignments {
+                visitor.visit_node(assignment);
+            }
+        }
+        Node::TupleElement { .. } => {}
+        // the body is a function of its own, among the top level nodes
+        Node::Lambda { .. } => {}
+        Node::ClosureCall { callee, args, .. } => {
+            visitor.visit_node(callee);
+            for arg in args {
+                visitor.visit_node(arg);
+            }
+        }
+        Node::ReturnStmt { expr, .. } => visitor.visit_node(expr),
+        Node::PostIncStmt { left, .. } => visitor.visit_node(left),
+        Node::PostDecStmt { left, .. } => visitor.visit_node(left),
+        Node::PreIncStmt { right, .. } => visitor.visit_node(right),
+        Node::PreDecStmt { right, .. } => visitor.visit_node(right),
+        Node::ToBool { expr, .. } => visitor.visit_node(expr),
+    }
+}
+
+pub fn walk_node_mut<V: VisitorMut + ?Sized>(visitor: &mut V, node: &mut Node) {
+    match node {
+        Node::BinaryExpr { left, right, .. } => {
+            visitor.visit_node_mut(left);
+            visitor.visit_node_mut(right);
+        }
+        Node::UnaryExpr { right, .. } => visitor.visit_node_mut(right),
+        Node::WidenExpr { right, .. } => visitor.visit_node_mut(right),
+        Node::ScaleExpr { right, .. } => visitor.visit_node_mut(right),
+        Node::LiteralExpr { .. } => {}
+        Node::VarDecl { .. } => {}
+        Node::VarDeclMany { .. } => {}
+        Node::AssignStmt { left, expr, .. } => {
+            visitor.visit_node_mut(left);
+            visitor.visit_node_mut(expr);
+        }
+        Node::CompoundStmt { statements, .. } => {
+            for statement in statements {
+                visitor.visit_node_mut(statement);
+            }
+        }
+        Node::IfStmt {
+            condition,
+            then_branch,
+            else_branch,
+            ..
+        } => {
+            visitor.visit_node_mut(condition);
+            visitor.visit_node_mut(then_branch);
+            if let Some(else_branch) = else_branch {
+                visitor.visit_node_mut(else_branch);
+            }
+        }
+        Node::WhileStmt {
+            condition,
+            body,
+            step,
+            ..
+        } => {
+            visitor.visit_node_mut(condition);
+            visitor.visit_node_mut(body);
+            if let Some(step) = step {
+                visitor.visit_node_mut(step);
+            }
+        }
+        Node::BreakStmt { .. } => {}
+        Node::ContinueStmt { .. } => {}
+        Node::FnDecl { body, .. } => visitor.visit_node_mut(body),
+        Node::FnCall { args, .. } => {
+            for arg in args {
+                visitor.visit_node_mut(arg);
+            }
+        }
+        Node::TupleExpr { elements, .. } => {
+            for element in elements {
+                visitor.visit_node_mut(element);
+            }
+        }
+        Node::TupleAssign {
+            call, assignments, ..
+        } => {
+            visitor.visit_node_mut(call);
+            for assignment in assignments {
+                visitor.visit_node_mut(assignment);
+            }
+        }
+        Node::TupleElement { .. } => {}
+        Node::Lambda { .. } => {}
+        Node::ClosureCall { callee, args, .. } => {
+            visitor.visit_node_mut(callee);
+            for arg in args {
+                visitor.visit_node_mut(arg);
+            }
+        }
+        Node::ReturnStmt { expr, .. } => visitor.visit_node_mut(expr),
+        Node::PostIncStmt { left, .. } => visitor.visit_node_mut(left),
+        Node::PostDecStmt { left, .. } => visitor.visit_node_mut(left),
+        Node::PreIncStmt { right, .. } => visitor.visit_node_mut(right),
+        Node::PreDecStmt { right, .. } => visitor.visit_node_mut(right),
+        Node::ToBool { expr, .. } => visitor.visit_node_mut(expr),
+    }
+}
@@ -0,0 +1,1417 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    codegen::Backend,
+    diagnostic::{Diagnostic, Result},
+    ir::{takes_command_line, Address, BinOp, Cond, Function, Inst, Label, Module, UnOp, VReg},
+    types::Type,
+};
+
+// Linear memory starts with the iovec at 0, the buffer printint and
+// printchar write out at 16 and the words of the profiler's report at 48,
+// then come the strings and the globals kept in memory and at the top the
+// stack, growing down.
+const DATA: u64 = 64;
+const STACK_SIZE: u64 = 1 << 20;
+const PAGE_SIZE: u64 = 1 << 16;
+
+// printint writes printf's %d of the low 32 bits backwards from the newline,
+// into the 32 bytes of the buffer, and printu64, printi64 and printhex all
+// 64 of them, in hex after 0x. What is written goes to the fd in
+// $crust.output, stdout but while crust.report writes to stderr. readint reads stdin a byte at a time,
+// skipping whatever comes before the digits, and exit is WASI's proc_exit.
+// A failed assert writes where it is to stderr and traps, WASI has no abort.
+// rand steps the same xorshift generator as the native runtimes, and clock_ms
+// asks WASI's monotonic clock for the time in nanoseconds, to the millisecond.
+// fopen opens the path in the first directory the runtime preopens, fd 3,
+// for reading, or creating and truncating with "w" and appending with "a",
+// with the rights to read, write, seek and tell. The FILE it gives is the fd.
+// fread and fwrite go on until all the items are moved or nothing more is.
+// strlen counts the bytes of a string up to its NUL, as printstr does before
+// writing them.
+// malloc hands out 8 byte aligned blocks of pages grown for it, and never
+// takes them back, there is no free.
+// getenv reads the environment the first time it is called, into pages of
+// its own grown for it: the number of variables, the pointers to them and
+// the `NAME=value` strings they point at.
+// The hooks of --instrument-functions count the calls into the record of the
+// function, linked onto a list the first time, and crust.report writes the
+// list out as the native runtimes do.
+// The command line is put on the stack for main: argv, with the pointers WASI
+// gives widened to the 8 bytes of a Crust pointer and a null one after them,
+// then the pointers WASI gives and the strings they point at. argc is left at
+// 0.
+const HELPERS: &str = "  (func $printint (param $value i64) (result i64)
+    local.get $value
+    i32.wrap_i64
+    i64.extend_i32_s
+    i32.const 1
+    i64.const 10
+    i32.const 10
+    call $crust.number)
+  (func $printu64 (param $value i64) (result i64)
+    local.get $value
+    i32.const 0
+    i64.const 10
+    i32.const 10
+    call $crust.number)
+  (func $printi64 (param $value i64) (result i64)
+    local.get $value
+    i32.const 1
+    i64.const 10
+    i32.const 10
+    call $crust.number)
+  (func $printhex (param $value i64) (result i64)
+    local.get $value
+    i32.const 0
+    i64.const 16
+    i32.const 10
+    call $crust.number)
+  (func $crust.number (param $n i64) (param $signed i32) (param $base i64) (param $end i32) (result i64)
+    (local $at i32) (local $negative i32) (local $digit i64)
+    local.get $signed
+    local.get $n
+    i64.const 0
+    i64.lt_s
+    i32.and
+    local.tee $negative
+    if
+      i64.const 0
+      local.get $n
+      i64.sub
+      local.set $n
+    end
+    i32.const 47
+    local.tee $at
+    local.get $end
+    i32.store8
+    loop $digit
+      local.get $at
+      i32.const 1
+      i32.sub
+      local.tee $at
+      local.get $n
+      local.get $base
+      i64.rem_u
+      local.tee $digit
+      i64.const 48
+      i64.add
+      local.get $digit
+      i64.const 9
+      i64.gt_u
+      i64.extend_i32_u
+      i64.const 39
+      i64.mul
+      i64.add
+      i64.store8
+      local.get $n
+      local.get $base
+      i64.div_u
+      local.tee $n
+      i64.const 0
+      i64.ne
+      br_if $digit
+    end
+    local.get $negative
+    if
+      local.get $at
+      i32.const 1
+      i32.sub
+      local.tee $at
+      i32.const 45
+      i32.store8
+    end
+    local.get $base
+    i64.const 16
+    i64.eq
+    if
+      local.get $at
+      i32.const 2
+      i32.sub
+      local.tee $at
+      i32.const 30768
+      i32.store16
+    end
+    local.get $at
+    i32.const 48
+    local.get $at
+    i32.sub
+    call $wasi.write
+    i64.const 0)
+  (func $printchar (param $value i64) (result i64)
+    i32.const 16
+    local.get $value
+    i64.store8
+    i32.const 16
+    i32.const 1
+    call $wasi.write
+    i64.const 0)
+  (func $printstr (param $value i64) (result i64)
+    (local $at i32) (local $end i32)
+    local.get $value
+    i32.wrap_i64
+    local.tee $at
+    local.set $end
+    block $found
+      loop $byte
+        local.get $end
+        i32.load8_u
+        i32.eqz
+        br_if $found
+        local.get $end
+        i32.const 1
+        i32.add
+        local.set $end
+        br $byte
+      end
+    end
+    local.get $at
+    local.get $end
+    local.get $at
+    i32.sub
+    call $wasi.write
+    i64.const 0)
+  (func $strlen (param $value i64) (result i64)
+    (local $at i32) (local $end i32)
+    local.get $value
+    i32.wrap_i64
+    local.tee $at
+    local.set $end
+    block $found
+      loop $byte
+        local.get $end
+        i32.load8_u
+        i32.eqz
+        br_if $found
+        local.get $end
+        i32.const 1
+        i32.add
+        local.set $end
+        br $byte
+      end
+    end
+    local.get $end
+    local.get $at
+    i32.sub
+    i64.extend_i32_u)
+  (func $readint (result i64)
+    (local $n i64) (local $c i32) (local $digits i32)
+    block $done
+      loop $byte
+        call $wasi.read
+        local.tee $c
+        i32.const 48
+        i32.sub
+        i32.const 10
+        i32.lt_u
+        if
+          local.get $n
+          i64.const 10
+          i64.mul
+          local.get $c
+          i32.const 48
+          i32.sub
+          i64.extend_i32_u
+          i64.add
+          local.set $n
+          i32.const 1
+          local.set $digits
+          br $byte
+        end
+        local.get $digits
+        br_if $done
+        local.get $c
+        i32.const 0
+        i32.lt_s
+        br_if $done
+        br $byte
+      end
+    end
+    local.get $n)
+  (func $wasi.read (result i32)
+    i32.const 0
+    i32.const 16
+    i32.store
+    i32.const 4
+    i32.const 1
+    i32.store
+    i32.const 0
+    i32.const 0
+    i32.const 1
+    i32.const 8
+    call $wasi.fd_read
+    drop
+    i32.const 8
+    i32.load
+    if (result i32)
+      i32.const 16
+      i32.load8_u
+    else
+      i32.const -1
+    end)
+  (func $exit (param $status i64) (result i64)
+    local.get $status
+    i32.wrap_i64
+    call $wasi.proc_exit
+    unreachable)
+  (global $crust.heap (mut i32) (i32.const 0))
+  (global $crust.heap.end (mut i32) (i32.const 0))
+  (func $malloc (param $size i64) (result i64)
+    (local $bytes i32) (local $block i32)
+    local.get $size
+    i32.wrap_i64
+    i32.const 7
+    i32.add
+    i32.const -8
+    i32.and
+    local.set $bytes
+    global.get $crust.heap
+    local.get $bytes
+    i32.add
+    global.get $crust.heap.end
+    i32.gt_u
+    if
+      local.get $bytes
+      i32.const 65535
+      i32.add
+      i32.const 16
+      i32.shr_u
+      local.tee $block
+      memory.grow
+      i32.const 16
+      i32.shl
+      global.set $crust.heap
+      global.get $crust.heap
+      local.get $block
+      i32.const 16
+      i32.shl
+      i32.add
+      global.set $crust.heap.end
+    end
+    global.get $crust.heap
+    local.tee $block
+    local.get $bytes
+    i32.add
+    global.set $crust.heap
+    local.get $block
+    i64.extend_i32_u)
+  (global $environ (mut i32) (i32.const 0))
+  (func $getenv (param $name i64) (result i64)
+    (local $key i32) (local $count i32) (local $i i32) (local $entry i32) (local $at i32)
+    (local $c i32)
+    global.get $environ
+    i32.eqz
+    if
+      i32.const 0
+      i32.const 4
+      call $wasi.environ_sizes_get
+      drop
+      i32.const 0
+      i32.load
+      local.set $count
+      i32.const 4
+      i32.load
+      local.get $count
+      i32.const 2
+      i32.shl
+      i32.add
+      i32.const 65539
+      i32.add
+      i32.const 16
+      i32.shr_u
+      memory.grow
+      i32.const 16
+      i32.shl
+      local.tee $entry
+      local.get $count
+      i32.store
+      local.get $entry
+      i32.const 4
+      i32.add
+      local.get $entry
+      i32.const 4
+      i32.add
+      local.get $count
+      i32.const 2
+      i32.shl
+      i32.add
+      call $wasi.environ_get
+      drop
+      local.get $entry
+      global.set $environ
+    end
+    global.get $environ
+    i32.load
+    local.set $count
+    local.get $name
+    i32.wrap_i64
+    local.set $key
+    block $missing
+      loop $variable
+        local.get $i
+        local.get $count
+        i32.ge_u
+        br_if $missing
+        global.get $environ
+        local.get $i
+        i32.const 2
+        i32.shl
+        i32.add
+        i32.load offset=4
+        local.set $entry
+        i32.const 0
+        local.set $at
+        block $next
+          loop $char
+            local.get $key
+            local.get $at
+            i32.add
+            i32.load8_u
+            local.tee $c
+            i32.eqz
+            if
+              local.get $entry
+              local.get $at
+              i32.add
+              i32.load8_u
+              i32.const 61
+              i32.ne
+              br_if $next
+              local.get $entry
+              local.get $at
+              i32.add
+              i32.const 1
+              i32.add
+              i64.extend_i32_u
+              return
+            end
+            local.get $c
+            local.get $entry
+            local.get $at
+            i32.add
+            i32.load8_u
+            i32.ne
+            br_if $next
+            local.get $at
+            i32.const 1
+            i32.add
+            local.set $at
+            br $char
+          end
+        end
+        local.get $i
+        i32.const 1
+        i32.add
+        local.set $i
+        br $variable
+      end
+    end
+    i64.const 0)
+  (func $crust.panic (param $message i64) (result i64)
+    (local $end i32)
+    local.get $message
+    i32.wrap_i64
+    local.set $end
+    block $found
+      loop $byte
+        local.get $end
+        i32.load8_u
+        i32.eqz
+        br_if $found
+        local.get $end
+        i32.const 1
+        i32.add
+        local.set $end
+        br $byte
+      end
+    end
+    i32.const 0
+    local.get $message
+    i64.store32
+    i32.const 4
+    local.get $end
+    local.get $message
+    i32.wrap_i64
+    i32.sub
+    i32.store
+    i32.const 2
+    i32.const 0
+    i32.const 1
+    i32.const 8
+    call $wasi.fd_write
+    drop
+    unreachable)
+  (global $crust.random (mut i64) (i64.const 88172645463325252))
+  (func $rand (result i64)
+    global.get $crust.random
+    global.get $crust.random
+    i64.const 13
+    i64.shl
+    i64.xor
+    global.set $crust.random
+    global.get $crust.random
+    global.get $crust.random
+    i64.const 7
+    i64.shr_u
+    i64.xor
+    global.set $crust.random
+    global.get $crust.random
+    global.get $crust.random
+    i64.const 17
+    i64.shl
+    i64.xor
+    global.set $crust.random
+    global.get $crust.random)
+  (func $clock_ms (result i64)
+    i32.const 1
+    i64.const 1000000
+    i32.const 0
+    call $wasi.clock_time_get
+    drop
+    i32.const 0
+    i64.load
+    i64.const 1000000
+    i64.div_u)
+  (func $fopen (param $path i64) (param $mode i64) (result i64)
+    (local $end i32) (local $oflags i32) (local $fdflags i32)
+    local.get $path
+    i32.wrap_i64
+    local.set $end
+    block $found
+      loop $byte
+        local.get $end
+        i32.load8_u
+        i32.eqz
+        br_if $found
+        local.get $end
+        i32.const 1
+        i32.add
+        local.set $end
+        br $byte
+      end
+    end
+    local.get $mode
+    i32.wrap_i64
+    i32.load8_u
+    i32.const 119
+    i32.eq
+    if
+      i32.const 9
+      local.set $oflags
+    end
+    local.get $mode
+    i32.wrap_i64
+    i32.load8_u
+    i32.const 97
+    i32.eq
+    if
+      i32.const 1
+      local.set $oflags
+      i32.const 1
+      local.set $fdflags
+    end
+    i32.const 3
+    i32.const 1
+    local.get $path
+    i32.wrap_i64
+    local.get $end
+    local.get $path
+    i32.wrap_i64
+    i32.sub
+    local.get $oflags
+    i64.const 102
+    i64.const 0
+    local.get $fdflags
+    i32.const 8
+    call $wasi.path_open
+    if
+      i64.const 0
+      return
+    end
+    i32.const 8
+    i64.load32_u)
+  (func $fread (param $buffer i64) (param $size i64) (param $count i64) (param $file i64) (result i64)
+    local.get $file
+    local.get $buffer
+    local.get $size
+    local.get $count
+    i32.const 0
+    call $wasi.transfer)
+  (func $fwrite (param $buffer i64) (param $size i64) (param $count i64) (param $file i64) (result i64)
+    local.get $file
+    local.get $buffer
+    local.get $size
+    local.get $count
+    i32.const 1
+    call $wasi.transfer)
+  (func $wasi.transfer (param $file i64) (param $buffer i64) (param $size i64) (param $count i64) (param $writing i32) (result i64)
+    (local $total i32) (local $want i32)
+    local.get $size
+    i64.eqz
+    if
+      i64.const 0
+      return
+    end
+    local.get $size
+    local.get $count
+    i64.mul
+    i32.wrap_i64
+    local.set $want
+    block $done
+      loop $more
+        local.get $total
+        local.get $want
+        i32.ge_u
+        br_if $done
+        i32.const 0
+        local.get $buffer
+        i32.wrap_i64
+        local.get $total
+        i32.add
+        i32.store
+        i32.const 4
+        local.get $want
+        local.get $total
+        i32.sub
+        i32.store
+        local.get $writing
+        if (result i32)
+          local.get $file
+          i32.wrap_i64
+          i32.const 0
+          i32.const 1
+          i32.const 8
+          call $wasi.fd_write
+        else
+          local.get $file
+          i32.wrap_i64
+          i32.const 0
+          i32.const 1
+          i32.const 8
+          call $wasi.fd_read
+        end
+        br_if $done
+        i32.const 8
+        i32.load
+        i32.eqz
+        br_if $done
+        local.get $total
+        i32.const 8
+        i32.load
+        i32.add
+        local.set $total
+        br $more
+      end
+    end
+    local.get $total
+    i64.extend_i32_u
+    local.get $size
+    i64.div_u)
+  (func $fclose (param $file i64) (result i64)
+    local.get $file
+    i32.wrap_i64
+    call $wasi.fd_close
+    if (result i64)
+      i64.const -1
+    else
+      i64.const 0
+    end)
+  (func $wasi.args (result i64)
+    (local $argc i32) (local $argv i32) (local $short i32) (local $i i32)
+    i32.const 0
+    i32.const 4
+    call $wasi.args_sizes_get
+    drop
+    i32.const 0
+    i32.load
+    local.set $argc
+    global.get $__stack_pointer
+    i32.wrap_i64
+    local.get $argc
+    i32.const 12
+    i32.mul
+    i32.const 8
+    i32.add
+    i32.const 4
+    i32.load
+    i32.add
+    i32.sub
+    i32.const -16
+    i32.and
+    local.tee $argv
+    i64.extend_i32_u
+    global.set $__stack_pointer
+    local.get $argv
+    local.get $argc
+    i32.const 1
+    i32.add
+    i32.const 3
+    i32.shl
+    i32.add
+    local.tee $short
+    local.get $short
+    local.get $argc
+    i32.const 2
+    i32.shl
+    i32.add
+    call $wasi.args_get
+    drop
+    block $done
+      loop $arg
+        local.get $i
+        local.get $argc
+        i32.ge_u
+        br_if $done
+        local.get $argv
+        local.get $i
+        i32.const 3
+        i32.shl
+        i32.add
+        local.get $short
+        local.get $i
+        i32.const 2
+        i32.shl
+        i32.add
+        i64.load32_u
+        i64.store
+        local.get $i
+        i32.const 1
+        i32.add
+        local.set $i
+        br $arg
+      end
+    end
+    local.get $argv
+    local.get $argc
+    i32.const 3
+    i32.shl
+    i32.add
+    i64.const 0
+    i64.store
+    local.get $argv
+    i64.extend_i32_u)
+  (global $crust.output (mut i32) (i32.const 1))
+  (func $wasi.write (param $at i32) (param $length i32)
+    i32.const 0
+    local.get $at
+    i32.store
+    i32.const 4
+    local.get $length
+    i32.store
+    global.get $crust.output
+    i32.const 0
+    i32.const 1
+    i32.const 8
+    call $wasi.fd_write
+    drop)
+  (data (i32.const 48) \": calls, deep\\0a\")
+  (global $crust.profile.first (mut i64) (i64.const 0))
+  (global $crust.profile.last (mut i64) (i64.const 0))
+  (func $crust.enter (param $record i64) (param $name i64) (result i64)
+    (local $at i32)
+    local.get $record
+    i32.wrap_i64
+    local.tee $at
+    i64.load
+    i64.eqz
+    if
+      local.get $at
+      local.get $name
+      i64.store offset=32
+      global.get $crust.profile.last
+      i64.eqz
+      if
+        local.get $record
+        global.set $crust.profile.first
+      else
+        global.get $crust.profile.last
+        i32.wrap_i64
+        local.get $record
+        i64.store offset=24
+      end
+      local.get $record
+      global.set $crust.profile.last
+    end
+    local.get $at
+    local.get $at
+    i64.load
+    i64.const 1
+    i64.add
+    i64.store
+    local.get $at
+    local.get $at
+    i64.load offset=8
+    i64.const 1
+    i64.add
+    i64.store offset=8
+    local.get $at
+    i64.load offset=8
+    local.get $at
+    i64.load offset=16
+    i64.gt_u
+    if
+      local.get $at
+      local.get $at
+      i64.load offset=8
+      i64.store offset=16
+    end
+    i64.const 0)
+  (func $crust.exit (param $record i64) (result i64)
+    (local $at i32)
+    local.get $record
+    i32.wrap_i64
+    local.tee $at
+    local.get $at
+    i64.load offset=8
+    i64.const 1
+    i64.sub
+    i64.store offset=8
+    i64.const 0)
+  (func $crust.report (result i64)
+    (local $at i32)
+    i32.const 2
+    global.set $crust.output
+    global.get $crust.profile.first
+    i32.wrap_i64
+    local.set $at
+    block $done
+      loop $record
+        local.get $at
+        i32.eqz
+        br_if $done
+        local.get $at
+        i64.load offset=32
+        call $printstr
+        drop
+        i32.const 48
+        i32.const 2
+        call $wasi.write
+        local.get $at
+        i64.load
+        i32.const 0
+        i64.const 10
+        i32.const 32
+        call $crust.number
+        drop
+        i32.const 50
+        i32.const 7
+        call $wasi.write
+        local.get $at
+        i64.load offset=16
+        i32.const 0
+        i64.const 10
+        i32.const 32
+        call $crust.number
+        drop
+        i32.const 57
+        i32.const 5
+        call $wasi.write
+        local.get $at
+        i64.load offset=24
+        i32.wrap_i64
+        local.set $at
+        br $record
+      end
+    end
+    i32.const 1
+    global.set $crust.output
+    i64.const 0)
+";
+
+fn compare_instruction(cond: Cond) -> &'static str {
+    match cond {
+        Cond::Eq => "i64.eq",
+        Cond::Ne => "i64.ne",
+        Cond::Lt => "i64.lt_s",
+        Cond::Le => "i64.le_s",
+        Cond::Gt => "i64.gt_s",
+        Cond::Ge => "i64.ge_s",
+        Cond::Ult => "i64.lt_u",
+        Cond::Ule => "i64.le_u",
+        Cond::Ugt => "i64.gt_u",
+        Cond::Uge => "i64.ge_u",
+    }
+}
+
+// A string as WAT writes it, anything but printable ASCII as hex escapes
+fn escape(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'"' | b'\\' => format!("\\{:02x}", byte),
+            0x20..=0x7e => (byte as char).to_string(),
+            _ => format!("\\{:02x}", byte),
+        })
+        .collect()
+}
+
+// The function's instructions split at its labels, the first block is the
+// one entered, and the block every label starts.
+fn blocks(insts: &[Inst]) -> (Vec<Vec<Inst>>, HashMap<Label, usize>) {
+    let mut blocks = vec![Vec::new()];
+    let mut starts = HashMap::new();
+    for inst in insts {
+        match inst {
+            Inst::Label(label) => {
+                starts.insert(*label, blocks.len());
+                blocks.push(Vec::new());
+            }
+            _ => blocks.last_mut().unwrap().push(inst.clone()),
+        }
+    }
+
+    (blocks, starts)
+}
+
+// Generates the WebAssembly text format for a WASI host. Every value is an
+// i64 and every virtual register a local. Locals and anything whose address
+// is taken live in linear memory, addressed by the low 32 bits of a pointer.
+// There are no jumps in WebAssembly, so each function is a loop around its
+// blocks that a branch sets the next one for and goes back to the top of.
+pub struct CodeGen {
+    module: Module,
+    wat: String,
+    // how deep the line being written is nested
+    depth: usize,
+    // where the string literals and the globals kept in memory start
+    addresses: HashMap<String, u64>,
+    // the scalar globals kept in wasm globals, when no pointer is taken to any
+    globals: HashSet<String>,
+    // the block every label of the function starts
+    starts: HashMap<Label, usize>,
+    // how many parameters every function takes
+    arities: HashMap<String, usize>,
+}
+
+impl Backend for CodeGen {
+    fn generate(&mut self) -> Result<String> {
+        self.line("(module");
+        self.depth += 1;
+        self.line("(import \"wasi_snapshot_preview1\" \"fd_write\" (func $wasi.fd_write (param i32 i32 i32 i32) (result i32)))");
+        self.line("(import \"wasi_snapshot_preview1\" \"fd_read\" (func $wasi.fd_read (param i32 i32 i32 i32) (result i32)))");
+        self.line(
+            "(import \"wasi_snapshot_preview1\" \"proc_exit\" (func $wasi.proc_exit (param i32)))",
+        );
+        self.line("(import \"wasi_snapshot_preview1\" \"args_sizes_get\" (func $wasi.args_sizes_get (param i32 i32) (result i32)))");
+        self.line("(import \"wasi_snapshot_preview1\" \"args_get\" (func $wasi.args_get (param i32 i32) (result i32)))");
+        self.line("(import \"wasi_snapshot_preview1\" \"environ_sizes_get\" (func $wasi.environ_sizes_get (param i32 i32) (result i32)))");
+        self.line("(import \"wasi_snapshot_preview1\" \"environ_get\" (func $wasi.environ_get (param i32 i32) (result i32)))");
+        self.line("(import \"wasi_snapshot_preview1\" \"clock_time_get\" (func $wasi.clock_time_get (param i32 i64 i32) (result i32)))");
+        self.line("(import \"wasi_snapshot_preview1\" \"path_open\" (func $wasi.path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))");
+        self.line("(import \"wasi_snapshot_preview1\" \"fd_close\" (func $wasi.fd_close (param i32) (result i32)))");
+        self.data()?;
+        self.wat.push_str(HELPERS);
+
+        self.arities = self
+            .module
+            .functions
+            .iter()
+            .map(|function| (function.name.clone(), function.params.len()))
+            .chain(
+                [
+                    "printint",
+                    "printu64",
+                    "printi64",
+                    "printhex",
+                    "printchar",
+                    "printstr",
+                    "exit",
+                    "getenv",
+                    "strlen",
+                    "malloc",
+                    "fclose",
+                    "crust.panic",
+                    "crust.exit",
+                ]
+                .map(|name| (name.to_string(), 1)),
+            )
+            .chain(
+                [
+                    ("fopen", 2),
+                    ("crust.enter", 2),
+                    ("fread", 4),
+                    ("fwrite", 4),
+                ]
+                .map(|(name, arity)| (name.to_string(), arity)),
+            )
+            .chain(
+                ["readint", "rand", "clock_ms", "crust.report"].map(|name| (name.to_string(), 0)),
+            )
+            .collect();
+
+        for function in self.module.functions.clone() {
+            self.function(&function)?;
+        }
+
+        // the value main returns is the exit status, it is given the command
+        // line if it takes it, otherwise any parameters it has are zero
+        self.line("(func $_start (export \"_start\")");
+        self.depth += 1;
+        let main = self
+            .module
+            .functions
+            .iter()
+            .find(|function| function.name == "main");
+        if main.is_some_and(|main| takes_command_line(&main.name, &main.params)) {
+            self.line("(local $argv i64)");
+            self.line("call $wasi.args");
+            self.line("local.set $argv");
+            self.line("i32.const 0");
+            self.line("i64.load32_u");
+            self.line("local.get $argv");
+        } else {
+            for _ in 0..self.arities.get("main").copied().unwrap_or(0) {
+                self.line("i64.const 0");
+            }
+        }
+        self.line("call $main");
+        self.line("i32.wrap_i64");
+        self.line("call $wasi.proc_exit)");
+        self.depth -= 2;
+        self.line(")");
+
+        Ok(std::mem::take(&mut self.wat))
+    }
+}
+
+impl CodeGen {
+    pub fn new(module: Module) -> Self {
+        Self {
+            module,
+            wat: String::new(),
+            depth: 0,
+            addresses: HashMap::new(),
+            globals: HashSet::new(),
+            starts: HashMap::new(),
+            arities: HashMap::new(),
+        }
+    }
+
+    fn line(&mut self, line: &str) {
+        self.wat.push_str(&"  ".repeat(self.depth));
+        self.wat.push_str(line);
+        self.wat.push('\n');
+    }
+
+    // Lays out linear memory. Identical string literals share their text,
+    // globals are zeroed, and so is memory, so only those with an initial
+    // value need a data segment.
+    // Pointer arithmetic may step from one global to the next, as it would
+    // in .bss, so once an address of any is taken they all go in memory, one
+    // after the other. There are no sections to place anything in, so
+    // `#[section]` is ignored, but `#[align]` is kept for globals in memory.
+    // Nothing is linked with the module, so there is no extern global.
+    fn data(&mut self) -> Result<()> {
+        if let Some(global) = self.module.globals.iter().find(|global| global.external) {
+            return Err(Diagnostic::error(format!(
+                "Extern global {} has nothing to be linked with on WebAssembly",
+                global.name
+            )));
+        }
+
+        let pointed_to = self
+            .module
+            .functions
+            .iter()
+            .flat_map(|function| &function.insts)
+            .any(|inst| {
+                matches!(
+                    inst,
+                    Inst::Lea {
+                        addr: Address::Global(_),
+                        ..
+                    }
+                )
+            });
+
+        let mut end = DATA;
+        let mut pool: HashMap<String, u64> = HashMap::new();
+        let mut segments = Vec::new();
+        for string in self.module.strings.clone() {
+            // a literal not seen before goes where memory is taken up to
+            let address = *pool.entry(string.value.clone()).or_insert(end);
+            if address == end {
+                let text = escape(&string.value);
+                segments.push(format!("(data (i32.const {}) \"{}\\00\")", end, text));
+                end += string.value.len() as u64 + 1;
+            }
+            self.addresses.insert(string.label, address);
+        }
+
+        let mut globals = Vec::new();
+        for global in self.module.globals.clone() {
+            if matches!(global.ty, Type::Array { .. }) || pointed_to {
+                let align = global.placement.align.unwrap_or(1).max(global.ty.align()) as u64;
+                end = end.div_ceil(align) * align;
+                if let Some(value) = global.init {
+                    let bytes = value.to_le_bytes()[..global.ty.storage_size()]
+                        .iter()
+                        .map(|byte| format!("\\{:02x}", byte))
+                        .collect::<String>();
+                    segments.push(format!("(data (i32.const {}) \"{}\")", end, bytes));
+                }
+                self.addresses.insert(global.name, end);
+                end += global.ty.storage_size() as u64;
+            } else {
+                globals.push(format!(
+                    "(global ${} (mut i64) (i64.const {}))",
+                    global.name,
+                    global.init.unwrap_or(0)
+                ));
+                self.globals.insert(global.name);
+            }
+        }
+
+        let pages = (end + STACK_SIZE).div_ceil(PAGE_SIZE);
+        self.line(&format!("(memory (export \"memory\") {})", pages));
+        self.line(&format!(
+            "(global $__stack_pointer (mut i64) (i64.const {}))",
+            pages * PAGE_SIZE
+        ));
+        for line in globals.iter().chain(&segments) {
+            self.line(line);
+        }
+        Ok(())
+    }
+
+    fn function(&mut self, function: &Function) -> Result<()> {
+        let params = (0..function.params.len())
+            .map(|i| format!(" (param $p{} i64)", i))
+            .collect::<String>();
+        self.line(&format!("(func ${}{} (result i64)", function.name, params));
+        self.depth += 1;
+
+        let mut vregs = function
+            .insts
+            .iter()
+            .flat_map(|inst| inst.uses().into_iter().chain(inst.def()))
+            .collect::<Vec<_>>();
+        vregs.sort();
+        vregs.dedup();
+        let locals = vregs
+            .iter()
+            .map(|vreg| format!(" (local $v{} i64)", vreg))
+            .collect::<String>();
+        self.line(&format!("(local $fp i64) (local $block i32){}", locals));
+
+        // the frame holds the locals, kept a multiple of 16
+        let size = (function.stack_size + 15) & !15;
+        self.line("global.get $__stack_pointer");
+        self.line("local.tee $fp");
+        self.line(&format!("i64.const {}", size));
+        self.line("i64.sub");
+        self.line("global.set $__stack_pointer");
+
+        // every parameter is copied to its slot
+        for (i, param) in function.params.iter().enumerate() {
+            self.address(&Address::Local(param.offset));
+            self.line(&format!("local.get $p{}", i));
+            self.store(&param.ty)?;
+        }
+
+        // Block i starts after the end of the i-th innermost wasm block, so
+        // breaking out of that one goes to it and the end of the one before
+        // falls through into it
+        let (blocks, starts) = blocks(&function.insts);
+        self.starts = starts;
+        self.line("loop $dispatch");
+        self.depth += 1;
+        for i in (0..blocks.len()).rev() {
+            self.line(&format!("block $b{}", i));
+            self.depth += 1;
+        }
+        self.line("local.get $block");
+        let targets = (0..blocks.len())
+            .map(|i| format!(" $b{}", i))
+            .collect::<String>();
+        self.line(&format!("br_table{}", targets));
+        for block in &blocks {
+            self.depth -= 1;
+            self.line("end");
+            for inst in block {
+                self.instruction(inst)?;
+            }
+        }
+        self.depth -= 1;
+        self.line("end");
+
+        // falling off the end returns nothing in particular
+        self.leave_frame();
+        self.line("i64.const 0)");
+        self.depth -= 1;
+
+        Ok(())
+    }
+
+    fn leave_frame(&mut self) {
+        self.line("local.get $fp");
+        self.line("global.set $__stack_pointer");
+    }
+
+    fn get(&mut self, vreg: VReg) {
+        self.line(&format!("local.get $v{}", vreg));
+    }
+
+    fn set(&mut self, vreg: VReg) {
+        self.line(&format!("local.set $v{}", vreg));
+    }
+
+    // continues at the block a label starts, from the top of the loop
+    fn jump(&mut self, target: Label) -> Result<()> {
+        let block = self
+            .starts
+            .get(&target)
+            .ok_or_else(|| Diagnostic::error(format!("Unknown label L{}", target)))?;
+        self.line(&format!("i32.const {}", block));
+        self.line("local.set $block");
+        self.line("br $dispatch");
+        Ok(())
+    }
+
+    // pushes the address in linear memory of something kept there
+    fn address(&mut self, address: &Address) {
+        match address {
+            Address::Global(name) => {
+                self.line(&format!("i32.const {}", self.addresses[name]));
+            }
+            Address::Local(offset) => {
+                self.line("local.get $fp");
+                self.line(&format!("i64.const {}", offset));
+                self.line("i64.add");
+                self.line("i32.wrap_i64");
+            }
+            Address::Indirect(vreg) => {
+                self.get(*vreg);
+                self.line("i32.wrap_i64");
+            }
+        }
+    }
+
+    fn instruction(&mut self, inst: &Inst) -> Result<()> {
+        match inst {
+            Inst::Const { dst, value } => {
+                self.line(&format!("i64.const {}", *value as i64));
+                self.set(*dst);
+            }
+            Inst::Copy { dst, src } => {
+                self.get(*src);
+                self.set(*dst);
+            }
+            Inst::Cast { dst, src, ty } => {
+                self.get(*src);
+                self.cast(ty);
+                self.set(*dst);
+            }
+            Inst::Binary {
+                op,
+                dst,
+                left,
+                right,
+            } => {
+                self.get(*left);
+                self.get(*right);
+                let instruction = match op {
+                    BinOp::Add => "i64.add",
+                    BinOp::Sub => "i64.sub",
+                    BinOp::Mul => "i64.mul",
+                    BinOp::Div => "i64.div_s",
+                    BinOp::UDiv => "i64.div_u",
+                    BinOp::And => "i64.and",
+                    BinOp::Or => "i64.or",
+                    BinOp::Xor => "i64.xor",
+                    BinOp::Shl => "i64.shl",
+                    BinOp::Shr => "i64.shr_s",
+                    BinOp::UShr => "i64.shr_u",
+                };
+                self.line(instruction);
+                self.set(*dst);
+            }
+            Inst::Unary { op, dst, src } => {
+                match op {
+                    UnOp::Neg => {
+                        self.line("i64.const 0");
+                        self.get(*src);
+                        self.line("i64.sub");
+                    }
+                    UnOp::Not => {
+                        self.get(*src);
+                        self.line("i64.const -1");
+                        self.line("i64.xor");
+                    }
+                    UnOp::LogicalNot => {
+                        self.get(*src);
+                        self.line("i64.eqz");
+                        self.line("i64.extend_i32_u");
+                    }
+                    UnOp::ToBool => {
+                        self.get(*src);
+                        self.line("i64.const 0");
+                        self.line("i64.ne");
+                        self.line("i64.extend_i32_u");
+                    }
+                }
+                self.set(*dst);
+            }
+            Inst::Compare {
+                cond,
+                dst,
+                left,
+                right,
+            } => {
+                self.get(*left);
+                self.get(*right);
+                self.line(compare_instruction(*cond));
+                self.line("i64.extend_i32_u");
+                self.set(*dst);
+            }
+            Inst::Select {
+                cond,
+                dst,
+                left,
+                right,
+                then,
+                otherwise,
+            } => {
+                self.get(*then);
+                self.get(*otherwise);
+                self.get(*left);
+                self.get(*right);
+                self.line(compare_instruction(*cond));
+                self.line("select");
+                self.set(*dst);
+            }
+            // wasm globals hold their value already cut down to their type
+            Inst::Load {
+                dst,
+                addr: Address::Global(name),
+                ..
+            } if self.globals.contains(name) => {
+                self.line(&format!("global.get ${}", name));
+                self.set(*dst);
+            }
+            Inst::Store {
+                src,
+                addr: Address::Global(name),
+                ty,
+            } if self.globals.contains(name) => {
+                self.get(*src);
+                self.cast(ty);
+                self.line(&format!("global.set ${}", name));
+            }
+            Inst::Load { dst, addr, ty } => {
+                self.address(addr);
+                self.load(ty)?;
+                self.set(*dst);
+            }
+            Inst::Store { src, addr, ty } => {
+                self.address(addr);
+                self.get(*src);
+                self.store(ty)?;
+            }
+            Inst::Lea { dst, addr } => {
+                match addr {
+                    Address::Global(name) => {
+                        self.line(&format!("i64.const {}", self.addresses[name]));
+                    }
+                    Address::Local(offset) => {
+                        self.line("local.get $fp");
+                        self.line(&format!("i64.const {}", offset));
+                        self.line("i64.add");
+                    }
+                    Address::Indirect(vreg) => self.get(*vreg),
+                }
+                self.set(*dst);
+            }
+            // A call has to pass exactly the parameters a function takes.
+            // Arguments beyond them are left out and missing ones are 0, as
+            // they would be left in registers nobody looks at.
+            Inst::Call { dst, name, args } => {
+                let arity = self.arities.get(name).copied().unwrap_or(args.len());
+                for arg in args.iter().take(arity) {
+                    self.get(*arg);
+                }
+                for _ in args.len()..arity {
+                    self.line("i64.const 0");
+                }
+                self.line(&format!("call ${}", name));
+                self.set(*dst);
+            }
+            Inst::Return { src, ty } => {
+                if !ty.is_int() && !matches!(ty, Type::Pointer { .. } | Type::Closure { .. }) {
+                    return Err(Diagnostic::error(format!("Unexpected type {:?}", ty)));
+                }
+                self.get(*src);
+                self.cast(ty);
+                self.leave_frame();
+                self.line("return");
+            }
+            Inst::Jump { target } => self.jump(*target)?,
+            Inst::Branch {
+                cond,
+                left,
+                right,
+                target,
+            } => {
+                self.get(*left);
+                self.get(*right);
+                self.line(compare_instruction(*cond));
+                self.line("if");
+                self.depth += 1;
+                self.jump(*target)?;
+                self.depth -= 1;
+                self.line("end");
+            }
+            Inst::Label(_) => {}
+            Inst::Phi { .. } => {
+                return Err(Diagnostic::error("Unexpected phi outside of SSA form"));
+            }
+        }
+
+        Ok(())
+    }
+
+    // cuts the value on top of the stack down to a type, extending it back
+    // by its signedness
+    fn cast(&mut self, ty: &Type) {
+        let mask = match ty {
+            Type::U8 | Type::Char => 0xff_u64,
+            Type::U16 => 0xffff,
+            Type::U32 => 0xffff_ffff,
+            Type::I8 => return self.line("i64.extend8_s"),
+            Type::I16 => return self.line("i64.extend16_s"),
+            Type::I32 => return self.line("i64.extend32_s"),
+            _ => return,
+        };
+        self.line(&format!("i64.const {}", mask));
+        self.line("i64.and");
+    }
+
+    fn load(&mut self, ty: &Type) -> Result<()> {
+        let instruction = match ty {
+            Type::U8 | Type::Char => "i64.load8_u",
+            Type::I8 => "i64.load8_s",
+            Type::U16 => "i64.load16_u",
+            Type::I16 => "i64.load16_s",
+            Type::U32 => "i64.load32_u",
+            Type::I32 => "i64.load32_s",
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => "i64.load",
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+        };
+
+        self.line(instruction);
+        Ok(())
+    }
+
+    fn store(&mut self, ty: &Type) -> Result<()> {
+        let instruction = match ty {
+            Type::U8 | Type::I8 | Type::Char => "i64.store8",
+            Type::U16 | Type::I16 => "i64.store16",
+            Type::U32 | Type::I32 => "i64.store32",
+            Type::U64 | Type::I64 | Type::Pointer { .. } | Type::Closure { .. } => "i64.store",
+            _ => return Err(Diagnostic::error(format!("Unexpected type {:?}", ty))),
+        };
+
+        self.line(instruction);
+        Ok(())
+    }
+}
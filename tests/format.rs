@@ -0,0 +1,73 @@
+// Formatting a program that is formatted already changes nothing, and the
+// program formatted parses to what it did before, for every test program
+// that `crust fmt` would take.
+
+use std::fs;
+
+use crust::{
+    format::format,
+    lexer::Lexer,
+    parser::Parser,
+    preprocess::{preprocess, Includes},
+    printer::dump_ast,
+};
+
+// the source in the canonical layout, if it lexes
+fn formatted(source: &str) -> Option<String> {
+    let mut lexer = Lexer::new(source.to_string()).with_comments();
+    Some(format(lexer.scan_tokens().ok()?))
+}
+
+// the syntax tree as text, if the source of the program parses
+fn parse(name: &str, source: &str) -> Option<String> {
+    let mut lexer = Lexer::new(source.to_string());
+    let includes = Includes {
+        files: vec![(format!("tests/{}", name), 1)],
+        paths: vec![String::from("tests/include")],
+    };
+    let tokens = preprocess(lexer.scan_tokens().ok()?.clone(), &includes, &[]).ok()?;
+    Parser::new(tokens)
+        .parse()
+        .ok()
+        .map(|nodes| dump_ast(nodes))
+}
+
+// the test programs that parse, by name
+fn programs() -> Vec<(String, String)> {
+    let mut programs: Vec<(String, String)> = fs::read_dir("tests")
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("test") && !name.contains('.'))
+        .map(|name| {
+            let source = fs::read_to_string(format!("tests/{}", name)).unwrap();
+            (name, source)
+        })
+        .filter(|(name, source)| parse(name, source).is_some())
+        .collect();
+    programs.sort();
+    programs
+}
+
+#[test]
+fn formatting_twice_is_formatting_once() {
+    let programs = programs();
+    assert!(!programs.is_empty());
+    for (name, source) in programs {
+        let once = formatted(&source).unwrap();
+        let twice = formatted(&once).unwrap();
+        assert_eq!(twice, once, "{} formats differently the second time", name);
+    }
+}
+
+#[test]
+fn formatting_keeps_the_program() {
+    for (name, source) in programs() {
+        let once = formatted(&source).unwrap();
+        assert_eq!(
+            parse(&name, &once),
+            parse(&name, &source),
+            "{} parses differently",
+            name
+        );
+    }
+}
@@ -0,0 +1,164 @@
+// An edited `Document` has to parse to what parsing its source from scratch
+// gives, whichever of its items the edits made it lex and parse again.
+
+use crust::{
+    diagnostic::Diagnostic,
+    incremental::{Document, Edit},
+    lexer::Lexer,
+    parser::Parser,
+    preprocess::{preprocess, Includes},
+    printer::dump_ast,
+};
+
+const SOURCE: &str = "let limit: u32 = 10;
+const STEP: u8 = 2;
+
+// sums every STEP-th number below the limit
+fn sum(from: u32): u32 {
+  let total: u32 = 0;
+  let i: u32;
+  for (i = from; i < limit; i = i + STEP) {
+    total = total + i;
+  }
+  return total;
+}
+
+fn main(): u8 {
+  printint(sum(1));
+  return 0;
+}
+";
+
+// the syntax tree as text, or the errors, of parsing the whole source
+fn parse(source: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut lexer = Lexer::new(source.to_string());
+    let tokens = preprocess(lexer.scan_tokens()?.clone(), &Includes::default(), &[])?;
+    let mut parser = Parser::new(tokens);
+    parser.parse().map(|nodes| dump_ast(nodes))
+}
+
+// makes the edit, and checks the document parses as its new source does
+fn edit(document: &mut Document, range: std::ops::Range<usize>, text: &str) {
+    document
+        .edit(Edit {
+            range: range.clone(),
+            text: text.to_string(),
+        })
+        .unwrap();
+    let parsed = document.parse().map(|nodes| dump_ast(&nodes));
+    assert_eq!(
+        parsed,
+        parse(document.source()),
+        "after replacing {:?} with {:?}:\n{}",
+        range,
+        text,
+        document.source()
+    );
+}
+
+fn at(source: &str, text: &str) -> std::ops::Range<usize> {
+    let start = source.find(text).unwrap();
+    start..start + text.len()
+}
+
+#[test]
+fn edits_in_a_body() {
+    let mut document = Document::new(SOURCE.to_string());
+    assert_eq!(
+        document.parse().map(|nodes| dump_ast(&nodes)),
+        parse(SOURCE)
+    );
+    edit(&mut document, at(SOURCE, "sum(1)"), "sum(3) + sum(4)");
+    let range = at(document.source(), "total + i");
+    edit(&mut document, range, "total + i * 2");
+    let range = at(document.source(), "return 0;");
+    edit(&mut document, range, "return 1;");
+}
+
+#[test]
+fn edits_of_the_declarations() {
+    let mut document = Document::new(SOURCE.to_string());
+    document.parse().unwrap();
+    // the functions kept refer to the global as it is declared now
+    edit(&mut document, at(SOURCE, "u32 = 10"), "u64 = 10");
+    let range = at(document.source(), "const STEP: u8 = 2;");
+    edit(&mut document, range, "const STEP: u8 = 3;");
+    let end = document.source().len();
+    edit(
+        &mut document,
+        end..end,
+        "\nfn twice(x: u32): u32 {\n  return x * 2;\n}\n",
+    );
+    let range = at(document.source(), "sum(1)");
+    edit(&mut document, range, "twice(sum(1))");
+}
+
+#[test]
+fn edits_that_break_and_mend_the_source() {
+    let mut document = Document::new(SOURCE.to_string());
+    document.parse().unwrap();
+    // a brace left open runs the function on into the next
+    let range = at(SOURCE, "  return total;\n}");
+    edit(&mut document, range.end - 1..range.end, "");
+    let range = at(document.source(), "  return total;\n");
+    edit(&mut document, range.end..range.end, "}");
+    // a comment left open and closed again
+    edit(&mut document, 0..0, "/");
+    edit(&mut document, 0..1, "");
+    // a character the lexer doesn't know
+    let range = at(document.source(), "sum(1)");
+    edit(&mut document, range.start..range.start, "$");
+    edit(&mut document, range.start..range.start + 1, "");
+}
+
+#[test]
+fn every_character_taken_out_and_put_back() {
+    let mut document = Document::new(SOURCE.to_string());
+    document.parse().unwrap();
+    for (i, c) in SOURCE.char_indices() {
+        edit(&mut document, i..i + c.len_utf8(), "");
+        edit(&mut document, i..i, &c.to_string());
+    }
+    assert_eq!(document.source(), SOURCE);
+}
+
+#[test]
+fn characters_of_several_bytes() {
+    let mut document = Document::new(SOURCE.to_string());
+    document.parse().unwrap();
+    edit(&mut document, 0..0, "// é ü\n");
+    let range = at(document.source(), "total = total + i;");
+    edit(
+        &mut document,
+        range.start..range.start,
+        "let é: u32 = 1;\n    ",
+    );
+    let range = at(document.source(), "i;\n  }");
+    edit(&mut document, range.start..range.start + 1, "é");
+}
+
+#[test]
+#[allow(clippy::reversed_empty_ranges)]
+fn edits_outside_the_source() {
+    let mut document = Document::new("// é\nfn main(): u8 {\n  return 0;\n}\n".to_string());
+    let source = document.source().to_string();
+    let edits = [
+        // past the end
+        source.len()..source.len() + 1,
+        // ending before it starts
+        5..4,
+        // within the é
+        4..4,
+        3..4,
+        4..6,
+    ];
+    for range in edits {
+        let edit = Edit {
+            range: range.clone(),
+            text: String::from("x"),
+        };
+        assert!(document.edit(edit).is_err(), "{:?} was taken", range);
+        assert_eq!(document.source(), source);
+    }
+    assert!(document.parse().is_ok());
+}